@@ -0,0 +1,151 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Tests of buddy allocator against a heap-backed arena
+
+use crate::buddy::*;
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+
+/// Frame size in bytes, same as in allocator
+const FRAME_SIZE: usize = 4096;
+const MAX_BLOCK: usize = FRAME_SIZE << (ORDERS - 1);
+const FRAMES: usize = 1 << (ORDERS - 1);
+
+/// Heap memory aligned to the largest block
+struct Arena {
+    addr: usize,
+    layout: Layout,
+}
+
+impl Arena {
+    fn new() -> Self {
+        let layout = Layout::from_size_align(MAX_BLOCK, MAX_BLOCK).unwrap();
+        let addr = unsafe { alloc_zeroed(layout) } as usize;
+        assert_ne!(addr, 0);
+        Self { addr, layout }
+    }
+
+    fn contains(&self, addr: usize, order: usize) -> bool {
+        addr >= self.addr && addr + (FRAME_SIZE << order) <= self.addr + MAX_BLOCK
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.addr as *mut u8, self.layout) }
+    }
+}
+
+fn buddy_on(arena: &Arena) -> Box<BuddyAllocator<FRAMES>> {
+    let mut buddy = Box::new(BuddyAllocator::new());
+    buddy.init(arena.addr, arena.addr + MAX_BLOCK);
+    buddy
+}
+
+/// Fill block at `addr` with `val`
+fn fill(addr: usize, order: usize, val: u8) {
+    unsafe { core::ptr::write_bytes(addr as *mut u8, val, FRAME_SIZE << order) }
+}
+
+/// Whether block at `addr` is filled with `val`
+fn filled(addr: usize, order: usize, val: u8) -> bool {
+    let block = unsafe { core::slice::from_raw_parts(addr as *const u8, FRAME_SIZE << order) };
+    block.iter().all(|&b| b == val)
+}
+
+#[test]
+fn kernel_tests() {
+    for (_, test) in tests::tests() {
+        test();
+    }
+}
+
+/// Test allocating a frame splits the largest block into one free block
+/// of each smaller order, and blocks handed out don't overlap
+#[test]
+fn split() {
+    let arena = Arena::new();
+    let mut buddy = buddy_on(&arena);
+    let a = buddy.alloc(0).unwrap();
+    assert_eq!(a, arena.addr);
+    let stats = buddy.stats();
+    assert_eq!(stats.free_frames, FRAMES - 1);
+    for o in 0..ORDERS - 1 {
+        assert_eq!(stats.free_blocks[o], 1);
+    }
+    assert_eq!(stats.free_blocks[ORDERS - 1], 0);
+
+    let blocks = [(a, 0), (buddy.alloc(2).unwrap(), 2), (buddy.alloc(0).unwrap(), 0), (buddy.alloc(5).unwrap(), 5)];
+    for (i, &(addr, order)) in blocks.iter().enumerate() {
+        assert!(arena.contains(addr, order));
+        assert_eq!((addr - arena.addr) % (FRAME_SIZE << order), 0);
+        fill(addr, order, i as u8 + 1);
+    }
+    for (i, &(addr, order)) in blocks.iter().enumerate() {
+        assert!(filled(addr, order, i as u8 + 1));
+    }
+}
+
+/// Test freed blocks are merged with their buddies back into the
+/// largest block, regardless of freeing order
+#[test]
+fn merge() {
+    let arena = Arena::new();
+    let mut buddy = buddy_on(&arena);
+    let blocks: Vec<usize> = (0..16).map(|_| buddy.alloc(0).unwrap()).collect();
+    let big = buddy.alloc(4).unwrap();
+    for &addr in blocks.iter().step_by(2) {
+        assert_eq!(buddy.free(addr), 0);
+    }
+    // no two free frames are buddies yet
+    assert_eq!(buddy.stats().free_blocks[0], 8);
+    for &addr in blocks.iter().skip(1).step_by(2) {
+        buddy.free(addr);
+    }
+    assert_eq!(buddy.stats().free_blocks[0], 0);
+    assert_eq!(buddy.free(big), 4);
+    let stats = buddy.stats();
+    assert_eq!(stats.free_frames, FRAMES);
+    assert_eq!(stats.free_blocks[ORDERS - 1], 1);
+    assert_eq!(stats.fragmentation(), 0);
+    assert_eq!(buddy.alloc(ORDERS - 1), Some(arena.addr));
+}
+
+/// Test every frame can be allocated once, after which allocation fails
+/// until a block is freed
+#[test]
+fn exhaustion() {
+    let arena = Arena::new();
+    let mut buddy = buddy_on(&arena);
+    let mut frames = Vec::new();
+    while let Some(addr) = buddy.alloc(0) {
+        assert!(arena.contains(addr, 0));
+        frames.push(addr);
+    }
+    assert_eq!(frames.len(), FRAMES);
+    frames.sort();
+    frames.dedup();
+    assert_eq!(frames.len(), FRAMES);
+    let stats = buddy.stats();
+    assert_eq!(stats.free_frames, 0);
+    assert_eq!(stats.largest_free_order(), None);
+    assert!(buddy.alloc(1).is_none());
+
+    let last = frames.pop().unwrap();
+    buddy.free(last);
+    assert!(buddy.alloc(1).is_none());
+    assert_eq!(buddy.alloc(0), Some(last));
+    assert!(buddy.alloc(0).is_none());
+}
+
+#[test]
+#[should_panic]
+fn double_free() {
+    let arena = Arena::new();
+    let mut buddy = buddy_on(&arena);
+    let a = buddy.alloc(0).unwrap();
+    buddy.free(a);
+    buddy.free(a);
+}
@@ -0,0 +1,21 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Kernel modules which don't touch hardware, built for host
+//!
+//! Modules are included from kernel source, so that they are tested on host
+//! as they are in kernel. Other modules are tested in kernel by
+//! `test::run_tests`.
+
+#![feature(const_generics)]
+#![allow(dead_code)]
+
+extern crate alloc;
+
+#[path = "../../src/mem/buddy.rs"]
+pub mod buddy;
+
+#[cfg(test)]
+mod buddy_tests;
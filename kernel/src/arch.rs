@@ -11,10 +11,25 @@ use riscv::register::*;
 use crate::symbols::*;
 use core::sync::atomic::Ordering;
 
-/// Get current time from MMIO
+/// Get current value of `mtime`, from MMIO, or from `time` CSR under SBI
+/// as CLINT is protected by firmware.
+pub fn mtime() -> u64 {
+    #[cfg(not(feature = "sbi"))]
+    {
+        let mtime = crate::clint::CLINT_MTIME_BASE() as *const u64;
+        unsafe { mtime.read_volatile() }
+    }
+    #[cfg(feature = "sbi")]
+    {
+        riscv::register::time::read() as u64
+    }
+}
+
+/// Get current time
 pub fn time() -> Duration {
-    let mtime = crate::clint::CLINT_MTIME_BASE as *const u64;
-    Duration::from_nanos(unsafe { mtime.read_volatile() } * 100)
+    let timebase = crate::platform::platform().timebase as u64;
+    let ticks = mtime();
+    Duration::new(ticks / timebase, ((ticks % timebase) * 1_000_000_000 / timebase) as u32)
 }
 
 /// Build satp value from mode, asid and page table base addr
@@ -86,6 +101,29 @@ pub fn r_satp() -> usize {
     x
 }
 
+#[inline]
+pub fn w_satp(x: usize) {
+    unsafe { llvm_asm!("csrw satp, $0" :: "r"(x) :: "volatile"); }
+}
+
+/// Flush all TLB entries of current hart
+#[inline]
+pub fn sfence_vma_all() {
+    unsafe { llvm_asm!("sfence.vma zero, zero" :::: "volatile"); }
+}
+
+/// Flush TLB entries of `asid` on current hart
+#[inline]
+pub fn sfence_vma_asid(asid: usize) {
+    unsafe { llvm_asm!("sfence.vma zero, $0" :: "r"(asid) :: "volatile"); }
+}
+
+/// Flush TLB entries of `vaddr` in `asid` on current hart
+#[inline]
+pub fn sfence_vma_page(vaddr: usize, asid: usize) {
+    unsafe { llvm_asm!("sfence.vma $0, $1" :: "r"(vaddr), "r"(asid) :: "volatile"); }
+}
+
 #[inline]
 pub fn w_sstatus(x: usize) {
     unsafe { llvm_asm!("csrw sstatus, $0" :: "r"(x) :: "volatile"); }
@@ -0,0 +1,4 @@
+// This file is automatically generated with `param.h.py`,
+// which contains constants shared by assembly and Rust.
+
+#define NCPUS 8
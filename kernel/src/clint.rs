@@ -10,13 +10,16 @@
 use crate::symbols::{NCPUS, SCHEDULER_INTERVAL};
 use crate::println;
 use crate::arch::{hart_id, sp};
+use crate::platform::platform;
 
-pub const CLINT_BASE: usize = 0x200_0000;
-pub const CLINT_MTIMECMP_BASE: usize = CLINT_BASE + 0x4000;
-pub const fn CLINT_MTIMECMP(hart: usize) -> usize { CLINT_MTIMECMP_BASE + 8 * hart }
-pub const CLINT_MTIME_BASE: usize = CLINT_BASE + 0xBFF8;
+pub fn CLINT_BASE() -> usize { platform().clint.base }
+pub fn CLINT_MTIMECMP_BASE() -> usize { CLINT_BASE() + 0x4000 }
+pub fn CLINT_MTIMECMP(hart: usize) -> usize { CLINT_MTIMECMP_BASE() + 8 * hart }
+pub fn CLINT_MTIME_BASE() -> usize { CLINT_BASE() + 0xBFF8 }
+pub fn CLINT_MSIP(hart: usize) -> usize { CLINT_BASE() + 4 * hart }
 
 /// space for timer trap to save information.
+/// scratch[6] is address of MSIP, and scratch[7] is set when timer fires.
 static mut MSCRATCH0: [[u64; 8]; NCPUS] = [[0; 8]; NCPUS];
 
 /// Initialize machine-mode timer interrupt
@@ -25,7 +28,7 @@ pub unsafe fn timer_init() {
     let id = mhartid::read();
     let interval = SCHEDULER_INTERVAL as u64;
     let mtimecmp = CLINT_MTIMECMP(id) as *mut u64;
-    let mtime = CLINT_MTIME_BASE as *const u64;
+    let mtime = CLINT_MTIME_BASE() as *const u64;
     mtimecmp.write_volatile(mtime.read_volatile() + interval);
     let scratch = &mut MSCRATCH0[id];
 
@@ -34,6 +37,8 @@ pub unsafe fn timer_init() {
     scratch[3] = mtime as u64;
     scratch[4] = mtimecmp as u64;
     scratch[5] = interval;
+    scratch[6] = CLINT_MSIP(id) as u64;
+    scratch[7] = 0;
 
     // set machine-mode trap handler as timervec in kernelvec.S
     mtvec::write(crate::symbols::timervec as usize, mtvec::TrapMode::Direct);
@@ -43,6 +48,25 @@ pub unsafe fn timer_init() {
 
     // enable machine-mode timer interrupt.
     mie::set_mtimer();
+
+    // enable machine-mode software interrupt, which is forwarded as IPI.
+    mie::set_msoft();
+}
+
+/// Send software interrupt to `hart`, which is forwarded to supervisor mode
+/// by `timervec`
+pub fn send_ipi(hart: usize) {
+    unsafe { (CLINT_MSIP(hart) as *mut u32).write_volatile(1); }
+}
+
+/// Whether timer has fired since last call on current hart
+pub fn take_tick() -> bool {
+    unsafe {
+        let flag = &mut MSCRATCH0[hart_id()][7] as *mut u64;
+        let fired = flag.read_volatile() != 0;
+        flag.write_volatile(0);
+        fired
+    }
 }
 
 pub fn debug() {
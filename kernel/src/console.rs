@@ -0,0 +1,44 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Console terminal
+//!
+//! Characters received by `uartintr` go through line discipline of console
+//! `Tty`, and are echoed back to UART. See `tty` module for how they are
+//! read.
+
+use crate::spinlock::Mutex;
+use crate::uart::UART;
+use crate::tty::{Tty, BACKSPACE, tty_input, tty_read, tty_ioctl};
+
+static CONSOLE: Mutex<Tty> = Mutex::new(Tty::new(), "console");
+
+/// Echo a character back to console
+fn echo(c: u8) {
+    let mut uart = UART().lock();
+    if c == BACKSPACE {
+        // overwrite last character with a space
+        uart.put_nowait(BACKSPACE);
+        uart.put_nowait(b' ');
+        uart.put_nowait(BACKSPACE);
+    } else {
+        uart.put_nowait(c);
+    }
+}
+
+/// Handle a character received from UART
+pub fn consoleintr(c: u8) {
+    tty_input(&CONSOLE, c, &mut echo);
+}
+
+/// Read from console into `dst`, see `tty_read`
+pub fn consoleread(dst: &mut [u8]) -> i32 {
+    tty_read(&CONSOLE, dst)
+}
+
+/// Console `ioctl`, see `Tty::ioctl`
+pub fn consoleioctl(cmd: u32, arg: *mut u8) -> i32 {
+    tty_ioctl(&CONSOLE, cmd, arg)
+}
@@ -6,7 +6,7 @@
 //! ELF parsing
 
 use crate::panic;
-use crate::mem;
+use crate::mem::{self, OutOfMemory};
 use crate::arch;
 use crate::page;
 use crate::process;
@@ -50,7 +50,8 @@ const ELF_PROG_FLAG_WRITE: u32 = 2;
 const ELF_PROG_FLAG_READ: u32 = 4;
 const ELF_MAGIC: u32 = 0x464C457F;
 
-pub fn parse_elf(a: &[u8], pgtable: &mut page::Table) -> u64 {
+/// Load ELF `a` into `pgtable`, and returns entry point
+pub fn parse_elf(a: &[u8], pgtable: &mut page::AddressSpace) -> Result<u64, OutOfMemory> {
     let a = a.as_ptr();
     /* TODO: Use something safer */
     // peek head of byte array to get ELF information
@@ -87,7 +88,7 @@ pub fn parse_elf(a: &[u8], pgtable: &mut page::Table) -> u64 {
             a,
             hdr.off as usize,
             hdr.filesz as usize,
-        );
+        )?;
         /* println!(
             "map segment ELF 0x{:X}~0x{:X} -> MEM 0x{:X}",
             hdr.off,
@@ -95,29 +96,30 @@ pub fn parse_elf(a: &[u8], pgtable: &mut page::Table) -> u64 {
             hdr.vaddr
         ); */
     }
-    elfhdr.entry
+    Ok(elfhdr.entry)
 }
 
 fn load_segment(
-    pgtable: &mut page::Table,
+    pgtable: &mut page::AddressSpace,
     vaddr: usize,
     elf: *const u8,
     offset: usize,
     sz: usize,
-) {
+) -> Result<(), OutOfMemory> {
     let num_pages = mem::align_val(sz, PAGE_ORDER) / PAGE_SIZE;
     for i in 0..num_pages {
-        let mut seg = page::Page::new();
+        let mut seg = page::Page::try_new()?;
         let src = elf as *const u8;
         unsafe {
             let src = src.add(offset + i * PAGE_SIZE);
             core::ptr::copy(src, seg.data.as_mut_ptr(), PAGE_SIZE);
         }
         use page::EntryAttributes;
-        pgtable.map(
+        pgtable.try_map(
             vaddr + i * PAGE_SIZE,
             seg,
             EntryAttributes::URX as usize
-        );
+        )?;
     }
+    Ok(())
 }
@@ -0,0 +1,294 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Async executor for in-kernel tasks
+//!
+//! Kernel tasks are Rust futures spawned with [`spawn`]. Every hart polls
+//! ready tasks in its scheduler loop before picking the next process, so
+//! tasks run in scheduler context and must never call `sleep`.
+//!
+//! Interrupt handlers wake tasks through [`WaitQueue`] or by waking a stored
+//! `Waker` directly, as `virtiointr` does for in-flight block requests.
+//!
+//! Process context may wait on a future with [`block_on`], which puts the
+//! process into sleep until the future is woken.
+//!
+//! ## Examples
+//!
+//! ```
+//! use crate::executor::spawn;
+//! use crate::virtio::VIRTIO;
+//! spawn(async {
+//!     let buf = VIRTIO().read_async(0).await.unwrap();
+//!     // ... flush it back to disk later
+//!     VIRTIO().write_async(buf).await;
+//! });
+//! ```
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use crate::spinlock::Mutex;
+use crate::process::{sleep, wakeup};
+
+/// A boxed kernel future
+type BoxFuture = Pin<Box<dyn Future<Output=()> + Send + 'static>>;
+
+/// Objects which can be woken through a `Waker` built from `Arc<Self>`
+pub trait ArcWake: Send + Sync {
+    fn wake_by_ref(arc_self: &Arc<Self>);
+}
+
+/// Build a `Waker` from an `Arc` of an `ArcWake` object
+pub fn waker_of<W: ArcWake + 'static>(w: Arc<W>) -> Waker {
+    let ptr = Arc::into_raw(w) as *const ();
+    unsafe { Waker::from_raw(RawWaker::new(ptr, vtable_of::<W>())) }
+}
+
+fn vtable_of<W: ArcWake + 'static>() -> &'static RawWakerVTable {
+    &RawWakerVTable::new(
+        clone_raw::<W>,
+        wake_raw::<W>,
+        wake_by_ref_raw::<W>,
+        drop_raw::<W>,
+    )
+}
+
+unsafe fn clone_raw<W: ArcWake + 'static>(ptr: *const ()) -> RawWaker {
+    let arc = Arc::from_raw(ptr as *const W);
+    core::mem::forget(arc.clone());
+    core::mem::forget(arc);
+    RawWaker::new(ptr, vtable_of::<W>())
+}
+
+unsafe fn wake_raw<W: ArcWake + 'static>(ptr: *const ()) {
+    let arc = Arc::from_raw(ptr as *const W);
+    ArcWake::wake_by_ref(&arc);
+}
+
+unsafe fn wake_by_ref_raw<W: ArcWake + 'static>(ptr: *const ()) {
+    let arc = Arc::from_raw(ptr as *const W);
+    ArcWake::wake_by_ref(&arc);
+    core::mem::forget(arc);
+}
+
+unsafe fn drop_raw<W: ArcWake + 'static>(ptr: *const ()) {
+    drop(Arc::from_raw(ptr as *const W));
+}
+
+/// A kernel task
+pub struct Task {
+    /// The future, `None` after it completes
+    future: Mutex<Option<BoxFuture>>,
+    /// Whether this task is in ready queue
+    queued: AtomicBool,
+}
+
+impl ArcWake for Task {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        schedule(arc_self.clone());
+    }
+}
+
+/// Tasks ready to be polled
+static READY: Mutex<Vec<Arc<Task>>> = Mutex::new(Vec::new(), "executor ready queue");
+
+/// Put a task into ready queue if it's not already there
+fn schedule(task: Arc<Task>) {
+    if !task.queued.swap(true, Ordering::SeqCst) {
+        READY.lock().push(task);
+    }
+}
+
+/// Spawn a kernel task. It will be polled by scheduler loop of any hart.
+pub fn spawn<F>(future: F)
+    where F: Future<Output=()> + Send + 'static {
+    let task = Arc::new(Task {
+        future: Mutex::new(Some(Box::pin(future)), "task"),
+        queued: AtomicBool::new(false),
+    });
+    schedule(task);
+}
+
+/// Poll a task once
+fn run(task: Arc<Task>) {
+    task.queued.store(false, Ordering::SeqCst);
+    let waker = waker_of(task.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut future = task.future.lock();
+    let done = match future.as_mut() {
+        Some(f) => f.as_mut().poll(&mut cx).is_ready(),
+        None => false
+    };
+    if done {
+        *future = None;
+    }
+}
+
+/// Poll all tasks which are ready. Called in scheduler loop.
+pub fn run_ready() {
+    let tasks = core::mem::replace(&mut *READY.lock(), Vec::new());
+    for task in tasks {
+        run(task);
+    }
+}
+
+/// Wakers waiting for an event, usually an interrupt
+///
+/// A future created by `wait` completes once `wake_all` is called after
+/// the future is created, so that no wakeup is lost between checking a
+/// condition and registering the waker.
+pub struct WaitQueue {
+    /// generation and waiting wakers
+    inner: Mutex<(usize, Vec<Waker>)>,
+}
+
+impl WaitQueue {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            inner: Mutex::new((0, Vec::new()), name)
+        }
+    }
+
+    /// Returns a future which completes on next `wake_all`
+    pub fn wait(&self) -> WaitFuture {
+        WaitFuture {
+            queue: self,
+            generation: self.inner.lock().0,
+        }
+    }
+
+    /// Wake all waiting wakers
+    pub fn wake_all(&self) {
+        let wakers = {
+            let mut inner = self.inner.lock();
+            inner.0 = inner.0.wrapping_add(1);
+            core::mem::replace(&mut inner.1, Vec::new())
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Future returned by `WaitQueue::wait`
+pub struct WaitFuture<'a> {
+    queue: &'a WaitQueue,
+    generation: usize,
+}
+
+impl Future for WaitFuture<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut inner = self.queue.inner.lock();
+        if inner.0 != self.generation {
+            Poll::Ready(())
+        } else {
+            inner.1.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// Future which gives up current poll once
+pub struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+/// Let other tasks run before polling current task again
+pub fn yield_now() -> YieldNow {
+    YieldNow(false)
+}
+
+/// Wakes a process sleeping in `block_on`
+struct Signal {
+    woken: Mutex<bool>,
+}
+
+impl ArcWake for Signal {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        *arc_self.woken.lock() = true;
+        wakeup(&**arc_self);
+    }
+}
+
+/// Run a future to completion in process context.
+///
+/// Current process sleeps while the future is pending.
+pub fn block_on<F: Future>(mut future: F) -> F::Output {
+    let signal = Arc::new(Signal { woken: Mutex::new(false, "block on") });
+    let waker = waker_of(signal.clone());
+    let mut cx = Context::from_waker(&waker);
+    // `future` is never moved after being pinned here
+    let mut future = unsafe { Pin::new_unchecked(&mut future) };
+    loop {
+        if let Poll::Ready(x) = future.as_mut().poll(&mut cx) {
+            return x;
+        }
+        let mut woken = signal.woken.lock();
+        while !*woken {
+            woken = sleep(&*signal, woken);
+        }
+        *woken = false;
+    }
+}
+
+pub mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("spawn", test_spawn),
+            ("wait queue", test_wait_queue),
+        ]
+    }
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    /// Test spawned task is polled by scheduler loop
+    pub fn test_spawn() {
+        COUNTER.store(0, Ordering::SeqCst);
+        spawn(async {
+            yield_now().await;
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        });
+        while COUNTER.load(Ordering::SeqCst) == 0 {}
+    }
+
+    static QUEUE: WaitQueue = WaitQueue::new("test wait queue");
+
+    /// Test task waiting on a wait queue is woken
+    pub fn test_wait_queue() {
+        COUNTER.store(0, Ordering::SeqCst);
+        let waiting = QUEUE.wait();
+        spawn(async {
+            QUEUE.wait().await;
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        });
+        QUEUE.wake_all();
+        block_on(waiting);
+        while COUNTER.load(Ordering::SeqCst) == 0 {
+            QUEUE.wake_all();
+        }
+    }
+}
@@ -0,0 +1,345 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Flattened device tree parser
+//!
+//! Only reads the blob in place and never allocates, so that it can be used
+//! before allocator is initialized.
+//!
+//! ## Examples
+//!
+//! ```
+//! let fdt = unsafe { Fdt::from_ptr(dtb) }.unwrap();
+//! for node in fdt.nodes() {
+//!     if node.is_compatible("ns16550a") {
+//!         let (base, size) = node.reg(2, 2).unwrap();
+//!     }
+//! }
+//! ```
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// Read big-endian u32 at `off`
+fn be32(data: &[u8], off: usize) -> Option<u32> {
+    let b = data.get(off..off + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Read a big-endian number of `cells` 32-bit cells
+fn cells_value(data: &[u8], cells: usize) -> Option<usize> {
+    let mut x = 0;
+    for i in 0..cells {
+        x = (x << 32) | be32(data, i * 4)? as usize;
+    }
+    Some(x)
+}
+
+/// Align offset to 4 bytes
+const fn align4(off: usize) -> usize {
+    (off + 3) & !3
+}
+
+/// Read a null-terminated string at `off`
+fn cstr(data: &[u8], off: usize) -> Option<&str> {
+    let rest = data.get(off..)?;
+    let len = rest.iter().position(|&c| c == 0)?;
+    core::str::from_utf8(&rest[..len]).ok()
+}
+
+/// A flattened device tree blob
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    /// Parse a blob in memory, returns `None` if header is invalid
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        if be32(data, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total = be32(data, 4)? as usize;
+        let off_struct = be32(data, 8)? as usize;
+        let off_strings = be32(data, 12)? as usize;
+        let size_strings = be32(data, 32)? as usize;
+        let size_struct = be32(data, 36)? as usize;
+        let data = data.get(..total)?;
+        Some(Self {
+            data,
+            structs: data.get(off_struct..off_struct + size_struct)?,
+            strings: data.get(off_strings..off_strings + size_strings)?,
+        })
+    }
+
+    /// Parse a blob at `ptr`
+    pub unsafe fn from_ptr(ptr: usize) -> Option<Fdt<'static>> {
+        if ptr == 0 || ptr % 4 != 0 {
+            return None;
+        }
+        let header = core::slice::from_raw_parts(ptr as *const u8, 8);
+        if be32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total = be32(header, 4)? as usize;
+        Fdt::new(core::slice::from_raw_parts(ptr as *const u8, total))
+    }
+
+    /// Total size of the blob
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Iterate over all nodes in depth-first order
+    pub fn nodes(&self) -> Nodes<'_, 'a> {
+        Nodes { fdt: self, off: 0, depth: 0 }
+    }
+}
+
+/// A node in device tree
+#[derive(Clone, Copy)]
+pub struct Node<'b, 'a> {
+    fdt: &'b Fdt<'a>,
+    /// Offset of first token after node name
+    props: usize,
+    /// Node name with unit address, empty for root node
+    pub name: &'a str,
+    /// Depth of node, 0 for root node
+    pub depth: usize,
+}
+
+impl<'b, 'a> Node<'b, 'a> {
+    /// Iterate over properties of this node
+    pub fn props(&self) -> Props<'b, 'a> {
+        Props { fdt: self.fdt, off: self.props }
+    }
+
+    /// Get value of property `name`
+    pub fn prop(&self, name: &str) -> Option<&'a [u8]> {
+        self.props().find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+
+    /// Get property `name` as a u32
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        be32(self.prop(name)?, 0)
+    }
+
+    /// Get property `name` as a string
+    pub fn prop_str(&self, name: &str) -> Option<&'a str> {
+        cstr(self.prop(name)?, 0)
+    }
+
+    /// Name without unit address
+    pub fn base_name(&self) -> &'a str {
+        self.name.split('@').next().unwrap_or("")
+    }
+
+    /// Whether `compatible` property contains `compat`
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        match self.prop("compatible") {
+            Some(list) => list.split(|&c| c == 0).any(|s| s == compat.as_bytes()),
+            None => false
+        }
+    }
+
+    /// Get `i`th (address, size) pair of `reg` property, with cell counts of parent node
+    pub fn reg_at(&self, i: usize, address_cells: usize, size_cells: usize) -> Option<(usize, usize)> {
+        let reg = self.prop("reg")?;
+        let entry = (address_cells + size_cells) * 4;
+        let item = reg.get(i * entry..(i + 1) * entry)?;
+        Some((cells_value(item, address_cells)?, cells_value(&item[address_cells * 4..], size_cells)?))
+    }
+
+    /// Get first (address, size) pair of `reg` property
+    pub fn reg(&self, address_cells: usize, size_cells: usize) -> Option<(usize, usize)> {
+        self.reg_at(0, address_cells, size_cells)
+    }
+}
+
+/// Iterator over nodes
+pub struct Nodes<'b, 'a> {
+    fdt: &'b Fdt<'a>,
+    off: usize,
+    depth: usize,
+}
+
+impl<'b, 'a> Iterator for Nodes<'b, 'a> {
+    type Item = Node<'b, 'a>;
+
+    fn next(&mut self) -> Option<Node<'b, 'a>> {
+        let s = self.fdt.structs;
+        loop {
+            let token = be32(s, self.off)?;
+            self.off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(s, self.off)?;
+                    self.off = align4(self.off + name.len() + 1);
+                    let node = Node { fdt: self.fdt, props: self.off, name, depth: self.depth };
+                    self.depth += 1;
+                    return Some(node);
+                }
+                FDT_END_NODE => {
+                    self.depth = self.depth.checked_sub(1)?;
+                }
+                FDT_PROP => {
+                    let len = be32(s, self.off)? as usize;
+                    self.off = align4(self.off + 8 + len);
+                }
+                FDT_NOP => {}
+                // FDT_END or invalid token
+                _ => return None
+            }
+        }
+    }
+}
+
+/// Iterator over (name, value) of properties
+pub struct Props<'b, 'a> {
+    fdt: &'b Fdt<'a>,
+    off: usize,
+}
+
+impl<'b, 'a> Iterator for Props<'b, 'a> {
+    type Item = (&'a str, &'a [u8]);
+
+    fn next(&mut self) -> Option<(&'a str, &'a [u8])> {
+        let s = self.fdt.structs;
+        loop {
+            let token = be32(s, self.off)?;
+            match token {
+                FDT_PROP => {
+                    let len = be32(s, self.off + 4)? as usize;
+                    let name_off = be32(s, self.off + 8)? as usize;
+                    let value = s.get(self.off + 12..self.off + 12 + len)?;
+                    self.off = align4(self.off + 12 + len);
+                    return Some((cstr(self.fdt.strings, name_off)?, value));
+                }
+                FDT_NOP => { self.off += 4; }
+                _ => return None
+            }
+        }
+    }
+}
+
+pub mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("parse", test_parse),
+            ("invalid", test_invalid),
+        ]
+    }
+
+    /// Builds a device tree blob for tests
+    struct Builder {
+        structs: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        fn token(&mut self, t: u32) {
+            self.structs.extend_from_slice(&t.to_be_bytes());
+        }
+
+        fn pad(&mut self) {
+            while self.structs.len() % 4 != 0 {
+                self.structs.push(0);
+            }
+        }
+
+        fn begin(&mut self, name: &str) {
+            self.token(FDT_BEGIN_NODE);
+            self.structs.extend_from_slice(name.as_bytes());
+            self.structs.push(0);
+            self.pad();
+        }
+
+        fn end(&mut self) {
+            self.token(FDT_END_NODE);
+        }
+
+        fn prop(&mut self, name: &str, value: &[u8]) {
+            let name_off = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.token(FDT_PROP);
+            self.token(value.len() as u32);
+            self.token(name_off);
+            self.structs.extend_from_slice(value);
+            self.pad();
+        }
+
+        fn prop_cells(&mut self, name: &str, cells: &[u32]) {
+            let mut value = Vec::new();
+            for c in cells {
+                value.extend_from_slice(&c.to_be_bytes());
+            }
+            self.prop(name, &value);
+        }
+
+        fn finish(mut self) -> Vec<u8> {
+            self.token(FDT_END);
+            let off_struct = 40;
+            let off_strings = off_struct + self.structs.len();
+            let total = off_strings + self.strings.len();
+            let mut blob = Vec::new();
+            for x in &[FDT_MAGIC, total as u32, off_struct as u32, off_strings as u32, 0, 17, 16, 0,
+                self.strings.len() as u32, self.structs.len() as u32] {
+                blob.extend_from_slice(&x.to_be_bytes());
+            }
+            blob.extend_from_slice(&self.structs);
+            blob.extend_from_slice(&self.strings);
+            blob
+        }
+    }
+
+    pub fn test_parse() {
+        let mut b = Builder { structs: Vec::new(), strings: Vec::new() };
+        b.begin("");
+        b.prop_cells("#address-cells", &[2]);
+        b.prop_cells("#size-cells", &[2]);
+        b.begin("memory@80000000");
+        b.prop("device_type", b"memory\0");
+        b.prop_cells("reg", &[0, 0x8000_0000, 0, 0x1000_0000]);
+        b.end();
+        b.begin("soc");
+        b.begin("uart@10000000");
+        b.prop_cells("interrupts", &[10]);
+        b.prop("compatible", b"vendor,uart\0ns16550a\0");
+        b.prop_cells("reg", &[0, 0x1000_0000, 0, 0x100]);
+        b.end();
+        b.end();
+        b.end();
+        let blob = b.finish();
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(fdt.size(), blob.len());
+        let nodes: Vec<_> = fdt.nodes().collect();
+        assert_eq!(nodes.len(), 4);
+        assert_eq!(nodes[0].prop_u32("#address-cells"), Some(2));
+        assert_eq!(nodes[1].base_name(), "memory");
+        assert_eq!(nodes[1].depth, 1);
+        assert_eq!(nodes[1].prop_str("device_type"), Some("memory"));
+        assert_eq!(nodes[1].reg(2, 2), Some((0x8000_0000, 0x1000_0000)));
+        assert_eq!(nodes[3].name, "uart@10000000");
+        assert_eq!(nodes[3].depth, 2);
+        assert!(nodes[3].is_compatible("ns16550a"));
+        assert!(!nodes[3].is_compatible("ns16550"));
+        assert_eq!(nodes[3].prop_u32("interrupts"), Some(10));
+        assert_eq!(nodes[3].reg_at(1, 2, 2), None);
+    }
+
+    pub fn test_invalid() {
+        assert!(Fdt::new(&[0; 8]).is_none());
+        assert!(Fdt::new(&FDT_MAGIC.to_be_bytes()).is_none());
+    }
+}
@@ -3,19 +3,27 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-//! File in core-os including file in filesystem, device, pipe and symbol link
+//! File in core-os including file in filesystem, device, socket, pipe and
+//! symbol link
 
 pub mod device;
-pub use device::{Device, Console};
+pub use device::{Device, Console, MemInfo};
 
 pub mod fsfile;
 pub use fsfile::FsFile;
 
+pub mod socket;
+pub use socket::Socket;
+
+pub mod fdtable;
+pub use fdtable::{FdTable, FD_CLOEXEC, O_CLOEXEC};
+
 use alloc::boxed::Box;
 
 /// File in core-os
 pub enum File {
     Device(Box<dyn Device>),
     FsFile(FsFile),
+    Socket(Socket),
     Pipe
 }
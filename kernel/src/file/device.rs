@@ -5,7 +5,10 @@
 
 //! Device trait for devices such as Console
 
-use crate::uart::UART;
+use crate::uart::uartwrite;
+use crate::console::{consoleread, consoleioctl};
+use crate::spinlock::Mutex;
+use crate::mem::stat;
 
 /// Device trait
 ///
@@ -15,30 +18,65 @@ pub trait Device: Send + Sync {
     fn read(&self, content: &mut [u8]) -> i32;
     /// Write content to file and returns number of characters written.
     fn write(&self, content: &[u8]) -> i32;
+    /// Device-specific control. `arg` points to argument in kernel space.
+    fn ioctl(&self, _cmd: u32, _arg: *mut u8) -> i32 {
+        -1
+    }
 }
 
 /// Console device
 pub struct Console {}
 
 impl Device for Console {
-    /// read from console
+    /// read from console, see `console` module
     fn read(&self, content: &mut [u8]) -> i32 {
-        let mut uart = UART().lock();
-        for i in 0..content.len() {
-            match uart.get() {
-                Some(ch) => { content[i] = ch; }
-                _ => { return i as i32; }
-            }
-        }
-        return content.len() as i32;
+        consoleread(content)
     }
 
-    /// write to console
+    /// write to console, sleeping while UART transmit buffer is full
     fn write(&self, content: &[u8]) -> i32 {
-        let mut uart = UART().lock();
-        for i in 0..content.len() {
-            uart.put(content[i]);
-        }
+        uartwrite(content);
         return content.len() as i32;
     }
+
+    /// terminal settings and foreground process group, see `tty` module
+    fn ioctl(&self, cmd: u32, arg: *mut u8) -> i32 {
+        consoleioctl(cmd, arg)
+    }
+}
+
+/// Size limit of `/proc/meminfo` report
+const MEMINFO_SIZE: usize = 512;
+
+/// `/proc/meminfo` device, reporting memory usage at the time of opening
+pub struct MemInfo {
+    /// report and read offset
+    report: Mutex<([u8; MEMINFO_SIZE], usize, usize)>,
+}
+
+impl MemInfo {
+    pub fn new() -> Self {
+        let mut buf = [0; MEMINFO_SIZE];
+        let len = stat::report(&mut buf);
+        Self {
+            report: Mutex::new((buf, len, 0), "meminfo"),
+        }
+    }
+}
+
+impl Device for MemInfo {
+    /// read memory report
+    fn read(&self, content: &mut [u8]) -> i32 {
+        let mut report = self.report.lock();
+        let (buf, len, offset) = &mut *report;
+        let n = core::cmp::min(content.len(), *len - *offset);
+        content[..n].copy_from_slice(&buf[*offset..*offset + n]);
+        *offset += n;
+        n as i32
+    }
+
+    /// memory report is read-only
+    fn write(&self, _content: &[u8]) -> i32 {
+        -1
+    }
 }
@@ -0,0 +1,195 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Per-process file descriptor table
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::File;
+
+/// Maximum number of file descriptors of a process, which is also its
+/// default limit
+pub const NOFILE: usize = 256;
+
+/// Close file descriptor on `exec`
+pub const FD_CLOEXEC: i32 = 1;
+
+/// `open` and `dup3` flag to set `FD_CLOEXEC` on new file descriptor
+pub const O_CLOEXEC: usize = 0x80000;
+
+/// An open file descriptor
+#[derive(Clone)]
+struct FdEntry {
+    file: Arc<File>,
+    cloexec: bool,
+}
+
+/// File descriptor table, which grows on demand up to `limit` entries
+#[derive(Clone)]
+pub struct FdTable {
+    entries: Vec<Option<FdEntry>>,
+    /// No descriptor below it is free
+    next_free: usize,
+    /// Descriptors must be less than `limit`
+    limit: usize,
+}
+
+impl FdTable {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            next_free: 0,
+            limit: NOFILE,
+        }
+    }
+
+    fn entry(&self, fd: usize) -> Option<&FdEntry> {
+        self.entries.get(fd)?.as_ref()
+    }
+
+    /// Get file of descriptor `fd`
+    pub fn get(&self, fd: usize) -> Option<&Arc<File>> {
+        self.entry(fd).map(|e| &e.file)
+    }
+
+    /// Install `file` at lowest free descriptor, returns the descriptor
+    pub fn alloc(&mut self, file: Arc<File>, cloexec: bool) -> Option<usize> {
+        let fd = self.alloc_from(self.next_free, file, cloexec)?;
+        self.next_free = fd + 1;
+        Some(fd)
+    }
+
+    /// Install `file` at lowest free descriptor not less than `min`
+    pub fn alloc_from(&mut self, min: usize, file: Arc<File>, cloexec: bool) -> Option<usize> {
+        let min = core::cmp::max(min, self.next_free);
+        let fd = (min..self.entries.len())
+            .find(|&fd| self.entries[fd].is_none())
+            .unwrap_or(core::cmp::max(min, self.entries.len()));
+        self.install(fd, file, cloexec)?;
+        Some(fd)
+    }
+
+    /// Install `file` at descriptor `fd`, closing the file previously there.
+    ///
+    /// Returns `None` if `fd` exceeds limit.
+    pub fn install(&mut self, fd: usize, file: Arc<File>, cloexec: bool) -> Option<()> {
+        if fd >= self.limit {
+            return None;
+        }
+        if fd >= self.entries.len() {
+            self.entries.resize(fd + 1, None);
+        }
+        self.entries[fd] = Some(FdEntry { file, cloexec });
+        Some(())
+    }
+
+    /// Close descriptor `fd`, returns the file if `fd` was open
+    pub fn close(&mut self, fd: usize) -> Option<Arc<File>> {
+        let entry = self.entries.get_mut(fd)?.take()?;
+        if fd < self.next_free {
+            self.next_free = fd;
+        }
+        while let Some(None) = self.entries.last() {
+            self.entries.pop();
+        }
+        Some(entry.file)
+    }
+
+    /// Whether `fd` will be closed on `exec`
+    pub fn cloexec(&self, fd: usize) -> Option<bool> {
+        self.entry(fd).map(|e| e.cloexec)
+    }
+
+    /// Set whether `fd` will be closed on `exec`, returns `None` if `fd` is not open
+    pub fn set_cloexec(&mut self, fd: usize, cloexec: bool) -> Option<()> {
+        self.entries.get_mut(fd)?.as_mut()?.cloexec = cloexec;
+        Some(())
+    }
+
+    /// Close all descriptors with `FD_CLOEXEC`. Called in `exec`.
+    pub fn close_on_exec(&mut self) {
+        for fd in 0..self.entries.len() {
+            if self.cloexec(fd) == Some(true) {
+                self.close(fd);
+            }
+        }
+    }
+
+    /// Maximum number of descriptors
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Set maximum number of descriptors. Fails if a descriptor above new
+    /// limit is open, or if it's above `NOFILE`.
+    pub fn set_limit(&mut self, limit: usize) -> Option<()> {
+        if self.entries.len() > limit || limit > NOFILE {
+            return None;
+        }
+        self.limit = limit;
+        Some(())
+    }
+}
+
+pub mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use crate::file::Console;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("alloc", test_alloc),
+            ("cloexec", test_cloexec),
+            ("limit", test_limit),
+        ]
+    }
+
+    fn console() -> Arc<File> {
+        Arc::new(File::Device(Box::new(Console {})))
+    }
+
+    /// Test lowest free descriptor is allocated
+    pub fn test_alloc() {
+        let mut table = FdTable::new();
+        assert_eq!(table.alloc(console(), false), Some(0));
+        assert_eq!(table.alloc(console(), false), Some(1));
+        assert_eq!(table.alloc(console(), false), Some(2));
+        assert!(table.close(1).is_some());
+        assert!(table.close(1).is_none());
+        assert_eq!(table.alloc(console(), false), Some(1));
+        assert_eq!(table.alloc_from(10, console(), false), Some(10));
+        assert_eq!(table.alloc(console(), false), Some(3));
+        assert!(table.get(9).is_none());
+        assert!(table.get(10).is_some());
+    }
+
+    /// Test `FD_CLOEXEC` descriptors are closed by `close_on_exec`
+    pub fn test_cloexec() {
+        let mut table = FdTable::new();
+        table.alloc(console(), false);
+        table.alloc(console(), true);
+        table.alloc(console(), false);
+        assert_eq!(table.set_cloexec(2, true), Some(()));
+        assert_eq!(table.set_cloexec(3, true), None);
+        table.close_on_exec();
+        assert!(table.get(0).is_some());
+        assert!(table.get(1).is_none());
+        assert!(table.get(2).is_none());
+        assert_eq!(table.alloc(console(), false), Some(1));
+    }
+
+    /// Test descriptors can't exceed limit
+    pub fn test_limit() {
+        let mut table = FdTable::new();
+        assert_eq!(table.set_limit(2), Some(()));
+        assert_eq!(table.alloc(console(), false), Some(0));
+        assert_eq!(table.alloc(console(), false), Some(1));
+        assert_eq!(table.alloc(console(), false), None);
+        assert_eq!(table.install(2, console(), false), None);
+        assert_eq!(table.set_limit(1), None);
+        assert_eq!(table.set_limit(NOFILE + 1), None);
+        assert_eq!(table.limit(), 2);
+    }
+}
@@ -4,10 +4,16 @@
 // https://opensource.org/licenses/MIT
 
 //! File on file system
+//!
+//! Disk begins with a table of headers, one per block, each holding size,
+//! offset and name of a file. Table ends at first empty header. Sockets
+//! bound to a path get a header with no data, which stays until unlinked.
 
-use crate::virtio::{VIRTIO, VirtIO, BSIZE};
+use crate::virtio::{VIRTIO, VirtIO, Buf, BSIZE};
 use crate::{print, println};
 use crate::spinlock::Mutex;
+use crate::sleeplock::SleepLock;
+use crate::syscall::EADDRINUSE;
 
 pub struct FsFile {
     offset: usize,
@@ -20,34 +26,116 @@ pub struct FsFile {
 const HEADER_SIZE: usize = 1024;
 const FILE_MAX: usize = 1024;
 
+/// Offset in header of socket node, which has no data
+const SOCKET: usize = usize::MAX;
+
+/// Held while changing header table
+static HEADERS: SleepLock = SleepLock::new("fs headers");
+
+/// Kind of node at a path
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Inode {
+    File,
+    /// created by binding an `AF_UNIX` socket
+    Socket,
+}
+
+/// Size, offset and name in header block `b`, `None` if it's empty
+fn header(b: &Buf) -> Option<(usize, usize, &str)> {
+    let sz = unsafe { core::ptr::read(b.data.as_ptr() as *const usize) };
+    let offset = unsafe { core::ptr::read((b.data.as_ptr() as *const usize).add(1)) };
+    if sz == 0 && offset == 0 {
+        return None;
+    }
+    let name_sz = b.data[16..].iter().position(|&d| d == 0).unwrap_or(b.data.len() - 16);
+    Some((sz, offset, core::str::from_utf8(&b.data[16..16 + name_sz]).unwrap()))
+}
+
+/// Find header of `path`. Returns its id, size and offset, or number of
+/// headers if it's not found. Table also ends at end of disk.
+fn find(virtio: &mut VirtIO, path: &str) -> Result<(usize, usize, usize), usize> {
+    for id in 0..FILE_MAX {
+        let b = match virtio.read(id as u32) {
+            Ok(b) => b,
+            Err(_) => return Err(id)
+        };
+        match header(&b) {
+            Some((sz, offset, name)) if name == path => return Ok((id, sz, offset)),
+            Some(_) => {}
+            None => return Err(id)
+        }
+    }
+    Err(FILE_MAX)
+}
+
+/// Kind of node at `path`, `None` if there's nothing
+pub fn lookup(path: &str) -> Option<Inode> {
+    match find(VIRTIO(), path) {
+        Ok((_, _, SOCKET)) => Some(Inode::Socket),
+        Ok(_) => Some(Inode::File),
+        Err(_) => None
+    }
+}
+
+/// Create a socket node at `path`. Returns `EADDRINUSE` if `path` exists,
+/// or -1 if it can't be written to disk.
+pub fn mknod_socket(path: &str) -> i32 {
+    let _headers = HEADERS.acquire();
+    let virtio = VIRTIO();
+    let id = match find(virtio, path) {
+        Ok(_) => return EADDRINUSE,
+        Err(id) => id
+    };
+    if id == FILE_MAX || path.len() >= BSIZE - 16 {
+        return -1;
+    }
+    let mut b = box Buf::new();
+    b.blockno = id as u32;
+    unsafe { core::ptr::write((b.data.as_mut_ptr() as *mut usize).add(1), SOCKET); }
+    b.data[16..16 + path.len()].copy_from_slice(path.as_bytes());
+    match virtio.write(b) {
+        Ok(()) => 0,
+        Err(_) => -1
+    }
+}
+
+/// Remove socket node at `path`. Files can't be removed, as there's no way
+/// to free their data.
+pub fn unlink(path: &str) -> i32 {
+    let _headers = HEADERS.acquire();
+    let virtio = VIRTIO();
+    let id = match find(virtio, path) {
+        Ok((id, _, SOCKET)) if !virtio.readonly() => id,
+        _ => return -1
+    };
+    // move last header into its place, so that table has no hole
+    let last = (id + 1..FILE_MAX)
+        .find(|&i| virtio.read(i as u32).map_or(true, |b| header(&b).is_none()))
+        .unwrap_or(FILE_MAX) - 1;
+    if last != id {
+        let mut b = match virtio.read(last as u32) {
+            Ok(b) => b,
+            Err(_) => return -1
+        };
+        b.blockno = id as u32;
+        if virtio.write(b).is_err() {
+            return -1;
+        }
+    }
+    let mut b = box Buf::new();
+    b.blockno = last as u32;
+    match virtio.write(b) {
+        Ok(()) => 0,
+        Err(_) => -1
+    }
+}
+
 impl FsFile {
     fn get_file_info(virtio: &mut VirtIO, path: &str) -> Option<(usize, usize)> {
-        for id in 0..FILE_MAX {
-            let b = virtio.read(1, id as u32);
-            let sz = unsafe { core::ptr::read(b.data.as_ptr() as *const usize) };
-            let offset = unsafe { core::ptr::read((b.data.as_ptr() as *const usize).add(1)) };
-            if sz == 0 {
-                break;
-            }
-            let name_sz = {
-                let mut i = 16;
-                loop {
-                    let d = b.data[i];
-                    if d == 0 {
-                        break;
-                    }
-                    i += 1;
-                    if i == b.data.len() { break; }
-                }
-                i - 16
-            };
-            let u8_slice = unsafe { core::slice::from_raw_parts(b.data.as_ptr().add(16), name_sz) };
-            let name = core::str::from_utf8(u8_slice).unwrap();
-            if name == path {
-                return Some((offset, sz));
-            }
+        match find(virtio, path) {
+            Ok((_, _, SOCKET)) | Err(_) => None,
+            Ok((_, sz, offset)) => Some((offset, sz))
         }
-        None
     }
 
     pub fn open(path: &str, mode: usize) -> Self {
@@ -71,7 +159,10 @@ impl FsFile {
         let virtio = VIRTIO();
         let read_offset = self.rw_offset.lock().0;
         let read_sz = (self.sz - read_offset).min(content.len());
-        let result = virtio.read(1, ((self.offset + read_offset) / BSIZE) as u32);
+        let result = match virtio.read(((self.offset + read_offset) / BSIZE) as u32) {
+            Ok(result) => result,
+            Err(_) => return -1
+        };
         // content.copy_from_slice(&result.data[0..content.len()]);
         for i in 0..read_sz {
             content[i] = result.data[i];
@@ -0,0 +1,333 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! BSD sockets of `AF_INET` domain, on UDP and TCP of `net` module, and of
+//! `AF_UNIX` domain
+//!
+//! State of a socket is kept under a spinlock, so handles of UDP port and
+//! TCP connection are cloned out of it before blocking operations.
+
+pub mod unix;
+pub use unix::{UnixSocket, SockAddrUn, AF_UNIX};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::spinlock::Mutex;
+use crate::net::{self, Ipv4Addr};
+use crate::net::udp::UdpSocket;
+use crate::net::tcp::{self, TcpSocket};
+use crate::syscall::{EADDRINUSE, EINTR};
+use super::File;
+
+pub const AF_INET: i32 = 2;
+
+pub const SOCK_STREAM: i32 = 1;
+pub const SOCK_DGRAM: i32 = 2;
+
+/// Default length of queue of connections waiting for `accept`
+pub const SOMAXCONN: usize = 16;
+
+/// `struct sockaddr_in`
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SockAddrIn {
+    pub family: u16,
+    /// port in network byte order
+    pub port: u16,
+    pub addr: [u8; 4],
+    pub zero: [u8; 8],
+}
+
+impl SockAddrIn {
+    pub fn new(addr: Ipv4Addr, port: u16) -> Self {
+        Self { family: AF_INET as u16, port: port.to_be(), addr: addr.0, zero: [0; 8] }
+    }
+
+    pub fn addr(&self) -> Ipv4Addr {
+        Ipv4Addr(self.addr)
+    }
+
+    pub fn port(&self) -> u16 {
+        u16::from_be(self.port)
+    }
+
+    /// Whether it's an address of this host, or `INADDR_ANY`
+    fn is_local(&self) -> bool {
+        net::is_ours(self.addr()) || self.addr() == Ipv4Addr::UNSPECIFIED
+    }
+}
+
+/// Socket address of any domain
+#[derive(Clone, Copy)]
+pub enum SockAddr {
+    In(SockAddrIn),
+    Un(SockAddrUn),
+}
+
+impl SockAddr {
+    /// Bytes of address, as passed to user
+    pub fn as_bytes(&self) -> &[u8] {
+        let (ptr, len) = match self {
+            SockAddr::In(addr) => (addr as *const _ as *const u8, core::mem::size_of::<SockAddrIn>()),
+            SockAddr::Un(addr) => (addr as *const _ as *const u8, core::mem::size_of::<SockAddrUn>())
+        };
+        unsafe { core::slice::from_raw_parts(ptr, len) }
+    }
+}
+
+/// State of UDP socket
+pub struct UdpState {
+    socket: Option<Arc<UdpSocket>>,
+    /// peer set by `connect`
+    peer: Option<SockAddrIn>,
+}
+
+/// State of TCP socket
+pub enum TcpState {
+    /// not listening or connected, with local port, 0 if not bound
+    Idle(u16),
+    Listener(Arc<TcpSocket>),
+    Stream(Arc<TcpSocket>),
+}
+
+/// A socket
+pub enum Socket {
+    Udp(Mutex<UdpState>),
+    Tcp(Mutex<TcpState>),
+    Unix(UnixSocket),
+}
+
+impl UdpState {
+    /// UDP port bound, binding an ephemeral one if there isn't
+    fn bound(&mut self) -> Option<Arc<UdpSocket>> {
+        if self.socket.is_none() {
+            self.socket = UdpSocket::bind(0).map(Arc::new);
+        }
+        self.socket.clone()
+    }
+}
+
+impl Socket {
+    /// Socket of `domain` and `ty`, `None` if it's not supported
+    pub fn new(domain: i32, ty: i32) -> Option<Self> {
+        match (domain, ty) {
+            (AF_INET, SOCK_DGRAM) => Some(Socket::Udp(Mutex::new(UdpState { socket: None, peer: None }, "udp socket"))),
+            (AF_INET, SOCK_STREAM) => Some(Socket::Tcp(Mutex::new(TcpState::Idle(0), "tcp socket"))),
+            (AF_UNIX, ty) => UnixSocket::new(ty).map(Socket::Unix),
+            _ => None
+        }
+    }
+
+    /// Bind to local `addr`
+    pub fn bind(&self, addr: &SockAddr) -> i32 {
+        let addr = match addr {
+            SockAddr::In(addr) => addr,
+            SockAddr::Un(addr) => return match self {
+                Socket::Unix(unix) => unix.bind(addr),
+                _ => -1
+            }
+        };
+        if !addr.is_local() {
+            return -1;
+        }
+        match self {
+            Socket::Udp(udp) => {
+                let mut udp = udp.lock();
+                if udp.socket.is_some() {
+                    return -1;
+                }
+                match UdpSocket::bind(addr.port()) {
+                    Some(socket) => {
+                        udp.socket = Some(Arc::new(socket));
+                        0
+                    }
+                    None => EADDRINUSE
+                }
+            }
+            Socket::Tcp(state) => {
+                let mut state = state.lock();
+                match *state {
+                    TcpState::Idle(0) if tcp::listening(addr.port()) => EADDRINUSE,
+                    TcpState::Idle(0) => {
+                        *state = TcpState::Idle(addr.port());
+                        0
+                    }
+                    _ => -1
+                }
+            }
+            Socket::Unix(_) => -1
+        }
+    }
+
+    /// Listen for connections, at most `backlog` of which wait for `accept`
+    pub fn listen(&self, backlog: usize) -> i32 {
+        let state = match self {
+            Socket::Tcp(state) => state,
+            Socket::Unix(unix) => return unix.listen(backlog),
+            _ => return -1
+        };
+        let mut state = state.lock();
+        match *state {
+            TcpState::Idle(port) => match tcp::listen(port, backlog) {
+                Ok(listener) => {
+                    *state = TcpState::Listener(Arc::new(listener));
+                    0
+                }
+                Err(err) => err
+            },
+            TcpState::Listener(_) => 0,
+            TcpState::Stream(_) => -1
+        }
+    }
+
+    /// Wait for a connection. Returns new socket and address of peer.
+    pub fn accept(&self) -> Result<(Socket, SockAddr), i32> {
+        let listener = match self {
+            Socket::Tcp(state) => match &*state.lock() {
+                TcpState::Listener(listener) => listener.clone(),
+                _ => return Err(-1)
+            },
+            Socket::Unix(unix) => {
+                let (socket, addr) = unix.accept()?;
+                return Ok((Socket::Unix(socket), SockAddr::Un(addr)));
+            }
+            _ => return Err(-1)
+        };
+        let stream = listener.accept()?;
+        let (addr, port) = stream.peer();
+        let socket = Socket::Tcp(Mutex::new(TcpState::Stream(Arc::new(stream)), "tcp socket"));
+        Ok((socket, SockAddr::In(SockAddrIn::new(addr, port))))
+    }
+
+    /// Connect to `addr`. On UDP sockets, it only sets destination of
+    /// `write` and filters datagrams received.
+    pub fn connect(&self, addr: &SockAddr) -> i32 {
+        let addr = match addr {
+            SockAddr::In(addr) => addr,
+            SockAddr::Un(addr) => return match self {
+                Socket::Unix(unix) => unix.connect(addr),
+                _ => -1
+            }
+        };
+        match self {
+            Socket::Udp(udp) => {
+                let mut udp = udp.lock();
+                if udp.bound().is_none() {
+                    return EADDRINUSE;
+                }
+                udp.peer = Some(*addr);
+                0
+            }
+            Socket::Tcp(state) => {
+                // connecting takes a while, so socket is left idle meanwhile
+                let port = match *state.lock() {
+                    TcpState::Idle(port) => port,
+                    _ => return -1
+                };
+                match tcp::connect(port, addr.addr(), addr.port()) {
+                    Ok(stream) => {
+                        *state.lock() = TcpState::Stream(Arc::new(stream));
+                        0
+                    }
+                    Err(err) => err
+                }
+            }
+            Socket::Unix(_) => -1
+        }
+    }
+
+    /// Send `data` and `files` to `addr`, or to peer if it's `None`. Returns
+    /// bytes sent. Only Unix sockets pass files.
+    pub fn send_msg(&self, data: &[u8], files: Vec<Arc<File>>, addr: Option<&SockAddr>) -> i32 {
+        let addr = match addr {
+            Some(SockAddr::In(addr)) => Some(addr),
+            Some(SockAddr::Un(addr)) => return match self {
+                Socket::Unix(unix) => unix.send(data, files, Some(addr)),
+                _ => -1
+            },
+            None => None
+        };
+        match self {
+            Socket::Unix(unix) if addr.is_none() => unix.send(data, files, None),
+            Socket::Unix(_) => -1,
+            _ if !files.is_empty() => -1,
+            Socket::Udp(udp) => {
+                let (socket, dst) = {
+                    let mut udp = udp.lock();
+                    let dst = match addr.copied().or(udp.peer) {
+                        Some(dst) => dst,
+                        None => return -1
+                    };
+                    match udp.bound() {
+                        Some(socket) => (socket, dst),
+                        None => return EADDRINUSE
+                    }
+                };
+                if socket.send_to(dst.addr(), dst.port(), data) { data.len() as i32 } else { -1 }
+            }
+            Socket::Tcp(state) => {
+                let stream = match &*state.lock() {
+                    TcpState::Stream(stream) => stream.clone(),
+                    _ => return -1
+                };
+                stream.send(data)
+            }
+        }
+    }
+
+    /// Receive into `buf`. Returns bytes received, files passed and address
+    /// of sender. Part of a datagram not fitting in `buf` is discarded.
+    pub fn recv_msg(&self, buf: &mut [u8]) -> Result<(usize, Vec<Arc<File>>, SockAddr), i32> {
+        match self {
+            Socket::Udp(udp) => {
+                let (socket, peer) = {
+                    let udp = udp.lock();
+                    match &udp.socket {
+                        Some(socket) => (socket.clone(), udp.peer),
+                        None => return Err(-1)
+                    }
+                };
+                loop {
+                    let datagram = socket.recv().ok_or(EINTR)?;
+                    let from = SockAddrIn::new(datagram.src, datagram.port);
+                    if peer.map_or(false, |p| p.addr != from.addr || p.port != from.port) {
+                        continue;
+                    }
+                    let n = core::cmp::min(buf.len(), datagram.data.len());
+                    buf[..n].copy_from_slice(&datagram.data[..n]);
+                    return Ok((n, Vec::new(), SockAddr::In(from)));
+                }
+            }
+            Socket::Tcp(state) => {
+                let stream = match &*state.lock() {
+                    TcpState::Stream(stream) => stream.clone(),
+                    _ => return Err(-1)
+                };
+                let (addr, port) = stream.peer();
+                match stream.recv(buf) {
+                    n if n >= 0 => Ok((n as usize, Vec::new(), SockAddr::In(SockAddrIn::new(addr, port)))),
+                    err => Err(err)
+                }
+            }
+            Socket::Unix(unix) => {
+                let (n, files, from) = unix.recv(buf)?;
+                Ok((n, files, SockAddr::Un(from)))
+            }
+        }
+    }
+
+    /// Read from connected socket. Files passed are closed.
+    pub fn read(&self, buf: &mut [u8]) -> i32 {
+        match self.recv_msg(buf) {
+            Ok((n, _, _)) => n as i32,
+            Err(err) => err
+        }
+    }
+
+    /// Write to connected socket
+    pub fn write(&self, data: &[u8]) -> i32 {
+        self.send_msg(data, Vec::new(), None)
+    }
+}
@@ -0,0 +1,650 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Sockets of `AF_UNIX` domain
+//!
+//! Binding a socket creates a socket node at its path on file system. The
+//! node outlives the socket, and path can't be bound again until it's
+//! unlinked. Sockets bound to nodes are found in a table of kernel.
+//! Files may be passed along with data, and stay open while in flight.
+
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::spinlock::Mutex;
+use crate::sleeplock::SleepLock;
+use crate::process::{my_proc, sleep, wakeup, signal};
+use crate::syscall::{ECONNREFUSED, EINTR, EPIPE};
+use crate::file::File;
+use crate::file::fsfile;
+use super::{SOCK_STREAM, SOCK_DGRAM};
+
+pub const AF_UNIX: i32 = 1;
+
+/// Length of path in `SockAddrUn`
+pub const UNIX_PATH_MAX: usize = 108;
+
+/// Files passed in a message at most
+pub const MAX_FDS: usize = 16;
+
+/// Bytes queued to a socket at most
+const BUF_SIZE: usize = 8192;
+
+/// `struct sockaddr_un`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SockAddrUn {
+    pub family: u16,
+    /// path ending with NUL, unless it fills the array
+    pub path: [u8; UNIX_PATH_MAX],
+}
+
+impl SockAddrUn {
+    /// Address of `path`, or of an unnamed socket if it's empty
+    pub fn new(path: &str) -> Self {
+        let mut addr = Self { family: AF_UNIX as u16, path: [0; UNIX_PATH_MAX] };
+        let n = min(path.len(), UNIX_PATH_MAX);
+        addr.path[..n].copy_from_slice(&path.as_bytes()[..n]);
+        addr
+    }
+
+    /// Path, `None` if it's empty or not UTF-8
+    pub fn path(&self) -> Option<&str> {
+        let len = self.path.iter().position(|&c| c == 0).unwrap_or(UNIX_PATH_MAX);
+        match core::str::from_utf8(&self.path[..len]) {
+            Ok(path) if !path.is_empty() => Some(path),
+            _ => None
+        }
+    }
+}
+
+/// Data sent, with files passed along
+struct Message {
+    data: Vec<u8>,
+    files: Vec<Arc<File>>,
+    /// name of sender of datagram
+    from: Option<String>,
+}
+
+/// Messages to a socket
+struct Queue {
+    messages: VecDeque<Message>,
+    /// bytes of data queued
+    len: usize,
+    /// sender is closed, so no message comes any more
+    eof: bool,
+    /// receiver is closed
+    closed: bool,
+}
+
+type Channel = Arc<Mutex<Queue>>;
+
+fn channel() -> Channel {
+    Arc::new(Mutex::new(Queue { messages: VecDeque::new(), len: 0, eof: false, closed: false }, "unix queue"))
+}
+
+/// Queue `message` to `chan`, sleeping while it's full. Returns bytes sent.
+fn push(chan: &Mutex<Queue>, message: Message) -> i32 {
+    let pid = my_proc().pid;
+    let n = message.data.len();
+    let mut queue = chan.lock();
+    loop {
+        if queue.closed {
+            return EPIPE;
+        }
+        // a message larger than buffer goes to an empty queue
+        if queue.len == 0 || queue.len + n <= BUF_SIZE {
+            break;
+        }
+        if signal::interrupted(pid) {
+            return EINTR;
+        }
+        queue = sleep(chan, queue);
+    }
+    queue.len += n;
+    queue.messages.push_back(message);
+    drop(queue);
+    wakeup(chan);
+    n as i32
+}
+
+/// Receive into `buf` from `chan`, sleeping until there's a message.
+/// Returns bytes received, files passed and name of sender.
+///
+/// On streams, data of following messages is received as well, unless
+/// they carry files, which come with the first byte of their message. On
+/// datagrams, part of message not fitting in `buf` is discarded.
+fn pop(chan: &Mutex<Queue>, buf: &mut [u8], stream: bool) -> Result<(usize, Vec<Arc<File>>, Option<String>), i32> {
+    let pid = my_proc().pid;
+    let mut queue = chan.lock();
+    while queue.messages.is_empty() {
+        if queue.eof {
+            return Ok((0, Vec::new(), None));
+        }
+        if signal::interrupted(pid) {
+            return Err(EINTR);
+        }
+        queue = sleep(chan, queue);
+    }
+    let mut message = queue.messages.pop_front().unwrap();
+    let files = mem::replace(&mut message.files, Vec::new());
+    let from = message.from.take();
+    let mut n = min(buf.len(), message.data.len());
+    buf[..n].copy_from_slice(&message.data[..n]);
+    if !stream {
+        queue.len -= message.data.len();
+    } else {
+        if n < message.data.len() {
+            message.data.drain(..n);
+            queue.messages.push_front(message);
+        }
+        while n < buf.len() {
+            let next = match queue.messages.front_mut() {
+                Some(next) if next.files.is_empty() => next,
+                _ => break
+            };
+            let m = min(buf.len() - n, next.data.len());
+            buf[n..n + m].copy_from_slice(&next.data[..m]);
+            next.data.drain(..m);
+            n += m;
+            if next.data.is_empty() {
+                queue.messages.pop_front();
+            }
+        }
+        queue.len -= n;
+    }
+    drop(queue);
+    wakeup(chan);
+    Ok((n, files, from))
+}
+
+/// Close receiving end of `chan`. Files in flight are closed as well.
+fn close(chan: &Mutex<Queue>) {
+    let mut queue = chan.lock();
+    queue.closed = true;
+    queue.len = 0;
+    let messages = mem::replace(&mut queue.messages, VecDeque::new());
+    drop(queue);
+    wakeup(chan);
+    // they may hold sockets, whose queues are locked on closing
+    drop(messages);
+}
+
+/// Close sending end of `chan`
+fn shutdown(chan: &Mutex<Queue>) {
+    chan.lock().eof = true;
+    wakeup(chan);
+}
+
+/// Connections waiting for `accept`
+struct Backlog {
+    streams: VecDeque<Stream>,
+    max: usize,
+    closed: bool,
+}
+
+/// What a name refers to
+#[derive(Clone)]
+enum Endpoint {
+    /// stream socket not listening yet
+    Idle,
+    Listener(Arc<Mutex<Backlog>>),
+    Datagram(Channel),
+}
+
+/// Path, id and endpoint of sockets bound to socket nodes
+static NAMES: Mutex<Vec<(String, usize, Endpoint)>> = Mutex::new(Vec::new(), "unix names");
+
+/// Id of next name bound
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Held while creating or removing socket nodes, so that `NAMES` agrees
+/// with them
+static BINDING: SleepLock = SleepLock::new("unix binding");
+
+/// A name bound, which is removed from `NAMES` when dropped. Its socket node
+/// stays on file system.
+pub struct Name {
+    path: String,
+    /// tells it from names bound to the same path after it's unlinked
+    id: usize,
+}
+
+impl Name {
+    /// Create socket node at `path` and bind it to `endpoint`. Returns
+    /// `EADDRINUSE` if `path` exists.
+    fn bind(path: &str, endpoint: Endpoint) -> Result<Self, i32> {
+        let _binding = BINDING.acquire();
+        match fsfile::mknod_socket(path) {
+            0 => {}
+            err => return Err(err)
+        }
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        NAMES.lock().push((path.to_string(), id, endpoint));
+        Ok(Name { path: path.to_string(), id })
+    }
+
+    /// Make name refer to `endpoint`, if it's not unlinked
+    fn set(&self, endpoint: Endpoint) {
+        if let Some(name) = NAMES.lock().iter_mut().find(|(_, id, _)| *id == self.id) {
+            name.2 = endpoint;
+        }
+    }
+}
+
+impl Drop for Name {
+    fn drop(&mut self) {
+        NAMES.lock().retain(|(_, id, _)| *id != self.id);
+    }
+}
+
+/// Endpoint bound to `path`
+fn lookup(path: &str) -> Option<Endpoint> {
+    NAMES.lock().iter().find(|(p, _, _)| p == path).map(|(_, _, endpoint)| endpoint.clone())
+}
+
+/// Remove socket node at `path`. Socket bound to it can't be reached any
+/// more, and `path` may be bound again.
+pub fn unlink(path: &str) -> i32 {
+    let _binding = BINDING.acquire();
+    let ret = fsfile::unlink(path);
+    if ret == 0 {
+        NAMES.lock().retain(|(p, _, _)| p != path);
+    }
+    ret
+}
+
+/// A connected stream, closed in both directions when dropped
+pub struct Stream {
+    rx: Channel,
+    tx: Channel,
+    /// name of peer
+    peer: Option<String>,
+    /// name bound before connecting
+    _name: Option<Name>,
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        close(&self.rx);
+        shutdown(&self.tx);
+    }
+}
+
+/// A listening stream socket. Connections not accepted are closed when
+/// it's dropped.
+pub struct Listener {
+    backlog: Arc<Mutex<Backlog>>,
+    _name: Name,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let mut backlog = self.backlog.lock();
+        backlog.closed = true;
+        let streams = mem::replace(&mut backlog.streams, VecDeque::new());
+        drop(backlog);
+        wakeup(&*self.backlog);
+        drop(streams);
+    }
+}
+
+/// State of stream socket
+pub enum StreamState {
+    /// not listening or connected, with name bound
+    Idle(Option<Name>),
+    Listener(Listener),
+    Stream(Arc<Stream>),
+}
+
+/// State of datagram socket, which receives datagrams once it's bound
+pub struct DgramState {
+    rx: Channel,
+    name: Option<Name>,
+    /// peer set by `connect`
+    peer: Option<String>,
+}
+
+impl Drop for DgramState {
+    fn drop(&mut self) {
+        close(&self.rx);
+    }
+}
+
+/// A socket of `AF_UNIX` domain
+pub enum UnixSocket {
+    Stream(Mutex<StreamState>),
+    Dgram(Mutex<DgramState>),
+}
+
+impl UnixSocket {
+    /// Socket of type `ty`, `None` if it's not supported
+    pub fn new(ty: i32) -> Option<Self> {
+        match ty {
+            SOCK_STREAM => Some(UnixSocket::Stream(Mutex::new(StreamState::Idle(None), "unix socket"))),
+            SOCK_DGRAM => Some(UnixSocket::Dgram(Mutex::new(DgramState { rx: channel(), name: None, peer: None }, "unix socket"))),
+            _ => None
+        }
+    }
+
+    /// Bind to `addr`
+    pub fn bind(&self, addr: &SockAddrUn) -> i32 {
+        let path = match addr.path() {
+            Some(path) => path,
+            None => return -1
+        };
+        // binding sleeps on disk, so state is checked again after it
+        match self {
+            UnixSocket::Stream(state) => {
+                match *state.lock() {
+                    StreamState::Idle(None) => {}
+                    _ => return -1
+                }
+                let name = match Name::bind(path, Endpoint::Idle) {
+                    Ok(name) => name,
+                    Err(err) => return err
+                };
+                let mut state = state.lock();
+                match *state {
+                    StreamState::Idle(None) => {
+                        *state = StreamState::Idle(Some(name));
+                        0
+                    }
+                    _ => -1
+                }
+            }
+            UnixSocket::Dgram(state) => {
+                let rx = {
+                    let state = state.lock();
+                    if state.name.is_some() {
+                        return -1;
+                    }
+                    state.rx.clone()
+                };
+                let name = match Name::bind(path, Endpoint::Datagram(rx)) {
+                    Ok(name) => name,
+                    Err(err) => return err
+                };
+                let mut state = state.lock();
+                if state.name.is_some() {
+                    return -1;
+                }
+                state.name = Some(name);
+                0
+            }
+        }
+    }
+
+    /// Listen for connections, at most `backlog` of which wait for `accept`.
+    /// Socket must be bound.
+    pub fn listen(&self, backlog: usize) -> i32 {
+        let state = match self {
+            UnixSocket::Stream(state) => state,
+            _ => return -1
+        };
+        let mut state = state.lock();
+        let name = match &mut *state {
+            StreamState::Idle(name) if name.is_some() => name.take().unwrap(),
+            StreamState::Listener(_) => return 0,
+            _ => return -1
+        };
+        let backlog = Arc::new(Mutex::new(Backlog { streams: VecDeque::new(), max: backlog, closed: false }, "unix backlog"));
+        name.set(Endpoint::Listener(backlog.clone()));
+        *state = StreamState::Listener(Listener { backlog, _name: name });
+        0
+    }
+
+    /// Wait for a connection. Returns new socket and address of peer.
+    pub fn accept(&self) -> Result<(UnixSocket, SockAddrUn), i32> {
+        let backlog = match self {
+            UnixSocket::Stream(state) => match &*state.lock() {
+                StreamState::Listener(listener) => listener.backlog.clone(),
+                _ => return Err(-1)
+            },
+            _ => return Err(-1)
+        };
+        let pid = my_proc().pid;
+        let mut pending = backlog.lock();
+        let stream = loop {
+            if let Some(stream) = pending.streams.pop_front() {
+                break stream;
+            }
+            if pending.closed {
+                return Err(-1);
+            }
+            if signal::interrupted(pid) {
+                return Err(EINTR);
+            }
+            pending = sleep(&*backlog, pending);
+        };
+        drop(pending);
+        let addr = SockAddrUn::new(stream.peer.as_deref().unwrap_or(""));
+        Ok((UnixSocket::Stream(Mutex::new(StreamState::Stream(Arc::new(stream)), "unix socket")), addr))
+    }
+
+    /// Connect to `addr`. Streams are connected at once, without waiting for
+    /// `accept`. On datagram sockets, it only sets destination of `write`.
+    pub fn connect(&self, addr: &SockAddrUn) -> i32 {
+        let path = match addr.path() {
+            Some(path) => path,
+            None => return -1
+        };
+        match (self, lookup(path)) {
+            (UnixSocket::Stream(state), Some(Endpoint::Listener(backlog))) => {
+                let mut state = state.lock();
+                let name = match &mut *state {
+                    StreamState::Idle(name) => name,
+                    _ => return -1
+                };
+                let (a, b) = (channel(), channel());
+                let mut pending = backlog.lock();
+                if pending.closed || pending.streams.len() >= pending.max {
+                    return ECONNREFUSED;
+                }
+                pending.streams.push_back(Stream {
+                    rx: a.clone(),
+                    tx: b.clone(),
+                    peer: name.as_ref().map(|name| name.path.clone()),
+                    _name: None,
+                });
+                drop(pending);
+                wakeup(&*backlog);
+                let stream = Stream { rx: b, tx: a, peer: Some(path.to_string()), _name: name.take() };
+                *state = StreamState::Stream(Arc::new(stream));
+                0
+            }
+            (UnixSocket::Dgram(state), Some(Endpoint::Datagram(_))) => {
+                state.lock().peer = Some(path.to_string());
+                0
+            }
+            _ => ECONNREFUSED
+        }
+    }
+
+    /// Send `data` and `files` to `addr`, or to peer if it's `None`.
+    /// Returns bytes sent.
+    pub fn send(&self, data: &[u8], files: Vec<Arc<File>>, addr: Option<&SockAddrUn>) -> i32 {
+        match self {
+            UnixSocket::Stream(state) => {
+                let stream = match &*state.lock() {
+                    StreamState::Stream(stream) => stream.clone(),
+                    _ => return -1
+                };
+                // files must come with some data, or reading them is taken as EOF
+                if data.is_empty() {
+                    return if files.is_empty() { 0 } else { -1 };
+                }
+                push(&stream.tx, Message { data: data.to_vec(), files, from: None })
+            }
+            UnixSocket::Dgram(state) => {
+                let (dst, from) = {
+                    let state = state.lock();
+                    let dst = match addr.and_then(|addr| addr.path()) {
+                        Some(path) => path.to_string(),
+                        None if addr.is_none() && state.peer.is_some() => state.peer.clone().unwrap(),
+                        None => return -1
+                    };
+                    (dst, state.name.as_ref().map(|name| name.path.clone()))
+                };
+                match lookup(&dst) {
+                    Some(Endpoint::Datagram(chan)) => push(&chan, Message { data: data.to_vec(), files, from }),
+                    _ => ECONNREFUSED
+                }
+            }
+        }
+    }
+
+    /// Receive into `buf`. Returns bytes received, files passed and address
+    /// of sender. Streams get 0 bytes when peer is closed.
+    pub fn recv(&self, buf: &mut [u8]) -> Result<(usize, Vec<Arc<File>>, SockAddrUn), i32> {
+        match self {
+            UnixSocket::Stream(state) => {
+                let stream = match &*state.lock() {
+                    StreamState::Stream(stream) => stream.clone(),
+                    _ => return Err(-1)
+                };
+                let (n, files, _) = pop(&stream.rx, buf, true)?;
+                Ok((n, files, SockAddrUn::new(stream.peer.as_deref().unwrap_or(""))))
+            }
+            UnixSocket::Dgram(state) => {
+                let rx = {
+                    let state = state.lock();
+                    if state.name.is_none() {
+                        return Err(-1);
+                    }
+                    state.rx.clone()
+                };
+                loop {
+                    let (n, files, from) = pop(&rx, buf, false)?;
+                    let peer = state.lock().peer.clone();
+                    if peer.is_some() && peer != from {
+                        continue;
+                    }
+                    return Ok((n, files, SockAddrUn::new(from.as_deref().unwrap_or(""))));
+                }
+            }
+        }
+    }
+}
+
+pub mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use crate::file::Console;
+    use crate::file::fsfile::Inode;
+    use crate::syscall::EADDRINUSE;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("address", test_address),
+            ("stream", test_stream),
+            ("datagram", test_datagram),
+            ("unlink", test_unlink),
+        ]
+    }
+
+    pub fn test_address() {
+        let addr = SockAddrUn::new("/tmp/sock");
+        assert_eq!(addr.path(), Some("/tmp/sock"));
+        assert_eq!(SockAddrUn::new("").path(), None);
+        assert_eq!(core::mem::size_of::<SockAddrUn>(), 110);
+    }
+
+    /// Pass a file on a stream
+    pub fn test_stream() {
+        let addr = SockAddrUn::new("/tmp/test-stream");
+        // left on disk by an earlier run which didn't finish
+        unlink("/tmp/test-stream");
+        let listener = UnixSocket::new(SOCK_STREAM).unwrap();
+        assert_eq!(listener.listen(1), -1);
+        assert_eq!(listener.bind(&addr), 0);
+        assert_eq!(UnixSocket::new(SOCK_STREAM).unwrap().bind(&addr), EADDRINUSE);
+        assert_eq!(listener.listen(1), 0);
+        let client = UnixSocket::new(SOCK_STREAM).unwrap();
+        assert_eq!(client.connect(&addr), 0);
+        // backlog is full
+        assert_eq!(UnixSocket::new(SOCK_STREAM).unwrap().connect(&addr), ECONNREFUSED);
+        let (server, _) = listener.accept().unwrap();
+
+        let file = Arc::new(File::Device(Box::new(Console {})));
+        assert_eq!(client.send(b"he", Vec::new(), None), 2);
+        assert_eq!(client.send(b"llo", alloc::vec![file.clone()], None), 3);
+        assert_eq!(Arc::strong_count(&file), 2);
+        let mut buf = [0; 16];
+        // stops before message with file
+        let (n, files, peer) = server.recv(&mut buf).unwrap();
+        assert_eq!((&buf[..n], files.len(), peer.path()), (&b"he"[..], 0, None));
+        let (n, files, _) = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"llo");
+        assert!(Arc::ptr_eq(&files[0], &file));
+
+        drop(client);
+        assert_eq!(server.recv(&mut buf).unwrap().0, 0);
+        assert_eq!(server.send(b"x", Vec::new(), None), EPIPE);
+        drop(listener);
+        assert!(lookup("/tmp/test-stream").is_none());
+        assert_eq!(unlink("/tmp/test-stream"), 0);
+    }
+
+    pub fn test_datagram() {
+        let a = UnixSocket::new(SOCK_DGRAM).unwrap();
+        let b = UnixSocket::new(SOCK_DGRAM).unwrap();
+        unlink("/tmp/test-a");
+        unlink("/tmp/test-b");
+        assert_eq!(a.bind(&SockAddrUn::new("/tmp/test-a")), 0);
+        assert_eq!(b.bind(&SockAddrUn::new("/tmp/test-b")), 0);
+        assert_eq!(a.send(b"hello", Vec::new(), Some(&SockAddrUn::new("/tmp/test-b"))), 5);
+        assert_eq!(a.send(b"world", Vec::new(), Some(&SockAddrUn::new("/tmp/test-b"))), 5);
+        let mut buf = [0; 3];
+        // rest of datagram is discarded
+        let (n, _, from) = b.recv(&mut buf).unwrap();
+        assert_eq!((&buf[..n], from.path()), (&b"hel"[..], Some("/tmp/test-a")));
+        let (n, _, _) = b.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"wor");
+        assert_eq!(a.send(b"x", Vec::new(), Some(&SockAddrUn::new("/tmp/none"))), ECONNREFUSED);
+        drop(b);
+        assert_eq!(a.send(b"x", Vec::new(), Some(&SockAddrUn::new("/tmp/test-b"))), ECONNREFUSED);
+        assert_eq!(unlink("/tmp/test-a"), 0);
+        assert_eq!(unlink("/tmp/test-b"), 0);
+    }
+
+    /// Test socket node stays after socket is closed, until it's unlinked
+    pub fn test_unlink() {
+        let addr = SockAddrUn::new("/tmp/test-unlink");
+        unlink("/tmp/test-unlink");
+        let listener = UnixSocket::new(SOCK_STREAM).unwrap();
+        assert_eq!(listener.bind(&addr), 0);
+        assert_eq!(fsfile::lookup("/tmp/test-unlink"), Some(Inode::Socket));
+        assert_eq!(listener.listen(1), 0);
+        drop(listener);
+        assert_eq!(fsfile::lookup("/tmp/test-unlink"), Some(Inode::Socket));
+        assert_eq!(UnixSocket::new(SOCK_STREAM).unwrap().connect(&addr), ECONNREFUSED);
+        assert_eq!(UnixSocket::new(SOCK_STREAM).unwrap().bind(&addr), EADDRINUSE);
+        // files on disk can't be bound or unlinked
+        assert_eq!(UnixSocket::new(SOCK_DGRAM).unwrap().bind(&SockAddrUn::new("/test.txt")), EADDRINUSE);
+        assert_eq!(unlink("/test.txt"), -1);
+
+        // unlinking a bound socket makes it unreachable
+        let a = UnixSocket::new(SOCK_DGRAM).unwrap();
+        assert_eq!(a.bind(&addr), EADDRINUSE);
+        assert_eq!(unlink("/tmp/test-unlink"), 0);
+        assert_eq!(unlink("/tmp/test-unlink"), -1);
+        assert_eq!(fsfile::lookup("/tmp/test-unlink"), None);
+        assert_eq!(a.bind(&addr), 0);
+        assert_eq!(unlink("/tmp/test-unlink"), 0);
+        assert_eq!(a.send(b"x", Vec::new(), Some(&addr)), ECONNREFUSED);
+        // name of unlinked socket is bound again
+        let b = UnixSocket::new(SOCK_DGRAM).unwrap();
+        assert_eq!(b.bind(&addr), 0);
+        drop(a);
+        assert_eq!(UnixSocket::new(SOCK_DGRAM).unwrap().send(b"x", Vec::new(), Some(&addr)), 1);
+        drop(b);
+        assert_eq!(unlink("/tmp/test-unlink"), 0);
+    }
+}
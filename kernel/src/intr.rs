@@ -11,11 +11,22 @@ use crate::uart::uartintr;
 use crate::arch;
 use crate::virtio::virtiointr;
 use crate::println;
+use crate::symbols::SCHEDULER_INTERVAL;
 
 #[derive(PartialEq)]
 pub enum Intr {
     Timer,
-    Device
+    Device,
+    /// Inter-processor interrupt
+    Software,
+}
+
+/// Send inter-processor interrupt to `hart`
+pub fn send_ipi(hart: usize) {
+    #[cfg(not(feature = "sbi"))]
+    crate::clint::send_ipi(hart);
+    #[cfg(feature = "sbi")]
+    crate::sbi::send_ipi(1 << hart);
 }
 
 /// Process device interrupts
@@ -25,22 +36,43 @@ pub fn devintr() -> Option<Intr> {
     if cause.is_interrupt() && cause.code() == 9 {
         let plic = crate::plic::PLIC();
         if let Some(interrupt) = plic.next() {
-            match interrupt {
-                plic::UART0_IRQ => {
-                    uartintr();
-                },
-                plic::VIRTIO0_IRQ => {
-                    virtiointr();
-                },
-                _ => {
-                    println!("Unrecognized external interrupt: {}", interrupt);
-                }
+            if interrupt == plic::UART0_IRQ() {
+                uartintr();
+            } else if !virtiointr(interrupt) {
+                println!("Unrecognized external interrupt: {}", interrupt);
             }
             plic.complete(interrupt);
         }
         Some(Intr::Device)
     } else if cause.is_interrupt() && cause.code() == 1 {
+        // supervisor software interrupt is raised by `timervec` for both
+        // timer and IPI, and only for IPI under SBI.
         arch::w_sip(arch::r_sip() & !2);
+        crate::mem::tlb::handle_ipi();
+        #[cfg(not(feature = "sbi"))]
+        {
+            if crate::clint::take_tick() {
+                crate::tty::tick();
+                crate::net::tick();
+                crate::process::wakeup_timeouts();
+                return Some(Intr::Timer);
+            }
+        }
+        Some(Intr::Software)
+    } else if cause.is_interrupt() && cause.code() == 5 {
+        // supervisor timer interrupt is only delivered under SBI,
+        // and setting next timer clears it.
+        #[cfg(feature = "sbi")]
+        {
+            crate::sbi::set_timer(arch::mtime() + SCHEDULER_INTERVAL as u64);
+            // firmware console doesn't interrupt, so poll it on timer
+            if crate::uart::UART_BASE_ADDR() == 0 {
+                uartintr();
+            }
+            crate::tty::tick();
+            crate::net::tick();
+            crate::process::wakeup_timeouts();
+        }
         Some(Intr::Timer)
     } else {
         None
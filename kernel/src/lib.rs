@@ -33,16 +33,24 @@ mod process;
 mod symbols;
 mod trap;
 mod uart;
+mod console;
+mod tty;
 mod plic;
 mod clint;
 mod syscall;
 mod start;
 mod jump;
 mod virtio;
+mod net;
 mod intr;
 mod test;
 mod sleeplock;
 mod file;
+mod executor;
+mod fdt;
+mod platform;
+#[cfg(feature = "sbi")]
+mod sbi;
 
 #[no_mangle]
 extern "C" fn eh_personality() {}
@@ -64,8 +72,11 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     abort();
 }
 
-/// Abort function
+/// Abort function. Under SBI, machine is shut down.
 #[no_mangle]
 extern "C" fn abort() -> ! {
+    #[cfg(feature = "sbi")]
+    sbi::system_reset(sbi::RESET_SHUTDOWN, sbi::REASON_FAILURE);
+    #[cfg(not(feature = "sbi"))]
     arch::wait_forever();
 }
@@ -12,24 +12,38 @@ use crate::symbols::*;
 use crate::spinlock::Mutex;
 use crate::page::EntryAttributes;
 use crate::page::{Table, KERNEL_PGTABLE};
-use crate::uart::UART_BASE_ADDR;
 use crate::process::*;
 use riscv::{register::*, asm};
 use crate::mem;
 use crate::arch;
 
+pub mod buddy;
+pub mod slab;
+pub mod stat;
+pub mod asid;
+pub mod tlb;
+pub mod swap;
+pub mod oom;
 
-/// Maximum number of pages. As QEMU and linker script `kernel.ld`
-/// are set to have 128MB of RAM, maximum number of pages can be calculated.
-pub const MAX_PAGE: usize = 128 * 1024 * 1024 / (1 << 12);
+use buddy::{BuddyAllocator, order_of_size, ORDERS};
+
+/// Maximum number of pages managed by frame allocator. RAM beyond 1 GiB
+/// from the start of memory reported by device tree is not used.
+pub const MAX_PAGE: usize = 1024 * 1024 * 1024 / (1 << 12);
+
+/// End of heap, which is the end of RAM, or the last page frame allocator
+/// can manage.
+#[allow(non_snake_case)]
+pub fn HEAP_END() -> usize {
+    let base = align_val_down(HEAP_START(), PAGE_ORDER + ORDERS - 1);
+    let end = crate::platform::platform().memory_end;
+    align_val_down(core::cmp::min(end, base + MAX_PAGE * PAGE_SIZE), PAGE_ORDER)
+}
 
 /// Frame allocator gives out one or more pages.
 pub struct Allocator {
-    /// A bool array records whether a page is handed out
-    pub page_allocated: [usize; MAX_PAGE],
-    /// Pages are handed out from `base_addr`, which is the start address
-    /// of HEAP.
-    pub base_addr: usize,
+    /// Buddy allocator managing all frames in HEAP
+    pub buddy: BuddyAllocator<MAX_PAGE>,
 }
 
 /// Align an address to upper bound according to specified order.
@@ -51,76 +65,36 @@ pub const fn page_down(val: usize) -> usize {
 impl Allocator {
     /// Returns a new allocator instance
     /// 
-    /// `base_addr` should be intialized later.
+    /// Buddy allocator should be intialized later.
     pub const fn new() -> Self {
         Allocator {
-            base_addr: 0,
-            page_allocated: [0; MAX_PAGE],
+            buddy: BuddyAllocator::new(),
         }
     }
 
-    fn offset_addr_of(&self, id: usize) -> usize {
-        let addr = self.base_addr + id * PAGE_SIZE;
-        addr
-    }
-
-    unsafe fn offset_id_of(&self, id: usize) -> *mut u8 {
-        self.offset_addr_of(id) as *mut u8
-    }
-
-    fn offset_page_of(&self, page: *mut u8) -> usize {
-        let id = (page as usize - self.base_addr) / PAGE_SIZE;
-        id
-    }
-
+    /// Allocate a block of at least `size` bytes. Size is rounded up to power of 2 pages.
+    /// Returns null if there's no free block large enough.
     pub fn allocate(&mut self, size: usize) -> *mut u8 {
-        let page_required = align_val(size, PAGE_ORDER) / PAGE_SIZE;
-        for i in 0..MAX_PAGE {
-            if self.page_allocated[i] == 0 {
-                let mut found = true;
-                for j in 0..page_required {
-                    if self.page_allocated[i + j] != 0 {
-                        found = false;
-                        break;
-                    }
-                }
-                if found {
-                    for j in 0..page_required {
-                        self.page_allocated[i + j] = page_required;
-                    }
-                    unsafe { return self.offset_id_of(i); }
-                }
+        match self.buddy.alloc(order_of_size(size)) {
+            Some(addr) => {
+                stat::update_peak_frames(&self.buddy.stats());
+                addr as *mut u8
             }
+            None => core::ptr::null_mut()
         }
-        panic!("no available page")
     }
 
     pub fn deallocate(&mut self, addr: *mut u8) {
-        let id = self.offset_page_of(addr);
-        let page_stride = self.page_allocated[id];
-        for j in 0..page_stride {
-            self.page_allocated[j + id] = 0;
-        }
+        self.buddy.free(addr as usize);
     }
 
     /// Print page allocation status
     pub fn debug(&self) {
-        let mut j = 0;
-        loop {
-            let size = self.page_allocated[j];
-            let addr = &self.page_allocated as *const usize;
-            let addr = unsafe { addr.add(j) };
-            if size != 0 {
-                let from = self.offset_addr_of(j);
-                let to = self.offset_addr_of(j + size);
-                println!("{} {:X} {:X}-{:X} (pages: {:X})", j, addr as usize, from, to, size);
-                j += size;
-            } else {
-                j += 1;
-            }
-            if j == MAX_PAGE {
-                break;
-            }
+        let stats = self.buddy.stats();
+        println!("pages: {} free / {} total, fragmentation {}%",
+                 stats.free_frames, stats.total_frames, stats.fragmentation());
+        for order in 0..ORDERS {
+            println!("  order {}: {} free blocks", order, stats.free_blocks[order]);
         }
     }
 }
@@ -132,13 +106,10 @@ static __ALLOC: Mutex<Allocator> = Mutex::new(Allocator::new(), "global allocato
 /// This function should only be called in boot hart
 pub unsafe fn init() {
     // Initialize allocator
-    ALLOC().get().base_addr = align_val(HEAP_START(), PAGE_ORDER);
-
-    // workaround for non-zero data region
-    let mut alloc = ALLOC().get();
-    for i in 0..MAX_PAGE {
-        alloc.page_allocated[i] = 0;
-    }
+    ALLOC().get().buddy.init(
+        align_val(HEAP_START(), PAGE_ORDER),
+        HEAP_END(),
+    );
 
     let pgtable: &mut Table = &mut *(&KERNEL_PGTABLE as *const _ as *mut _); // to bypass mut ref
     pgtable.id_map_range(
@@ -166,16 +137,17 @@ pub unsafe fn init() {
         KERNEL_STACK_END(),
         EntryAttributes::RW as usize,
     );
-    pgtable.kernel_map(
-        UART_BASE_ADDR,
-        UART_BASE_ADDR,
-        EntryAttributes::RW as usize,
-    );
-    pgtable.kernel_map(
-        VIRTIO_MMIO_BASE,
-        VIRTIO_MMIO_BASE,
-        EntryAttributes::RW as usize,
-    );
+    let platform = crate::platform::platform();
+    if platform.uart.base != 0 {
+        pgtable.kernel_map(
+            platform.uart.base,
+            platform.uart.base,
+            EntryAttributes::RW as usize,
+        );
+    }
+    for virtio in platform.virtio() {
+        pgtable.id_map_range(virtio.base, virtio.base + virtio.size, EntryAttributes::RW as usize);
+    }
     pgtable.kernel_map(
         TRAMPOLINE_START,
         TRAMPOLINE_TEXT_START(),
@@ -183,53 +155,86 @@ pub unsafe fn init() {
     );
     pgtable.id_map_range(
         HEAP_START(),
-        HEAP_START() + HEAP_SIZE(),
+        HEAP_END(),
         EntryAttributes::RW as usize,
     );
     // CLINT
-    pgtable.id_map_range(CLINT_BASE, CLINT_BASE + 0x10000, EntryAttributes::RW as usize);
+    pgtable.id_map_range(platform.clint.base, platform.clint.base + platform.clint.size, EntryAttributes::RW as usize);
     // PLIC
-    pgtable.id_map_range(PLIC_BASE, PLIC_BASE + 0x400000, EntryAttributes::RW as usize);
+    pgtable.id_map_range(platform.plic.base, platform.plic.base + platform.plic.size, EntryAttributes::RW as usize);
 }
 
 pub fn hartinit() {
     let root_ppn = &KERNEL_PGTABLE as *const Table as usize;
-    let satp_val = arch::build_satp(8, 0, root_ppn);
-    unsafe {
-        llvm_asm!("csrw satp, $0" :: "r"(satp_val));
-        asm::sfence_vma(0, 0);
-    }
+    // kernel page table always uses ASID 0
+    arch::w_satp(arch::build_satp(8, 0, root_ppn));
+    arch::sfence_vma_all();
 }
 
 #[allow(non_snake_case)]
 pub fn ALLOC() -> &'static Mutex<Allocator> { &__ALLOC }
 
 use core::alloc::{GlobalAlloc, Layout};
-use crate::plic::PLIC_BASE;
-use crate::clint::CLINT_BASE;
+use alloc::boxed::Box;
 use crate::arch::hart_id;
 use crate::process::my_cpu;
-use crate::virtio::VIRTIO_MMIO_BASE;
 
 struct OsAllocator {}
 
+/// Small objects are allocated from slab allocator, and others from frame allocator.
+/// Blocks from frame allocator are aligned to their size, which is power of 2 pages.
 unsafe impl GlobalAlloc for OsAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        ALLOC().lock().allocate(layout.size())
+        match slab::class_of(&layout) {
+            Some(class) => slab::alloc(class),
+            None => ALLOC().lock().allocate(core::cmp::max(layout.size(), layout.align()))
+        }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        ALLOC().lock().deallocate(ptr);
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match slab::class_of(&layout) {
+            Some(class) => slab::dealloc(class, ptr),
+            None => ALLOC().lock().deallocate(ptr)
+        }
     }
 }
 
 #[global_allocator]
 static GA: OsAllocator = OsAllocator {};
 
+/// Allocation failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutOfMemory;
+
+/// Allocate `T` with all bytes zero on heap.
+/// `T` must be valid when all of its bytes are zero.
+pub unsafe fn try_zeroed_box<T>() -> Result<Box<T>, OutOfMemory> {
+    let ptr = alloc::alloc::alloc_zeroed(Layout::new::<T>()) as *mut T;
+    if ptr.is_null() {
+        Err(OutOfMemory)
+    } else {
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+/// Move `value` to heap
+pub fn try_box<T>(value: T) -> Result<Box<T>, OutOfMemory> {
+    unsafe {
+        let ptr = alloc::alloc::alloc(Layout::new::<T>()) as *mut T;
+        if ptr.is_null() {
+            return Err(OutOfMemory);
+        }
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+/// Only infallible allocations of kernel itself end up here. Allocations
+/// on behalf of user processes use `try_` APIs and fail with `OutOfMemory`.
 #[alloc_error_handler]
 pub fn alloc_error(l: Layout) -> ! {
     panic!(
-        "Allocator failed to allocate {} bytes with {}-byte alignment.",
+        "out of memory: failed to allocate {} bytes with {}-byte alignment",
         l.size(),
         l.align()
     );
@@ -258,7 +263,3 @@ pub fn debug() {
         }
     }
 }
-
-pub fn alloc_stack() -> *mut u8 {
-    ALLOC().lock().allocate(PAGE_SIZE * 1024)
-}
@@ -0,0 +1,133 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Address space identifiers
+//!
+//! ASID 0 belongs to kernel page table, and user address spaces get ASIDs
+//! from 1 to `max` in generations. An address space keeps its ASID tagged
+//! with the generation it was handed out in. When all ASIDs of a generation
+//! are used up, a new generation begins, and address spaces of older
+//! generations get new ASIDs when they are switched to next time.
+//!
+//! ASIDs are never handed out twice in a generation, so a hart only has to
+//! flush its whole TLB before it first uses an ASID of a new generation.
+
+use crate::spinlock::Mutex;
+use crate::symbols::NCPUS;
+use crate::arch::{self, hart_id};
+
+/// Bits of generation are above this in tagged ASIDs
+const GENERATION_SHIFT: usize = 16;
+
+/// Hands out ASIDs in generations
+pub struct AsidAllocator {
+    /// Current generation, starting from 1
+    generation: usize,
+    /// Next ASID to hand out
+    next: usize,
+    /// Maximum ASID supported by hardware, 0 if ASID isn't supported
+    max: usize,
+}
+
+impl AsidAllocator {
+    pub const fn new(max: usize) -> Self {
+        Self { generation: 1, next: 1, max }
+    }
+
+    /// Make sure `tagged` is an ASID of current generation. Returns
+    /// whether a new ASID is handed out.
+    pub fn alloc(&mut self, tagged: &mut usize) -> bool {
+        if self.max == 0 || *tagged >> GENERATION_SHIFT == self.generation {
+            return false;
+        }
+        if self.next > self.max {
+            self.generation += 1;
+            self.next = 1;
+        }
+        *tagged = self.generation << GENERATION_SHIFT | self.next;
+        self.next += 1;
+        true
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+}
+
+/// ASID part of tagged ASID
+pub const fn asid_of(tagged: usize) -> usize {
+    tagged & ((1 << GENERATION_SHIFT) - 1)
+}
+
+static ASIDS: Mutex<AsidAllocator> = Mutex::new(AsidAllocator::new(0), "asid");
+
+/// Latest generation each hart has flushed its TLB for. Only accessed by
+/// its hart with interrupts off.
+static mut HART_GENERATION: [usize; NCPUS] = [0; NCPUS];
+
+/// Find out how many ASIDs hardware supports. Should be called on boot hart
+/// with kernel page table in `satp`.
+pub fn init() {
+    let satp = arch::r_satp();
+    arch::w_satp(satp | 0xffff << 44);
+    let max = (arch::r_satp() >> 44) & 0xffff;
+    arch::w_satp(satp);
+    arch::sfence_vma_all();
+    *ASIDS.lock() = AsidAllocator::new(max);
+}
+
+/// Maximum ASID supported, 0 if ASID isn't supported
+pub fn max_asid() -> usize {
+    ASIDS.lock().max
+}
+
+/// Get ASID of an address space to be used on current hart, allocating a new
+/// one if `tagged` is from an older generation. Returns 0 if ASID isn't
+/// supported. Should be called with interrupts off.
+pub fn activate(tagged: &mut usize) -> usize {
+    let mut asids = ASIDS.lock();
+    if asids.max == 0 {
+        return 0;
+    }
+    asids.alloc(tagged);
+    let generation = asids.generation;
+    drop(asids);
+    let hart_generation = unsafe { &mut HART_GENERATION[hart_id()] };
+    if *hart_generation != generation {
+        arch::sfence_vma_all();
+        *hart_generation = generation;
+    }
+    asid_of(*tagged)
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("rollover", test_rollover),
+        ]
+    }
+
+    pub fn test_rollover() {
+        let mut asids = AsidAllocator::new(2);
+        let (mut a, mut b, mut c) = (0, 0, 0);
+        assert!(asids.alloc(&mut a));
+        assert!(asids.alloc(&mut b));
+        assert!(!asids.alloc(&mut a));
+        assert_eq!((asid_of(a), asid_of(b)), (1, 2));
+        assert_eq!(asids.generation(), 1);
+        // ASIDs used up, c begins a new generation
+        assert!(asids.alloc(&mut c));
+        assert_eq!(asids.generation(), 2);
+        assert_eq!(asid_of(c), 1);
+        // a is from older generation
+        assert!(asids.alloc(&mut a));
+        assert_eq!(asid_of(a), 2);
+        assert!(!asids.alloc(&mut c));
+        let mut none = AsidAllocator::new(0);
+        assert!(!none.alloc(&mut a));
+    }
+}
@@ -0,0 +1,321 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Buddy allocator for physical frames
+//!
+//! Memory is managed in blocks of `2^order` frames. A block of order `o`
+//! is always aligned to `2^o` frames, so its buddy is found by flipping
+//! bit `o` of its frame number. Freed blocks are merged with their buddies
+//! as long as the buddy is free and of the same order.
+//!
+//! All metadata lives in arrays inside the allocator, and frames are never
+//! touched, so the allocator only depends on `core`. Besides kernel tests,
+//! it is tested on host against a heap-backed arena, see `kernel/host-tests`.
+
+/// Number of block orders. The largest block is `2^(ORDERS - 1)` frames.
+pub const ORDERS: usize = 12;
+
+/// Frame size in bytes
+const FRAME_SIZE: usize = 4096;
+
+/// End of free list
+const NIL: u32 = u32::MAX;
+
+/// Frame is not the head of any block, or not managed
+const NONE: u8 = 0;
+/// Frame is the head of a free block
+const FREE: u8 = 1;
+/// Frame is the head of an allocated block
+const ALLOCATED: u8 = 2;
+
+/// Buddy allocator managing up to `N` frames
+pub struct BuddyAllocator<const N: usize> {
+    /// Address of frame 0, aligned to largest block
+    base: usize,
+    /// Head of free list of each order
+    heads: [u32; ORDERS],
+    /// Next block in free list
+    next: [u32; N],
+    /// Previous block in free list
+    prev: [u32; N],
+    /// Order of block starting at this frame
+    order: [u8; N],
+    /// State of block starting at this frame
+    state: [u8; N],
+    /// Number of free blocks of each order
+    free_blocks: [usize; ORDERS],
+    /// Number of managed frames
+    total_frames: usize,
+    /// Number of free frames
+    free_frames: usize,
+}
+
+/// Statistics of a buddy allocator
+#[derive(Debug, Clone, Copy)]
+pub struct BuddyStats {
+    /// Number of managed frames
+    pub total_frames: usize,
+    /// Number of free frames
+    pub free_frames: usize,
+    /// Number of free blocks of each order
+    pub free_blocks: [usize; ORDERS],
+}
+
+impl BuddyStats {
+    /// Order of the largest free block, `None` if no frame is free
+    pub fn largest_free_order(&self) -> Option<usize> {
+        (0..ORDERS).rev().find(|&o| self.free_blocks[o] != 0)
+    }
+
+    /// External fragmentation in percent: how much of free memory can't be
+    /// handed out as a single block of the largest free size.
+    pub fn fragmentation(&self) -> usize {
+        match self.largest_free_order() {
+            Some(o) => 100 - (1 << o) * 100 / self.free_frames,
+            None => 0
+        }
+    }
+}
+
+impl<const N: usize> BuddyAllocator<N> {
+    pub const fn new() -> Self {
+        Self {
+            base: 0,
+            heads: [NIL; ORDERS],
+            next: [NIL; N],
+            prev: [NIL; N],
+            order: [0; N],
+            state: [NONE; N],
+            free_blocks: [0; ORDERS],
+            total_frames: 0,
+            free_frames: 0,
+        }
+    }
+
+    /// Manage frames in `[start, end)`. Both should be aligned to frame size,
+    /// and the range must fit in `N` frames after aligning `start` down to the
+    /// largest block.
+    pub fn init(&mut self, start: usize, end: usize) {
+        if start % FRAME_SIZE != 0 || end % FRAME_SIZE != 0 {
+            panic!("buddy: range {:x}-{:x} not aligned", start, end);
+        }
+        self.base = start & !(FRAME_SIZE * (1 << (ORDERS - 1)) - 1);
+        if (end - self.base) / FRAME_SIZE > N {
+            panic!("buddy: range {:x}-{:x} too large", start, end);
+        }
+        self.heads = [NIL; ORDERS];
+        self.free_blocks = [0; ORDERS];
+        for i in 0..N {
+            self.state[i] = NONE;
+        }
+        self.total_frames = 0;
+        self.free_frames = 0;
+        let mut frame = (start - self.base) / FRAME_SIZE;
+        let end = (end - self.base) / FRAME_SIZE;
+        while frame < end {
+            // largest aligned block fitting in the rest of range
+            let mut order = ORDERS - 1;
+            while frame % (1 << order) != 0 || frame + (1 << order) > end {
+                order -= 1;
+            }
+            self.push(frame, order);
+            self.total_frames += 1 << order;
+            self.free_frames += 1 << order;
+            frame += 1 << order;
+        }
+    }
+
+    /// Add free block to free list
+    fn push(&mut self, frame: usize, order: usize) {
+        let head = self.heads[order];
+        self.next[frame] = head;
+        self.prev[frame] = NIL;
+        if head != NIL {
+            self.prev[head as usize] = frame as u32;
+        }
+        self.heads[order] = frame as u32;
+        self.order[frame] = order as u8;
+        self.state[frame] = FREE;
+        self.free_blocks[order] += 1;
+    }
+
+    /// Remove free block from free list
+    fn remove(&mut self, frame: usize) {
+        let order = self.order[frame] as usize;
+        let (next, prev) = (self.next[frame], self.prev[frame]);
+        if prev != NIL {
+            self.next[prev as usize] = next;
+        } else {
+            self.heads[order] = next;
+        }
+        if next != NIL {
+            self.prev[next as usize] = prev;
+        }
+        self.state[frame] = NONE;
+        self.free_blocks[order] -= 1;
+    }
+
+    /// Allocate a block of `2^order` frames, returns its address
+    pub fn alloc(&mut self, order: usize) -> Option<usize> {
+        if order >= ORDERS {
+            return None;
+        }
+        let from = (order..ORDERS).find(|&o| self.heads[o] != NIL)?;
+        let frame = self.heads[from] as usize;
+        self.remove(frame);
+        // split, returning upper halves to free lists
+        for o in (order..from).rev() {
+            self.push(frame + (1 << o), o);
+        }
+        self.order[frame] = order as u8;
+        self.state[frame] = ALLOCATED;
+        self.free_frames -= 1 << order;
+        Some(self.base + frame * FRAME_SIZE)
+    }
+
+    /// Free a block returned by `alloc`, returns its order
+    pub fn free(&mut self, addr: usize) -> usize {
+        let mut frame = match self.frame_of(addr) {
+            Some(frame) if self.state[frame] == ALLOCATED => frame,
+            _ => panic!("buddy: invalid free {:x}", addr)
+        };
+        let order = self.order[frame] as usize;
+        self.state[frame] = NONE;
+        self.free_frames += 1 << order;
+        let mut o = order;
+        while o < ORDERS - 1 {
+            let buddy = frame ^ (1 << o);
+            if buddy >= N || self.state[buddy] != FREE || self.order[buddy] as usize != o {
+                break;
+            }
+            self.remove(buddy);
+            frame &= !(1 << o);
+            o += 1;
+        }
+        self.push(frame, o);
+        order
+    }
+
+    /// Frame number of `addr`, `None` if it's not a managed frame
+    fn frame_of(&self, addr: usize) -> Option<usize> {
+        if addr < self.base || addr % FRAME_SIZE != 0 {
+            return None;
+        }
+        let frame = (addr - self.base) / FRAME_SIZE;
+        if frame < N { Some(frame) } else { None }
+    }
+
+    /// Order of allocated block at `addr`
+    pub fn order_of(&self, addr: usize) -> Option<usize> {
+        match self.frame_of(addr) {
+            Some(frame) if self.state[frame] == ALLOCATED => Some(self.order[frame] as usize),
+            _ => None
+        }
+    }
+
+    pub fn stats(&self) -> BuddyStats {
+        BuddyStats {
+            total_frames: self.total_frames,
+            free_frames: self.free_frames,
+            free_blocks: self.free_blocks,
+        }
+    }
+}
+
+/// Smallest order of block holding `size` bytes
+pub const fn order_of_size(size: usize) -> usize {
+    let frames = (size + FRAME_SIZE - 1) / FRAME_SIZE;
+    if frames <= 1 {
+        0
+    } else {
+        (core::mem::size_of::<usize>() * 8) - (frames - 1).leading_zeros() as usize
+    }
+}
+
+pub mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("order of size", test_order_of_size),
+            ("split and merge", test_split_merge),
+            ("unaligned range", test_unaligned_range),
+            ("fragmentation", test_fragmentation),
+        ]
+    }
+
+    const BASE: usize = 0x8000_0000;
+    const MAX_BLOCK: usize = FRAME_SIZE << (ORDERS - 1);
+
+    pub fn test_order_of_size() {
+        assert_eq!(order_of_size(1), 0);
+        assert_eq!(order_of_size(FRAME_SIZE), 0);
+        assert_eq!(order_of_size(FRAME_SIZE + 1), 1);
+        assert_eq!(order_of_size(FRAME_SIZE * 4), 2);
+        assert_eq!(order_of_size(FRAME_SIZE * 5), 3);
+    }
+
+    /// Test blocks are split on allocation and merged back on free
+    pub fn test_split_merge() {
+        let mut buddy: Box<BuddyAllocator<2048>> = Box::new(BuddyAllocator::new());
+        buddy.init(BASE, BASE + MAX_BLOCK);
+        assert_eq!(buddy.stats().free_blocks[ORDERS - 1], 1);
+        let a = buddy.alloc(0).unwrap();
+        let b = buddy.alloc(0).unwrap();
+        let c = buddy.alloc(3).unwrap();
+        assert_eq!(a, BASE);
+        assert_eq!(b, BASE + FRAME_SIZE);
+        assert_eq!(c % (FRAME_SIZE << 3), 0);
+        assert_eq!(buddy.order_of(c), Some(3));
+        assert_eq!(buddy.stats().free_frames, 2048 - 10);
+        assert_eq!(buddy.free(b), 0);
+        assert_eq!(buddy.free(a), 0);
+        assert_eq!(buddy.free(c), 3);
+        let stats = buddy.stats();
+        assert_eq!(stats.free_frames, 2048);
+        assert_eq!(stats.free_blocks[ORDERS - 1], 1);
+        assert_eq!(stats.largest_free_order(), Some(ORDERS - 1));
+        assert!(buddy.alloc(ORDERS).is_none());
+    }
+
+    /// Test range not aligned to largest block
+    pub fn test_unaligned_range() {
+        let mut buddy: Box<BuddyAllocator<4096>> = Box::new(BuddyAllocator::new());
+        let start = BASE + 3 * FRAME_SIZE;
+        let end = BASE + MAX_BLOCK + 5 * FRAME_SIZE;
+        buddy.init(start, end);
+        assert_eq!(buddy.stats().total_frames, 2048 + 2);
+        let mut n = 0;
+        while let Some(addr) = buddy.alloc(0) {
+            assert!(addr >= start && addr < end);
+            n += 1;
+        }
+        assert_eq!(n, 2048 + 2);
+        assert_eq!(buddy.stats().largest_free_order(), None);
+    }
+
+    /// Test fragmentation is reported when free frames are scattered
+    pub fn test_fragmentation() {
+        let mut buddy: Box<BuddyAllocator<16>> = Box::new(BuddyAllocator::new());
+        buddy.init(BASE, BASE + 16 * FRAME_SIZE);
+        assert_eq!(buddy.stats().fragmentation(), 0);
+        let mut frames = [0; 16];
+        for f in frames.iter_mut() {
+            *f = buddy.alloc(0).unwrap();
+        }
+        for i in (0..16).step_by(2) {
+            buddy.free(frames[i]);
+        }
+        let stats = buddy.stats();
+        assert_eq!(stats.free_frames, 8);
+        assert_eq!(stats.largest_free_order(), Some(0));
+        assert_eq!(stats.fragmentation(), 88);
+        for i in (1..16).step_by(2) {
+            buddy.free(frames[i]);
+        }
+        assert_eq!(buddy.stats().fragmentation(), 0);
+    }
+}
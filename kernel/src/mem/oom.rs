@@ -0,0 +1,185 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Out-of-memory handling
+//!
+//! User memory is allocated with `try_` APIs, so that a syscall fails with
+//! `ENOMEM` instead of panicking when memory runs out. Before a syscall or
+//! page fault allocates user memory, `reserve` makes room for it by swapping
+//! out pages, and if that isn't enough, by killing the process with most
+//! resident pages.
+//!
+//! Only address spaces without pages pinned by syscalls are killed by
+//! others, and their pages are freed right away. A syscall fails instead if
+//! current process has most resident pages, while on page fault current
+//! process is killed when it returns from trap, and its pages are freed on
+//! exit.
+
+use crate::symbols::NMAXPROCS;
+use crate::page::{AddressSpace, Entry};
+use crate::process::{my_proc, PageTable};
+use crate::warn;
+use super::swap::{self, free_frames, pooled_pgtable};
+
+/// Frames kept free for page tables and kernel objects besides pages reserved
+const MARGIN: usize = 16;
+
+/// Number of resident user pages in `space`
+pub fn resident(space: &mut AddressSpace) -> usize {
+    let mut pages = 0;
+    space.walk_user(0, &mut |_, v: &mut Entry| {
+        if v.is_v() {
+            pages += 1;
+        }
+        true
+    });
+    pages
+}
+
+/// Make sure `pages` frames are free for a syscall, swapping out pages and
+/// killing other processes if needed. Returns false if there can't be
+/// enough memory, or current process has most resident pages. Should be
+/// called in process context without holding any lock.
+pub fn reserve(pages: usize) -> bool {
+    make_room(pages, false)
+}
+
+/// Make sure `pages` frames are free for a page fault like `reserve`, but
+/// current process is killed if it has most resident pages.
+pub fn reserve_fault(pages: usize) -> bool {
+    make_room(pages, true)
+}
+
+fn make_room(pages: usize, kill_self: bool) -> bool {
+    let (_, free_slots) = swap::stats();
+    if pages > free_frames() + free_slots {
+        return false;
+    }
+    let target = pages + MARGIN;
+    while !swap::reclaim(target) {
+        if !kill_victim(kill_self) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Kill process with most resident pages, or current process if it has
+/// most and `kill_self` is set. Returns true if memory is freed, or should
+/// be looked for again.
+fn kill_victim(kill_self: bool) -> bool {
+    let me = my_proc();
+    let mine = resident(&mut me.pgtable.lock());
+    let mut victim: Option<(usize, usize, PageTable)> = None;
+    // init is never killed
+    for pid in 1..NMAXPROCS {
+        let pgtable = match pooled_pgtable(pid) {
+            Some(pgtable) => pgtable,
+            None => continue
+        };
+        let pages = {
+            let mut space = pgtable.lock();
+            if !space.pinned.is_empty() || space.killed {
+                continue;
+            }
+            resident(&mut space)
+        };
+        if victim.as_ref().map_or(true, |(_, max, _)| pages > *max) {
+            victim = Some((pid, pages, pgtable));
+        }
+    }
+    match victim {
+        Some((pid, pages, pgtable)) if pages > mine => {
+            let mut space = pgtable.lock();
+            // a page may have been pinned in the meantime
+            if space.pinned.is_empty() {
+                warn!("out of memory: killed pid {} with {} resident pages", pid, pages);
+                space.killed = true;
+                space.unmap_user();
+            }
+            true
+        }
+        _ => {
+            if kill_self && me.pid != 0 {
+                warn!("out of memory: killed pid {} with {} resident pages", me.pid, mine);
+                me.pgtable.lock().killed = true;
+            }
+            false
+        }
+    }
+}
+
+pub mod tests {
+    use super::*;
+    use crate::page::{Table, Page, EntryAttributes};
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("resident", test_resident),
+            ("kill victim", test_kill_victim),
+            ("reserve too much", test_reserve_too_much),
+        ]
+    }
+
+    pub fn test_resident() {
+        let mut space = AddressSpace::new(Table::alloc());
+        assert_eq!(resident(&mut space), 0);
+        space.map(0x1000, Page::new(), EntryAttributes::URW as usize);
+        space.map(0x4000_0000, Page::new(), EntryAttributes::URW as usize);
+        space.try_kernel_map(0x2000, 0x8000_0000, EntryAttributes::RW as usize).unwrap();
+        // kernel pages aren't counted
+        assert_eq!(resident(&mut space), 2);
+        space.unmap(0x1000);
+        assert_eq!(resident(&mut space), 1);
+    }
+
+    /// Test process with most resident pages is killed when memory runs
+    /// out, after which allocation succeeds again
+    pub fn test_kill_victim() {
+        use crate::process::{alloc_pid, put_back_proc, Process, ProcessState, PROCS_POOL, ProcInPool};
+        use crate::arch;
+        use crate::symbols::PAGE_SIZE;
+
+        let pid = alloc_pid().unwrap();
+        let mut victim = Process::new(pid);
+        // never scheduled or woken up
+        victim.state = ProcessState::SLEEPING;
+        let pgtable = victim.pgtable.clone();
+        put_back_proc(box victim);
+
+        // nothing else should allocate while memory is exhausted
+        arch::intr_off();
+        let mut vaddr = 0x1000_0000;
+        while let Ok(pg) = Page::try_new() {
+            if pgtable.lock().try_map(vaddr, pg, EntryAttributes::URW as usize).is_err() {
+                break;
+            }
+            vaddr += PAGE_SIZE;
+        }
+        // page freed as page table for it can't be allocated
+        let spare = Page::try_new().ok();
+        assert!(Page::try_new().is_err());
+        assert!(kill_victim(false));
+        assert!(Page::try_new().is_ok());
+        drop(spare);
+        arch::intr_on();
+
+        {
+            let mut space = pgtable.lock();
+            assert!(space.killed);
+            assert_eq!(resident(&mut space), 0);
+        }
+        let victim = core::mem::replace(&mut PROCS_POOL.lock()[pid as usize], ProcInPool::NoProc);
+        assert!(matches!(victim, ProcInPool::Pooling(_)));
+    }
+
+    /// Test syscall fails at once when more is asked than frames and swap
+    /// can hold, and its caller isn't killed
+    pub fn test_reserve_too_much() {
+        let (_, free_slots) = swap::stats();
+        assert!(!reserve(free_frames() + free_slots + 1024));
+        assert!(!my_proc().pgtable.lock().killed);
+    }
+}
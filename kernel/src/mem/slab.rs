@@ -0,0 +1,307 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Slab allocator for small kernel objects
+//!
+//! Objects up to 2 KiB are served from size classes of power of 2 bytes.
+//! A slab is a block of frames from the buddy allocator, holding a `Slab`
+//! header at its beginning and objects of one class after it. Objects are
+//! aligned to their class size, so `Layout::align` is honored by picking a
+//! class of at least `align` bytes.
+//!
+//! Every hart caches a few free objects of each class in a magazine, which
+//! is only accessed with interrupts off. Magazines are refilled from and
+//! flushed to the per-class depot, which holds partially used slabs.
+
+use core::alloc::Layout;
+use core::ptr::null_mut;
+use crate::spinlock::Mutex;
+use crate::symbols::{PAGE_SIZE, NCPUS};
+use crate::process::my_cpu;
+use crate::arch::hart_id;
+use super::{ALLOC, align_val};
+use super::stat::{self, Kind};
+
+/// Size of smallest class is `2^MIN_CLASS_ORDER`
+const MIN_CLASS_ORDER: usize = 4;
+
+/// Number of size classes, from 16 B to 2 KiB
+pub const CLASSES: usize = 8;
+
+/// Largest object served by slab allocator
+pub const MAX_OBJECT_SIZE: usize = 1 << (MIN_CLASS_ORDER + CLASSES - 1);
+
+/// Number of objects cached in a magazine
+const MAGAZINE_SIZE: usize = 16;
+
+/// A free object, linked in free list of its slab
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Header at the beginning of a slab
+struct Slab {
+    /// Free objects in this slab
+    free: *mut FreeObject,
+    /// Objects handed out from this slab, including those in magazines
+    in_use: usize,
+    /// Previous slab in partial list
+    prev: *mut Slab,
+    /// Next slab in partial list
+    next: *mut Slab,
+}
+
+/// Object size of class
+const fn class_size(class: usize) -> usize {
+    1 << (class + MIN_CLASS_ORDER)
+}
+
+/// Buddy order of slabs of class, so that a slab holds at least 15 objects
+const fn slab_order(class: usize) -> usize {
+    let order = class + MIN_CLASS_ORDER;
+    if order > 8 { order - 8 } else { 0 }
+}
+
+/// Size of slabs of class
+const fn slab_size(class: usize) -> usize {
+    PAGE_SIZE << slab_order(class)
+}
+
+/// Offset of first object in slab of class
+fn first_object(class: usize) -> usize {
+    align_val(core::mem::size_of::<Slab>(), class + MIN_CLASS_ORDER)
+}
+
+/// Size class of `layout`, `None` if it should be allocated from buddy allocator
+pub fn class_of(layout: &Layout) -> Option<usize> {
+    let size = core::cmp::max(layout.size(), layout.align());
+    if size > MAX_OBJECT_SIZE {
+        return None;
+    }
+    let mut class = 0;
+    while class_size(class) < size {
+        class += 1;
+    }
+    Some(class)
+}
+
+/// Partially used slabs of a class
+struct Depot {
+    class: usize,
+    /// Slabs with at least one free object
+    partial: *mut Slab,
+    /// Number of slabs of this class
+    slabs: usize,
+}
+
+unsafe impl Send for Depot {}
+
+impl Depot {
+    const fn new(class: usize) -> Self {
+        Self { class, partial: null_mut(), slabs: 0 }
+    }
+
+    /// Allocate a new slab from buddy allocator and put it into partial list.
+    /// Returns false if there's no free frame.
+    unsafe fn grow(&mut self) -> bool {
+        let base = ALLOC().lock().allocate(slab_size(self.class));
+        if base.is_null() {
+            return false;
+        }
+        let slab = base as *mut Slab;
+        let size = class_size(self.class);
+        let mut free = null_mut();
+        let mut offset = slab_size(self.class) - size;
+        while offset >= first_object(self.class) {
+            let obj = base.add(offset) as *mut FreeObject;
+            (*obj).next = free;
+            free = obj;
+            offset -= size;
+        }
+        slab.write(Slab { free, in_use: 0, prev: null_mut(), next: null_mut() });
+        self.push(slab);
+        self.slabs += 1;
+        true
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.partial = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+    }
+
+    /// Take an object from partial slabs, growing if there's none.
+    /// Returns null if slab can't grow.
+    unsafe fn take(&mut self) -> *mut u8 {
+        if self.partial.is_null() && !self.grow() {
+            return null_mut();
+        }
+        let slab = self.partial;
+        let obj = (*slab).free;
+        (*slab).free = (*obj).next;
+        (*slab).in_use += 1;
+        if (*slab).free.is_null() {
+            self.remove(slab);
+        }
+        obj as *mut u8
+    }
+
+    /// Return an object to its slab, freeing the slab if it's no longer used
+    unsafe fn give(&mut self, obj: *mut u8) {
+        let slab = (obj as usize & !(slab_size(self.class) - 1)) as *mut Slab;
+        let obj = obj as *mut FreeObject;
+        if (*slab).free.is_null() {
+            self.push(slab);
+        }
+        (*obj).next = (*slab).free;
+        (*slab).free = obj;
+        (*slab).in_use -= 1;
+        if (*slab).in_use == 0 {
+            self.remove(slab);
+            self.slabs -= 1;
+            ALLOC().lock().deallocate(slab as *mut u8);
+        }
+    }
+}
+
+static DEPOTS: [Mutex<Depot>; CLASSES] = [
+    Mutex::new(Depot::new(0), "slab 16"),
+    Mutex::new(Depot::new(1), "slab 32"),
+    Mutex::new(Depot::new(2), "slab 64"),
+    Mutex::new(Depot::new(3), "slab 128"),
+    Mutex::new(Depot::new(4), "slab 256"),
+    Mutex::new(Depot::new(5), "slab 512"),
+    Mutex::new(Depot::new(6), "slab 1024"),
+    Mutex::new(Depot::new(7), "slab 2048"),
+];
+
+/// Free objects of a class cached on a hart
+#[derive(Clone, Copy)]
+struct Magazine {
+    count: usize,
+    objs: [*mut u8; MAGAZINE_SIZE],
+}
+
+impl Magazine {
+    const fn new() -> Self {
+        Self { count: 0, objs: [null_mut(); MAGAZINE_SIZE] }
+    }
+}
+
+/// Magazines of each hart, only accessed by its hart with interrupts off
+static mut MAGAZINES: [[Magazine; CLASSES]; NCPUS] = [[Magazine::new(); CLASSES]; NCPUS];
+
+/// Allocate an object of class, null if out of memory
+pub fn alloc(class: usize) -> *mut u8 {
+    let _intr_lock = my_cpu().intr_lock.lock();
+    let mag = unsafe { &mut MAGAZINES[hart_id()][class] };
+    if mag.count == 0 {
+        let mut depot = DEPOTS[class].lock();
+        while mag.count < MAGAZINE_SIZE / 2 {
+            let obj = unsafe { depot.take() };
+            if obj.is_null() {
+                break;
+            }
+            mag.objs[mag.count] = obj;
+            mag.count += 1;
+        }
+        if mag.count == 0 {
+            return null_mut();
+        }
+    }
+    mag.count -= 1;
+    stat::add(Kind::Heap, class_size(class));
+    mag.objs[mag.count]
+}
+
+/// Free an object allocated by `alloc` with the same class
+pub fn dealloc(class: usize, ptr: *mut u8) {
+    let _intr_lock = my_cpu().intr_lock.lock();
+    let mag = unsafe { &mut MAGAZINES[hart_id()][class] };
+    if mag.count == MAGAZINE_SIZE {
+        let mut depot = DEPOTS[class].lock();
+        while mag.count > MAGAZINE_SIZE / 2 {
+            mag.count -= 1;
+            unsafe { depot.give(mag.objs[mag.count]); }
+        }
+    }
+    mag.objs[mag.count] = ptr;
+    mag.count += 1;
+    stat::sub(Kind::Heap, class_size(class));
+}
+
+/// Number of slabs of each class
+pub fn slabs() -> [usize; CLASSES] {
+    let mut slabs = [0; CLASSES];
+    for class in 0..CLASSES {
+        slabs[class] = DEPOTS[class].lock().slabs;
+    }
+    slabs
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("size class", test_class_of),
+            ("alloc", test_alloc),
+            ("alignment", test_alignment),
+        ]
+    }
+
+    pub fn test_class_of() {
+        assert_eq!(class_of(&Layout::from_size_align(1, 1).unwrap()), Some(0));
+        assert_eq!(class_of(&Layout::from_size_align(16, 8).unwrap()), Some(0));
+        assert_eq!(class_of(&Layout::from_size_align(17, 8).unwrap()), Some(1));
+        assert_eq!(class_of(&Layout::from_size_align(8, 256).unwrap()), Some(4));
+        assert_eq!(class_of(&Layout::from_size_align(2048, 8).unwrap()), Some(7));
+        assert_eq!(class_of(&Layout::from_size_align(2049, 8).unwrap()), None);
+        assert_eq!(class_of(&Layout::from_size_align(8, 4096).unwrap()), None);
+    }
+
+    /// Test objects spanning several slabs don't overlap
+    pub fn test_alloc() {
+        const N: usize = 600;
+        let class = 2;
+        let mut objs = [null_mut(); N];
+        for i in 0..N {
+            objs[i] = alloc(class);
+            unsafe { core::ptr::write_bytes(objs[i], i as u8, class_size(class)); }
+        }
+        assert!(slabs()[class] >= N * class_size(class) / slab_size(class));
+        for i in 0..N {
+            let obj = unsafe { core::slice::from_raw_parts(objs[i], class_size(class)) };
+            assert!(obj.iter().all(|&x| x == i as u8));
+            dealloc(class, objs[i]);
+        }
+    }
+
+    /// Test `Layout::align` is honored by boxes and global allocator
+    pub fn test_alignment() {
+        use alloc::alloc::{alloc as global_alloc, dealloc as global_dealloc};
+        for &(size, align) in &[(8, 8), (24, 64), (100, 512), (3000, 8), (16, 8192)] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = unsafe { global_alloc(layout) };
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align, 0);
+            unsafe { global_dealloc(ptr, layout); }
+        }
+    }
+}
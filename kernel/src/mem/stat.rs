@@ -0,0 +1,191 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Memory usage accounting
+//!
+//! Each subsystem reports memory it takes and gives back with `add` and
+//! `sub`. Current usage and high-water marks are exposed in `/proc/meminfo`.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::ALLOC;
+use super::buddy::BuddyStats;
+use crate::symbols::PAGE_SIZE;
+
+/// Kinds of memory usage
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    /// Page table pages
+    PageTable,
+    /// Kernel stacks of processes
+    KernelStack,
+    /// Pages mapped in user space
+    UserPage,
+    /// Small objects from slab allocator
+    Heap,
+    /// Block buffers
+    BufferCache,
+}
+
+const KINDS: usize = 5;
+
+const NAMES: [&str; KINDS] = ["PageTables", "KernelStack", "UserPages", "Heap", "BufferCache"];
+
+/// Bytes in use of each kind
+static USED: [AtomicUsize; KINDS] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0)
+];
+
+/// High-water mark of each kind
+static PEAK: [AtomicUsize; KINDS] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0)
+];
+
+/// High-water mark of frames handed out by frame allocator
+static PEAK_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// Record `bytes` taken by `kind`
+pub fn add(kind: Kind, bytes: usize) {
+    let used = USED[kind as usize].fetch_add(bytes, Ordering::Relaxed) + bytes;
+    PEAK[kind as usize].fetch_max(used, Ordering::Relaxed);
+}
+
+/// Record `bytes` given back by `kind`
+pub fn sub(kind: Kind, bytes: usize) {
+    USED[kind as usize].fetch_sub(bytes, Ordering::Relaxed);
+}
+
+/// Bytes currently used by `kind`
+pub fn used(kind: Kind) -> usize {
+    USED[kind as usize].load(Ordering::Relaxed)
+}
+
+/// Maximum bytes ever used by `kind`
+pub fn peak(kind: Kind) -> usize {
+    PEAK[kind as usize].load(Ordering::Relaxed)
+}
+
+/// Update high-water mark of frames. Called by frame allocator.
+pub fn update_peak_frames(stats: &BuddyStats) {
+    PEAK_FRAMES.fetch_max(stats.total_frames - stats.free_frames, Ordering::Relaxed);
+}
+
+/// Writes into a byte slice, dropping what doesn't fit
+struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = core::cmp::min(s.len(), self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Write memory report into `buf`, returns length of the report
+pub fn report(buf: &mut [u8]) -> usize {
+    let stats = ALLOC().lock().buddy.stats();
+    let kb = |frames: usize| frames * PAGE_SIZE / 1024;
+    let mut w = SliceWriter { buf, len: 0 };
+    writeln!(w, "MemTotal:      {:>8} kB", kb(stats.total_frames)).ok();
+    writeln!(w, "MemFree:       {:>8} kB", kb(stats.free_frames)).ok();
+    writeln!(w, "MemPeak:       {:>8} kB", kb(PEAK_FRAMES.load(Ordering::Relaxed))).ok();
+    writeln!(w, "Fragmentation: {:>8} %", stats.fragmentation()).ok();
+    let (swap_total, swap_free) = super::swap::stats();
+    writeln!(w, "SwapTotal:     {:>8} kB", kb(swap_total)).ok();
+    writeln!(w, "SwapFree:      {:>8} kB", kb(swap_free)).ok();
+    for i in 0..KINDS {
+        writeln!(w, "{:<15}{:>8} kB, peak {:>8} kB", NAMES[i],
+                 USED[i].load(Ordering::Relaxed) / 1024,
+                 PEAK[i].load(Ordering::Relaxed) / 1024).ok();
+    }
+    w.len
+}
+
+pub mod tests {
+    use super::*;
+    use crate::page::{Table, Page, EntryAttributes};
+    use crate::process::{my_proc, fork, signal, sleep_timeout};
+    use crate::process::process::tests::{map_exit_code, EXIT_CODE};
+    use crate::spinlock::Mutex;
+    use alloc::vec::Vec;
+    use core::time::Duration;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("page accounting", test_page_accounting),
+            ("report", test_report),
+            ("fork and exit", test_fork_exit),
+        ]
+    }
+
+    /// Test user pages and page tables are given back when page table is dropped
+    pub fn test_page_accounting() {
+        let user = used(Kind::UserPage);
+        let table = used(Kind::PageTable);
+        {
+            let mut pgtable = Table::alloc();
+            pgtable.map(0x1000, Page::new(), EntryAttributes::URW as usize);
+            pgtable.map(0x2000, Page::new(), EntryAttributes::URW as usize);
+            assert_eq!(used(Kind::UserPage), user + 2 * PAGE_SIZE);
+            assert_eq!(used(Kind::PageTable), table + 3 * PAGE_SIZE);
+            pgtable.unmap(0x1000);
+            assert_eq!(used(Kind::UserPage), user + PAGE_SIZE);
+            let cloned = pgtable.clone();
+            assert_eq!(used(Kind::UserPage), user + 2 * PAGE_SIZE);
+            assert_eq!(used(Kind::PageTable), table + 6 * PAGE_SIZE);
+            drop(cloned);
+        }
+        assert_eq!(used(Kind::UserPage), user);
+        assert_eq!(used(Kind::PageTable), table);
+        assert!(peak(Kind::PageTable) >= table + 6 * PAGE_SIZE);
+    }
+
+    /// Lock to sleep on while waiting for children to be released
+    static TICK: Mutex<()> = Mutex::new((), "memstat test");
+
+    /// Fork a child which exits at once, and wait until it's released
+    fn fork_exit() {
+        let p = my_proc();
+        let epc = p.trapframe.epc;
+        p.trapframe.epc = EXIT_CODE;
+        let pid = fork();
+        p.trapframe.epc = epc;
+        assert!(pid > 0);
+        let mut tick = TICK.lock();
+        while signal::exists(pid) {
+            tick = sleep_timeout(&TICK, tick, Duration::from_millis(10));
+        }
+    }
+
+    /// Test memory of exited processes is given back
+    pub fn test_fork_exit() {
+        map_exit_code();
+        // kernel stack of child's pid is mapped once and kept
+        fork_exit();
+        let kinds = [Kind::PageTable, Kind::UserPage, Kind::KernelStack];
+        let before: Vec<usize> = kinds.iter().map(|&kind| used(kind)).collect();
+        for _ in 0..8 {
+            fork_exit();
+        }
+        for (&kind, &used_before) in kinds.iter().zip(before.iter()) {
+            assert_eq!(used(kind), used_before, "{:?} leaked", kind);
+        }
+        my_proc().pgtable.lock().unmap(EXIT_CODE);
+    }
+
+    pub fn test_report() {
+        let mut buf = [0; 1024];
+        let len = report(&mut buf);
+        let report = core::str::from_utf8(&buf[..len]).unwrap();
+        assert!(report.starts_with("MemTotal:"));
+        assert!(report.contains("KernelStack"));
+    }
+}
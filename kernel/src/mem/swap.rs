@@ -0,0 +1,465 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Swapping user pages out to a virtio swap disk
+//!
+//! When free frames run low, `balance` picks victims among user pages of
+//! processes not running with a clock algorithm: the hand sweeps through
+//! user pages of each process, clearing `A` bits of pages accessed since
+//! last sweep, and evicts the first page not accessed. The page table entry
+//! of an evicted page is replaced by a swap entry recording its slot, and
+//! the page is brought back by `swap_in` from page fault handler.
+//!
+//! User pages accessed by syscalls are pinned and never picked, as kernel
+//! accesses them through physical addresses.
+//!
+//! A frame is kept in its slot while it is being written out, so that a
+//! page fault during the write takes the frame back directly, and `fork`
+//! copies it instead of sharing the slot.
+//!
+//! A page brought back keeps its slot until it's written, so that it's
+//! swapped out again without writing if its `D` bit isn't set.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use alloc::vec;
+use alloc::collections::BTreeMap;
+use crate::spinlock::Mutex;
+use crate::symbols::{PAGE_SIZE, NMAXPROCS};
+use crate::page::{Page, Entry, EntryAttributes, AddressSpace};
+use crate::process::{PROCS_POOL, ProcInPool, ProcessState, PageTable, Process, my_proc};
+use crate::virtio::{DISK, VirtIO, Buf, BSIZE};
+use crate::mem::{ALLOC, OutOfMemory, page_down, oom};
+use crate::mem::stat::{self, Kind};
+
+/// Disk used as swap
+const SWAP_DISK: usize = 1;
+
+/// Number of blocks holding a page
+const BLOCKS_PER_PAGE: usize = PAGE_SIZE / BSIZE;
+
+/// Maximum number of pages in swap
+pub const MAX_SLOTS: usize = 16384;
+
+/// Start reclaiming when free frames fall below this
+const LOW_WATERMARK: usize = 256;
+
+/// Stop reclaiming when this many frames are free
+const HIGH_WATERMARK: usize = 512;
+
+/// State of a swap slot
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Slot {
+    Free,
+    /// Frame is being written to slot
+    Writing(usize),
+    /// Page is on disk, referred by this many swap entries
+    Stored(usize),
+    /// Frame was taken back by page fault while being written
+    Taken,
+    /// Swap entry was dropped while frame is being written
+    Dropped(usize),
+}
+
+struct Swap {
+    slots: Vec<Slot>,
+    /// Number of free slots
+    free: usize,
+    /// Where to look for next free slot. Freed slots are reused only after
+    /// all other slots, so that a stale read of a slot is unlikely to race
+    /// with a new write to it.
+    next: usize,
+    /// Frames brought back and the slots still holding them, each holding
+    /// a reference to the slot
+    clean: BTreeMap<usize, usize>,
+}
+
+impl Swap {
+    const fn new() -> Self {
+        Self { slots: Vec::new(), free: 0, next: 0, clean: BTreeMap::new() }
+    }
+
+    /// Allocate a slot for `frame` to be written to
+    fn alloc(&mut self, frame: usize) -> Option<usize> {
+        if self.free == 0 {
+            return None;
+        }
+        let n = self.slots.len();
+        for i in 0..n {
+            let slot = (self.next + i) % n;
+            if self.slots[slot] == Slot::Free {
+                self.slots[slot] = Slot::Writing(frame);
+                self.free -= 1;
+                self.next = (slot + 1) % n;
+                return Some(slot);
+            }
+        }
+        unreachable!()
+    }
+
+    fn release(&mut self, slot: usize) {
+        self.slots[slot] = Slot::Free;
+        self.free += 1;
+    }
+
+    /// Called after frame is written to `slot`. Returns frame to be freed.
+    fn written(&mut self, slot: usize) -> Option<usize> {
+        match self.slots[slot] {
+            Slot::Writing(frame) => {
+                self.slots[slot] = Slot::Stored(1);
+                Some(frame)
+            }
+            Slot::Taken => {
+                self.release(slot);
+                None
+            }
+            Slot::Dropped(frame) => {
+                self.release(slot);
+                Some(frame)
+            }
+            state => panic!("swap slot {} written in state {:?}", slot, state)
+        }
+    }
+
+    /// Drop a swap entry referring to `slot`
+    fn drop_ref(&mut self, slot: usize) {
+        match self.slots[slot] {
+            Slot::Writing(frame) => self.slots[slot] = Slot::Dropped(frame),
+            Slot::Stored(1) => self.release(slot),
+            Slot::Stored(refs) => self.slots[slot] = Slot::Stored(refs - 1),
+            state => panic!("swap slot {} dropped in state {:?}", slot, state)
+        }
+    }
+}
+
+static SWAP: Mutex<Swap> = Mutex::new(Swap::new(), "swap");
+
+/// Clock hand, pid and virtual address to look at next
+static CLOCK: Mutex<(usize, usize)> = Mutex::new((0, 0), "swap clock");
+
+fn disk() -> Option<&'static mut VirtIO> {
+    DISK(SWAP_DISK)
+}
+
+/// Set up swap on second virtio disk if present and writable. Returns
+/// number of slots.
+pub fn init() -> usize {
+    let slots = match disk() {
+        Some(disk) if !disk.readonly() => core::cmp::min(disk.capacity() / BLOCKS_PER_PAGE, MAX_SLOTS),
+        _ => 0
+    };
+    let mut swap = SWAP.lock();
+    swap.slots = vec![Slot::Free; slots];
+    swap.free = slots;
+    slots
+}
+
+/// Total and free swap slots
+pub fn stats() -> (usize, usize) {
+    let swap = SWAP.lock();
+    (swap.slots.len(), swap.free)
+}
+
+/// Free user frame no longer mapped
+fn free_frame(frame: usize) {
+    let _pg = unsafe { Box::from_raw(frame as *mut Page) };
+    stat::sub(Kind::UserPage, PAGE_SIZE);
+}
+
+/// Drop a swap entry referring to `slot`. Called when page table entry is
+/// unmapped or page table is dropped.
+pub fn free_slot(slot: usize) {
+    SWAP.lock().drop_ref(slot);
+}
+
+/// Called when user frame is freed, or may be written by kernel, dropping
+/// the slot it's read from
+pub fn forget(frame: usize) {
+    let mut swap = SWAP.lock();
+    if let Some(slot) = swap.clean.remove(&frame) {
+        swap.drop_ref(slot);
+    }
+}
+
+/// Share `slot` with a copied swap entry in `fork`. If the page is being
+/// written out, returns a copy of it instead.
+pub fn share(slot: usize) -> Result<Option<Box<Page>>, OutOfMemory> {
+    let mut swap = SWAP.lock();
+    match swap.slots[slot] {
+        Slot::Writing(frame) => {
+            let mut pg = Page::try_new()?;
+            pg.data.copy_from_slice(unsafe { &(*(frame as *const Page)).data });
+            Ok(Some(pg))
+        }
+        Slot::Stored(refs) => {
+            swap.slots[slot] = Slot::Stored(refs + 1);
+            Ok(None)
+        }
+        state => panic!("swap slot {} shared in state {:?}", slot, state)
+    }
+}
+
+fn write_page(slot: usize, frame: usize) {
+    let page = unsafe { &*(frame as *const Page) };
+    for i in 0..BLOCKS_PER_PAGE {
+        let mut buf = box Buf::new();
+        buf.blockno = (slot * BLOCKS_PER_PAGE + i) as u32;
+        buf.data.copy_from_slice(&page.data[i * BSIZE..(i + 1) * BSIZE]);
+        // slots are within capacity of a writable disk
+        disk().unwrap().write(buf).unwrap();
+    }
+}
+
+fn read_page(slot: usize, page: &mut Page) {
+    for i in 0..BLOCKS_PER_PAGE {
+        let buf = disk().unwrap().read((slot * BLOCKS_PER_PAGE + i) as u32).unwrap();
+        page.data[i * BSIZE..(i + 1) * BSIZE].copy_from_slice(&buf.data);
+    }
+}
+
+/// Number of free frames in frame allocator
+pub fn free_frames() -> usize {
+    ALLOC().lock().buddy.stats().free_frames
+}
+
+/// Page table of process `pid` if it is waiting in process pool
+pub fn pooled_pgtable(pid: usize) -> Option<PageTable> {
+    match &PROCS_POOL.lock()[pid] {
+        ProcInPool::Pooling(p) if p.state != ProcessState::ZOMBIE => Some(p.pgtable.clone()),
+        _ => None
+    }
+}
+
+/// Bring back user page at `vaddr` of `pgtable`, and keep it from being
+/// swapped out or freed by OOM killer until current process returns from
+/// syscall, as kernel accesses it through physical address. Returns its
+/// physical address, or `None` if it isn't mapped, or address space is
+/// killed.
+pub fn pin(pgtable: &PageTable, vaddr: usize) -> Option<usize> {
+    let vaddr = page_down(vaddr);
+    loop {
+        let resolved = swap_in(pgtable, vaddr);
+        let mut space = pgtable.lock();
+        if space.killed {
+            return None;
+        }
+        match space.paddr_of(vaddr) {
+            Some(paddr) => {
+                // `D` isn't set when kernel writes to it
+                forget(paddr);
+                space.pinned.push(vaddr);
+                my_proc().pinned.push(vaddr);
+                return Some(paddr);
+            }
+            // swapped out again before it's pinned
+            None if resolved => continue,
+            None => return None
+        }
+    }
+}
+
+/// Allow user pages pinned by process `p` to be swapped out again
+pub fn unpin(p: &mut Process) {
+    if p.pinned.is_empty() {
+        return;
+    }
+    let mut space = p.pgtable.lock();
+    for vaddr in p.pinned.drain(..) {
+        let i = space.pinned.iter().position(|&v| v == vaddr).unwrap();
+        space.pinned.swap_remove(i);
+    }
+}
+
+/// Sweep user pages of `space` from `start`, and swap out the first page
+/// not accessed since last sweep. Returns its address, slot, frame, and
+/// whether it should be written to the slot.
+fn pick_victim(space: &mut AddressSpace, start: usize) -> Option<(usize, usize, usize, bool)> {
+    let pinned = core::mem::take(&mut space.pinned);
+    let mut victim = None;
+    // changed entries are flushed by `walk_user`, so no hart may write to
+    // the frame after it is written out
+    space.walk_user(start, &mut |vaddr, v: &mut Entry| {
+        if v.is_swapped() || pinned.contains(&vaddr) {
+            return true;
+        }
+        if v.is_a() {
+            // second chance
+            v.clear_a();
+            return true;
+        }
+        let frame = v.paddr().addr();
+        let mut swap = SWAP.lock();
+        // no entry refers to the slot it's read from, so it's reused
+        let (slot, clean) = match swap.clean.remove(&frame) {
+            Some(slot) => (slot, true),
+            None => match swap.alloc(frame) {
+                Some(slot) => (slot, false),
+                None => return false
+            }
+        };
+        // `D` may be set by hardware until the entry is replaced
+        let old = v.replace(Entry::swapped(slot, v.flags()));
+        let write = !clean || old.is_d();
+        if clean && write {
+            swap.slots[slot] = Slot::Writing(frame);
+        }
+        victim = Some((vaddr, slot, frame, write));
+        false
+    });
+    space.pinned = pinned;
+    victim
+}
+
+/// Swap out one user page. Returns false if there's nothing to swap out,
+/// or swap is full.
+fn swap_out_one() -> bool {
+    // each process is visited at most twice, and the first visit
+    // gives every page a second chance
+    for _ in 0..2 * NMAXPROCS + 2 {
+        if SWAP.lock().free == 0 {
+            return false;
+        }
+        let (pid, start) = *CLOCK.lock();
+        let victim = pooled_pgtable(pid).and_then(|pgtable| pick_victim(&mut pgtable.lock(), start));
+        match victim {
+            Some((vaddr, slot, frame, write)) => {
+                *CLOCK.lock() = (pid, vaddr + PAGE_SIZE);
+                if !write {
+                    free_frame(frame);
+                    return true;
+                }
+                write_page(slot, frame);
+                if let Some(frame) = SWAP.lock().written(slot) {
+                    free_frame(frame);
+                }
+                return true;
+            }
+            None => *CLOCK.lock() = ((pid + 1) % NMAXPROCS, 0)
+        }
+    }
+    false
+}
+
+/// Swap out user pages if free frames run low. Should be called in process
+/// context without holding any lock, as it sleeps on disk.
+pub fn balance() {
+    if free_frames() < LOW_WATERMARK {
+        reclaim(HIGH_WATERMARK);
+    }
+}
+
+/// Swap out user pages until `target` frames are free. Returns false if
+/// there's not enough to swap out. Should be called like `balance`.
+pub fn reclaim(target: usize) -> bool {
+    while free_frames() < target {
+        if disk().is_none() || !swap_out_one() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Map frame back at swap entry `v`, with `D` bit cleared
+fn install(v: &mut Entry, frame: usize) {
+    *v = Entry::new(frame, v.flags() & 0xff & !(EntryAttributes::D as usize) | EntryAttributes::V as usize);
+}
+
+/// Bring page at `vaddr` back from swap. Returns false if it isn't swapped
+/// out, or current process is killed as there's no memory for it. Should be
+/// called in process context without holding any lock.
+pub fn swap_in(pgtable: &PageTable, vaddr: usize) -> bool {
+    let vaddr = page_down(vaddr);
+    let slot = {
+        // slot to read from, or whether page is resolved without reading
+        let found = pgtable.lock().update_entry(vaddr, |v| {
+            if !v.is_swapped() {
+                return Err(false);
+            }
+            let slot = v.swap_slot();
+            let mut swap = SWAP.lock();
+            if let Slot::Writing(frame) = swap.slots[slot] {
+                // still being written, take it back
+                swap.slots[slot] = Slot::Taken;
+                install(v, frame);
+                return Err(true);
+            }
+            Ok(slot)
+        });
+        match found {
+            Some(Ok(slot)) => slot,
+            Some(Err(resolved)) => return resolved,
+            None => return false
+        }
+    };
+    let mut pg = loop {
+        match Page::try_new() {
+            Ok(pg) => break pg,
+            Err(_) if oom::reserve_fault(1) => continue,
+            Err(_) => return false
+        }
+    };
+    read_page(slot, &mut pg);
+    pgtable.lock().update_entry(vaddr, |v| {
+        // may be brought back by another thread
+        if v.is_swapped() && v.swap_slot() == slot {
+            stat::add(Kind::UserPage, PAGE_SIZE);
+            let frame = Box::into_raw(pg) as usize;
+            install(v, frame);
+            let mut swap = SWAP.lock();
+            if swap.slots[slot] == Slot::Stored(1) {
+                // keep the slot, with reference of the entry
+                swap.clean.insert(frame, slot);
+            } else {
+                swap.drop_ref(slot);
+            }
+        }
+    });
+    true
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("slot states", test_slot_states),
+            ("swap entry", test_swap_entry),
+        ]
+    }
+
+    pub fn test_slot_states() {
+        let mut swap = Swap::new();
+        swap.slots = vec![Slot::Free; 2];
+        swap.free = 2;
+        let a = swap.alloc(0x1000).unwrap();
+        let b = swap.alloc(0x2000).unwrap();
+        assert_eq!(swap.alloc(0x3000), None);
+        // written out and shared by fork
+        assert_eq!(swap.written(a), Some(0x1000));
+        swap.slots[a] = Slot::Stored(2);
+        swap.drop_ref(a);
+        assert_eq!(swap.slots[a], Slot::Stored(1));
+        swap.drop_ref(a);
+        assert_eq!(swap.slots[a], Slot::Free);
+        // dropped while being written
+        swap.drop_ref(b);
+        assert_eq!(swap.written(b), Some(0x2000));
+        assert_eq!(swap.free, 2);
+        // taken back while being written
+        let c = swap.alloc(0x3000).unwrap();
+        assert_eq!(c, a);
+        swap.slots[c] = Slot::Taken;
+        assert_eq!(swap.written(c), None);
+        assert_eq!(swap.free, 2);
+    }
+
+    pub fn test_swap_entry() {
+        let v = Entry::swapped(1234, EntryAttributes::URW as usize | EntryAttributes::V as usize);
+        assert!(v.is_swapped());
+        assert!(!v.is_v());
+        assert_eq!(v.swap_slot(), 1234);
+        assert!(v.is_u() && v.is_r() && v.is_w());
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! TLB shootdown across harts
+//!
+//! Kernel runs on its own page table, so a hart only uses translations of
+//! user address spaces while it is in user mode. To flush a translation on
+//! other harts, the initiator posts a request to each of them. A hart drains
+//! its requests before it enters user mode, and harts already in user mode
+//! are interrupted with an IPI. The initiator waits until every hart in user
+//! mode has acknowledged, or left user mode, in which case it will drain the
+//! request before entering user mode again.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::spinlock::Mutex;
+use crate::symbols::NCPUS;
+use crate::arch::{self, hart_id};
+use crate::intr::send_ipi;
+
+/// Number of requests kept for a hart before falling back to a full flush
+const MAX_REQUESTS: usize = 8;
+
+/// Flush requests posted to a hart
+#[derive(Clone, Copy)]
+struct Requests {
+    /// (address, ASID) to flush, address `None` for all of ASID
+    entries: [(Option<usize>, usize); MAX_REQUESTS],
+    count: usize,
+    /// Too many requests, flush whole TLB
+    all: bool,
+}
+
+impl Requests {
+    const fn new() -> Self {
+        Self { entries: [(None, 0); MAX_REQUESTS], count: 0, all: false }
+    }
+
+    fn push(&mut self, vaddr: Option<usize>, asid: usize) {
+        if self.count == MAX_REQUESTS {
+            self.all = true;
+        } else {
+            self.entries[self.count] = (vaddr, asid);
+            self.count += 1;
+        }
+    }
+
+    fn flush(&self) {
+        if self.all {
+            arch::sfence_vma_all();
+            return;
+        }
+        for &(vaddr, asid) in &self.entries[..self.count] {
+            match (vaddr, asid) {
+                // ASID isn't supported
+                (_, 0) => arch::sfence_vma_all(),
+                (Some(vaddr), asid) => arch::sfence_vma_page(vaddr, asid),
+                (None, asid) => arch::sfence_vma_asid(asid),
+            }
+        }
+    }
+}
+
+static REQUESTS: [Mutex<Requests>; NCPUS] = [Mutex::new(Requests::new(), "tlb requests"); NCPUS];
+
+/// Sequence number of last request posted to each hart
+static POSTED: [AtomicUsize; NCPUS] = [AtomicUsize::new(0); NCPUS];
+
+/// Sequence number of last request each hart has done
+static DONE: [AtomicUsize; NCPUS] = [AtomicUsize::new(0); NCPUS];
+
+/// Whether each hart is in user mode, or about to enter it
+static IN_USER: [AtomicBool; NCPUS] = [AtomicBool::new(false); NCPUS];
+
+/// Flush requests posted to current hart
+fn drain() {
+    let hart = hart_id();
+    let (requests, seq) = {
+        let mut requests = REQUESTS[hart].lock();
+        let taken = *requests;
+        *requests = Requests::new();
+        (taken, POSTED[hart].load(Ordering::SeqCst))
+    };
+    requests.flush();
+    DONE[hart].store(seq, Ordering::SeqCst);
+}
+
+/// Called before current hart enters user mode, with interrupts off
+pub fn enter_user() {
+    IN_USER[hart_id()].store(true, Ordering::SeqCst);
+    drain();
+}
+
+/// Called when current hart traps from user mode
+pub fn leave_user() {
+    IN_USER[hart_id()].store(false, Ordering::SeqCst);
+}
+
+/// Handle IPI sent by `shootdown`
+pub fn handle_ipi() {
+    drain();
+}
+
+/// Flush `vaddr`, or all translations if `None`, of `asid` on harts in
+/// `harts` mask, and wait for harts in user mode to acknowledge.
+pub fn shootdown(harts: usize, vaddr: Option<usize>, asid: usize) {
+    let me = hart_id();
+    let mut waiting = [0; NCPUS];
+    for hart in 0..NCPUS {
+        if hart == me || harts & (1 << hart) == 0 {
+            continue;
+        }
+        let seq = {
+            let mut requests = REQUESTS[hart].lock();
+            requests.push(vaddr, asid);
+            POSTED[hart].fetch_add(1, Ordering::SeqCst) + 1
+        };
+        if IN_USER[hart].load(Ordering::SeqCst) {
+            waiting[hart] = seq;
+            send_ipi(hart);
+        }
+    }
+    for hart in 0..NCPUS {
+        if waiting[hart] == 0 {
+            continue;
+        }
+        while DONE[hart].load(Ordering::SeqCst) < waiting[hart]
+            && IN_USER[hart].load(Ordering::SeqCst) {}
+    }
+}
+
+pub mod tests {
+    use super::*;
+    use crate::page::{Page, EntryAttributes};
+    use crate::process::{my_proc, clone, join, set_affinity, get_affinity, online_harts};
+    use crate::process::signal::{self, SIGKILL};
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("overflow", test_overflow),
+            ("shootdown self", test_shootdown_self),
+            ("shootdown other hart", test_shootdown_other),
+        ]
+    }
+
+    /// User address of code looping forever
+    const SPIN_CODE: usize = 0x7000_2000;
+
+    pub fn test_overflow() {
+        let mut requests = Requests::new();
+        for i in 0..MAX_REQUESTS {
+            requests.push(Some(i * 0x1000), 1);
+        }
+        assert_eq!(requests.count, MAX_REQUESTS);
+        assert!(!requests.all);
+        requests.push(None, 2);
+        assert!(requests.all);
+    }
+
+    pub fn test_shootdown_self() {
+        // current hart is never interrupted or waited for
+        let hart = hart_id();
+        let posted = POSTED[hart].load(Ordering::SeqCst);
+        shootdown(1 << hart, None, 1);
+        assert_eq!(POSTED[hart].load(Ordering::SeqCst), posted);
+    }
+
+    /// Test a hart running a thread in user mode acknowledges shootdown
+    pub fn test_shootdown_other() {
+        let me = hart_id();
+        let other = match (0..NCPUS).find(|&hart| hart != me && online_harts() & (1 << hart) != 0) {
+            Some(hart) => hart,
+            None => return
+        };
+        let old = get_affinity(0);
+        // stay on this hart, and run thread on the other
+        assert_eq!(set_affinity(0, 1 << me), 0);
+        let mut page = Page::new();
+        // j .
+        page.data[0..4].copy_from_slice(&0x6fu32.to_le_bytes());
+        my_proc().pgtable.lock().map(SPIN_CODE, page, EntryAttributes::URX as usize);
+        let tid = clone(SPIN_CODE, 0, 0);
+        assert!(tid > 0);
+        assert_eq!(set_affinity(tid, 1 << other), 0);
+        while !IN_USER[other].load(Ordering::SeqCst) {}
+
+        let seq = POSTED[other].load(Ordering::SeqCst) + 1;
+        shootdown(1 << other, None, 0);
+        assert!(POSTED[other].load(Ordering::SeqCst) >= seq);
+        // done on IPI, or before it enters user mode again
+        while DONE[other].load(Ordering::SeqCst) < seq {}
+
+        assert!(signal::kill(tid, SIGKILL));
+        assert_eq!(join(tid), Some(128 + SIGKILL as i32));
+        my_proc().pgtable.lock().unmap(SPIN_CODE);
+        assert_eq!(set_affinity(0, old as usize), 0);
+    }
+}
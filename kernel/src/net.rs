@@ -0,0 +1,205 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Network stack
+//!
+//! A minimal stack of Ethernet, ARP, IPv4, ICMP echo, UDP and TCP over
+//! virtio-net. Addresses are fixed to those of QEMU user-mode networking,
+//! where guest is `10.0.2.15` and gateway is `10.0.2.2`. Loopback interface
+//! `127.0.0.1` works without a network device.
+//!
+//! Frames are received in interrupt context, so nothing in the stack sleeps
+//! on receiving path. Packets are dropped if buffers run out, or their
+//! destination can't be resolved.
+
+pub mod ethernet;
+pub mod arp;
+pub mod ipv4;
+pub mod icmp;
+pub mod udp;
+pub mod tcp;
+pub mod loopback;
+
+use core::fmt;
+use crate::virtio::net::NET;
+
+/// MAC address
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: MacAddr = MacAddr([0xff; 6]);
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let m = &self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", m[0], m[1], m[2], m[3], m[4], m[5])
+    }
+}
+
+/// IPv4 address
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([0xff; 4]);
+
+    /// Address from a big-endian integer
+    pub const fn from_u32(addr: u32) -> Self {
+        Ipv4Addr(addr.to_be_bytes())
+    }
+
+    /// Address as a big-endian integer
+    pub const fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    /// Whether it's in subnet of this host
+    pub fn is_local(self) -> bool {
+        self.to_u32() & NETMASK.to_u32() == IP.to_u32() & NETMASK.to_u32()
+    }
+
+    /// Whether it's in `127.0.0.0/8`
+    pub fn is_loopback(self) -> bool {
+        self.0[0] == 127
+    }
+
+    /// Whether it's limited broadcast, or broadcast of subnet of this host
+    pub fn is_broadcast(self) -> bool {
+        self == Self::BROADCAST || self.to_u32() == IP.to_u32() | !NETMASK.to_u32()
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.0[0], self.0[1], self.0[2], self.0[3])
+    }
+}
+
+/// Address of this host
+pub const IP: Ipv4Addr = Ipv4Addr([10, 0, 2, 15]);
+/// Subnet mask of this host
+pub const NETMASK: Ipv4Addr = Ipv4Addr([255, 255, 255, 0]);
+/// Default gateway
+pub const GATEWAY: Ipv4Addr = Ipv4Addr([10, 0, 2, 2]);
+/// Address of loopback interface
+pub const LOOPBACK: Ipv4Addr = Ipv4Addr([127, 0, 0, 1]);
+
+/// Whether `addr` is an address of this host
+pub fn is_ours(addr: Ipv4Addr) -> bool {
+    addr == IP || addr.is_loopback()
+}
+
+/// Source address of packets to `dst`
+pub fn source(dst: Ipv4Addr) -> Ipv4Addr {
+    if dst.is_loopback() { dst } else { IP }
+}
+
+/// Read big-endian `u16` at `offset` of `buf`
+pub fn get_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+/// Write big-endian `u16` at `offset` of `buf`
+pub fn put_u16(buf: &mut [u8], offset: usize, val: u16) {
+    buf[offset..offset + 2].copy_from_slice(&val.to_be_bytes());
+}
+
+/// Read big-endian `u32` at `offset` of `buf`
+pub fn get_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}
+
+/// Write big-endian `u32` at `offset` of `buf`
+pub fn put_u32(buf: &mut [u8], offset: usize, val: u32) {
+    buf[offset..offset + 4].copy_from_slice(&val.to_be_bytes());
+}
+
+/// Add 16-bit big-endian words of `data` to `acc`, for internet checksum
+pub fn sum(data: &[u8], mut acc: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        acc += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        acc += (*last as u32) << 8;
+    }
+    acc
+}
+
+/// Fold sum of words into internet checksum
+pub fn fold(mut acc: u32) -> u16 {
+    while acc >> 16 != 0 {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    !(acc as u16)
+}
+
+/// Internet checksum of `data`. Data with a correct checksum in it gives 0.
+pub fn checksum(data: &[u8]) -> u16 {
+    fold(sum(data, 0))
+}
+
+/// MAC address of network device, `None` if there isn't one
+pub fn mac() -> Option<MacAddr> {
+    NET().map(|net| net.mac())
+}
+
+/// Handle an Ethernet frame received. Called in interrupt context.
+pub fn receive(frame: &[u8]) {
+    ethernet::receive(frame);
+}
+
+/// Run timers of network stack. Called on timer interrupt.
+pub fn tick() {
+    tcp::tick();
+    loopback::poll();
+}
+
+/// Transmit an Ethernet frame. Returns false if it's dropped.
+pub fn transmit(frame: &[u8]) -> bool {
+    match NET() {
+        Some(net) => net.send(frame),
+        None => false
+    }
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("checksum", test_checksum),
+            ("address", test_address),
+        ]
+    }
+
+    /// Test internet checksum with example of RFC 1071
+    pub fn test_checksum() {
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(sum(&data, 0), 0x2ddf0);
+        assert_eq!(checksum(&data), !0xddf2);
+        // odd length is padded with zero
+        assert_eq!(checksum(&[0x01]), !0x0100);
+        let mut with_sum = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7, 0, 0];
+        put_u16(&mut with_sum, 8, checksum(&data));
+        assert_eq!(checksum(&with_sum), 0);
+    }
+
+    pub fn test_address() {
+        assert!(GATEWAY.is_local());
+        assert!(!Ipv4Addr([8, 8, 8, 8]).is_local());
+        assert!(Ipv4Addr([10, 0, 2, 255]).is_broadcast());
+        assert!(Ipv4Addr::BROADCAST.is_broadcast());
+        assert!(!GATEWAY.is_broadcast());
+        assert!(LOOPBACK.is_loopback() && is_ours(LOOPBACK) && is_ours(IP));
+        assert!(!is_ours(GATEWAY));
+        assert_eq!(source(LOOPBACK), LOOPBACK);
+        assert_eq!(source(GATEWAY), IP);
+        assert_eq!(Ipv4Addr::from_u32(0x0a00020f), IP);
+    }
+}
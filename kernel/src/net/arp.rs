@@ -0,0 +1,224 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Address Resolution Protocol
+//!
+//! Addresses learned are kept in a small cache, replaced round-robin. An
+//! IPv4 packet to an address not yet resolved waits in its cache entry,
+//! and is sent when a reply comes. Only the latest one of such packets is
+//! kept.
+
+use alloc::vec::Vec;
+use crate::spinlock::Mutex;
+use super::{MacAddr, Ipv4Addr, IP, get_u16, put_u16};
+use super::ethernet::{self, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+
+/// Size of ARP packet for IPv4 over Ethernet
+pub const PACKET_SIZE: usize = 28;
+
+pub const OP_REQUEST: u16 = 1;
+pub const OP_REPLY: u16 = 2;
+
+/// Number of cache entries
+const CACHE_SIZE: usize = 16;
+
+struct Entry {
+    ip: Ipv4Addr,
+    /// `None` if being resolved
+    mac: Option<MacAddr>,
+    /// packet waiting for address to be resolved
+    pending: Option<Vec<u8>>,
+}
+
+struct Cache {
+    entries: [Option<Entry>; CACHE_SIZE],
+    /// entry to be replaced next
+    next: usize,
+}
+
+impl Cache {
+    const fn new() -> Self {
+        Self { entries: [None; CACHE_SIZE], next: 0 }
+    }
+
+    fn find(&mut self, ip: Ipv4Addr) -> Option<&mut Entry> {
+        self.entries.iter_mut().filter_map(|e| e.as_mut()).find(|e| e.ip == ip)
+    }
+
+    /// Entry of `ip`, replacing an old one if there isn't
+    fn entry(&mut self, ip: Ipv4Addr) -> &mut Entry {
+        let i = match self.entries.iter().position(|e| e.as_ref().map_or(false, |e| e.ip == ip)) {
+            Some(i) => i,
+            None => {
+                let i = self.next;
+                self.next = (self.next + 1) % CACHE_SIZE;
+                self.entries[i] = Some(Entry { ip, mac: None, pending: None });
+                i
+            }
+        };
+        self.entries[i].as_mut().unwrap()
+    }
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache::new(), "arp cache");
+
+/// ARP packet for IPv4 over Ethernet
+#[derive(PartialEq, Debug)]
+pub struct Packet {
+    pub op: u16,
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl Packet {
+    /// Parse `buf`, `None` if it's not for IPv4 over Ethernet
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < PACKET_SIZE {
+            return None;
+        }
+        // hardware type, protocol type, and their sizes
+        if get_u16(buf, 0) != 1 || get_u16(buf, 2) != ETHERTYPE_IPV4 || buf[4] != 6 || buf[5] != 4 {
+            return None;
+        }
+        let mut packet = Self {
+            op: get_u16(buf, 6),
+            sender_mac: MacAddr([0; 6]),
+            sender_ip: Ipv4Addr([0; 4]),
+            target_mac: MacAddr([0; 6]),
+            target_ip: Ipv4Addr([0; 4]),
+        };
+        packet.sender_mac.0.copy_from_slice(&buf[8..14]);
+        packet.sender_ip.0.copy_from_slice(&buf[14..18]);
+        packet.target_mac.0.copy_from_slice(&buf[18..24]);
+        packet.target_ip.0.copy_from_slice(&buf[24..28]);
+        Some(packet)
+    }
+
+    pub fn build(&self) -> [u8; PACKET_SIZE] {
+        let mut buf = [0; PACKET_SIZE];
+        put_u16(&mut buf, 0, 1);
+        put_u16(&mut buf, 2, ETHERTYPE_IPV4);
+        buf[4] = 6;
+        buf[5] = 4;
+        put_u16(&mut buf, 6, self.op);
+        buf[8..14].copy_from_slice(&self.sender_mac.0);
+        buf[14..18].copy_from_slice(&self.sender_ip.0);
+        buf[18..24].copy_from_slice(&self.target_mac.0);
+        buf[24..28].copy_from_slice(&self.target_ip.0);
+        buf
+    }
+}
+
+/// Handle an ARP packet received
+pub fn receive(buf: &[u8]) {
+    let packet = match Packet::parse(buf) {
+        Some(packet) => packet,
+        None => return
+    };
+    let mac = match super::mac() {
+        Some(mac) => mac,
+        None => return
+    };
+    let for_me = packet.target_ip == IP;
+    let pending = {
+        let mut cache = CACHE.lock();
+        // learn sender if it's asking for this host, or being resolved
+        let entry = if for_me {
+            Some(cache.entry(packet.sender_ip))
+        } else {
+            cache.find(packet.sender_ip)
+        };
+        match entry {
+            Some(entry) => {
+                entry.mac = Some(packet.sender_mac);
+                entry.pending.take()
+            }
+            None => None
+        }
+    };
+    if let Some(pending) = pending {
+        ethernet::send(packet.sender_mac, ETHERTYPE_IPV4, &pending);
+    }
+    if for_me && packet.op == OP_REQUEST {
+        let reply = Packet {
+            op: OP_REPLY,
+            sender_mac: mac,
+            sender_ip: IP,
+            target_mac: packet.sender_mac,
+            target_ip: packet.sender_ip,
+        };
+        ethernet::send(packet.sender_mac, ETHERTYPE_ARP, &reply.build());
+    }
+}
+
+/// MAC address of `ip` if it's resolved
+pub fn lookup(ip: Ipv4Addr) -> Option<MacAddr> {
+    CACHE.lock().find(ip).and_then(|e| e.mac)
+}
+
+/// Send IPv4 `packet` to neighbor `ip`, resolving its address first if
+/// needed. Returns false if it's dropped.
+pub fn send(ip: Ipv4Addr, packet: Vec<u8>) -> bool {
+    let mac = match super::mac() {
+        Some(mac) => mac,
+        None => return false
+    };
+    {
+        let mut cache = CACHE.lock();
+        let entry = cache.entry(ip);
+        if let Some(dst) = entry.mac {
+            drop(cache);
+            return ethernet::send(dst, ETHERTYPE_IPV4, &packet);
+        }
+        entry.pending = Some(packet);
+    }
+    let request = Packet {
+        op: OP_REQUEST,
+        sender_mac: mac,
+        sender_ip: IP,
+        target_mac: MacAddr([0; 6]),
+        target_ip: ip,
+    };
+    ethernet::send(MacAddr::BROADCAST, ETHERTYPE_ARP, &request.build())
+}
+
+pub mod tests {
+    use super::*;
+    use crate::net::GATEWAY;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("packet", test_packet),
+            ("cache", test_cache),
+        ]
+    }
+
+    pub fn test_packet() {
+        let packet = Packet {
+            op: OP_REQUEST,
+            sender_mac: MacAddr([0x52, 0x54, 0, 0x12, 0x34, 0x56]),
+            sender_ip: IP,
+            target_mac: MacAddr([0; 6]),
+            target_ip: GATEWAY,
+        };
+        let buf = packet.build();
+        assert_eq!(&buf[..8], &[0, 1, 8, 0, 6, 4, 0, 1]);
+        assert_eq!(Packet::parse(&buf), Some(packet));
+        assert_eq!(Packet::parse(&buf[..PACKET_SIZE - 1]), None);
+    }
+
+    pub fn test_cache() {
+        let mut cache = Cache::new();
+        for i in 0..CACHE_SIZE + 1 {
+            cache.entry(Ipv4Addr([192, 168, 0, i as u8])).mac = Some(MacAddr([i as u8; 6]));
+        }
+        // first one is replaced
+        assert!(cache.find(Ipv4Addr([192, 168, 0, 0])).is_none());
+        assert_eq!(cache.find(Ipv4Addr([192, 168, 0, 1])).unwrap().mac, Some(MacAddr([1; 6])));
+        assert_eq!(cache.entry(Ipv4Addr([192, 168, 0, 16])).mac, Some(MacAddr([16; 6])));
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Ethernet II frames
+
+use alloc::vec::Vec;
+use super::{MacAddr, get_u16, put_u16, arp, ipv4, transmit};
+
+/// Size of Ethernet header
+pub const HDR_SIZE: usize = 14;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Handle a frame received, dropping those not for this host
+pub fn receive(frame: &[u8]) {
+    let mac = match super::mac() {
+        Some(mac) => mac,
+        None => return
+    };
+    if frame.len() < HDR_SIZE {
+        return;
+    }
+    let mut dst = [0; 6];
+    dst.copy_from_slice(&frame[0..6]);
+    let dst = MacAddr(dst);
+    if dst != mac && dst != MacAddr::BROADCAST {
+        return;
+    }
+    let payload = &frame[HDR_SIZE..];
+    match get_u16(frame, 12) {
+        ETHERTYPE_ARP => arp::receive(payload),
+        ETHERTYPE_IPV4 => ipv4::receive(payload),
+        _ => {}
+    }
+}
+
+/// Send `payload` of `ethertype` to `dst`. Returns false if it's dropped.
+pub fn send(dst: MacAddr, ethertype: u16, payload: &[u8]) -> bool {
+    let mac = match super::mac() {
+        Some(mac) => mac,
+        None => return false
+    };
+    let mut frame = Vec::with_capacity(HDR_SIZE + payload.len());
+    frame.extend_from_slice(&dst.0);
+    frame.extend_from_slice(&mac.0);
+    frame.extend_from_slice(&[0, 0]);
+    put_u16(&mut frame, 12, ethertype);
+    frame.extend_from_slice(payload);
+    transmit(&frame)
+}
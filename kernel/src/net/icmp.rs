@@ -0,0 +1,147 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Internet Control Message Protocol
+//!
+//! Echo requests are answered, and echo replies wake up processes pinging.
+//! Other messages are ignored.
+
+use alloc::vec::Vec;
+use core::time::Duration;
+use crate::spinlock::Mutex;
+use crate::process::{my_proc, sleep_timeout, wakeup, signal};
+use crate::arch;
+use super::{Ipv4Addr, get_u16, put_u16, checksum, loopback};
+use super::ipv4::{self, PROTO_ICMP};
+
+/// Size of echo header
+pub const HDR_SIZE: usize = 8;
+
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+
+/// Identifier of echo requests sent by kernel
+const ECHO_ID: u16 = 0x636f;
+
+/// A process waiting for echo reply
+struct Waiter {
+    dst: Ipv4Addr,
+    seq: u16,
+    replied: bool,
+}
+
+static WAITING: Mutex<Vec<Waiter>> = Mutex::new(Vec::new(), "icmp");
+
+/// Echo message with checksum filled
+fn build_echo(ty: u8, id: u16, seq: u16, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HDR_SIZE + data.len());
+    buf.resize(HDR_SIZE, 0);
+    buf[0] = ty;
+    put_u16(&mut buf, 4, id);
+    put_u16(&mut buf, 6, seq);
+    buf.extend_from_slice(data);
+    let sum = checksum(&buf);
+    put_u16(&mut buf, 2, sum);
+    buf
+}
+
+/// Handle an ICMP message from `src`
+pub fn receive(src: Ipv4Addr, buf: &[u8]) {
+    if buf.len() < HDR_SIZE || checksum(buf) != 0 {
+        return;
+    }
+    let (id, seq) = (get_u16(buf, 4), get_u16(buf, 6));
+    match buf[0] {
+        TYPE_ECHO_REQUEST => {
+            let reply = build_echo(TYPE_ECHO_REPLY, id, seq, &buf[HDR_SIZE..]);
+            ipv4::send(src, PROTO_ICMP, &reply);
+        }
+        TYPE_ECHO_REPLY if id == ECHO_ID => {
+            let mut waiting = WAITING.lock();
+            let mut found = false;
+            for w in waiting.iter_mut().filter(|w| w.dst == src && w.seq == seq) {
+                w.replied = true;
+                found = true;
+            }
+            drop(waiting);
+            if found {
+                wakeup(&WAITING);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Send an echo request with sequence number `seq` to `dst`, and wait for
+/// its reply. Returns round-trip time, or `None` if there's no reply within
+/// `timeout` or a signal comes.
+pub fn ping(dst: Ipv4Addr, seq: u16, timeout: Duration) -> Option<Duration> {
+    let pid = my_proc().pid;
+    let start = arch::time();
+    WAITING.lock().push(Waiter { dst, seq, replied: false });
+    let sent = ipv4::send(dst, PROTO_ICMP, &build_echo(TYPE_ECHO_REQUEST, ECHO_ID, seq, b"core-os ping"));
+    loopback::poll();
+
+    let mut waiting = WAITING.lock();
+    let mut replied = false;
+    while sent {
+        let i = waiting.iter().position(|w| w.dst == dst && w.seq == seq).unwrap();
+        replied = waiting[i].replied;
+        let elapsed = arch::time() - start;
+        if replied || elapsed >= timeout || signal::interrupted(pid) {
+            break;
+        }
+        waiting = sleep_timeout(&WAITING, waiting, timeout - elapsed);
+    }
+    let i = waiting.iter().position(|w| w.dst == dst && w.seq == seq).unwrap();
+    waiting.remove(i);
+    if replied {
+        Some(arch::time() - start)
+    } else {
+        None
+    }
+}
+
+pub mod tests {
+    use super::*;
+    use crate::net::{GATEWAY, LOOPBACK};
+    use crate::virtio::net::NET;
+    use crate::{info, warn};
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("echo", test_echo),
+            ("ping loopback", test_ping_loopback),
+            ("ping gateway", test_ping),
+        ]
+    }
+
+    pub fn test_echo() {
+        let buf = build_echo(TYPE_ECHO_REQUEST, ECHO_ID, 1, &[1, 2, 3]);
+        assert_eq!(buf.len(), HDR_SIZE + 3);
+        assert_eq!(checksum(&buf), 0);
+        assert_eq!(&buf[..2], &[TYPE_ECHO_REQUEST, 0]);
+        assert_eq!(get_u16(&buf, 6), 1);
+    }
+
+    pub fn test_ping_loopback() {
+        // reply is delivered before `ping` sleeps
+        assert!(ping(LOOPBACK, 0, Duration::from_secs(1)).is_some());
+    }
+
+    pub fn test_ping() {
+        if NET().is_none() {
+            info!("      skipped, no network device");
+            return;
+        }
+        // retry in case a request or reply is lost
+        let rtt = (0..3).find_map(|seq| ping(GATEWAY, seq, Duration::from_secs(1)));
+        match rtt {
+            Some(rtt) => info!("      {} replied in {:?}", GATEWAY, rtt),
+            // there may be no gateway answering ping, see `test_ping_loopback`
+            None => warn!("      no reply from {}", GATEWAY)
+        }
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Internet Protocol version 4
+//!
+//! Options are skipped, and fragmented packets are dropped. Packets out of
+//! subnet are sent through `GATEWAY`.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+use super::{Ipv4Addr, MacAddr, IP, GATEWAY, get_u16, put_u16, checksum, sum, is_ours, source, arp, icmp, udp, tcp, loopback};
+use super::ethernet::{self, ETHERTYPE_IPV4};
+
+/// Size of header without options
+pub const HDR_SIZE: usize = 20;
+
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+
+/// Time to live of packets sent
+const TTL: u8 = 64;
+
+/// Identification of next packet sent
+static ID: AtomicU16 = AtomicU16::new(0);
+
+/// An IPv4 packet
+#[derive(PartialEq, Debug)]
+pub struct Packet<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub proto: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    /// Parse `buf`. Returns `None` if it's malformed or fragmented.
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < HDR_SIZE || buf[0] >> 4 != 4 {
+            return None;
+        }
+        let hdr_len = (buf[0] & 0xf) as usize * 4;
+        let total_len = get_u16(buf, 2) as usize;
+        if hdr_len < HDR_SIZE || total_len < hdr_len || total_len > buf.len() {
+            return None;
+        }
+        if checksum(&buf[..hdr_len]) != 0 {
+            return None;
+        }
+        // more fragments, or fragment offset
+        if get_u16(buf, 6) & 0x3fff != 0 {
+            return None;
+        }
+        let mut src = [0; 4];
+        let mut dst = [0; 4];
+        src.copy_from_slice(&buf[12..16]);
+        dst.copy_from_slice(&buf[16..20]);
+        Some(Self {
+            src: Ipv4Addr(src),
+            dst: Ipv4Addr(dst),
+            proto: buf[9],
+            payload: &buf[hdr_len..total_len],
+        })
+    }
+
+    /// Packet with header, which gets a new identification
+    pub fn build(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HDR_SIZE + self.payload.len());
+        buf.resize(HDR_SIZE, 0);
+        buf[0] = 0x45;
+        put_u16(&mut buf, 2, (HDR_SIZE + self.payload.len()) as u16);
+        put_u16(&mut buf, 4, ID.fetch_add(1, Ordering::Relaxed));
+        // don't fragment
+        put_u16(&mut buf, 6, 0x4000);
+        buf[8] = TTL;
+        buf[9] = self.proto;
+        buf[12..16].copy_from_slice(&self.src.0);
+        buf[16..20].copy_from_slice(&self.dst.0);
+        let sum = checksum(&buf);
+        put_u16(&mut buf, 10, sum);
+        buf.extend_from_slice(self.payload);
+        buf
+    }
+}
+
+/// Sum of pseudo header for checksum of UDP and TCP
+pub fn pseudo_sum(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, len: usize) -> u32 {
+    let acc = sum(&src.0, 0);
+    let acc = sum(&dst.0, acc);
+    acc + proto as u32 + len as u32
+}
+
+/// Handle an IPv4 packet received
+pub fn receive(buf: &[u8]) {
+    let packet = match Packet::parse(buf) {
+        Some(packet) => packet,
+        None => return
+    };
+    if !is_ours(packet.dst) && !packet.dst.is_broadcast() {
+        return;
+    }
+    match packet.proto {
+        PROTO_ICMP => icmp::receive(packet.src, packet.payload),
+        PROTO_TCP => tcp::receive(packet.src, packet.dst, packet.payload),
+        PROTO_UDP => udp::receive(packet.src, packet.dst, packet.payload),
+        _ => {}
+    }
+}
+
+/// Send `payload` of protocol `proto` to `dst`. Returns false if it's
+/// dropped. Packets to this host are queued on loopback.
+pub fn send(dst: Ipv4Addr, proto: u8, payload: &[u8]) -> bool {
+    if HDR_SIZE + payload.len() > u16::max_value() as usize {
+        return false;
+    }
+    let packet = Packet { src: source(dst), dst, proto, payload }.build();
+    if is_ours(dst) {
+        loopback::send(packet)
+    } else if dst.is_broadcast() {
+        ethernet::send(MacAddr::BROADCAST, ETHERTYPE_IPV4, &packet)
+    } else if dst.is_local() {
+        arp::send(dst, packet)
+    } else {
+        arp::send(GATEWAY, packet)
+    }
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("packet", test_packet),
+        ]
+    }
+
+    pub fn test_packet() {
+        let payload = [1, 2, 3];
+        let buf = Packet { src: IP, dst: GATEWAY, proto: PROTO_UDP, payload: &payload }.build();
+        assert_eq!(buf.len(), HDR_SIZE + 3);
+        assert_eq!(checksum(&buf[..HDR_SIZE]), 0);
+        let packet = Packet::parse(&buf).unwrap();
+        assert_eq!(packet, Packet { src: IP, dst: GATEWAY, proto: PROTO_UDP, payload: &payload });
+        // trailing padding of Ethernet is ignored
+        let mut padded = buf.clone();
+        padded.extend_from_slice(&[0; 8]);
+        assert_eq!(Packet::parse(&padded).unwrap().payload, &payload);
+        // corrupted header
+        let mut bad = buf.clone();
+        bad[8] ^= 1;
+        assert!(Packet::parse(&bad).is_none());
+        // fragment
+        let mut frag = buf.clone();
+        put_u16(&mut frag, 6, 0x2000);
+        assert!(Packet::parse(&frag).is_none());
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Loopback interface
+//!
+//! Packets to `127.0.0.0/8` or to this host are queued instead of being
+//! transmitted. They are sent while locks of the stack are held, so the
+//! queue is delivered later by `poll`, which is called when no lock is held
+//! and on timer interrupt.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::spinlock::Mutex;
+use super::ipv4;
+
+/// Packets queued at most
+const QUEUE_LEN: usize = 64;
+
+static QUEUE: Mutex<VecDeque<Vec<u8>>> = Mutex::new(VecDeque::new(), "loopback");
+
+/// Whether packets are being delivered, so they're delivered in order
+static POLLING: AtomicBool = AtomicBool::new(false);
+
+/// Queue IPv4 `packet`. Returns false if it's dropped.
+pub fn send(packet: Vec<u8>) -> bool {
+    let mut queue = QUEUE.lock();
+    if queue.len() >= QUEUE_LEN {
+        return false;
+    }
+    queue.push_back(packet);
+    true
+}
+
+/// Whether `poll` would deliver packets now
+pub fn pending() -> bool {
+    !POLLING.load(Ordering::SeqCst) && !QUEUE.lock().is_empty()
+}
+
+/// Deliver packets queued, including those sent in reply meanwhile. Must
+/// be called with no lock of the stack held.
+pub fn poll() {
+    loop {
+        if POLLING.swap(true, Ordering::SeqCst) {
+            // packets will be delivered by the one polling
+            return;
+        }
+        loop {
+            let packet = match QUEUE.lock().pop_front() {
+                Some(packet) => packet,
+                None => break
+            };
+            ipv4::receive(&packet);
+        }
+        POLLING.store(false, Ordering::SeqCst);
+        // packets queued after the last one was taken
+        if QUEUE.lock().is_empty() {
+            return;
+        }
+    }
+}
+
+pub mod tests {
+    use super::*;
+    use crate::net::{LOOPBACK, IP};
+    use crate::net::udp::UdpSocket;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("udp", test_udp),
+        ]
+    }
+
+    pub fn test_udp() {
+        let a = UdpSocket::bind(0).unwrap();
+        let b = UdpSocket::bind(0).unwrap();
+        assert!(a.send_to(LOOPBACK, b.port(), &[1, 2, 3]));
+        let datagram = b.try_recv().unwrap();
+        assert_eq!((datagram.src, datagram.port, &datagram.data[..]), (LOOPBACK, a.port(), &[1, 2, 3][..]));
+        // address of this host goes through loopback as well
+        assert!(b.send_to(IP, a.port(), &[4]));
+        let datagram = a.try_recv().unwrap();
+        assert_eq!((datagram.src, datagram.port, &datagram.data[..]), (IP, b.port(), &[4][..]));
+        assert!(!pending());
+    }
+}
@@ -0,0 +1,1081 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Transmission Control Protocol
+//!
+//! Connections live in a global table, and sockets refer to them by index.
+//! Segments are handled as in RFC 793 with some simplifications:
+//!
+//! * segments out of order are dropped and acknowledged again, so that
+//!   peer retransmits them
+//! * every segment with data is acknowledged at once
+//! * unacknowledged data is retransmitted from `snd_una` on timeout, which
+//!   is estimated as in RFC 6298 and doubled on each retransmission
+//! * zero window is probed with the same timer
+//! * connections closed by sockets linger until peer acknowledges FIN, and
+//!   unread data of them is discarded
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::cmp::{min, max};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+use crate::spinlock::Mutex;
+use crate::process::{my_proc, sleep, wakeup, signal};
+use crate::syscall::{EINTR, EADDRINUSE, ECONNRESET, ETIMEDOUT, ECONNREFUSED};
+use crate::virtio::net::FRAME_SIZE;
+use crate::arch;
+use super::{Ipv4Addr, IP, get_u16, put_u16, get_u32, put_u32, fold, sum, is_ours, source, ethernet, loopback};
+use super::ipv4::{self, pseudo_sum, PROTO_TCP};
+
+/// Size of header without options
+pub const HDR_SIZE: usize = 20;
+
+pub const FIN: u8 = 0x01;
+pub const SYN: u8 = 0x02;
+pub const RST: u8 = 0x04;
+pub const PSH: u8 = 0x08;
+pub const ACK: u8 = 0x10;
+
+/// Maximum segment size advertised, filling an Ethernet frame
+const MSS: usize = FRAME_SIZE - ethernet::HDR_SIZE - ipv4::HDR_SIZE - HDR_SIZE;
+/// Maximum segment size of peer if it doesn't tell
+const DEFAULT_MSS: usize = 536;
+
+/// Size of receive buffer, and so largest window advertised
+const RECV_BUF: usize = 8192;
+/// Size of send buffer
+const SEND_BUF: usize = 8192;
+
+const RTO_INIT: Duration = Duration::from_secs(1);
+const RTO_MIN: Duration = Duration::from_millis(200);
+const RTO_MAX: Duration = Duration::from_secs(60);
+/// Retransmissions before connection is aborted
+const MAX_RETRIES: u32 = 8;
+/// Time spent in `TimeWait`, and in `FinWait2` after socket is closed
+const TIME_WAIT: Duration = Duration::from_secs(10);
+
+/// First ephemeral port
+const EPHEMERAL: u16 = 49152;
+
+/// `a` < `b` in sequence space
+fn lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// `a` <= `b` in sequence space
+fn le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+/// A TCP segment
+#[derive(PartialEq, Debug)]
+pub struct Segment<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    /// maximum segment size option
+    pub mss: Option<u16>,
+    pub data: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    /// Parse `buf` from `src` to `dst`. Returns `None` if it's malformed.
+    pub fn parse(src: Ipv4Addr, dst: Ipv4Addr, buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < HDR_SIZE {
+            return None;
+        }
+        let hdr_len = (buf[12] >> 4) as usize * 4;
+        if hdr_len < HDR_SIZE || hdr_len > buf.len() {
+            return None;
+        }
+        if fold(sum(buf, pseudo_sum(src, dst, PROTO_TCP, buf.len()))) != 0 {
+            return None;
+        }
+        let mut mss = None;
+        let mut options = &buf[HDR_SIZE..hdr_len];
+        while let Some(&kind) = options.first() {
+            match kind {
+                0 => break,
+                1 => options = &options[1..],
+                _ => {
+                    let len = *options.get(1)? as usize;
+                    if len < 2 || len > options.len() {
+                        return None;
+                    }
+                    if kind == 2 && len == 4 {
+                        mss = Some(get_u16(options, 2));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+        Some(Self {
+            src_port: get_u16(buf, 0),
+            dst_port: get_u16(buf, 2),
+            seq: get_u32(buf, 4),
+            ack: get_u32(buf, 8),
+            flags: buf[13] & 0x3f,
+            window: get_u16(buf, 14),
+            mss,
+            data: &buf[hdr_len..],
+        })
+    }
+
+    /// Segment from `src` to `dst` with header and checksum
+    pub fn build(&self, src: Ipv4Addr, dst: Ipv4Addr) -> Vec<u8> {
+        let hdr_len = if self.mss.is_some() { HDR_SIZE + 4 } else { HDR_SIZE };
+        let mut buf = Vec::with_capacity(hdr_len + self.data.len());
+        buf.resize(hdr_len, 0);
+        put_u16(&mut buf, 0, self.src_port);
+        put_u16(&mut buf, 2, self.dst_port);
+        put_u32(&mut buf, 4, self.seq);
+        put_u32(&mut buf, 8, self.ack);
+        buf[12] = ((hdr_len / 4) as u8) << 4;
+        buf[13] = self.flags;
+        put_u16(&mut buf, 14, self.window);
+        if let Some(mss) = self.mss {
+            buf[20] = 2;
+            buf[21] = 4;
+            put_u16(&mut buf, 22, mss);
+        }
+        buf.extend_from_slice(self.data);
+        let sum = fold(sum(&buf, pseudo_sum(src, dst, PROTO_TCP, buf.len())));
+        put_u16(&mut buf, 16, sum);
+        buf
+    }
+
+    /// Length in sequence space, counting SYN and FIN
+    pub fn len(&self) -> u32 {
+        self.data.len() as u32 + (self.flags & SYN != 0) as u32 + (self.flags & FIN != 0) as u32
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum State {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+/// Transmission control block
+struct Tcb {
+    state: State,
+    local_port: u16,
+    remote: Ipv4Addr,
+    remote_port: u16,
+
+    /// initial send sequence number
+    iss: u32,
+    /// oldest unacknowledged sequence number
+    snd_una: u32,
+    /// next sequence number to send
+    snd_nxt: u32,
+    /// window of peer
+    snd_wnd: u32,
+    /// sequence and acknowledgment numbers of last window update
+    snd_wl1: u32,
+    snd_wl2: u32,
+    /// next sequence number expected
+    rcv_nxt: u32,
+
+    /// data from `snd_una`, sent or not
+    send_buf: VecDeque<u8>,
+    recv_buf: VecDeque<u8>,
+    /// maximum segment size of peer
+    mss: usize,
+
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    /// retransmission timeout
+    rto: Duration,
+    /// end sequence number and sending time of segment being timed
+    rtt_probe: Option<(u32, Duration)>,
+    /// deadline of retransmission, or of `TimeWait` and `FinWait2`
+    timer: Option<Duration>,
+    retries: u32,
+
+    /// error reported to socket, 0 if there isn't one
+    error: i32,
+    /// FIN is sent after all data
+    fin_queued: bool,
+    fin_sent: bool,
+    fin_received: bool,
+    /// owned by a socket, or queued for `accept`
+    owned: bool,
+
+    /// listener of connection not accepted yet
+    parent: Option<usize>,
+    /// pending connections allowed on listener
+    backlog: usize,
+    /// connections established, waiting for `accept`
+    accept_queue: VecDeque<usize>,
+}
+
+/// Initial sequence number, driven by a 4 microseconds clock
+fn new_iss() -> u32 {
+    static OFFSET: AtomicU32 = AtomicU32::new(0);
+    (arch::time().as_micros() as u32 / 4).wrapping_add(OFFSET.fetch_add(64000, Ordering::Relaxed))
+}
+
+impl Tcb {
+    fn new(state: State, local_port: u16, remote: Ipv4Addr, remote_port: u16) -> Self {
+        let iss = new_iss();
+        Self {
+            state,
+            local_port,
+            remote,
+            remote_port,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            rcv_nxt: 0,
+            send_buf: VecDeque::new(),
+            recv_buf: VecDeque::new(),
+            mss: DEFAULT_MSS,
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            rto: RTO_INIT,
+            rtt_probe: None,
+            timer: None,
+            retries: 0,
+            error: 0,
+            fin_queued: false,
+            fin_sent: false,
+            fin_received: false,
+            owned: false,
+            parent: None,
+            backlog: 0,
+            accept_queue: VecDeque::new(),
+        }
+    }
+
+    /// Window advertised
+    fn window(&self) -> u16 {
+        min(RECV_BUF - self.recv_buf.len(), u16::max_value() as usize) as u16
+    }
+
+    /// Bytes of data sent but not acknowledged
+    fn in_flight(&self) -> usize {
+        let fin = self.fin_sent && self.snd_una != self.snd_nxt;
+        self.snd_nxt.wrapping_sub(self.snd_una) as usize - fin as usize
+    }
+
+    /// Send a segment of `flags` and `data` at `seq`
+    fn transmit(&self, seq: u32, flags: u8, data: &[u8]) {
+        let segment = Segment {
+            src_port: self.local_port,
+            dst_port: self.remote_port,
+            seq,
+            ack: if flags & ACK != 0 { self.rcv_nxt } else { 0 },
+            flags,
+            window: self.window(),
+            mss: if flags & SYN != 0 { Some(MSS as u16) } else { None },
+            data,
+        };
+        ipv4::send(self.remote, PROTO_TCP, &segment.build(source(self.remote), self.remote));
+    }
+
+    fn send_ack(&self) {
+        self.transmit(self.snd_nxt, ACK, &[]);
+    }
+
+    /// Send SYN, or SYN and ACK in `SynReceived`
+    fn send_syn(&mut self, now: Duration) {
+        let flags = if self.state == State::SynReceived { SYN | ACK } else { SYN };
+        self.transmit(self.iss, flags, &[]);
+        self.snd_nxt = self.iss.wrapping_add(1);
+        self.timer = Some(now + self.rto);
+        if self.retries == 0 {
+            self.rtt_probe = Some((self.snd_nxt, now));
+        }
+    }
+
+    /// Data of `len` bytes from `offset` of send buffer
+    fn send_data(&self, offset: usize, len: usize) -> Vec<u8> {
+        self.send_buf.iter().skip(offset).take(len).copied().collect()
+    }
+
+    /// Send data allowed by window, and FIN after all data if it's queued
+    fn output(&mut self, now: Duration) {
+        match self.state {
+            State::Established | State::CloseWait => {}
+            _ => return
+        }
+        loop {
+            let in_flight = self.in_flight();
+            let unsent = self.send_buf.len() - in_flight;
+            let usable = (self.snd_wnd as usize).saturating_sub(in_flight);
+            let len = min(min(unsent, usable), self.mss);
+            if len == 0 {
+                break;
+            }
+            let data = self.send_data(in_flight, len);
+            self.transmit(self.snd_nxt, ACK | PSH, &data);
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+            if self.rtt_probe.is_none() && self.retries == 0 {
+                self.rtt_probe = Some((self.snd_nxt, now));
+            }
+        }
+        if self.fin_queued && !self.fin_sent && self.in_flight() == self.send_buf.len() {
+            self.transmit(self.snd_nxt, FIN | ACK, &[]);
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.fin_sent = true;
+            self.state = if self.state == State::Established { State::FinWait1 } else { State::LastAck };
+        }
+        let waiting = self.snd_una != self.snd_nxt || self.send_buf.len() > self.in_flight();
+        if waiting && self.timer.is_none() {
+            self.timer = Some(now + self.rto);
+        }
+    }
+
+    /// Retransmit on timeout, or probe zero window
+    fn retransmit(&mut self, now: Duration) {
+        let probe = self.snd_wnd == 0 && self.state != State::SynSent && self.state != State::SynReceived;
+        if !probe {
+            self.retries += 1;
+            if self.retries > MAX_RETRIES {
+                self.abort(ETIMEDOUT);
+                return;
+            }
+        }
+        self.rto = min(self.rto * 2, RTO_MAX);
+        // Karn's algorithm: retransmitted segments aren't timed
+        self.rtt_probe = None;
+        match self.state {
+            State::SynSent | State::SynReceived => self.send_syn(now),
+            _ => {
+                let in_flight = self.in_flight();
+                if in_flight > 0 {
+                    let len = min(in_flight, self.mss);
+                    let fin = if self.fin_sent && len == in_flight { FIN } else { 0 };
+                    let data = self.send_data(0, len);
+                    self.transmit(self.snd_una, ACK | PSH | fin, &data);
+                } else if self.fin_sent && self.snd_una != self.snd_nxt {
+                    self.transmit(self.snd_una, ACK | FIN, &[]);
+                } else if probe && !self.send_buf.is_empty() {
+                    let data = self.send_data(0, 1);
+                    self.transmit(self.snd_nxt, ACK | PSH, &data);
+                    self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                } else {
+                    return;
+                }
+                self.timer = Some(now + self.rto);
+            }
+        }
+    }
+
+    /// Take a round-trip time sample if segment being timed is acknowledged
+    /// by `ack`
+    fn sample_rtt(&mut self, ack: u32, now: Duration) {
+        if let Some((end, sent)) = self.rtt_probe {
+            if le(end, ack) {
+                self.update_rto(now - sent);
+                self.rtt_probe = None;
+            }
+        }
+    }
+
+    /// Update retransmission timeout with round-trip time sample `rtt`
+    fn update_rto(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                let diff = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = (self.rttvar * 3 + diff) / 4;
+                (srtt * 7 + rtt) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = min(max(srtt + self.rttvar * 4, RTO_MIN), RTO_MAX);
+    }
+
+    /// Close connection at once, reporting `error` to socket
+    fn abort(&mut self, error: i32) {
+        self.state = State::Closed;
+        self.error = error;
+        self.timer = None;
+        self.send_buf.clear();
+    }
+}
+
+/// Send a reset in response to `seg` from `src`, which doesn't belong to
+/// any connection
+fn send_reset(src: Ipv4Addr, seg: &Segment) {
+    if seg.flags & RST != 0 {
+        return;
+    }
+    let (seq, ack, flags) = if seg.flags & ACK != 0 {
+        (seg.ack, 0, RST)
+    } else {
+        (0, seg.seq.wrapping_add(seg.len()), RST | ACK)
+    };
+    let reset = Segment {
+        src_port: seg.dst_port,
+        dst_port: seg.src_port,
+        seq,
+        ack,
+        flags,
+        window: 0,
+        mss: None,
+        data: &[],
+    };
+    ipv4::send(src, PROTO_TCP, &reset.build(source(src), src));
+}
+
+struct Tcp {
+    conns: Vec<Option<Tcb>>,
+    /// ephemeral port to try next
+    next_port: u16,
+}
+
+static TCP: Mutex<Tcp> = Mutex::new(Tcp { conns: Vec::new(), next_port: EPHEMERAL }, "tcp");
+
+/// Number of connections in table, so that timer needn't lock it if there
+/// isn't any
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+impl Tcp {
+    fn conn(&mut self, id: usize) -> &mut Tcb {
+        self.conns[id].as_mut().unwrap()
+    }
+
+    fn alloc(&mut self, tcb: Tcb) -> usize {
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+        match self.conns.iter().position(|c| c.is_none()) {
+            Some(id) => {
+                self.conns[id] = Some(tcb);
+                id
+            }
+            None => {
+                self.conns.push(Some(tcb));
+                self.conns.len() - 1
+            }
+        }
+    }
+
+    /// Remove connection `id` if it's closed and not owned
+    fn free_if_done(&mut self, id: usize) {
+        if let Some(c) = &self.conns[id] {
+            if c.state == State::Closed && !c.owned {
+                self.conns[id] = None;
+                ACTIVE.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Connection of `port` with `remote`, or listener of `port`
+    fn find(&self, port: u16, remote: Ipv4Addr, remote_port: u16) -> Option<usize> {
+        let conns = || self.conns.iter().enumerate().filter_map(|(id, c)| c.as_ref().map(|c| (id, c)));
+        conns()
+            .find(|(_, c)| c.local_port == port && c.remote == remote && c.remote_port == remote_port
+                && c.state != State::Listen && c.state != State::Closed)
+            .or_else(|| conns().find(|(_, c)| c.local_port == port && c.state == State::Listen))
+            .map(|(id, _)| id)
+    }
+
+    fn listening(&self, port: u16) -> bool {
+        self.conns.iter().flatten().any(|c| c.local_port == port && c.state == State::Listen)
+    }
+
+    /// A port not used by any connection
+    fn ephemeral(&mut self) -> Option<u16> {
+        let start = self.next_port;
+        loop {
+            let port = self.next_port;
+            self.next_port = if port == u16::max_value() { EPHEMERAL } else { port + 1 };
+            if !self.conns.iter().flatten().any(|c| c.local_port == port) {
+                return Some(port);
+            }
+            if self.next_port == start {
+                return None;
+            }
+        }
+    }
+
+    /// Handle `seg` from `src`
+    fn input(&mut self, src: Ipv4Addr, seg: &Segment, now: Duration) {
+        let id = match self.find(seg.dst_port, src, seg.src_port) {
+            Some(id) => id,
+            None => {
+                send_reset(src, seg);
+                return;
+            }
+        };
+        match self.conn(id).state {
+            State::Listen => self.input_listen(id, src, seg, now),
+            State::SynSent => self.input_syn_sent(id, seg, now),
+            _ => self.input_synchronized(id, seg, now),
+        }
+        self.free_if_done(id);
+    }
+
+    fn input_listen(&mut self, id: usize, src: Ipv4Addr, seg: &Segment, now: Duration) {
+        if seg.flags & RST != 0 {
+            return;
+        }
+        if seg.flags & ACK != 0 {
+            send_reset(src, seg);
+            return;
+        }
+        if seg.flags & SYN == 0 {
+            return;
+        }
+        let pending = self.conns.iter().flatten().filter(|c| c.parent == Some(id)).count();
+        let listener = self.conn(id);
+        if pending >= listener.backlog {
+            // peer will retry
+            return;
+        }
+        let mut child = Tcb::new(State::SynReceived, listener.local_port, src, seg.src_port);
+        child.parent = Some(id);
+        child.rcv_nxt = seg.seq.wrapping_add(1);
+        child.mss = seg.mss.map_or(DEFAULT_MSS, |mss| min(mss as usize, MSS));
+        child.snd_wnd = seg.window as u32;
+        child.snd_wl1 = seg.seq;
+        child.send_syn(now);
+        self.alloc(child);
+    }
+
+    fn input_syn_sent(&mut self, id: usize, seg: &Segment, now: Duration) {
+        let c = self.conn(id);
+        if seg.flags & ACK != 0 && (le(seg.ack, c.iss) || lt(c.snd_nxt, seg.ack)) {
+            send_reset(c.remote, seg);
+            return;
+        }
+        if seg.flags & RST != 0 {
+            if seg.flags & ACK != 0 {
+                c.abort(ECONNREFUSED);
+            }
+            return;
+        }
+        if seg.flags & SYN == 0 {
+            return;
+        }
+        c.rcv_nxt = seg.seq.wrapping_add(1);
+        c.mss = seg.mss.map_or(DEFAULT_MSS, |mss| min(mss as usize, MSS));
+        c.snd_wnd = seg.window as u32;
+        c.snd_wl1 = seg.seq;
+        c.snd_wl2 = seg.ack;
+        if seg.flags & ACK != 0 {
+            c.snd_una = seg.ack;
+            c.state = State::Established;
+            c.sample_rtt(seg.ack, now);
+            c.timer = None;
+            c.retries = 0;
+            c.send_ack();
+        } else {
+            // simultaneous open
+            c.state = State::SynReceived;
+            c.send_syn(now);
+        }
+    }
+
+    fn input_synchronized(&mut self, id: usize, seg: &Segment, now: Duration) {
+        let c = self.conn(id);
+        let mut seq = seg.seq;
+        let mut data = seg.data;
+        let mut syn = seg.flags & SYN != 0;
+        let mut fin = seg.flags & FIN != 0;
+
+        // trim what's received already
+        if lt(seq, c.rcv_nxt) {
+            if syn {
+                syn = false;
+                seq = seq.wrapping_add(1);
+            }
+            let dup = c.rcv_nxt.wrapping_sub(seq) as usize;
+            if dup > data.len() || (dup == data.len() && !fin) {
+                // nothing new, acknowledge again in case our ACK is lost
+                if seg.len() > 0 && seg.flags & RST == 0 {
+                    if c.state == State::SynReceived {
+                        c.transmit(c.iss, SYN | ACK, &[]);
+                    } else {
+                        c.send_ack();
+                    }
+                }
+                if seg.len() > 0 {
+                    return;
+                }
+                fin = false;
+            }
+            let dup = min(dup, data.len());
+            data = &data[dup..];
+            seq = seq.wrapping_add(dup as u32);
+        }
+        if lt(c.rcv_nxt, seq) {
+            // out of order
+            if seg.flags & RST == 0 {
+                c.send_ack();
+            }
+            return;
+        }
+
+        if seg.flags & RST != 0 {
+            if seq != c.rcv_nxt {
+                return;
+            }
+            if c.state == State::SynReceived && c.parent.is_some() {
+                c.state = State::Closed;
+            } else {
+                c.abort(ECONNRESET);
+            }
+            return;
+        }
+        if syn {
+            send_reset(c.remote, seg);
+            c.abort(ECONNRESET);
+            return;
+        }
+        if seg.flags & ACK == 0 {
+            return;
+        }
+        let ack = seg.ack;
+
+        if c.state == State::SynReceived {
+            if !(lt(c.snd_una, ack) && le(ack, c.snd_nxt)) {
+                send_reset(c.remote, seg);
+                return;
+            }
+            c.snd_una = ack;
+            c.state = State::Established;
+            c.sample_rtt(ack, now);
+            c.timer = None;
+            c.retries = 0;
+            if let Some(parent) = c.parent {
+                c.owned = true;
+                self.conn(parent).accept_queue.push_back(id);
+            }
+        }
+        let c = self.conn(id);
+
+        if lt(c.snd_una, ack) && le(ack, c.snd_nxt) {
+            let fin_acked = c.fin_sent && ack == c.snd_nxt;
+            let acked = ack.wrapping_sub(c.snd_una) as usize - fin_acked as usize;
+            c.send_buf.drain(..acked);
+            c.snd_una = ack;
+            c.sample_rtt(ack, now);
+            c.retries = 0;
+            c.timer = if c.snd_una == c.snd_nxt { None } else { Some(now + c.rto) };
+            if fin_acked {
+                match c.state {
+                    State::FinWait1 => {
+                        c.state = State::FinWait2;
+                        c.timer = Some(now + TIME_WAIT);
+                    }
+                    State::Closing => {
+                        c.state = State::TimeWait;
+                        c.timer = Some(now + TIME_WAIT);
+                    }
+                    State::LastAck => {
+                        c.state = State::Closed;
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+        } else if lt(c.snd_nxt, ack) {
+            // acknowledging what's not sent
+            c.send_ack();
+            return;
+        }
+        if lt(c.snd_wl1, seq) || (c.snd_wl1 == seq && le(c.snd_wl2, ack)) {
+            c.snd_wnd = seg.window as u32;
+            c.snd_wl1 = seq;
+            c.snd_wl2 = ack;
+        }
+
+        let mut ack_now = false;
+        if !data.is_empty() {
+            match c.state {
+                State::Established | State::FinWait1 | State::FinWait2 => {
+                    let n = min(RECV_BUF - c.recv_buf.len(), data.len());
+                    if c.owned {
+                        c.recv_buf.extend(&data[..n]);
+                    }
+                    c.rcv_nxt = c.rcv_nxt.wrapping_add(n as u32);
+                    if n < data.len() {
+                        // FIN is out of window
+                        fin = false;
+                    }
+                    ack_now = true;
+                }
+                _ => {}
+            }
+        }
+        if fin && !c.fin_received {
+            c.rcv_nxt = c.rcv_nxt.wrapping_add(1);
+            c.fin_received = true;
+            ack_now = true;
+            match c.state {
+                State::Established => c.state = State::CloseWait,
+                State::FinWait1 => c.state = State::Closing,
+                State::FinWait2 => {
+                    c.state = State::TimeWait;
+                    c.timer = Some(now + TIME_WAIT);
+                }
+                _ => {}
+            }
+        }
+        if ack_now {
+            c.send_ack();
+        }
+        c.output(now);
+    }
+
+    /// Run timers due at `now`. Returns whether any connection changes.
+    fn tick(&mut self, now: Duration) -> bool {
+        let mut changed = false;
+        for id in 0..self.conns.len() {
+            let c = match &mut self.conns[id] {
+                Some(c) if c.timer.map_or(false, |t| now >= t) => c,
+                _ => continue
+            };
+            c.timer = None;
+            match c.state {
+                State::TimeWait | State::FinWait2 => c.state = State::Closed,
+                _ => c.retransmit(now),
+            }
+            self.free_if_done(id);
+            changed = true;
+        }
+        changed
+    }
+}
+
+/// Handle a TCP segment from `src` to `dst`
+pub fn receive(src: Ipv4Addr, dst: Ipv4Addr, buf: &[u8]) {
+    if !is_ours(dst) {
+        return;
+    }
+    let seg = match Segment::parse(src, dst, buf) {
+        Some(seg) => seg,
+        None => return
+    };
+    TCP.lock().input(src, &seg, arch::time());
+    wakeup(&TCP);
+}
+
+/// Retransmit and close connections on time. Called on timer interrupt.
+pub fn tick() {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    if TCP.lock().tick(arch::time()) {
+        wakeup(&TCP);
+    }
+}
+
+/// Sleep until `f` returns a result. Returns `EINTR` if a signal comes.
+///
+/// Segments on loopback are delivered before checking `f`, and before
+/// returning, as they can't be delivered while `TCP` is locked.
+fn wait<R>(mut f: impl FnMut(&mut Tcp) -> Option<Result<R, i32>>) -> Result<R, i32> {
+    let pid = my_proc().pid;
+    loop {
+        loopback::poll();
+        let mut tcp = TCP.lock();
+        if let Some(result) = f(&mut *tcp) {
+            drop(tcp);
+            loopback::poll();
+            return result;
+        }
+        if signal::interrupted(pid) {
+            return Err(EINTR);
+        }
+        if loopback::pending() {
+            continue;
+        }
+        drop(sleep(&TCP, tcp));
+    }
+}
+
+/// Whether there's a listener on `port`
+pub fn listening(port: u16) -> bool {
+    TCP.lock().listening(port)
+}
+
+/// Listen on `port`, or on an ephemeral port if it's 0, with at most
+/// `backlog` connections waiting for `accept`
+pub fn listen(port: u16, backlog: usize) -> Result<TcpSocket, i32> {
+    let mut tcp = TCP.lock();
+    let port = match port {
+        0 => tcp.ephemeral().ok_or(EADDRINUSE)?,
+        port if tcp.listening(port) => return Err(EADDRINUSE),
+        port => port
+    };
+    let mut listener = Tcb::new(State::Listen, port, Ipv4Addr::UNSPECIFIED, 0);
+    listener.owned = true;
+    listener.backlog = max(backlog, 1);
+    Ok(TcpSocket { id: tcp.alloc(listener) })
+}
+
+/// Connect from `local_port`, or an ephemeral port if it's 0, to `port` of
+/// `dst`, and wait until connection is established
+pub fn connect(local_port: u16, dst: Ipv4Addr, port: u16) -> Result<TcpSocket, i32> {
+    let socket = {
+        let mut tcp = TCP.lock();
+        let local_port = match local_port {
+            0 => tcp.ephemeral().ok_or(EADDRINUSE)?,
+            local_port => local_port
+        };
+        if tcp.find(local_port, dst, port).map_or(false, |id| tcp.conn(id).state != State::Listen) {
+            return Err(EADDRINUSE);
+        }
+        let mut c = Tcb::new(State::SynSent, local_port, dst, port);
+        c.owned = true;
+        c.send_syn(arch::time());
+        TcpSocket { id: tcp.alloc(c) }
+    };
+    let id = socket.id;
+    wait(|tcp| {
+        let c = tcp.conn(id);
+        match c.state {
+            State::SynSent | State::SynReceived => None,
+            State::Closed => Some(Err(c.error)),
+            _ => Some(Ok(()))
+        }
+    })?;
+    Ok(socket)
+}
+
+/// A TCP listener or connection, closed when dropped
+pub struct TcpSocket {
+    id: usize,
+}
+
+impl TcpSocket {
+    /// Address and port of peer
+    pub fn peer(&self) -> (Ipv4Addr, u16) {
+        let mut tcp = TCP.lock();
+        let c = tcp.conn(self.id);
+        (c.remote, c.remote_port)
+    }
+
+    /// Wait for a connection on listener
+    pub fn accept(&self) -> Result<TcpSocket, i32> {
+        let id = self.id;
+        wait(|tcp| {
+            let listener = tcp.conn(id);
+            if listener.state != State::Listen {
+                return Some(Err(-1));
+            }
+            let child = listener.accept_queue.pop_front()?;
+            tcp.conn(child).parent = None;
+            Some(Ok(TcpSocket { id: child }))
+        })
+    }
+
+    /// Send all of `data`, sleeping while send buffer is full. Returns
+    /// bytes sent, or an error if nothing is sent.
+    pub fn send(&self, data: &[u8]) -> i32 {
+        let id = self.id;
+        let mut sent = 0;
+        let result = wait(|tcp| {
+            let c = tcp.conn(id);
+            if c.error != 0 {
+                return Some(Err(c.error));
+            }
+            if c.fin_queued || (c.state != State::Established && c.state != State::CloseWait) {
+                return Some(Err(-1));
+            }
+            let n = min(SEND_BUF - c.send_buf.len(), data.len() - sent);
+            if n > 0 {
+                c.send_buf.extend(&data[sent..sent + n]);
+                sent += n;
+                c.output(arch::time());
+            }
+            if sent == data.len() { Some(Ok(())) } else { None }
+        });
+        match result {
+            Err(err) if sent == 0 => err,
+            _ => sent as i32
+        }
+    }
+
+    /// Receive into `buf`, sleeping until there's data. Returns bytes
+    /// received, 0 if peer closes connection, or an error.
+    pub fn recv(&self, buf: &mut [u8]) -> i32 {
+        let id = self.id;
+        let result = wait(|tcp| {
+            let c = tcp.conn(id);
+            if !c.recv_buf.is_empty() {
+                let reopen = (c.window() as usize) < c.mss;
+                let n = min(buf.len(), c.recv_buf.len());
+                for (dst, src) in buf.iter_mut().zip(c.recv_buf.drain(..n)) {
+                    *dst = src;
+                }
+                // tell peer that window opens again
+                if reopen && c.window() as usize >= c.mss && !c.fin_received {
+                    c.send_ack();
+                }
+                return Some(Ok(n as i32));
+            }
+            if c.error != 0 {
+                return Some(Err(c.error));
+            }
+            if c.fin_received || c.state == State::Closed {
+                return Some(Ok(0));
+            }
+            None
+        });
+        result.unwrap_or_else(|err| err)
+    }
+}
+
+impl Drop for TcpSocket {
+    fn drop(&mut self) {
+        let mut tcp = TCP.lock();
+        let id = self.id;
+        let now = arch::time();
+        let c = tcp.conn(id);
+        c.owned = false;
+        match c.state {
+            State::Listen => {
+                c.state = State::Closed;
+                // reset connections not accepted
+                for child in 0..tcp.conns.len() {
+                    if let Some(c) = &mut tcp.conns[child] {
+                        if c.parent == Some(id) {
+                            c.transmit(c.snd_nxt, RST, &[]);
+                            c.state = State::Closed;
+                            c.owned = false;
+                            c.parent = None;
+                            tcp.free_if_done(child);
+                        }
+                    }
+                }
+            }
+            State::SynSent => c.state = State::Closed,
+            State::SynReceived | State::Established | State::CloseWait => {
+                c.fin_queued = true;
+                c.recv_buf.clear();
+                c.output(now);
+            }
+            _ => {}
+        }
+        tcp.free_if_done(id);
+        drop(tcp);
+        loopback::poll();
+    }
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("sequence", test_sequence),
+            ("segment", test_segment),
+            ("handshake", test_handshake),
+            ("loopback", test_loopback),
+        ]
+    }
+
+    pub fn test_sequence() {
+        assert!(lt(1, 2));
+        assert!(lt(0xffff_fff0, 0x10));
+        assert!(!lt(0x10, 0xffff_fff0));
+        assert!(le(5, 5));
+        assert!(!lt(5, 5));
+    }
+
+    pub fn test_segment() {
+        let remote = Ipv4Addr([10, 0, 2, 100]);
+        let seg = Segment {
+            src_port: 7,
+            dst_port: 40000,
+            seq: 0x1234_5678,
+            ack: 0x9abc_def0,
+            flags: SYN | ACK,
+            window: 8192,
+            mss: Some(1460),
+            data: &[],
+        };
+        let buf = seg.build(IP, remote);
+        assert_eq!(buf.len(), HDR_SIZE + 4);
+        assert_eq!(Segment::parse(IP, remote, &buf), Some(seg));
+        let seg = Segment { flags: ACK | PSH | FIN, mss: None, data: &[1, 2, 3], ..Segment::parse(IP, remote, &buf).unwrap() };
+        let buf = seg.build(IP, remote);
+        assert_eq!(seg.len(), 4);
+        assert_eq!(Segment::parse(IP, remote, &buf), Some(seg));
+        // checksum covers addresses
+        assert!(Segment::parse(IP, Ipv4Addr([10, 0, 2, 101]), &buf).is_none());
+    }
+
+    /// Feed segments of a remote host into a listener
+    pub fn test_handshake() {
+        let remote = Ipv4Addr([10, 0, 2, 100]);
+        let input = |seq: u32, ack: u32, flags: u8, data: &[u8]| {
+            let seg = Segment { src_port: 40000, dst_port: 7007, seq, ack, flags, window: 8192, mss: None, data };
+            receive(remote, IP, &seg.build(remote, IP));
+        };
+        let listener = listen(7007, 1).unwrap();
+        assert_eq!(listen(7007, 1).err(), Some(EADDRINUSE));
+        input(1000, 0, SYN, &[]);
+        let iss = {
+            let mut tcp = TCP.lock();
+            let id = tcp.find(7007, remote, 40000).unwrap();
+            let child = tcp.conn(id);
+            assert_eq!(child.state, State::SynReceived);
+            assert_eq!(child.rcv_nxt, 1001);
+            child.iss
+        };
+        // final ACK carries data
+        input(1001, iss.wrapping_add(1), ACK | PSH, b"hello");
+        let conn = listener.accept().unwrap();
+        assert_eq!(conn.peer(), (remote, 40000));
+        let mut buf = [0; 16];
+        assert_eq!(conn.recv(&mut buf), 5);
+        assert_eq!(&buf[..5], b"hello");
+        // out of order data is dropped
+        input(1010, iss.wrapping_add(1), ACK, b"later");
+        input(1006, iss.wrapping_add(1), ACK | FIN, &[]);
+        assert_eq!(conn.recv(&mut buf), 0);
+        drop(conn);
+        {
+            let mut tcp = TCP.lock();
+            let id = tcp.find(7007, remote, 40000).unwrap();
+            assert_eq!(tcp.conn(id).state, State::LastAck);
+        }
+        input(1007, iss.wrapping_add(2), ACK, &[]);
+        assert!(TCP.lock().find(7007, remote, 40000).map_or(true, |id| id == listener.id));
+        drop(listener);
+        assert!(!listening(7007));
+    }
+
+    /// Talk to a listener through loopback
+    pub fn test_loopback() {
+        use crate::net::LOOPBACK;
+        let listener = listen(7008, 1).unwrap();
+        let client = connect(0, LOOPBACK, 7008).unwrap();
+        let server = listener.accept().unwrap();
+        assert_eq!(client.peer(), (LOOPBACK, 7008));
+        assert_eq!(server.peer().0, LOOPBACK);
+        assert_eq!(client.send(b"hello"), 5);
+        let mut buf = [0; 16];
+        assert_eq!(server.recv(&mut buf), 5);
+        assert_eq!(&buf[..5], b"hello");
+        drop(client);
+        assert_eq!(server.recv(&mut buf), 0);
+    }
+}
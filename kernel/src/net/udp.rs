@@ -0,0 +1,209 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! User Datagram Protocol
+//!
+//! Each bound port keeps a short queue of datagrams received. Datagrams to
+//! ports not bound, or to a full queue, are dropped.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use crate::spinlock::Mutex;
+use crate::process::{my_proc, sleep, wakeup, signal};
+use super::{Ipv4Addr, IP, get_u16, put_u16, fold, sum, source, loopback};
+use super::ipv4::{self, pseudo_sum, PROTO_UDP};
+
+/// Size of header
+pub const HDR_SIZE: usize = 8;
+
+/// Datagrams queued in each port
+const QUEUE_LEN: usize = 16;
+
+/// First ephemeral port
+const EPHEMERAL: u16 = 49152;
+
+/// A datagram received
+pub struct Datagram {
+    pub src: Ipv4Addr,
+    pub port: u16,
+    pub data: Vec<u8>,
+}
+
+struct Binding {
+    port: u16,
+    queue: VecDeque<Datagram>,
+}
+
+struct Bindings {
+    bindings: Vec<Binding>,
+    /// ephemeral port to try next
+    next: u16,
+}
+
+impl Bindings {
+    fn find(&mut self, port: u16) -> Option<&mut Binding> {
+        self.bindings.iter_mut().find(|b| b.port == port)
+    }
+}
+
+static BINDINGS: Mutex<Bindings> = Mutex::new(Bindings { bindings: Vec::new(), next: EPHEMERAL }, "udp");
+
+/// Checksum of datagram `buf` from `src` to `dst`
+fn udp_checksum(src: Ipv4Addr, dst: Ipv4Addr, buf: &[u8]) -> u16 {
+    fold(sum(buf, pseudo_sum(src, dst, PROTO_UDP, buf.len())))
+}
+
+/// Datagram with header and checksum
+fn build(src: Ipv4Addr, src_port: u16, dst: Ipv4Addr, dst_port: u16, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HDR_SIZE + data.len());
+    buf.resize(HDR_SIZE, 0);
+    put_u16(&mut buf, 0, src_port);
+    put_u16(&mut buf, 2, dst_port);
+    put_u16(&mut buf, 4, (HDR_SIZE + data.len()) as u16);
+    buf.extend_from_slice(data);
+    let sum = match udp_checksum(src, dst, &buf) {
+        // zero means no checksum
+        0 => 0xffff,
+        sum => sum
+    };
+    put_u16(&mut buf, 6, sum);
+    buf
+}
+
+/// Handle a UDP datagram from `src` to `dst`
+pub fn receive(src: Ipv4Addr, dst: Ipv4Addr, buf: &[u8]) {
+    if buf.len() < HDR_SIZE {
+        return;
+    }
+    let len = get_u16(buf, 4) as usize;
+    if len < HDR_SIZE || len > buf.len() {
+        return;
+    }
+    let buf = &buf[..len];
+    if get_u16(buf, 6) != 0 && udp_checksum(src, dst, buf) != 0 {
+        return;
+    }
+    let mut bindings = BINDINGS.lock();
+    if let Some(binding) = bindings.find(get_u16(buf, 2)) {
+        if binding.queue.len() < QUEUE_LEN {
+            binding.queue.push_back(Datagram {
+                src,
+                port: get_u16(buf, 0),
+                data: buf[HDR_SIZE..].to_vec(),
+            });
+            drop(bindings);
+            wakeup(&BINDINGS);
+        }
+    }
+}
+
+/// A bound UDP port, unbound when dropped
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Bind `port`, or an ephemeral port if it's 0. Returns `None` if it's
+    /// in use.
+    pub fn bind(port: u16) -> Option<Self> {
+        let mut bindings = BINDINGS.lock();
+        let port = if port == 0 {
+            let mut port = bindings.next;
+            while bindings.find(port).is_some() {
+                port = if port == u16::max_value() { EPHEMERAL } else { port + 1 };
+                if port == bindings.next {
+                    return None;
+                }
+            }
+            bindings.next = if port == u16::max_value() { EPHEMERAL } else { port + 1 };
+            port
+        } else if bindings.find(port).is_some() {
+            return None;
+        } else {
+            port
+        };
+        bindings.bindings.push(Binding { port, queue: VecDeque::new() });
+        Some(Self { port })
+    }
+
+    /// Port bound
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Send `data` to `port` of `dst`. Returns false if it's dropped.
+    pub fn send_to(&self, dst: Ipv4Addr, port: u16, data: &[u8]) -> bool {
+        if HDR_SIZE + data.len() > u16::max_value() as usize {
+            return false;
+        }
+        let sent = ipv4::send(dst, PROTO_UDP, &build(source(dst), self.port, dst, port, data));
+        loopback::poll();
+        sent
+    }
+
+    /// Datagram received, if there is one
+    pub fn try_recv(&self) -> Option<Datagram> {
+        BINDINGS.lock().find(self.port).unwrap().queue.pop_front()
+    }
+
+    /// Wait for a datagram. Returns `None` if a signal comes.
+    pub fn recv(&self) -> Option<Datagram> {
+        let pid = my_proc().pid;
+        let mut bindings = BINDINGS.lock();
+        loop {
+            if let Some(datagram) = bindings.find(self.port).unwrap().queue.pop_front() {
+                return Some(datagram);
+            }
+            if signal::interrupted(pid) {
+                return None;
+            }
+            bindings = sleep(&BINDINGS, bindings);
+        }
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        BINDINGS.lock().bindings.retain(|b| b.port != self.port);
+    }
+}
+
+pub mod tests {
+    use super::*;
+    use crate::net::GATEWAY;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("datagram", test_datagram),
+            ("bind", test_bind),
+        ]
+    }
+
+    pub fn test_datagram() {
+        let buf = build(IP, 1234, GATEWAY, 53, &[1, 2, 3]);
+        assert_eq!(buf.len(), HDR_SIZE + 3);
+        assert_eq!(udp_checksum(IP, GATEWAY, &buf), 0);
+        let socket = UdpSocket::bind(53).unwrap();
+        receive(GATEWAY, IP, &buf);
+        let datagram = socket.try_recv().unwrap();
+        assert_eq!((datagram.src, datagram.port, &datagram.data[..]), (GATEWAY, 1234, &[1, 2, 3][..]));
+        // corrupted
+        let mut bad = buf.clone();
+        bad[HDR_SIZE] ^= 1;
+        receive(GATEWAY, IP, &bad);
+        assert!(socket.try_recv().is_none());
+    }
+
+    pub fn test_bind() {
+        let a = UdpSocket::bind(0).unwrap();
+        let b = UdpSocket::bind(0).unwrap();
+        assert!(a.port() >= EPHEMERAL && b.port() >= EPHEMERAL);
+        assert_ne!(a.port(), b.port());
+        assert!(UdpSocket::bind(a.port()).is_none());
+        let port = a.port();
+        drop(a);
+        UdpSocket::bind(port).unwrap();
+    }
+}
@@ -5,12 +5,18 @@
 
 //! Paging implementaion and page table abstraction
 
-use crate::mem::{self, ALLOC};
+use crate::mem::{self, ALLOC, OutOfMemory};
+use crate::mem::stat::{self, Kind};
 use crate::spinlock::Mutex;
 use crate::{print, println, panic};
 use crate::symbols::*;
 use alloc::boxed::Box;
-use crate::process::my_cpu;
+use alloc::vec::Vec;
+use crate::process::{my_cpu, USER_HEAP};
+use crate::mem::{asid, tlb, swap};
+use crate::arch::{self, hart_id};
+use core::ops::Deref;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 const TABLE_ENTRY_CNT: usize = 512;
 
@@ -28,9 +34,12 @@ pub struct Page {
 
 impl Page {
     pub fn new() -> Box<Self> {
-        Box::new(Self {
-            data: [0; PAGE_SIZE]
-        })
+        Self::try_new().expect("out of memory")
+    }
+
+    /// Allocate a zeroed page, or fail if there's no free frame
+    pub fn try_new() -> Result<Box<Self>, OutOfMemory> {
+        unsafe { mem::try_zeroed_box() }
     }
 }
 
@@ -60,6 +69,8 @@ pub enum EntryAttributes {
     UR = 0b10010,
     URW = 0b10110,
     URX = 0b11010,
+    /// Software bit marking an invalid entry as a page in swap
+    SWAP = 1 << 8,
 }
 
 impl Entry {
@@ -99,9 +110,33 @@ impl Entry {
     pub const fn new(ppn: usize, flags: usize) -> Self {
         Self(((ppn & !0xfff) >> 2) | flags)
     }
+    /// Entry of a page swapped out to `slot`, keeping permission `flags`
+    pub const fn swapped(slot: usize, flags: usize) -> Self {
+        Self(slot << 10 | (flags & 0xfe) | EntryAttributes::SWAP as usize)
+    }
+    pub fn is_swapped(&self) -> bool {
+        !self.is_v() && self.0 & EntryAttributes::SWAP as usize != 0
+    }
+    /// Swap slot of a swapped entry
+    pub fn swap_slot(&self) -> usize {
+        self.0 >> 10
+    }
+    /// Clear accessed bit, so that next access sets it again
+    pub fn clear_a(&mut self) {
+        self.0 &= !(EntryAttributes::A as usize);
+    }
+    /// Replace entry atomically, so that `A` or `D` bit set by hardware in
+    /// the meantime is returned instead of lost
+    pub fn replace(&mut self, new: Entry) -> Entry {
+        let v = unsafe { &*(&mut self.0 as *mut usize as *const AtomicUsize) };
+        Entry(v.swap(new.0, Ordering::SeqCst))
+    }
 }
 
 impl PPN {
+    pub fn addr(&self) -> usize {
+        self.0
+    }
     pub fn ppn0(&self) -> usize {
         (self.0 >> 12) & 0x1ff
     }
@@ -119,10 +154,10 @@ impl PPN {
             _ => unreachable!(),
         }
     }
-    pub fn clone_page(&self) -> Box<Page> {
-        let mut pg = Page::new();
+    pub fn clone_page(&self) -> Result<Box<Page>, OutOfMemory> {
+        let mut pg = Page::try_new()?;
         unsafe { core::ptr::copy(self.0 as *const u8, pg.data.as_mut_ptr(), PAGE_SIZE); }
-        pg
+        Ok(pg)
     }
 }
 
@@ -153,58 +188,169 @@ impl Table {
         }
     }
 
+    /// Allocate an empty page table on heap
+    pub fn alloc() -> Box<Self> {
+        Self::try_alloc().expect("out of memory")
+    }
+
+    /// Allocate an empty page table on heap, or fail if there's no free frame
+    pub fn try_alloc() -> Result<Box<Self>, OutOfMemory> {
+        // an empty table is all zero
+        let table = unsafe { mem::try_zeroed_box()? };
+        stat::add(Kind::PageTable, PAGE_SIZE);
+        Ok(table)
+    }
+
     pub const fn len(&self) -> usize {
         TABLE_ENTRY_CNT
     }
 
     pub fn map(&mut self, vaddr: usize, pg: Box<Page>, flags: usize) {
+        self.try_map(vaddr, pg, flags).expect("out of memory");
+    }
+
+    /// Map user page `pg` at `vaddr`. Fails if page tables on the way can't
+    /// be allocated, in which case `pg` is freed.
+    pub fn try_map(&mut self, vaddr: usize, pg: Box<Page>, flags: usize) -> Result<(), OutOfMemory> {
         if flags & EntryAttributes::U as usize == 0 {
             panic!("you may only map user page");
         }
-        self.map_addr(vaddr, Box::into_raw(pg) as usize, flags, 0);
+        let v = self.walk_alloc(vaddr, 0)?;
+        stat::add(Kind::UserPage, PAGE_SIZE);
+        Self::set_leaf(v, vaddr, Box::into_raw(pg) as usize, flags);
+        Ok(())
     }
 
     pub fn kernel_map(&mut self, vaddr: usize, paddr: usize, flags: usize) {
+        self.try_kernel_map(vaddr, paddr, flags).expect("out of memory");
+    }
+
+    /// Map kernel page, failing if page tables on the way can't be allocated
+    pub fn try_kernel_map(&mut self, vaddr: usize, paddr: usize, flags: usize) -> Result<(), OutOfMemory> {
         if flags & EntryAttributes::U as usize != 0 {
             panic!("you may only map kernel page");
         }
-        self.map_addr(vaddr, paddr, flags, 0);
+        self.try_map_addr(vaddr, paddr, flags, 0)
     }
 
+    /// Map `vaddr` to `paddr` with a leaf entry at `level`, which is a
+    /// 4 KiB page at level 0, a 2 MiB megapage at level 1 and a 1 GiB
+    /// gigapage at level 2.
     fn map_addr(&mut self, vaddr: usize, paddr: usize, flags: usize, level: usize) {
-        if paddr % PAGE_SIZE != 0 {
+        self.try_map_addr(vaddr, paddr, flags, level).expect("out of memory");
+    }
+
+    fn try_map_addr(&mut self, vaddr: usize, paddr: usize, flags: usize, level: usize) -> Result<(), OutOfMemory> {
+        if paddr % level_size(level) != 0 {
             panic!("paddr {:x} not aligned", paddr);
         }
-        if vaddr % PAGE_SIZE != 0 {
+        let v = self.walk_alloc(vaddr, level)?;
+        Self::set_leaf(v, vaddr, paddr, flags);
+        Ok(())
+    }
+
+    /// Find entry at `level` for `vaddr`, allocating page tables on the way
+    fn walk_alloc(&mut self, vaddr: usize, level: usize) -> Result<&mut Entry, OutOfMemory> {
+        if vaddr % level_size(level) != 0 {
             panic!("vaddr {:x} not aligned", vaddr);
         }
         let vpn = VPN(vaddr);
         let mut v = &mut self.entries[vpn.vpn2()];
         for lvl in (level..2).rev() {
             if !v.is_v() {
-                let page = box Table::new();
+                let page = Table::try_alloc()?;
                 *v = Entry::new(Box::into_raw(page) as usize, EntryAttributes::V as usize);
+            } else if v.is_leaf() {
+                panic!("vaddr {:x} already mapped by a megapage", vaddr);
             }
             let entry = v.paddr().0 as *mut Entry;
             v = unsafe { entry.add(vpn.idx(lvl)).as_mut().unwrap() };
         }
+        Ok(v)
+    }
+
+    fn set_leaf(v: &mut Entry, vaddr: usize, paddr: usize, flags: usize) {
+        if v.is_v() && !v.is_leaf() {
+            panic!("vaddr {:x} already mapped by a page table", vaddr);
+        }
         *v = Entry::new(paddr, flags | EntryAttributes::V as usize)
     }
 
-    pub fn paddr_of(&self, vaddr: usize) -> Option<usize> {
+    /// Find leaf entry mapping `vaddr` and its level. If `vaddr` isn't
+    /// mapped, returns the invalid entry where walk stops.
+    fn leaf_of(&self, vaddr: usize) -> (&Entry, usize) {
         let vpn = VPN(vaddr);
         let mut v = &self.entries[vpn.vpn2()];
-        if vaddr % PAGE_SIZE != 0 {
-            panic!("vaddr {:x} not aligned", vaddr);
+        for lvl in (0..2).rev() {
+            if !v.is_v() || v.is_leaf() {
+                return (v, lvl + 1);
+            }
+            v = unsafe { &*(v.paddr().0 as *const Entry).add(vpn.idx(lvl)) };
         }
+        (v, 0)
+    }
+
+    /// Same as `leaf_of`, but the entry may be changed
+    fn leaf_of_mut(&mut self, vaddr: usize) -> (&mut Entry, usize) {
+        let vpn = VPN(vaddr);
+        let mut v = &mut self.entries[vpn.vpn2()];
         for lvl in (0..2).rev() {
-            if !v.is_v() {
-                return None;
+            if !v.is_v() || v.is_leaf() {
+                return (v, lvl + 1);
             }
-            let entry = v.paddr().0 as *mut Entry;
-            v = unsafe { entry.add(vpn.idx(lvl)).as_mut().unwrap() };
+            v = unsafe { &mut *(v.paddr().0 as *mut Entry).add(vpn.idx(lvl)) };
         }
-        Some(v.paddr().0)
+        (v, 0)
+    }
+
+    /// Level 0 entry of `vaddr`, `None` if it's not reachable or `vaddr` is
+    /// in a megapage
+    pub fn entry_mut(&mut self, vaddr: usize) -> Option<&mut Entry> {
+        match self.leaf_of_mut(vaddr) {
+            (v, 0) => Some(v),
+            _ => None
+        }
+    }
+
+    pub fn paddr_of(&self, vaddr: usize) -> Option<usize> {
+        if vaddr % PAGE_SIZE != 0 {
+            panic!("vaddr {:x} not aligned", vaddr);
+        }
+        let (v, level) = self.leaf_of(vaddr);
+        if !v.is_v() {
+            return None;
+        }
+        Some(v.paddr().0 + (vaddr & (level_size(level) - 1)))
+    }
+
+    /// Remove mapping at `vaddr`, freeing the page if it's a user page
+    pub fn unmap(&mut self, vaddr: usize) {
+        if vaddr % PAGE_SIZE != 0 {
+            panic!("vaddr {:x} not aligned", vaddr);
+        }
+        let (v, level) = self.leaf_of_mut(vaddr);
+        if v.is_swapped() {
+            swap::free_slot(v.swap_slot());
+            *v = Entry(0);
+            return;
+        }
+        if !v.is_v() {
+            return;
+        }
+        if vaddr % level_size(level) != 0 {
+            panic!("vaddr {:x} is inside a megapage", vaddr);
+        }
+        if v.is_u() {
+            free_user_page(v);
+        }
+        *v = Entry(0);
+    }
+
+    /// Whether a leaf entry at `level` can be put at `vaddr` without
+    /// replacing existing mappings
+    fn is_free(&self, vaddr: usize, level: usize) -> bool {
+        let (v, lvl) = self.leaf_of(vaddr);
+        !v.is_v() && lvl >= level
     }
 
     fn _walk(&self, level: usize, vpn: usize) {
@@ -229,12 +375,13 @@ impl Table {
                     let r_flag = if v.is_r() { "R" } else { "" };
                     let w_flag = if v.is_w() { "W" } else { "" };
                     let x_flag = if v.is_x() { "X" } else { "" };
-                    let vaddr = (vpn << 9 | i) << 12;
+                    let vaddr = (vpn << 9 | i) << (9 * level + 12);
+                    let size = match level { 0 => "", 1 => " 2M", _ => " 1G" };
                     if vaddr != v.paddr().0 || true {
                         for _j in 0..(2 - level) {
                             print!(".");
                         }
-                        println!("{}: 0x{:X} -> 0x{:X}  {}{}{}{}", i, vaddr, v.paddr().0, u_flag, r_flag, w_flag, x_flag);
+                        println!("{}: 0x{:X} -> 0x{:X}  {}{}{}{}{}", i, vaddr, v.paddr().0, u_flag, r_flag, w_flag, x_flag, size);
                     }
                 }
             }
@@ -245,12 +392,20 @@ impl Table {
         self._walk(2, 0);
     }
 
+    /// Identity map `start` to `end`, using megapages and gigapages where
+    /// alignment permits
     pub fn id_map_range(&mut self, start: usize, end: usize, bits: usize) {
         let mut memaddr = mem::align_val_down(start, PAGE_ORDER);
-        let num_kb_pages = (mem::align_val(end, 12) - memaddr) / PAGE_SIZE;
-        for _ in 0..num_kb_pages {
-            self.map_addr(memaddr, memaddr, bits, 0);
-            memaddr += PAGE_SIZE;
+        let end = mem::align_val(end, PAGE_ORDER);
+        while memaddr < end {
+            let level = (0..3).rev()
+                .find(|&level| {
+                    let size = level_size(level);
+                    memaddr % size == 0 && end - memaddr >= size && self.is_free(memaddr, level)
+                })
+                .unwrap_or(0);
+            self.map_addr(memaddr, memaddr, bits, level);
+            memaddr += level_size(level);
         }
     }
 
@@ -268,52 +423,84 @@ impl Table {
 
     /* TODO: use same function for drop_walk, unmap_user and walk */
 
+    /// Free user pages and page tables below this table at `level`. Leaves
+    /// above level 0 are kernel megapages, which are never freed.
     fn drop_walk(&mut self, level: usize) {
         for i in 0..self.len() {
             let v = &mut self.entries[i];
-            if v.is_v() {
+            if v.is_swapped() {
+                swap::free_slot(v.swap_slot());
+                *v = Entry(0);
+            } else if v.is_v() {
                 if v.is_leaf() {
                     if v.is_u() {
+                        if level != 0 {
+                            panic!("user megapage at level {}", level);
+                        }
                         // drop user page
-                        let _pg = unsafe { Box::from_raw(v.paddr().0 as *mut Page) };
+                        free_user_page(v);
                     }
                 } else {
-                    // drop page table
+                    // free entries of page table, and then page table itself
                     let mut table = unsafe { Box::from_raw(v.paddr().0 as *mut Table) };
                     table.drop_walk(level - 1);
                 }
+                *v = Entry(0);
             }
         }
     }
 
-    fn clone_walk(&self, level: usize) -> Box<Self> {
-        let mut pgtable = Table::new();
+    /// Copy page table at `level`. If it fails, whatever is copied so far
+    /// is freed.
+    fn clone_walk(&self, level: usize) -> Result<Box<Self>, OutOfMemory> {
+        let mut pgtable = Table::try_alloc()?;
+        if let Err(err) = self.clone_entries(&mut pgtable, level) {
+            pgtable.drop_walk(level);
+            return Err(err);
+        }
+        Ok(pgtable)
+    }
+
+    fn clone_entries(&self, pgtable: &mut Table, level: usize) -> Result<(), OutOfMemory> {
         for i in 0..self.len() {
             let v = &self.entries[i];
-            if v.is_v() {
+            if v.is_swapped() {
+                pgtable.entries[i] = match swap::share(v.swap_slot())? {
+                    // page is being written out, copy it
+                    Some(pg) => {
+                        stat::add(Kind::UserPage, PAGE_SIZE);
+                        Entry::new(Box::into_raw(pg) as usize, v.flags() & 0xff | EntryAttributes::V as usize)
+                    }
+                    None => *v
+                };
+            } else if v.is_v() {
                 if v.is_leaf() {
                     if v.is_u() {
-                        let pg = v.paddr().clone_page();
+                        let pg = v.paddr().clone_page()?;
+                        stat::add(Kind::UserPage, PAGE_SIZE);
                         pgtable.entries[i] = Entry::new(Box::into_raw(pg) as usize, v.flags());
                     }
                 } else {
                     let table = unsafe { (v.paddr().0 as *mut Table).as_mut().unwrap() };
-                    let pg = table.clone_walk(level - 1);
+                    let pg = table.clone_walk(level - 1)?;
                     pgtable.entries[i] = Entry::new(Box::into_raw(pg) as usize, v.flags());
                 }
             }
         }
-        box pgtable
+        Ok(())
     }
 
      pub fn unmap_user(&mut self) {
         for i in 0..self.len() {
             let v = &mut self.entries[i];
-            if v.is_v() {
+            if v.is_swapped() {
+                swap::free_slot(v.swap_slot());
+                *v = Entry(0);
+            } else if v.is_v() {
                 if v.is_leaf() {
                     if v.is_u() {
                         // drop user page
-                        let _pg = unsafe { Box::from_raw(v.paddr().0 as *mut Page) };
+                        free_user_page(v);
                         *v = Entry(0);
                     }
                 } else {
@@ -324,19 +511,265 @@ impl Table {
             }
         }
     }
+
+    /// Call `f` with virtual address and entry of each resident or swapped
+    /// user page at or above `start`, in order, until `f` returns false.
+    /// Returns false if stopped by `f`.
+    pub fn walk_user(&mut self, start: usize, f: &mut dyn FnMut(usize, &mut Entry) -> bool) -> bool {
+        self._walk_user(2, 0, start, f)
+    }
+
+    fn _walk_user(&mut self, level: usize, vpn: usize, start: usize,
+                  f: &mut dyn FnMut(usize, &mut Entry) -> bool) -> bool {
+        for i in 0..self.len() {
+            let vaddr = (vpn << 9 | i) << (9 * level + 12);
+            if vaddr + level_size(level) <= start {
+                continue;
+            }
+            let v = &mut self.entries[i];
+            if v.is_v() && !v.is_leaf() {
+                let table = unsafe { (v.paddr().0 as *mut Table).as_mut().unwrap() };
+                if !table._walk_user(level - 1, vpn << 9 | i, start, f) {
+                    return false;
+                }
+            } else if level == 0 && (v.is_swapped() || v.is_v() && v.is_u()) {
+                if !f(vaddr, v) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Size of memory mapped by a leaf entry at `level`
+pub const fn level_size(level: usize) -> usize {
+    PAGE_SIZE << (9 * level)
+}
+
+/// Free user page mapped by entry `v`
+fn free_user_page(v: &Entry) {
+    swap::forget(v.paddr().0);
+    let _pg = unsafe { Box::from_raw(v.paddr().0 as *mut Page) };
+    stat::sub(Kind::UserPage, PAGE_SIZE);
 }
 
 impl Drop for Table {
     fn drop(&mut self) {
+        // entries of page tables below are cleared by `drop_walk`, so
+        // this only walks a root table
         self.drop_walk(2);
+        stat::sub(Kind::PageTable, PAGE_SIZE);
     }
 }
 
-impl Clone for Box<Table> {
-    fn clone(&self) -> Self {
-        self.clone_walk(2)
-    }
-}
 
 /// Kernel page table
 pub static KERNEL_PGTABLE: Table = Table::new();
+
+/// A user page table tagged with an ASID
+///
+/// Page table is only changed through `AddressSpace`, which flushes changed
+/// mappings from TLB, on this hart and through `mem::tlb` on other harts
+/// that may have cached them.
+pub struct AddressSpace {
+    table: Box<Table>,
+    /// ASID tagged with generation, see `mem::asid`
+    asid: usize,
+    /// Bitmask of harts that may have cached translations with `asid`
+    harts: usize,
+    /// Bitmask of harts that may still run with an ASID given up earlier
+    stale: usize,
+    /// User pages accessed by threads in syscalls, which must not be swapped
+    /// out, one entry for each time it's pinned, see `mem::swap::pin`
+    pub pinned: Vec<usize>,
+    /// Killed by OOM killer, all threads should exit
+    pub killed: bool,
+    /// End of user heap, see `process::sbrk`
+    pub brk: usize,
+    /// Number of processes and threads using it which haven't exited
+    pub threads: usize,
+}
+
+impl AddressSpace {
+    pub fn new(table: Box<Table>) -> Self {
+        Self { table, asid: 0, harts: 0, stale: 0, pinned: Vec::new(), killed: false, brk: USER_HEAP, threads: 0 }
+    }
+
+    /// Get `satp` value to switch to this address space on current hart.
+    /// Should be called with interrupts off.
+    pub fn satp(&mut self) -> usize {
+        let old = self.asid;
+        let asid = asid::activate(&mut self.asid);
+        let me = 1 << hart_id();
+        if self.asid != old {
+            self.stale |= self.harts & !me;
+        }
+        self.stale &= !me;
+        self.harts |= me;
+        arch::build_satp(8, asid, &*self.table as *const Table as usize)
+    }
+
+    /// Copy of page table and all user pages in it, for a new address space
+    pub fn clone_table(&self) -> Result<Box<Table>, OutOfMemory> {
+        self.table.clone_walk(2)
+    }
+
+    /// Flush `vaddr`, or all translations if `None`, after they're changed,
+    /// on this hart and other harts that may have cached them
+    pub fn flush(&mut self, vaddr: Option<usize>) {
+        let asid = asid::asid_of(self.asid);
+        let others = self.harts & !self.stale & !(1 << hart_id());
+        if others != 0 {
+            tlb::shootdown(others, vaddr, asid);
+        }
+        if self.stale != 0 {
+            // we don't know which ASID they run with
+            tlb::shootdown(self.stale, None, 0);
+        }
+        if asid == 0 {
+            // never activated, or ASID isn't supported and TLB is flushed on every switch
+            return;
+        }
+        match vaddr {
+            Some(vaddr) => arch::sfence_vma_page(vaddr, asid),
+            None => arch::sfence_vma_asid(asid)
+        }
+    }
+
+    pub fn map(&mut self, vaddr: usize, pg: Box<Page>, flags: usize) {
+        self.try_map(vaddr, pg, flags).expect("out of memory");
+    }
+
+    /// Map user page `pg` at `vaddr`, see `Table::try_map`
+    pub fn try_map(&mut self, vaddr: usize, pg: Box<Page>, flags: usize) -> Result<(), OutOfMemory> {
+        self.table.try_map(vaddr, pg, flags)?;
+        self.flush(Some(vaddr));
+        Ok(())
+    }
+
+    /// Map kernel page, see `Table::try_kernel_map`
+    pub fn try_kernel_map(&mut self, vaddr: usize, paddr: usize, flags: usize) -> Result<(), OutOfMemory> {
+        self.table.try_kernel_map(vaddr, paddr, flags)?;
+        self.flush(Some(vaddr));
+        Ok(())
+    }
+
+    /// Call `f` with level 0 entry of `vaddr`, flushing it if changed.
+    /// Returns `None` if there's no such entry, see `Table::entry_mut`.
+    pub fn update_entry<R>(&mut self, vaddr: usize, f: impl FnOnce(&mut Entry) -> R) -> Option<R> {
+        let v = self.table.entry_mut(vaddr)?;
+        let old = v.0;
+        let result = f(v);
+        if v.0 != old {
+            self.flush(Some(vaddr));
+        }
+        Some(result)
+    }
+
+    /// Walk user pages like `Table::walk_user`, flushing all translations
+    /// if any entry is changed by `f`
+    pub fn walk_user(&mut self, start: usize, f: &mut dyn FnMut(usize, &mut Entry) -> bool) -> bool {
+        let mut changed = false;
+        let finished = self.table.walk_user(start, &mut |vaddr, v: &mut Entry| {
+            let old = v.0;
+            let go_on = f(vaddr, v);
+            changed |= v.0 != old;
+            go_on
+        });
+        if changed {
+            self.flush(None);
+        }
+        finished
+    }
+
+    /// Remove mapping at `vaddr`, freeing the page if it's a user page
+    pub fn unmap(&mut self, vaddr: usize) {
+        self.table.unmap(vaddr);
+        self.flush(Some(vaddr));
+    }
+
+    /// Remove all user mappings
+    pub fn unmap_user(&mut self) {
+        self.table.unmap_user();
+        self.flush(None);
+    }
+
+    /// Called when a thread using it exits. User pages are freed after the
+    /// last one exits, no matter which thread is the leader.
+    pub fn thread_exit(&mut self) {
+        self.threads -= 1;
+        if self.threads == 0 {
+            self.unmap_user();
+        }
+    }
+
+    /// Replace page table with that of `image`, a new image loaded in
+    /// `exec`, freeing the old one
+    pub fn replace(&mut self, image: AddressSpace) {
+        let _old = core::mem::replace(&mut self.table, image.table);
+        self.brk = USER_HEAP;
+        self.flush(None);
+    }
+}
+
+impl Deref for AddressSpace {
+    type Target = Table;
+
+    fn deref(&self) -> &Table {
+        &self.table
+    }
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("megapage", test_megapage),
+            ("unaligned", test_unaligned),
+        ]
+    }
+
+    /// Number of valid entries in `table` and page tables below it
+    fn count(table: &Table) -> (usize, usize) {
+        let (mut leaves, mut tables) = (0, 0);
+        for v in table.entries.iter() {
+            if v.is_v() {
+                if v.is_leaf() {
+                    leaves += 1;
+                } else {
+                    let (l, t) = count(unsafe { &*(v.paddr().0 as *const Table) });
+                    leaves += l;
+                    tables += t + 1;
+                }
+            }
+        }
+        (leaves, tables)
+    }
+
+    pub fn test_megapage() {
+        let mut table = Table::alloc();
+        // one gigapage and two megapages
+        table.id_map_range(0x4000_0000, 0x8040_0000, EntryAttributes::RW as usize);
+        assert_eq!(count(&table), (3, 1));
+        assert_eq!(table.paddr_of(0x4000_0000), Some(0x4000_0000));
+        assert_eq!(table.paddr_of(0x7fff_f000), Some(0x7fff_f000));
+        assert_eq!(table.paddr_of(0x8023_4000), Some(0x8023_4000));
+        assert_eq!(table.paddr_of(0x8040_0000), None);
+        table.unmap(0x8020_0000);
+        assert_eq!(table.paddr_of(0x8023_4000), None);
+        assert_eq!(table.paddr_of(0x8000_0000), Some(0x8000_0000));
+    }
+
+    pub fn test_unaligned() {
+        let mut table = Table::alloc();
+        // 4K pages up to 2M boundary, a megapage, and 4K pages after it
+        table.id_map_range(0x801f_e000, 0x8040_2000, EntryAttributes::RW as usize);
+        assert_eq!(count(&table), (2 + 1 + 2, 3));
+        assert_eq!(table.paddr_of(0x801f_f000), Some(0x801f_f000));
+        assert_eq!(table.paddr_of(0x8030_0000), Some(0x8030_0000));
+        assert_eq!(table.paddr_of(0x8040_1000), Some(0x8040_1000));
+        assert_eq!(table.paddr_of(0x8040_2000), None);
+    }
+}
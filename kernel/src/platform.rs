@@ -0,0 +1,167 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Platform information discovered from device tree
+//!
+//! Boot hart parses the device tree blob passed by firmware in `kinit`,
+//! before any driver is initialized. If there's no valid blob, layout of
+//! QEMU `virt` machine with 128 MiB of RAM is assumed.
+
+use crate::fdt::Fdt;
+
+/// Maximum number of virtio-mmio slots recorded
+pub const MAX_VIRTIO: usize = 8;
+
+/// Maximum depth of device tree nodes being parsed
+const MAX_DEPTH: usize = 8;
+
+/// A memory-mapped device
+#[derive(Clone, Copy, Debug)]
+pub struct MmioDevice {
+    pub base: usize,
+    pub size: usize,
+    /// Interrupt number on PLIC, 0 if there's none
+    pub irq: u32,
+}
+
+impl MmioDevice {
+    const fn new(base: usize, size: usize, irq: u32) -> Self {
+        Self { base, size, irq }
+    }
+}
+
+/// Information of the machine
+pub struct Platform {
+    /// Address of device tree blob, 0 if none is found
+    pub dtb: usize,
+    /// Start address of RAM
+    pub memory_start: usize,
+    /// End address of RAM
+    pub memory_end: usize,
+    /// Number of harts in `/cpus`
+    pub ncpus: usize,
+    /// Frequency of `mtime` in Hz
+    pub timebase: usize,
+    /// UART, with base 0 if there's none
+    pub uart: MmioDevice,
+    pub plic: MmioDevice,
+    pub clint: MmioDevice,
+    /// virtio-mmio slots, sorted by address
+    pub virtio: [MmioDevice; MAX_VIRTIO],
+    pub nvirtio: usize,
+}
+
+impl Platform {
+    /// Layout of QEMU `virt` machine
+    const fn qemu_virt() -> Self {
+        Self {
+            dtb: 0,
+            memory_start: 0x8000_0000,
+            memory_end: 0x8000_0000 + 128 * 1024 * 1024,
+            ncpus: 1,
+            timebase: 10_000_000,
+            uart: MmioDevice::new(0x1000_0000, 0x100, 10),
+            plic: MmioDevice::new(0x0c00_0000, 0x400000, 0),
+            clint: MmioDevice::new(0x200_0000, 0x10000, 0),
+            virtio: [
+                MmioDevice::new(0x1000_1000, 0x1000, 1),
+                MmioDevice::new(0x1000_2000, 0x1000, 2),
+                MmioDevice::new(0x1000_3000, 0x1000, 3),
+                MmioDevice::new(0x1000_4000, 0x1000, 4),
+                MmioDevice::new(0x1000_5000, 0x1000, 5),
+                MmioDevice::new(0x1000_6000, 0x1000, 6),
+                MmioDevice::new(0x1000_7000, 0x1000, 7),
+                MmioDevice::new(0x1000_8000, 0x1000, 8),
+            ],
+            nvirtio: MAX_VIRTIO,
+        }
+    }
+
+    /// Fill in platform information from device tree
+    fn parse(&mut self, fdt: &Fdt) {
+        // (#address-cells, #size-cells) of nodes on current path
+        let mut cells = [(2, 1); MAX_DEPTH];
+        let mut ncpus = 0;
+        let mut nvirtio = 0;
+        let mut uart = None;
+        for node in fdt.nodes() {
+            if node.depth >= MAX_DEPTH {
+                continue;
+            }
+            let (ac, sc) = if node.depth == 0 { (2, 1) } else { cells[node.depth - 1] };
+            cells[node.depth] = (
+                node.prop_u32("#address-cells").unwrap_or(2) as usize,
+                node.prop_u32("#size-cells").unwrap_or(1) as usize,
+            );
+            let device = || -> Option<MmioDevice> {
+                let (base, size) = node.reg(ac, sc)?;
+                Some(MmioDevice::new(base, size, node.prop_u32("interrupts").unwrap_or(0)))
+            };
+            let device_type = node.prop_str("device_type");
+            if device_type == Some("memory") {
+                if let Some((base, size)) = node.reg(ac, sc) {
+                    self.memory_start = base;
+                    self.memory_end = base + size;
+                }
+            } else if node.depth == 1 && node.name == "cpus" {
+                if let Some(freq) = node.prop_u32("timebase-frequency") {
+                    self.timebase = freq as usize;
+                }
+            } else if device_type == Some("cpu") {
+                if node.prop_str("status") != Some("disabled") {
+                    ncpus += 1;
+                }
+            } else if node.is_compatible("ns16550a") {
+                uart = uart.or_else(device);
+            } else if node.is_compatible("riscv,plic0") || node.is_compatible("sifive,plic-1.0.0") {
+                if let Some(dev) = device() {
+                    self.plic = dev;
+                }
+            } else if node.is_compatible("riscv,clint0") || node.is_compatible("sifive,clint0") {
+                if let Some(dev) = device() {
+                    self.clint = dev;
+                }
+            } else if node.is_compatible("virtio,mmio") && nvirtio < MAX_VIRTIO {
+                if let Some(dev) = device() {
+                    self.virtio[nvirtio] = dev;
+                    nvirtio += 1;
+                }
+            }
+        }
+        // without UART, console falls back to firmware
+        self.uart = uart.unwrap_or(MmioDevice::new(0, 0, 0));
+        if ncpus != 0 {
+            self.ncpus = ncpus;
+        }
+        if nvirtio != 0 {
+            self.nvirtio = nvirtio;
+            self.virtio[..nvirtio].sort_unstable_by_key(|dev| dev.base);
+        }
+    }
+
+    /// virtio-mmio slots found
+    pub fn virtio(&self) -> &[MmioDevice] {
+        &self.virtio[..self.nvirtio]
+    }
+}
+
+static mut PLATFORM: Platform = Platform::qemu_virt();
+
+/// Get platform information
+pub fn platform() -> &'static Platform {
+    unsafe { &PLATFORM }
+}
+
+/// Parse device tree blob at `dtb`, keeping defaults if it's invalid.
+///
+/// This function should only be called in boot hart before other harts
+/// read platform information. The blob may be overwritten after this
+/// function returns, as it's not reserved from frame allocator.
+pub unsafe fn init(dtb: usize) {
+    if let Some(fdt) = Fdt::from_ptr(dtb) {
+        PLATFORM.parse(&fdt);
+        PLATFORM.dtb = dtb;
+    }
+}
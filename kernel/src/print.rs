@@ -13,16 +13,17 @@ pub static INFO_LOCK: Mutex<()> = Mutex::new((), "info");
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
 	use core::fmt::Write;
-	let mut uart = crate::uart::UART().lock();
-	uart.write_fmt(args).unwrap();
+	crate::uart::Printer.write_fmt(args).unwrap();
 }
 
 #[doc(hidden)]
 pub fn _panic_print(args: fmt::Arguments) {
     use core::fmt::Write;
     use crate::uart::*;
-	let mut uart = Uart::new(UART_BASE_ADDR);
-	uart.write_fmt(args).unwrap();
+	// characters already buffered come first, and the lock may be held
+	unsafe { UART().get().flush(); }
+	let mut uart = Uart::new(UART_BASE_ADDR());
+	SyncWriter(&mut uart).write_fmt(args).unwrap();
 }
 
 /// Print information
@@ -12,7 +12,7 @@ mod cpu;
 
 pub use cpu::*;
 
-mod process;
+pub mod process;
 
 pub use process::*;
 
@@ -20,10 +20,20 @@ mod context;
 
 pub use context::*;
 
-mod schedule;
+pub mod schedule;
 
 pub use schedule::*;
 
+pub mod futex;
+
+pub use futex::*;
+
+pub mod kstack;
+
+pub use kstack::*;
+
+pub mod signal;
+
 use crate::symbols::*;
 use crate::spinlock::Mutex;
 use crate::arch;
@@ -44,11 +54,14 @@ static mut CPUS: [CPU; NCPUS] = [CPU::zero(); NCPUS];
 /// `Pooling`: This process is not being scheduled
 ///
 /// `BeingSlept`: This process holds a sleep lock and is to be put back
+///
+/// `Reserved`: This pid is taken by a process being created
 pub enum ProcInPool {
     NoProc,
     Scheduled,
     Pooling(Box<Process>),
     BeingSlept,
+    Reserved,
 }
 
 /// An array holding all process information.
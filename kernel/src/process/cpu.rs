@@ -9,6 +9,7 @@ use core::mem::MaybeUninit;
 use core::cell::UnsafeCell;
 use crate::{arch, panic};
 use crate::arch::hart_id;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 /// Holding CPU information
 #[repr(C)]
@@ -111,3 +112,16 @@ impl<'a> Drop for IntrLockGuard<'_> {
 unsafe impl Sync for IntrLock {}
 
 unsafe impl Send for IntrLock {}
+
+/// Bitmask of harts which have entered scheduler
+static ONLINE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Mark current hart as online
+pub fn mark_online() {
+    ONLINE_HARTS.fetch_or(1 << arch::hart_id(), Ordering::SeqCst);
+}
+
+/// Get bitmask of online harts
+pub fn online_harts() -> usize {
+    ONLINE_HARTS.load(Ordering::SeqCst)
+}
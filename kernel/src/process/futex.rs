@@ -0,0 +1,168 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Fast user-space mutex
+//!
+//! A futex is a 32-bit word in user memory. `FUTEX_WAIT` sleeps on the word
+//! if it still holds the expected value, and `FUTEX_WAKE` wakes processes
+//! sleeping on it. The sleep channel is the physical address of the word,
+//! so threads sharing a page table agree on it.
+//!
+//! This relies on the word staying in the same frame while threads wait on
+//! it. Its page is pinned until the syscall returns (see `mem::swap::pin`),
+//! so it's neither swapped out nor freed by the OOM killer under a sleeping
+//! waiter.
+
+use super::{my_proc, sleep, wakeup_n};
+use crate::spinlock::Mutex;
+use crate::mem::page_down;
+
+pub const FUTEX_WAIT: i32 = 0;
+pub const FUTEX_WAKE: i32 = 1;
+
+/// Lock held while checking futex value, so that no wakeup is lost
+/// between checking the value and going to sleep.
+static FUTEX_LOCK: Mutex<()> = Mutex::new((), "futex");
+
+/// Translate user address of a futex word into physical address
+fn futex_paddr(addr: usize) -> Option<usize> {
+    if addr % core::mem::size_of::<u32>() != 0 {
+        return None;
+    }
+    let p = my_proc();
+    let pg_begin = page_down(addr);
+    let paddr = crate::mem::swap::pin(&p.pgtable, pg_begin)?;
+    Some(paddr + addr - pg_begin)
+}
+
+/// futex syscall
+///
+/// `FUTEX_WAIT`: sleep if word at `addr` equals `val`. Returns 0 when woken up,
+/// or -1 if the value differs.
+///
+/// `FUTEX_WAKE`: wake up at most `val` processes waiting on `addr`. Returns
+/// number of processes woken up.
+pub fn futex(addr: usize, op: i32, val: u32) -> i32 {
+    let paddr = match futex_paddr(addr) {
+        Some(paddr) => paddr,
+        None => return -1
+    };
+    let word = paddr as *const u32;
+    match op {
+        FUTEX_WAIT => {
+            let lock = FUTEX_LOCK.lock();
+            if unsafe { core::ptr::read_volatile(word) } != val {
+                return -1;
+            }
+            sleep(word, lock);
+            0
+        }
+        FUTEX_WAKE => {
+            let _lock = FUTEX_LOCK.lock();
+            wakeup_n(word, val as usize) as i32
+        }
+        _ => -1
+    }
+}
+
+pub mod tests {
+    use super::*;
+    use crate::page::{Page, EntryAttributes};
+    use crate::process::{clone, join, PROCS_POOL, ProcInPool, ProcessState, sleep_timeout};
+    use crate::syscall::{SYS_EXIT, SYS_FUTEX};
+    use core::time::Duration;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("wait mismatch", test_wait_mismatch),
+            ("wake no waiters", test_wake_no_waiters),
+            ("wake count", test_wake_count),
+        ]
+    }
+
+    /// User page holding futex word at its beginning, followed by code of
+    /// waiting threads
+    const PAGE: usize = 0x7000_1000;
+    const WORD: usize = PAGE;
+    const WAITER: usize = PAGE + 0x100;
+
+    /// `addi rd, rs1, imm`
+    const fn addi(rd: u32, rs1: u32, imm: u32) -> u32 {
+        imm << 20 | rs1 << 15 | rd << 7 | 0x13
+    }
+
+    const ECALL: u32 = 0x73;
+
+    /// Map test page in current process, with word set to `val`. Code at
+    /// `WAITER` calls `futex(a0, FUTEX_WAIT, a1)` and exits with its result.
+    fn map_page(val: u32) {
+        let mut page = Page::new();
+        page.data[0..4].copy_from_slice(&val.to_le_bytes());
+        let (a1, a2, a7) = (11, 12, 17);
+        let insts = [
+            addi(a2, a1, 0),
+            addi(a1, 0, FUTEX_WAIT as u32),
+            addi(a7, 0, SYS_FUTEX as u32),
+            ECALL,
+            addi(a7, 0, SYS_EXIT as u32),
+            ECALL,
+        ];
+        for (i, inst) in insts.iter().enumerate() {
+            let off = WAITER - PAGE + i * 4;
+            page.data[off..off + 4].copy_from_slice(&inst.to_le_bytes());
+        }
+        my_proc().pgtable.lock().map(PAGE, page, EntryAttributes::URW as usize | EntryAttributes::URX as usize);
+    }
+
+    fn unmap_page() {
+        my_proc().pgtable.lock().unmap(PAGE);
+    }
+
+    /// Number of processes sleeping on futex word at physical address `paddr`
+    fn waiters(paddr: usize) -> usize {
+        PROCS_POOL.lock().iter().filter(|p| match p {
+            ProcInPool::Pooling(p) => p.state == ProcessState::SLEEPING && p.channel == paddr,
+            _ => false
+        }).count()
+    }
+
+    /// Test waiting on a word not holding expected value returns immediately
+    pub fn test_wait_mismatch() {
+        map_page(1);
+        assert_eq!(futex(WORD, FUTEX_WAIT, 0), -1);
+        // unaligned word
+        assert_eq!(futex(WORD + 1, FUTEX_WAIT, 1), -1);
+        unmap_page();
+    }
+
+    pub fn test_wake_no_waiters() {
+        map_page(0);
+        assert_eq!(futex(WORD, FUTEX_WAKE, 1), 0);
+        assert_eq!(futex(WORD, FUTEX_WAKE, u32::MAX), 0);
+        unmap_page();
+    }
+
+    /// Lock to sleep on while waiting for threads to sleep on futex
+    static TICK: Mutex<()> = Mutex::new((), "futex test");
+
+    /// Test wake returns at most `val` waiters woken up
+    pub fn test_wake_count() {
+        map_page(0);
+        let paddr = futex_paddr(WORD).unwrap();
+        let tids = [clone(WAITER, WORD, 0), clone(WAITER, WORD, 0), clone(WAITER, WORD, 0)];
+        assert!(tids.iter().all(|&tid| tid > 0));
+        let mut tick = TICK.lock();
+        while waiters(paddr) < tids.len() {
+            tick = sleep_timeout(&TICK, tick, Duration::from_millis(10));
+        }
+        drop(tick);
+        assert_eq!(futex(WORD, FUTEX_WAKE, 2), 2);
+        assert_eq!(futex(WORD, FUTEX_WAKE, 2), 1);
+        for &tid in &tids {
+            assert_eq!(join(tid), Some(0));
+        }
+        unmap_page();
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Kernel stacks of processes
+//!
+//! Kernel stack of process `pid` lives in slot `pid` of a dedicated virtual
+//! region in kernel page table. Only the top `KSTACK_PAGES` pages of a slot
+//! are mapped, and the rest of it is left unmapped as guard, so that stack
+//! overflow traps instead of corrupting memory.
+//!
+//! Frames of a slot stay mapped after the process exits, and are reused by
+//! the next process with that pid. Therefore a translation cached by any hart
+//! never goes stale.
+
+use crate::symbols::{PAGE_SIZE, NMAXPROCS};
+use crate::spinlock::Mutex;
+use crate::page::{Page, Table, EntryAttributes, KERNEL_PGTABLE};
+use crate::mem::stat::{self, Kind};
+use crate::mem::OutOfMemory;
+use alloc::boxed::Box;
+
+/// Start of kernel stack region. `kernelvec.S` checks for overflow by
+/// `sp >> 32 == 0x3f`.
+pub const KSTACK_START: usize = 0x3f_0000_0000;
+
+/// Virtual size of each slot, including guard
+pub const KSTACK_SLOT_SIZE: usize = 0x10000;
+
+/// Number of mapped pages in a slot. `kernelvec.S` takes the other
+/// `16 - KSTACK_PAGES` pages as guard.
+pub const KSTACK_PAGES: usize = 8;
+
+/// Usable size of kernel stack
+pub const KSTACK_SIZE: usize = KSTACK_PAGES * PAGE_SIZE;
+
+/// Bottom of kernel stack of process `pid`
+#[allow(non_snake_case)]
+pub const fn KSTACK(pid: usize) -> usize {
+    KSTACK_START + (pid + 1) * KSTACK_SLOT_SIZE - KSTACK_SIZE
+}
+
+/// Whether slot of each pid is mapped
+static MAPPED: Mutex<[bool; NMAXPROCS]> = Mutex::new([false; NMAXPROCS], "kstack");
+
+/// Map kernel stack of `pid` if not yet mapped, and returns its bottom.
+/// If it runs out of memory, pages mapped so far are kept for next try.
+pub fn alloc_kstack(pid: usize) -> Result<usize, OutOfMemory> {
+    let mut mapped = MAPPED.lock();
+    if !mapped[pid] {
+        let pgtable = unsafe { &mut *(&KERNEL_PGTABLE as *const _ as *mut Table) };
+        for i in 0..KSTACK_PAGES {
+            let vaddr = KSTACK(pid) + i * PAGE_SIZE;
+            if pgtable.paddr_of(vaddr).is_some() {
+                continue;
+            }
+            let page = Box::into_raw(Page::try_new()?) as usize;
+            if let Err(err) = pgtable.try_kernel_map(vaddr, page, EntryAttributes::RW as usize) {
+                drop(unsafe { Box::from_raw(page as *mut Page) });
+                return Err(err);
+            }
+            stat::add(Kind::KernelStack, PAGE_SIZE);
+        }
+        crate::arch::sfence_vma_all();
+        mapped[pid] = true;
+    }
+    Ok(KSTACK(pid))
+}
+
+/// Pid whose kernel stack slot contains `addr`
+pub fn kstack_owner(addr: usize) -> Option<usize> {
+    if addr < KSTACK_START || addr >= KSTACK_START + NMAXPROCS * KSTACK_SLOT_SIZE {
+        return None;
+    }
+    Some((addr - KSTACK_START) / KSTACK_SLOT_SIZE)
+}
+
+/// Whether `addr` is in guard pages of a kernel stack
+pub fn is_kstack_guard(addr: usize) -> bool {
+    match kstack_owner(addr) {
+        Some(pid) => addr < KSTACK(pid),
+        None => false
+    }
+}
+
+pub mod tests {
+    use super::*;
+    use crate::process::{is_user_stack_guard, USER_STACK, THREAD_STACK, USER_STACK_PAGE};
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("kernel stack guard", test_kstack_guard),
+            ("user stack guard", test_user_stack_guard),
+        ]
+    }
+
+    pub fn test_kstack_guard() {
+        assert_eq!(KSTACK(0) % PAGE_SIZE, 0);
+        assert!(!is_kstack_guard(KSTACK(3)));
+        assert!(!is_kstack_guard(KSTACK(3) + KSTACK_SIZE - 8));
+        assert!(is_kstack_guard(KSTACK(3) - 8));
+        assert!(is_kstack_guard(KSTACK_START + 3 * KSTACK_SLOT_SIZE));
+        assert_eq!(kstack_owner(KSTACK(3) - 8), Some(3));
+        assert_eq!(kstack_owner(KSTACK(3) + KSTACK_SIZE), Some(4));
+        assert!(!is_kstack_guard(KSTACK_START - 8));
+    }
+
+    pub fn test_user_stack_guard() {
+        assert!(is_user_stack_guard(USER_STACK - 8));
+        assert!(!is_user_stack_guard(USER_STACK));
+        assert!(is_user_stack_guard(THREAD_STACK(0) - 8));
+        assert!(is_user_stack_guard(THREAD_STACK(5) - 8));
+        assert!(!is_user_stack_guard(THREAD_STACK(5)));
+        assert!(!is_user_stack_guard(THREAD_STACK(5) + USER_STACK_PAGE * PAGE_SIZE - 8));
+    }
+}
@@ -6,18 +6,24 @@
 use super::{TrapFrame, Context, Register, ContextRegisters};
 use crate::{page, panic, info, warn};
 use crate::symbols::*;
-use crate::mem;
+use crate::mem::{self, OutOfMemory, oom};
+use crate::mem::stat::{self, Kind};
 use crate::arch;
 use crate::println;
 use crate::trap::usertrapret;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use crate::process::{put_back_proc, my_proc, PROCS_POOL, my_cpu, sched, ProcInPool};
-use crate::page::{Page, Table, EntryAttributes};
+use crate::page::{Page, Table, EntryAttributes, AddressSpace};
 use crate::process::Register::a0;
 use crate::jump::*;
 use crate::spinlock::{Mutex, MutexGuard};
 use alloc::sync::Arc;
-use crate::file::{File, FsFile};
+use crate::file::{FdTable, FsFile};
+use super::{AFFINITY_ALL, init_affinity, affinity_of, alloc_kstack, KSTACK_SIZE, signal};
+use crate::syscall::ENOMEM;
+use core::time::Duration;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(PartialEq)]
 #[derive(Debug)]
@@ -29,32 +35,70 @@ pub enum ProcessState {
     ZOMBIE,
 }
 
+/// Page table shared by all threads of a process
+pub type PageTable = Arc<Mutex<AddressSpace>>;
+
+/// File descriptors shared by all threads of a process
+pub type FileTable = Arc<Mutex<FdTable>>;
+
 #[repr(C)]
 #[repr(align(4096))]
 pub struct Process {
-    pub pgtable: Box<page::Table>,
+    pub pgtable: PageTable,
     pub trapframe: Box<TrapFrame>,
     pub context: Box<Context>,
     pub state: ProcessState,
+    /// Bottom of kernel stack
     pub kstack: usize,
+    /// Top of kernel stack
     pub kstack_sp: usize,
     pub pid: i32,
     pub channel: usize,
     pub drop_on_put_back: Option<MutexGuard<'static, ()>>,
-    pub files: [Option<Arc<File>>; 256],
+    pub files: FileTable,
+    /// Bottom of user stack if this is a thread created by `clone`
+    pub thread_stack: Option<usize>,
+    /// Exit code, valid in `ZOMBIE` state
+    pub exit_code: i32,
+    /// User pages pinned by this process in current syscall, see
+    /// `mem::swap::pin`
+    pub pinned: Vec<usize>,
+    /// Time to be woken up if sleeping with a timeout
+    pub wake_at: Option<Duration>,
 }
 
 impl Process {
     pub fn new(pid: i32) -> Self {
-        Self::from_exist(pid, box page::Table::new(), box TrapFrame::zero())
+        Self::from_exist(pid, page::Table::alloc(), box TrapFrame::zero())
     }
 
     pub fn from_exist(pid: i32, pgtable: Box<Table>, trapframe: Box<TrapFrame>) -> Self {
+        Self::try_from_exist(pid, pgtable, trapframe).expect("out of memory")
+    }
+
+    /// Same as `from_exist`, but fails if kernel stack or page tables can't be allocated
+    pub fn try_from_exist(pid: i32, pgtable: Box<Table>, trapframe: Box<TrapFrame>) -> Result<Self, OutOfMemory> {
+        Self::try_from_shared(
+            pid,
+            Arc::new(Mutex::new(AddressSpace::new(pgtable), "page table")),
+            Arc::new(Mutex::new(FdTable::new(), "file table")),
+            trapframe,
+        )
+    }
+
+    /// Create a process with page table and files shared with other processes
+    pub fn from_shared(pid: i32, pgtable: PageTable, files: FileTable, trapframe: Box<TrapFrame>) -> Self {
+        Self::try_from_shared(pid, pgtable, files, trapframe).expect("out of memory")
+    }
+
+    /// Same as `from_shared`, but fails if kernel stack or page tables can't be allocated
+    pub fn try_from_shared(pid: i32, pgtable: PageTable, files: FileTable, trapframe: Box<TrapFrame>) -> Result<Self, OutOfMemory> {
         if pid < 0 {
             panic!("invalid pid");
         }
 
-        let kstack = mem::ALLOC().lock().allocate(PAGE_SIZE * 1024) as usize;
+        let kstack = alloc_kstack(pid as usize)?;
+        init_affinity(pid, AFFINITY_ALL);
 
         let mut p = Self {
             trapframe,
@@ -62,37 +106,56 @@ impl Process {
             context: box Context::zero(),
             state: ProcessState::UNUSED,
             kstack: kstack,
-            kstack_sp: kstack + PAGE_SIZE * 1024,
+            kstack_sp: kstack + KSTACK_SIZE,
             pid,
             channel: 0,
             drop_on_put_back: None,
-            files: [None; 256],
+            files,
+            thread_stack: None,
+            exit_code: 0,
+            pinned: Vec::new(),
+            wake_at: None,
         };
 
-        // map trampoline
-        p.pgtable.kernel_map(
-            TRAMPOLINE_START,
-            TRAMPOLINE_TEXT_START(),
-            page::EntryAttributes::RX as usize,
-        );
-
-        let trapframe = &*p.trapframe as *const _ as usize;
-        // map trapframe
-        p.pgtable.kernel_map(
-            TRAPFRAME_START,
-            trapframe,
-            page::EntryAttributes::RW as usize,
-        );
+        {
+            let mut space = p.pgtable.lock();
+            map_kernel(&mut space, pid, &p.trapframe)?;
+            space.threads += 1;
+        }
         p.context.regs[ContextRegisters::ra as usize] = forkret as usize;
-        p.context.regs[ContextRegisters::sp as usize] = p.kstack + PAGE_SIZE;
+        p.context.regs[ContextRegisters::sp as usize] = p.kstack_sp;
 
-        p
+        Ok(p)
     }
 }
 
+/// Map trampoline and trapframe of process `pid` in `pgtable`
+fn map_kernel(pgtable: &mut AddressSpace, pid: i32, trapframe: &TrapFrame) -> Result<(), OutOfMemory> {
+    // map trampoline
+    pgtable.try_kernel_map(
+        TRAMPOLINE_START,
+        TRAMPOLINE_TEXT_START(),
+        page::EntryAttributes::RX as usize,
+    )?;
+    // map trapframe
+    pgtable.try_kernel_map(
+        TRAPFRAME(pid as usize),
+        trapframe as *const _ as usize,
+        page::EntryAttributes::RW as usize,
+    )
+}
+
 impl Drop for Process {
     fn drop(&mut self) {
-        let _kstack = unsafe { Box::from_raw(self.kstack as *mut Page) };
+        // kernel stack is kept mapped for next process with this pid
+        // page table may still be used by other threads
+        let mut pgtable = self.pgtable.lock();
+        pgtable.unmap(TRAPFRAME(self.pid as usize));
+        if let Some(stack) = self.thread_stack {
+            for i in 0..USER_STACK_PAGE {
+                pgtable.unmap(stack + i * PAGE_SIZE);
+            }
+        }
     }
 }
 
@@ -119,69 +182,340 @@ pub fn init_proc() {
     let content = init_code();
     let mut page = Page::new();
     page.data[0..content.len()].copy_from_slice(content);
-    p.pgtable.map(0, page, EntryAttributes::URX as usize);
+    p.pgtable.lock().map(0, page, EntryAttributes::URX as usize);
     // map user stack
-    let sp = map_stack(&mut p.pgtable, 0x80001000);
+    let sp = map_stack(&mut p.pgtable.lock(), USER_STACK).expect("out of memory");
     p.trapframe.epc = 0;
     p.trapframe.regs[Register::sp as usize] = sp;
     p.state = ProcessState::RUNNABLE;
+    signal::reset(0, 0);
     put_back_proc(box p);
 }
 
-pub fn find_available_pid() -> Option<i32> {
-    let pool = PROCS_POOL.lock();
-    for i in 0..NMAXPROCS {
-        match &pool[i] {
-            ProcInPool::NoProc => return Some(i as i32),
-            _ => {}
-        }
+/// Take a free pid, which is kept from others until the new process is put
+/// back, or the pid is given back with `free_pid`
+pub fn alloc_pid() -> Option<i32> {
+    let mut pool = PROCS_POOL.lock();
+    let pid = (0..NMAXPROCS).find(|&i| match pool[i] {
+        ProcInPool::NoProc => true,
+        _ => false
+    })?;
+    pool[pid] = ProcInPool::Reserved;
+    Some(pid as i32)
+}
+
+/// Give back pid taken by `alloc_pid` if process can't be created
+pub fn free_pid(pid: i32) {
+    let mut pool = PROCS_POOL.lock();
+    let p_in_pool = &mut pool[pid as usize];
+    match p_in_pool {
+        ProcInPool::Reserved => *p_in_pool = ProcInPool::NoProc,
+        _ => panic!("pid {} not reserved", pid)
     }
-    None
 }
 
 pub fn fork() -> i32 {
     let p = my_proc();
-    let f_pid = find_available_pid();
-    if f_pid.is_none() {
-        panic!("pid unavailable");
+    let pages = oom::resident(&mut p.pgtable.lock());
+    // may sleep, so pid is taken after it
+    if !oom::reserve(pages + KSTACK_SIZE / PAGE_SIZE) {
+        return ENOMEM;
     }
-    let f_pid = f_pid.unwrap();
-    let pgtable = p.pgtable.clone();
+    let f_pid = match alloc_pid() {
+        Some(f_pid) => f_pid,
+        None => return -1
+    };
+    let (pgtable, brk) = {
+        let space = p.pgtable.lock();
+        match space.clone_table() {
+            Ok(pgtable) => (pgtable, space.brk),
+            Err(_) => {
+                free_pid(f_pid);
+                return ENOMEM;
+            }
+        }
+    };
     let trapframe = box *p.trapframe.clone();
-    let mut fork_p = Process::from_exist(f_pid, pgtable, trapframe);
-    for i in 0..fork_p.files.len() {
-        fork_p.files[i] = match &p.files[i] {
-            Some(x) => Some(x.clone()),
-            None => None
+    let mut fork_p = match Process::try_from_exist(f_pid, pgtable, trapframe) {
+        Ok(fork_p) => fork_p,
+        Err(_) => {
+            free_pid(f_pid);
+            return ENOMEM;
         }
-    }
+    };
+    fork_p.pgtable.lock().brk = brk;
+    *fork_p.files.lock() = p.files.lock().clone();
+    init_affinity(f_pid, affinity_of(p.pid));
     fork_p.trapframe.regs[a0 as usize] = 0;
     fork_p.state = ProcessState::RUNNABLE;
+    signal::reset(f_pid, signal::pgid(p.pid));
     put_back_proc(box fork_p);
     f_pid
 }
 
+/// Create a thread sharing page table and files with current process.
+///
+/// The thread begins at `entry` with `a0 = arg0` and `a1 = arg1`,
+/// on a new user stack mapped at `THREAD_STACK(tid)`.
+/// Returns thread id, which is a pid in `PROCS_POOL`, or `ENOMEM`.
+pub fn clone(entry: usize, arg0: usize, arg1: usize) -> i32 {
+    let p = my_proc();
+    // may sleep, so tid is taken after it
+    if !oom::reserve(USER_STACK_PAGE + KSTACK_SIZE / PAGE_SIZE) {
+        return ENOMEM;
+    }
+    let tid = match alloc_pid() {
+        Some(tid) => tid,
+        None => return -1
+    };
+    let stack = THREAD_STACK(tid as usize);
+    let sp = match map_stack(&mut p.pgtable.lock(), stack) {
+        Ok(sp) => sp,
+        Err(_) => {
+            free_pid(tid);
+            return ENOMEM;
+        }
+    };
+    let trapframe = box *p.trapframe.clone();
+    let mut t = match Process::try_from_shared(tid, p.pgtable.clone(), p.files.clone(), trapframe) {
+        Ok(t) => t,
+        Err(_) => {
+            unmap_stack(&mut p.pgtable.lock(), stack, USER_STACK_PAGE);
+            free_pid(tid);
+            return ENOMEM;
+        }
+    };
+    t.thread_stack = Some(stack);
+    init_affinity(tid, affinity_of(p.pid));
+    t.trapframe.epc = entry;
+    t.trapframe.regs[Register::sp as usize] = sp;
+    t.trapframe.regs[Register::a0 as usize] = arg0;
+    t.trapframe.regs[Register::a1 as usize] = arg1;
+    t.state = ProcessState::RUNNABLE;
+    signal::reset(tid, signal::pgid(p.pid));
+    put_back_proc(box t);
+    tid
+}
+
+/// Lock to be held while looking for zombie threads, so that `join` won't miss wakeups
+pub static JOIN_LOCK: Mutex<()> = Mutex::new((), "join");
+
+/// Wake up processes waiting in `join`. Called after a zombie is put back.
+pub fn wakeup_joiners() {
+    let _join = JOIN_LOCK.lock();
+    wakeup(&JOIN_LOCK);
+}
+
+/// Wait for thread `tid` sharing page table with current process to exit,
+/// and release its resources. Threads which are not joined before the
+/// leader exits are released by `reap`.
+///
+/// Returns exit code of the thread, or `None` if there's no such thread.
+pub fn join(tid: i32) -> Option<i32> {
+    let p = my_proc();
+    if tid < 0 || tid as usize >= NMAXPROCS || tid == p.pid {
+        return None;
+    }
+    let mut join = JOIN_LOCK.lock();
+    loop {
+        let mut pool = PROCS_POOL.lock();
+        let zombie = match &pool[tid as usize] {
+            ProcInPool::Pooling(t) => {
+                if !Arc::ptr_eq(&t.pgtable, &p.pgtable) {
+                    return None;
+                }
+                t.state == ProcessState::ZOMBIE
+            }
+            ProcInPool::NoProc => return None,
+            _ => false
+        };
+        if zombie {
+            let t = core::mem::replace(&mut pool[tid as usize], ProcInPool::NoProc);
+            drop(pool);
+            return match t {
+                ProcInPool::Pooling(t) => Some(t.exit_code),
+                _ => unreachable!()
+            };
+        }
+        drop(pool);
+        join = sleep(&JOIN_LOCK, join);
+    }
+}
+
+/// Release zombies sharing `pgtable`, as nobody waits for processes. Called
+/// after a zombie is put back.
+///
+/// Zombie threads are released once their leader, the process without a
+/// thread stack, has exited, as nobody is to join them. The leader is
+/// released with them after all threads have exited, along with page table
+/// and trapframe. Kernel stack of its pid is kept for next process.
+pub fn reap(pgtable: &PageTable) {
+    let in_group = |p: &Process| Arc::ptr_eq(&p.pgtable, pgtable);
+    // no thread is left to clone new ones, so it never goes up again
+    let all_exited = pgtable.lock().threads == 0;
+    let mut reaped = Vec::new();
+    {
+        let mut pool = PROCS_POOL.lock();
+        let leader_exited = pool.iter().any(|p| match p {
+            ProcInPool::Pooling(p) => in_group(p) && p.thread_stack.is_none() && p.state == ProcessState::ZOMBIE,
+            _ => false
+        });
+        if !leader_exited {
+            return;
+        }
+        for in_pool in pool.iter_mut() {
+            let reap = match in_pool {
+                ProcInPool::Pooling(t) => in_group(t) && (t.thread_stack.is_some() || all_exited) && t.state == ProcessState::ZOMBIE,
+                _ => false
+            };
+            if reap {
+                reaped.push(core::mem::replace(in_pool, ProcInPool::NoProc));
+            }
+        }
+    }
+    // dropping a process locks its page table, so do it after releasing the pool
+    drop(reaped);
+}
+
 pub const USER_STACK_PAGE: usize = 4;
 
-/// map user stack in `pgtable` at `stack_begin` and returns `sp`
-pub fn map_stack(pgtable: &mut Table, stack_begin: usize) -> usize {
+/// Bottom of user stack of main thread. Page below it is left unmapped as guard.
+pub const USER_STACK: usize = 0x8000_1000;
+
+/// Start address of thread user stacks
+pub const THREAD_STACK_START: usize = 0x1_0000_0000;
+
+/// Bottom of user stack of thread `tid`. Stacks are separated by one unmapped page.
+#[allow(non_snake_case)]
+pub const fn THREAD_STACK(tid: usize) -> usize {
+    THREAD_STACK_START + tid * (USER_STACK_PAGE + 1) * PAGE_SIZE
+}
+
+/// Whether `addr` is in guard page below main thread stack or a thread stack
+pub fn is_user_stack_guard(addr: usize) -> bool {
+    if addr >= USER_STACK - PAGE_SIZE && addr < USER_STACK {
+        return true;
+    }
+    let slot = (USER_STACK_PAGE + 1) * PAGE_SIZE;
+    addr >= THREAD_STACK_START - PAGE_SIZE
+        && addr < THREAD_STACK(NMAXPROCS)
+        && (addr + PAGE_SIZE - THREAD_STACK_START) % slot < PAGE_SIZE
+}
+
+/// map user stack in `pgtable` at `stack_begin` and returns `sp`.
+/// Page below `stack_begin` must be left unmapped as guard.
+/// Nothing is left mapped if it runs out of memory.
+pub fn map_stack(pgtable: &mut AddressSpace, stack_begin: usize) -> Result<usize, OutOfMemory> {
+    if pgtable.paddr_of(stack_begin - PAGE_SIZE).is_some() {
+        panic!("guard page of stack {:x} is mapped", stack_begin);
+    }
     for i in 0..USER_STACK_PAGE {
-        let stack = page::Page::new();
-        pgtable.map(
+        let mapped = page::Page::try_new().and_then(|stack| pgtable.try_map(
             stack_begin + i * PAGE_SIZE,
             stack,
             page::EntryAttributes::URW as usize,
-        );
+        ));
+        if let Err(err) = mapped {
+            unmap_stack(pgtable, stack_begin, i);
+            return Err(err);
+        }
     }
 
-    stack_begin + PAGE_SIZE * USER_STACK_PAGE
+    Ok(stack_begin + PAGE_SIZE * USER_STACK_PAGE)
+}
+
+/// Unmap first `pages` pages of user stack at `stack_begin`
+fn unmap_stack(pgtable: &mut AddressSpace, stack_begin: usize, pages: usize) {
+    for i in 0..pages {
+        pgtable.unmap(stack_begin + i * PAGE_SIZE);
+    }
+}
+
+/// Start of user heap grown by `sbrk`
+pub const USER_HEAP: usize = 0x4000_0000;
+
+/// End of user heap, a page below guard page of main thread stack, so that
+/// break returned by `sbrk` fits in `i32`
+pub const USER_HEAP_END: usize = USER_STACK - 2 * PAGE_SIZE;
+
+/// End of user heap moved from `brk` by `increment`, `None` if it's out of
+/// heap region
+fn heap_end(brk: usize, increment: isize) -> Option<usize> {
+    let new = brk as isize + increment;
+    if new < USER_HEAP as isize || new > USER_HEAP_END as isize {
+        return None;
+    }
+    Some(new as usize)
+}
+
+/// sbrk syscall
+///
+/// Grow or shrink user heap by `increment` bytes, and returns old end of
+/// heap, -1 if new end is out of heap region, or `ENOMEM`.
+pub fn sbrk(increment: isize) -> i32 {
+    let p = my_proc();
+    if heap_end(p.pgtable.lock().brk, increment).is_none() {
+        return -1;
+    }
+    let pages = if increment > 0 { mem::align_val(increment as usize, PAGE_ORDER) / PAGE_SIZE } else { 0 };
+    if !oom::reserve(pages) {
+        return ENOMEM;
+    }
+    let mut space = p.pgtable.lock();
+    let old = space.brk;
+    // may be moved by another thread while reserving
+    let new = match heap_end(old, increment) {
+        Some(new) => new,
+        None => return -1
+    };
+    let (from, to) = (mem::align_val(old, PAGE_ORDER), mem::align_val(new, PAGE_ORDER));
+    for vaddr in (from..to).step_by(PAGE_SIZE) {
+        let mapped = Page::try_new().and_then(|pg| space.try_map(vaddr, pg, EntryAttributes::URW as usize));
+        if mapped.is_err() {
+            for vaddr in (from..vaddr).step_by(PAGE_SIZE) {
+                space.unmap(vaddr);
+            }
+            return ENOMEM;
+        }
+    }
+    for vaddr in (to..from).step_by(PAGE_SIZE) {
+        space.unmap(vaddr);
+    }
+    space.brk = new;
+    old as i32
+}
+
+/// Maximum size of ELF file loaded by `exec`
+const EXEC_MAX_SIZE: usize = 131072;
+
+/// Frames needed by `exec` for file content, new image and user stack
+const EXEC_PAGES: usize = 2 * EXEC_MAX_SIZE / PAGE_SIZE + USER_STACK_PAGE;
+
+/// Load ELF `content` into a new address space for process `p`, and
+/// returns the address space, entry point and `sp`
+fn load_image(p: &Process, content: &[u8]) -> Result<(AddressSpace, usize, usize), OutOfMemory> {
+    let mut space = AddressSpace::new(Table::try_alloc()?);
+    map_kernel(&mut space, p.pid, &p.trapframe)?;
+    let entry = crate::elf::parse_elf(content, &mut space)?;
+    // map user stack
+    let sp = map_stack(&mut space, USER_STACK)?;
+    Ok((space, entry as usize, sp))
 }
 
 /// exec syscall
-pub fn exec(path: &str) {
+///
+/// Returns `ENOMEM` if new image can't be loaded, in which case current
+/// image is kept.
+pub fn exec(path: &str) -> i32 {
     let p = my_proc();
     info!("loading elf {}", path);
-    let mut content: Box<[u8; 131072]> = box [0; 131072];
+    if !oom::reserve(EXEC_PAGES) {
+        return ENOMEM;
+    }
+    let mut content: Box<[u8; EXEC_MAX_SIZE]> = match unsafe { mem::try_zeroed_box() } {
+        Ok(content) => content,
+        Err(_) => return ENOMEM
+    };
     {
         let f = FsFile::open(path, 0);
         let mut blk = [0; 1024];
@@ -198,25 +532,31 @@ pub fn exec(path: &str) {
         }
     }
     info!("parsing...");
-    p.pgtable.unmap_user();
-    let entry = crate::elf::parse_elf(
-        &*content,
-        &mut p.pgtable,
-    );
+    let (space, entry, sp) = match load_image(p, &*content) {
+        Ok(image) => image,
+        Err(_) => return ENOMEM
+    };
     info!("done");
-    // map user stack
-    let sp = map_stack(&mut p.pgtable, 0x80001000);
-    p.trapframe.epc = entry as usize;
+    p.pgtable.lock().replace(space);
+    p.files.lock().close_on_exec();
+    p.trapframe.epc = entry;
     p.trapframe.regs[Register::sp as usize] = sp;
+    0
 }
 
 /// exit syscall
+///
+/// Only the calling thread exits. Other threads sharing its page table keep running.
 pub fn exit(status: i32) -> ! {
     {
         let p = my_proc();
         if p.pid == 0 {
             panic!("init exiting");
         }
+        p.exit_code = status;
+        crate::mem::swap::unpin(p);
+        // free user pages early, as zombie leader is kept until its threads exit
+        p.pgtable.lock().thread_exit();
         p.state = ProcessState::ZOMBIE;
     }
     arch::intr_off();
@@ -269,6 +609,30 @@ pub fn sleep<T, U>(channel: *const T, lck: MutexGuard<U>) -> MutexGuard<U> {
     return weak_lock.into_guard();
 }
 
+/// Number of processes sleeping with a timeout
+static TIMED_SLEEPERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Same as `sleep`, but process is also woken up after `timeout`. Caller
+/// should check whether time is up after woken up.
+pub fn sleep_timeout<T, U>(channel: *const T, lck: MutexGuard<U>, timeout: Duration) -> MutexGuard<U> {
+    my_proc().wake_at = Some(arch::time() + timeout);
+    TIMED_SLEEPERS.fetch_add(1, Ordering::SeqCst);
+    let lck = sleep(channel, lck);
+    TIMED_SLEEPERS.fetch_sub(1, Ordering::SeqCst);
+    my_proc().wake_at = None;
+    lck
+}
+
+/// wakeup processes whose timeout of `sleep_timeout` passes. Called on
+/// timer interrupt.
+pub fn wakeup_timeouts() {
+    if TIMED_SLEEPERS.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+    let now = arch::time();
+    wakeup_where(usize::MAX, |p| p.wake_at.map_or(false, |t| now >= t));
+}
+
 /// wakeup process on channel
 ///
 /// `channel` is an identifier of sleep lock channel. Should be the same as in `sleep`.
@@ -278,16 +642,36 @@ pub fn sleep<T, U>(channel: *const T, lck: MutexGuard<U>) -> MutexGuard<U> {
 /// `PROCS_POOL` lock and wait for `PROCS_POOL_SLEEP` to be unlocked, so that there won't be
 /// lost wakeup issues.
 pub fn wakeup<T>(channel: *const T) {
+    wakeup_n(channel, usize::MAX);
+}
+
+/// wakeup at most `n` processes on channel, and returns number of processes woken up
+///
+/// See `wakeup` for details.
+pub fn wakeup_n<T>(channel: *const T, n: usize) -> usize {
     // info!("wakeup {:x}", channel as usize);
     let channel = channel as *const _ as usize;
+    wakeup_where(n, |p| p.channel == channel)
+}
+
+/// wakeup process `pid` if it's sleeping on any channel. Sleeps should
+/// check their condition again after woken up.
+pub fn wakeup_pid(pid: i32) {
+    wakeup_where(1, |p| p.pid == pid);
+}
+
+/// wakeup at most `n` sleeping processes for which `f` returns true
+fn wakeup_where(n: usize, f: impl Fn(&Process) -> bool) -> usize {
     let mut pool = PROCS_POOL.lock();
     let mut i = 0;
-    while i < NMAXPROCS {
+    let mut woken = 0;
+    while i < NMAXPROCS && woken < n {
         match &mut pool[i] {
             ProcInPool::Pooling(p) => {
                 // if p.state == ProcessState::SLEEPING { info!("channel of {} = {:x}", p.pid, p.channel); }
-                if p.state == ProcessState::SLEEPING && p.channel == channel {
+                if p.state == ProcessState::SLEEPING && f(p) {
                     p.state = ProcessState::RUNNABLE;
+                    woken += 1;
                 }
                 i += 1;
             }
@@ -299,4 +683,118 @@ pub fn wakeup<T>(channel: *const T) {
             _ => { i += 1; }
         }
     }
+    woken
+}
+
+pub mod tests {
+    use super::*;
+    use crate::syscall::SYS_EXIT;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("clone and join", test_clone_join),
+            ("join invalid", test_join_invalid),
+            ("reap", test_reap),
+            ("leader exits first", test_leader_exits_first),
+        ]
+    }
+
+    /// User address of code which exits with `a0`
+    pub const EXIT_CODE: usize = 0x7000_0000;
+
+    /// Map `li a7, SYS_exit; ecall` at `EXIT_CODE` of current process
+    pub fn map_exit_code() {
+        let mut page = Page::new();
+        let insts = [(SYS_EXIT as u32) << 20 | 17 << 7 | 0x13, 0x73];
+        for (i, inst) in insts.iter().enumerate() {
+            page.data[i * 4..i * 4 + 4].copy_from_slice(&inst.to_le_bytes());
+        }
+        my_proc().pgtable.lock().map(EXIT_CODE, page, EntryAttributes::URX as usize);
+    }
+
+    /// Test thread exit code is returned by join, after which the thread is released
+    pub fn test_clone_join() {
+        map_exit_code();
+        let tids = [clone(EXIT_CODE, 42, 0), clone(EXIT_CODE, 43, 0)];
+        assert!(tids.iter().all(|&tid| tid > 0));
+        // join in reverse order, so the second thread may have exited before
+        assert_eq!(join(tids[1]), Some(43));
+        assert_eq!(join(tids[0]), Some(42));
+        for &tid in &tids {
+            assert_eq!(join(tid), None);
+        }
+        my_proc().pgtable.lock().unmap(EXIT_CODE);
+    }
+
+    pub fn test_join_invalid() {
+        assert_eq!(join(-1), None);
+        assert_eq!(join(NMAXPROCS as i32), None);
+        assert_eq!(join(my_proc().pid), None);
+    }
+
+    /// Test zombie threads are released only after their leader exits, and
+    /// the leader after all threads exit
+    pub fn test_reap() {
+        let leader_pid = alloc_pid().unwrap();
+        let mut leader = Process::new(leader_pid);
+        let (pgtable, files) = (leader.pgtable.clone(), leader.files.clone());
+        let thread = |files: &FileTable| {
+            let tid = alloc_pid().unwrap();
+            let mut t = Process::from_shared(tid, pgtable.clone(), files.clone(), box TrapFrame::zero());
+            t.thread_stack = Some(THREAD_STACK(tid as usize));
+            t
+        };
+        let (mut t, mut t2) = (thread(&files), thread(&files));
+        let (tid, tid2) = (t.pid, t2.pid);
+        let in_pool = |pid: i32| match PROCS_POOL.lock()[pid as usize] {
+            ProcInPool::Pooling(_) => true,
+            _ => false
+        };
+
+        pgtable.lock().thread_exit();
+        t.state = ProcessState::ZOMBIE;
+        put_back_proc(box t);
+        t2.state = ProcessState::SLEEPING;
+        put_back_proc(box t2);
+        // leader hasn't been put back, so it is still running
+        reap(&pgtable);
+        assert!(in_pool(tid));
+
+        pgtable.lock().thread_exit();
+        leader.state = ProcessState::ZOMBIE;
+        put_back_proc(box leader);
+        reap(&pgtable);
+        assert!(!in_pool(tid));
+        // kept until the other thread exits
+        assert!(in_pool(leader_pid));
+
+        pgtable.lock().thread_exit();
+        if let ProcInPool::Pooling(t2) = &mut PROCS_POOL.lock()[tid2 as usize] {
+            t2.state = ProcessState::ZOMBIE;
+        }
+        reap(&pgtable);
+        assert!(!in_pool(tid2));
+        assert!(!in_pool(leader_pid));
+        assert_eq!(Arc::strong_count(&pgtable), 1);
+    }
+
+    /// Test user pages are freed when the last thread exits, after its leader
+    pub fn test_leader_exits_first() {
+        let leader_pid = alloc_pid().unwrap();
+        let leader = Process::new(leader_pid);
+        let tid = alloc_pid().unwrap();
+        let t = Process::from_shared(tid, leader.pgtable.clone(), leader.files.clone(), box TrapFrame::zero());
+        let mut space = leader.pgtable.lock();
+        assert_eq!(space.threads, 2);
+        space.map(EXIT_CODE, Page::new(), EntryAttributes::URX as usize);
+        space.thread_exit();
+        assert!(space.paddr_of(EXIT_CODE).is_some());
+        space.thread_exit();
+        assert!(space.paddr_of(EXIT_CODE).is_none());
+        drop(space);
+        drop(t);
+        drop(leader);
+        free_pid(tid);
+        free_pid(leader_pid);
+    }
 }
@@ -7,20 +7,42 @@ use crate::arch::wait_forever;
 use crate::arch;
 use crate::trap::usertrapret;
 use crate::symbols::*;
-use crate::process::{ProcInPool, PROCS_POOL, ProcessState, swtch, Register, Context, my_cpu, Process};
+use crate::process::{ProcInPool, PROCS_POOL, ProcessState, swtch, Register, Context, my_cpu, my_proc, Process, yield_cpu};
+use crate::process::{mark_online, online_harts, wakeup_joiners, reap};
 use crate::{info, println};
 use crate::panic;
 use alloc::boxed::Box;
 use core::borrow::BorrowMut;
 use crate::jump::*;
+use crate::executor;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-/// Find a runnable process whose pid >= `from_pid`
+/// Bitmask of all harts a process may run on
+pub const AFFINITY_ALL: usize = (1 << NCPUS) - 1;
+
+/// Bitmask of harts each pid may be scheduled on. It's kept out of
+/// `Process`, so that it can be changed while the process is running on
+/// another hart, and takes effect the next time it's scheduled.
+static AFFINITY: [AtomicUsize; NMAXPROCS] = [AtomicUsize::new(AFFINITY_ALL); NMAXPROCS];
+
+/// Called when process `pid` is created with affinity `mask`
+pub fn init_affinity(pid: i32, mask: usize) {
+    AFFINITY[pid as usize].store(mask, Ordering::SeqCst);
+}
+
+/// CPU affinity of process `pid`
+pub fn affinity_of(pid: i32) -> usize {
+    AFFINITY[pid as usize].load(Ordering::SeqCst)
+}
+
+/// Find a runnable process whose pid >= `from_pid` and may run on current hart
 fn find_next_runnable_proc(from_pid: usize) -> Option<Box<Process>> {
+    let hart_mask = 1 << arch::hart_id();
     let mut pool = PROCS_POOL.lock();
     for pid in from_pid..NMAXPROCS {
         let in_pool = &mut pool[pid];
         let schedule_this = match in_pool {
-            ProcInPool::Pooling(p) => p.state == ProcessState::RUNNABLE,
+            ProcInPool::Pooling(p) => p.state == ProcessState::RUNNABLE && affinity_of(p.pid) & hart_mask != 0,
             _ => false
         };
         if schedule_this {
@@ -48,9 +70,11 @@ pub fn put_back_proc(mut p: Box<Process>) {
 pub fn scheduler() -> ! {
     let c = my_cpu();
     let mut lst_pid = 0;
+    mark_online();
     // info!("scheduling on {}", arch::hart_id());
     loop {
         arch::intr_on();
+        executor::run_ready();
         if let Some(p) = find_next_runnable_proc(lst_pid) {
             c.process = Some(p);
             let p = c.process.as_mut().unwrap();
@@ -65,9 +89,156 @@ pub fn scheduler() -> ! {
                 lst_pid = 0;
             }
             // info!("put back...");
+            let zombie = if p.state == ProcessState::ZOMBIE { Some(p.pgtable.clone()) } else { None };
             put_back_proc(p);
+            if let Some(pgtable) = zombie {
+                reap(&pgtable);
+                wakeup_joiners();
+            }
         } else {
             lst_pid = 0;
         }
     }
 }
+
+/// Whether `pid` refers to a process which is created
+fn is_process(pool: &[ProcInPool; NMAXPROCS], pid: i32) -> bool {
+    if pid < 0 || pid as usize >= NMAXPROCS {
+        return false;
+    }
+    match pool[pid as usize] {
+        ProcInPool::NoProc | ProcInPool::Reserved => false,
+        _ => true
+    }
+}
+
+/// Set CPU affinity of process `pid` to `mask`. `pid` of 0 refers to current process.
+///
+/// Harts which are not online are removed from `mask`. If current process is
+/// no longer allowed to run on current hart, it gives up CPU immediately.
+/// A process running on another hart keeps running until it gives up CPU.
+pub fn set_affinity(pid: i32, mask: usize) -> i32 {
+    let mask = mask & online_harts();
+    if mask == 0 {
+        return -1;
+    }
+    let p = my_proc();
+    let pid = if pid == 0 { p.pid } else { pid };
+    {
+        // pid can't be freed and taken by another process meanwhile
+        let pool = PROCS_POOL.lock();
+        if !is_process(&pool, pid) {
+            return -1;
+        }
+        AFFINITY[pid as usize].store(mask, Ordering::SeqCst);
+    }
+    if pid == p.pid && mask & (1 << arch::hart_id()) == 0 {
+        arch::intr_off();
+        yield_cpu();
+    }
+    0
+}
+
+/// Get CPU affinity of process `pid`. `pid` of 0 refers to current process.
+pub fn get_affinity(pid: i32) -> i32 {
+    let pid = if pid == 0 { my_proc().pid } else { pid };
+    if !is_process(&PROCS_POOL.lock(), pid) {
+        return -1;
+    }
+    affinity_of(pid) as i32
+}
+
+pub mod tests {
+    use super::*;
+    use crate::process::alloc_pid;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("empty mask", test_empty_mask),
+            ("offline harts", test_offline_harts),
+            ("other process", test_other_process),
+            ("scheduler respects mask", test_scheduler_mask),
+        ]
+    }
+
+    pub fn test_empty_mask() {
+        let old = get_affinity(0);
+        assert_eq!(set_affinity(0, 0), -1);
+        assert_eq!(get_affinity(0), old);
+    }
+
+    /// Test harts which are not online are removed from mask
+    pub fn test_offline_harts() {
+        let old = get_affinity(0);
+        let offline = AFFINITY_ALL & !online_harts();
+        if offline != 0 {
+            assert_eq!(set_affinity(0, offline), -1);
+        }
+        assert_eq!(set_affinity(0, 1 << NCPUS), -1);
+        assert_eq!(set_affinity(0, usize::MAX), 0);
+        assert_eq!(get_affinity(0), online_harts() as i32);
+        assert_eq!(set_affinity(0, old as usize), 0);
+    }
+
+    /// Test affinity of another process is set, even while it's running
+    pub fn test_other_process() {
+        assert_eq!(set_affinity(-1, AFFINITY_ALL), -1);
+        assert_eq!(get_affinity(NMAXPROCS as i32), -1);
+        let pid = alloc_pid().unwrap();
+        // pid reserved for a process being created
+        assert_eq!(set_affinity(pid, AFFINITY_ALL), -1);
+        let mut p = Process::new(pid);
+        p.state = ProcessState::SLEEPING;
+        put_back_proc(box p);
+        let mask = 1 << arch::hart_id();
+        assert_eq!(set_affinity(pid, mask), 0);
+        assert_eq!(get_affinity(pid), mask as i32);
+
+        // as if it's running on another hart
+        let p = core::mem::replace(&mut PROCS_POOL.lock()[pid as usize], ProcInPool::Scheduled);
+        assert_eq!(set_affinity(pid, online_harts()), 0);
+        assert_eq!(get_affinity(pid), online_harts() as i32);
+        PROCS_POOL.lock()[pid as usize] = p;
+
+        let p = core::mem::replace(&mut PROCS_POOL.lock()[pid as usize], ProcInPool::NoProc);
+        drop(p);
+        assert_eq!(get_affinity(pid), -1);
+    }
+
+    /// Whether current hart picks process `pid` to run. Processes picked
+    /// instead are put back.
+    fn picks(pid: i32) -> bool {
+        match find_next_runnable_proc(pid as usize) {
+            Some(p) if p.pid == pid => {
+                PROCS_POOL.lock()[pid as usize] = ProcInPool::Pooling(p);
+                true
+            }
+            Some(p) => {
+                put_back_proc(p);
+                false
+            }
+            None => false
+        }
+    }
+
+    /// Test a runnable process is only picked by harts in its mask
+    pub fn test_scheduler_mask() {
+        let pid = alloc_pid().unwrap();
+        let mut p = Process::new(pid);
+        // no hart may run it, as its context is never set up
+        init_affinity(pid, 1 << NCPUS);
+        p.state = ProcessState::RUNNABLE;
+        // stay on this hart until it's marked as not runnable
+        arch::intr_off();
+        put_back_proc(box p);
+        assert!(!picks(pid));
+        init_affinity(pid, 1 << arch::hart_id());
+        assert!(picks(pid));
+        if let ProcInPool::Pooling(p) = &mut PROCS_POOL.lock()[pid as usize] {
+            p.state = ProcessState::SLEEPING;
+        }
+        arch::intr_on();
+        let p = core::mem::replace(&mut PROCS_POOL.lock()[pid as usize], ProcInPool::NoProc);
+        drop(p);
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Signals and process groups
+//!
+//! Pending signals and process group of each pid are kept in atomics, as a
+//! process running on another hart isn't in `PROCS_POOL`. Signals only take
+//! their default actions, when the process is about to return to user mode:
+//! most of them terminate the process, `SIGSTOP` and `SIGTSTP` stop it until
+//! `SIGCONT`, and a few are ignored.
+//!
+//! Sending a signal wakes up the process if it's sleeping. Sleeps that may
+//! last long, such as reading from a terminal, give up if `interrupted`.
+
+use core::sync::atomic::{AtomicU32, AtomicI32, Ordering};
+use crate::symbols::NMAXPROCS;
+use crate::spinlock::Mutex;
+use super::{Process, PROCS_POOL, ProcInPool, sleep, wakeup_pid, exit};
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGKILL: usize = 9;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGWINCH: usize = 28;
+
+/// Signals are numbered from 1 to `NSIG - 1`
+pub const NSIG: usize = 32;
+
+/// Signals stopping a process
+const STOP: u32 = 1 << SIGSTOP | 1 << SIGTSTP;
+
+/// Signals ignored by default
+const IGNORE: u32 = 1 << SIGCHLD | 1 << SIGCONT | 1 << SIGWINCH;
+
+/// Signals terminating a process by default
+const TERMINATE: u32 = !(STOP | IGNORE | 1);
+
+/// Pending signals of each pid
+static PENDING: [AtomicU32; NMAXPROCS] = [AtomicU32::new(0); NMAXPROCS];
+
+/// Process group of each pid
+static PGID: [AtomicI32; NMAXPROCS] = [AtomicI32::new(0); NMAXPROCS];
+
+/// Lock held by stopped processes while checking for `SIGCONT`, so that
+/// it won't be missed
+static STOP_LOCK: Mutex<()> = Mutex::new((), "signal stop");
+
+/// Called when process `pid` is created in process group `pgid`
+pub fn reset(pid: i32, pgid: i32) {
+    PENDING[pid as usize].store(0, Ordering::SeqCst);
+    PGID[pid as usize].store(pgid, Ordering::SeqCst);
+}
+
+/// Whether `pid` refers to a process
+pub fn exists(pid: i32) -> bool {
+    if pid < 0 || pid as usize >= NMAXPROCS {
+        return false;
+    }
+    match PROCS_POOL.lock()[pid as usize] {
+        ProcInPool::NoProc => false,
+        _ => true
+    }
+}
+
+/// Process group of `pid`
+pub fn pgid(pid: i32) -> i32 {
+    PGID[pid as usize].load(Ordering::SeqCst)
+}
+
+/// Move `pid` to process group `pgid`. Returns false if there's no such process.
+pub fn set_pgid(pid: i32, pgid: i32) -> bool {
+    if !exists(pid) || pgid < 0 {
+        return false;
+    }
+    PGID[pid as usize].store(pgid, Ordering::SeqCst);
+    true
+}
+
+/// Send `sig` to process `pid`. Returns false if there's no such process
+/// or signal.
+pub fn kill(pid: i32, sig: usize) -> bool {
+    // init never receives signals
+    if sig == 0 || sig >= NSIG || pid == 0 || !exists(pid) {
+        return false;
+    }
+    {
+        let _stop = STOP_LOCK.lock();
+        let pending = &PENDING[pid as usize];
+        if sig == SIGCONT {
+            pending.fetch_and(!STOP, Ordering::SeqCst);
+        } else if STOP & (1 << sig) != 0 {
+            pending.fetch_and(!(1 << SIGCONT), Ordering::SeqCst);
+        }
+        pending.fetch_or(1 << sig, Ordering::SeqCst);
+    }
+    wakeup_pid(pid);
+    true
+}
+
+/// Send `sig` to all processes in group `pgid`. Returns false if there's
+/// no such process.
+pub fn kill_group(pgid: i32, sig: usize) -> bool {
+    let mut found = false;
+    for pid in 0..NMAXPROCS as i32 {
+        if PGID[pid as usize].load(Ordering::SeqCst) == pgid && kill(pid, sig) {
+            found = true;
+        }
+    }
+    found
+}
+
+/// Whether a signal is pending for `pid`, which should interrupt its sleep
+pub fn interrupted(pid: i32) -> bool {
+    PENDING[pid as usize].load(Ordering::SeqCst) & !IGNORE != 0
+}
+
+/// Take default actions of signals pending for current process `p`.
+/// Called before returning to user mode.
+pub fn handle(p: &mut Process) {
+    let pending = &PENDING[p.pid as usize];
+    loop {
+        let signals = pending.load(Ordering::SeqCst);
+        if signals & TERMINATE != 0 {
+            let sig = (signals & TERMINATE).trailing_zeros();
+            exit(128 + sig as i32);
+        }
+        if signals & STOP == 0 {
+            pending.fetch_and(!IGNORE, Ordering::SeqCst);
+            return;
+        }
+        // stopped until continued or killed
+        let mut stop = STOP_LOCK.lock();
+        pending.fetch_and(!STOP, Ordering::SeqCst);
+        while pending.load(Ordering::SeqCst) & (1 << SIGCONT | 1 << SIGKILL) == 0 {
+            stop = sleep(&STOP_LOCK, stop);
+        }
+    }
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("masks", test_masks),
+        ]
+    }
+
+    pub fn test_masks() {
+        assert!(TERMINATE & (1 << SIGINT) != 0);
+        assert!(TERMINATE & (1 << SIGKILL) != 0);
+        assert!(TERMINATE & (1 << SIGHUP) != 0);
+        assert!(TERMINATE & (STOP | IGNORE) == 0);
+        // bit 0 isn't a signal
+        assert!(TERMINATE & 1 == 0);
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Supervisor Binary Interface client
+//!
+//! When built with `sbi` feature, kernel boots in supervisor mode under
+//! firmware like OpenSBI, and asks firmware for timer, IPI, hart management
+//! and system reset through `ecall`.
+
+/// Base extension
+const EID_BASE: usize = 0x10;
+/// Timer extension
+const EID_TIME: usize = 0x5449_4D45;
+/// IPI extension
+const EID_IPI: usize = 0x73_5049;
+/// Hart state management extension
+const EID_HSM: usize = 0x48_534D;
+/// System reset extension
+const EID_SRST: usize = 0x5352_5354;
+
+/// Legacy `sbi_set_timer`
+const LEGACY_SET_TIMER: usize = 0x00;
+/// Legacy `sbi_console_putchar`
+const LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+/// Legacy `sbi_console_getchar`
+const LEGACY_CONSOLE_GETCHAR: usize = 0x02;
+/// Legacy `sbi_send_ipi`
+const LEGACY_SEND_IPI: usize = 0x04;
+/// Legacy `sbi_shutdown`
+const LEGACY_SHUTDOWN: usize = 0x08;
+
+/// Reset type of `system_reset`
+pub const RESET_SHUTDOWN: usize = 0;
+pub const RESET_COLD_REBOOT: usize = 1;
+pub const RESET_WARM_REBOOT: usize = 2;
+
+/// Reset reason of `system_reset`
+pub const REASON_NONE: usize = 0;
+pub const REASON_FAILURE: usize = 1;
+
+/// Hart states returned by `hart_status`
+pub const HART_STARTED: usize = 0;
+pub const HART_STOPPED: usize = 1;
+
+/// Return value of SBI calls. Negative `error` means failure.
+#[derive(Debug, Clone, Copy)]
+pub struct SbiRet {
+    pub error: isize,
+    pub value: usize,
+}
+
+impl SbiRet {
+    pub fn ok(&self) -> bool {
+        self.error == 0
+    }
+}
+
+/// Call function `fid` of extension `eid`
+unsafe fn sbi_call(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> SbiRet {
+    let error: isize;
+    let value: usize;
+    llvm_asm!("ecall"
+        : "={x10}"(error), "={x11}"(value)
+        : "{x10}"(arg0), "{x11}"(arg1), "{x12}"(arg2), "{x16}"(fid), "{x17}"(eid)
+        : "memory"
+        : "volatile");
+    SbiRet { error, value }
+}
+
+/// Call legacy extension `eid`, which returns in a0 only
+unsafe fn legacy_call(eid: usize, arg0: usize) -> isize {
+    let ret: isize;
+    llvm_asm!("ecall"
+        : "={x10}"(ret)
+        : "{x10}"(arg0), "{x17}"(eid)
+        : "memory"
+        : "volatile");
+    ret
+}
+
+/// Whether firmware implements extension `eid`
+pub fn probe_extension(eid: usize) -> bool {
+    let ret = unsafe { sbi_call(EID_BASE, 3, eid, 0, 0) };
+    ret.ok() && ret.value != 0
+}
+
+/// Program next timer interrupt at `stime_value`, which also clears
+/// pending supervisor timer interrupt.
+pub fn set_timer(stime_value: u64) {
+    unsafe {
+        if !sbi_call(EID_TIME, 0, stime_value as usize, 0, 0).ok() {
+            legacy_call(LEGACY_SET_TIMER, stime_value as usize);
+        }
+    }
+}
+
+/// Send supervisor software interrupt to harts in `hart_mask`
+pub fn send_ipi(hart_mask: usize) {
+    unsafe {
+        if !sbi_call(EID_IPI, 0, hart_mask, 0, 0).ok() {
+            legacy_call(LEGACY_SEND_IPI, &hart_mask as *const usize as usize);
+        }
+    }
+}
+
+/// Start `hartid` at physical address `start_addr` in supervisor mode,
+/// with hart id in a0 and `opaque` in a1.
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> SbiRet {
+    unsafe { sbi_call(EID_HSM, 0, hartid, start_addr, opaque) }
+}
+
+/// Stop current hart. Only returns on failure.
+pub fn hart_stop() -> SbiRet {
+    unsafe { sbi_call(EID_HSM, 1, 0, 0, 0) }
+}
+
+/// Get state of `hartid`
+pub fn hart_status(hartid: usize) -> SbiRet {
+    unsafe { sbi_call(EID_HSM, 2, hartid, 0, 0) }
+}
+
+/// Write a byte to firmware console
+pub fn console_putchar(c: u8) {
+    unsafe { legacy_call(LEGACY_CONSOLE_PUTCHAR, c as usize); }
+}
+
+/// Read a byte from firmware console
+pub fn console_getchar() -> Option<u8> {
+    let c = unsafe { legacy_call(LEGACY_CONSOLE_GETCHAR, 0) };
+    if c < 0 { None } else { Some(c as u8) }
+}
+
+/// Shut down or reboot the machine
+pub fn system_reset(reset_type: usize, reason: usize) -> ! {
+    unsafe {
+        sbi_call(EID_SRST, 0, reset_type, reason, 0);
+        legacy_call(LEGACY_SHUTDOWN, 0);
+    }
+    crate::arch::wait_forever()
+}
@@ -38,10 +38,10 @@ impl SleepLock {
         }
     }
 
-    pub fn acquire(&mut self) -> SleepLockGuard {
+    pub fn acquire(&self) -> SleepLockGuard {
         let mut lk = self.spin.lock();
         while lk.locked {
-            lk = sleep(&self, lk);
+            lk = sleep(self, lk);
         }
         lk.locked = true;
         lk.pid = my_proc().pid;
@@ -61,6 +61,6 @@ impl Drop for SleepLockGuard<'_> {
         let mut lk = self.lock.spin.lock();
         lk.locked = false;
         lk.pid = 0;
-        wakeup(&self.lock);
+        wakeup(self.lock);
     }
 }
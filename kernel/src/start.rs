@@ -7,14 +7,31 @@
 
 use riscv::{asm, register::*};
 use crate::arch::{hart_id, wait_forever};
-use crate::{clint, plic, mem, uart, process, spinlock, trap, virtio};
-use crate::info;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::{clint, plic, mem, uart, process, spinlock, trap, virtio, platform};
+use crate::{info, warn};
+use crate::symbols::{NCPUS, PAGE_SIZE, SCHEDULER_INTERVAL, KERNEL_STACK_START, KERNEL_STACK_END};
+#[cfg(feature = "sbi")]
+use crate::sbi;
 use crate::jump::*;
 
+/// Set by boot hart after device tree is parsed
+static PLATFORM_READY: AtomicBool = AtomicBool::new(false);
+
 /// Initialize kernel page table and drivers in machine mode,
 /// and prepare to switch to supervisor mode
+///
+/// `dtb` is the address of device tree blob passed by firmware.
+#[cfg(not(feature = "sbi"))]
 #[no_mangle]
-unsafe extern "C" fn kinit() {
+unsafe extern "C" fn kinit(dtb: usize) {
+    // boot hart discovers devices, and others wait for it
+    if mhartid::read() == 0 {
+        platform::init(dtb);
+        PLATFORM_READY.store(true, Ordering::Release);
+    } else {
+        while !PLATFORM_READY.load(Ordering::Acquire) {}
+    }
     // next mode is supervisor mode
     mstatus::set_mpp(mstatus::MPP::Supervisor);
     // mret jump to kmain
@@ -35,6 +52,49 @@ unsafe extern "C" fn kinit() {
     asm!("mret");
 }
 
+/// Prepare hart already in supervisor mode under SBI firmware
+///
+/// `dtb` is the address of device tree blob passed by firmware, or by
+/// boot hart when starting other harts.
+#[cfg(feature = "sbi")]
+#[no_mangle]
+unsafe extern "C" fn kinit(hartid: usize, dtb: usize) -> ! {
+    // boot hart discovers devices, and others wait for it
+    if hartid == 0 {
+        platform::init(dtb);
+        PLATFORM_READY.store(true, Ordering::Release);
+    } else {
+        while !PLATFORM_READY.load(Ordering::Acquire) {}
+    }
+    // disable paging
+    asm!("csrw satp, zero");
+    // save cpuid to tp
+    llvm_asm!("mv tp, $0" :: "r"(hartid) :: "volatile");
+    // set up timer interrupt
+    sbi::set_timer(crate::arch::mtime() + SCHEDULER_INTERVAL as u64);
+    kmain()
+}
+
+/// Start other harts with HSM extension. Harts already running, as with
+/// legacy firmware, are skipped.
+#[cfg(feature = "sbi")]
+fn start_harts() {
+    extern "C" {
+        /// Entry of other harts in `boot_sbi.S`
+        fn _secondary_start();
+    }
+    let dtb = platform::platform().dtb;
+    for hart in 1..core::cmp::min(platform::platform().ncpus, NCPUS) {
+        let status = sbi::hart_status(hart);
+        if status.ok() && status.value == sbi::HART_STOPPED {
+            let ret = sbi::hart_start(hart, _secondary_start as usize, dtb);
+            if !ret.ok() {
+                warn!("failed to start hart {}: {}", hart, ret.error);
+            }
+        }
+    }
+}
+
 /// Controls whether other harts may start boot procedure
 static mut MAY_BOOT: bool = false;
 
@@ -45,14 +105,38 @@ extern "C" fn kmain() -> ! {
         unsafe { uart::init(); }
         info!("booting core-os on hart {}...", hart_id());
         info!("  UART... \x1b[0;32minitialized\x1b[0m");
+        let platform = platform::platform();
+        if platform.dtb != 0 {
+            info!("  device tree at 0x{:x}: {} harts, {} MiB RAM, {} virtio slots",
+                  platform.dtb, platform.ncpus,
+                  (platform.memory_end - platform.memory_start) / 1024 / 1024,
+                  platform.nvirtio);
+        } else {
+            warn!("no device tree found, assuming QEMU virt machine");
+        }
+        if platform.ncpus > NCPUS {
+            warn!("only {} of {} harts are used", NCPUS, platform.ncpus);
+        }
+        // boot code gives each hart below NCPUS a 64K stack
+        if KERNEL_STACK_END() - KERNEL_STACK_START() < NCPUS * 0x10000 {
+            panic!("kernel stack region too small for {} harts", NCPUS);
+        }
         unsafe { mem::init(); }
         info!("  kernel page table... \x1b[0;32minitialized\x1b[0m");
         unsafe { virtio::init(); }
         info!("  virt-io... \x1b[0;32minitialized\x1b[0m");
+        let swap = mem::swap::init();
+        if swap != 0 {
+            info!("  swap... {} MiB", swap * PAGE_SIZE / 1024 / 1024);
+        } else {
+            warn!("no swap disk found");
+        }
         unsafe { plic::init(); }
         info!("  PLIC... \x1b[0;32minitialized\x1b[0m");
         mem::hartinit();
         info!("kernel page table configured");
+        mem::asid::init();
+        info!("  ASID... {} available", mem::asid::max_asid());
         info!("  Trap... \x1b[0;32minitialized\x1b[0m");
         info!("  Timer... \x1b[0;32minitialized\x1b[0m");
         plic::hartinit();
@@ -65,6 +149,8 @@ extern "C" fn kmain() -> ! {
             asm!("fence");
             MAY_BOOT = true
         }
+        #[cfg(feature = "sbi")]
+        start_harts();
     } else {
         loop {
             if unsafe { MAY_BOOT } == true {
@@ -37,8 +37,11 @@ pub const TRAMPOLINE_START: usize = MAXVA - PAGE_SIZE;
 /// Address to map trapframe
 pub const TRAPFRAME_START: usize = TRAMPOLINE_START - PAGE_SIZE;
 
-/// Maximum supported CPU on machine
-pub const NCPUS : usize = 8;
+/// Trapframe of process `pid`. Threads sharing a page table have their own trapframes.
+#[allow(non_snake_case)]
+pub const fn TRAPFRAME(pid: usize) -> usize {
+    TRAPFRAME_START - pid * PAGE_SIZE
+}
 
 /// Maximum process on machine.
 pub const NMAXPROCS: usize = 256;
@@ -65,6 +68,6 @@ pub fn print_map_symbols() {
 	println!(
 		"HEAP:   0x{:x} -> 0x{:x}",
 		HEAP_START(),
-		HEAP_START() + HEAP_SIZE()
+		crate::mem::HEAP_END()
 	);
 }
@@ -31,3 +31,4 @@ extern "C" { static __kernel_stack_end: usize; }
 #[inline] pub fn KERNEL_STACK_END() -> usize { unsafe { &__kernel_stack_end as *const _ as _ } }
 extern "C" { static __trampoline_text_start: usize; }
 #[inline] pub fn TRAMPOLINE_TEXT_START() -> usize { unsafe { &__trampoline_text_start as *const _ as _ } }
+pub const NCPUS: usize = 8;
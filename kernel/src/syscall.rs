@@ -15,19 +15,37 @@
 
 mod gen;
 mod file;
+mod socket;
 
 pub use gen::*;
-use crate::process::{TrapFrame, Register, my_proc, fork, exec, exit, Process};
+use crate::process::{signal, TrapFrame, Register, my_proc, fork, exec, exit, sbrk, Process, PageTable, set_affinity, get_affinity, clone, join, futex};
 use crate::{info, panic, print, println};
-use crate::page;
-use crate::mem::{align_val, page_down};
+use crate::mem::{align_val, page_down, swap};
 use crate::symbols::{PAGE_ORDER, PAGE_SIZE};
 use file::*;
+use socket::*;
 use alloc::sync::Arc;
 use crate::file::File;
 use alloc::boxed::Box;
 use crate::spinlock::Mutex;
 
+/// Returned by syscalls when there's not enough memory
+pub const ENOMEM: i32 = -12;
+/// Interrupted by a signal
+pub const EINTR: i32 = -4;
+/// Path refers to no device, such as a socket node opened
+pub const ENXIO: i32 = -6;
+/// Peer of connection is closed
+pub const EPIPE: i32 = -32;
+/// Address is in use
+pub const EADDRINUSE: i32 = -98;
+/// Connection reset by peer
+pub const ECONNRESET: i32 = -104;
+/// Connection timed out
+pub const ETIMEDOUT: i32 = -110;
+/// Connection refused by peer
+pub const ECONNREFUSED: i32 = -111;
+
 /// Get the `pos`th argument from syscall
 pub fn argraw(tf: &TrapFrame, pos: usize) -> usize {
     match pos {
@@ -56,29 +74,32 @@ pub fn arg_uint(tf: &TrapFrame, pos: usize) -> usize {
 }
 
 /// Get the `pos`th argument as a pointer from syscall, return kernel-space pointer (involve security issues!)
-pub fn arg_ptr(pgtable: &page::Table, tf: &TrapFrame, pos: usize, sz: usize) -> *const u8 {
+///
+/// Page is brought back if swapped out, and pinned until syscall returns.
+pub fn arg_ptr(pgtable: &PageTable, tf: &TrapFrame, pos: usize, sz: usize) -> *const u8 {
     let ptr = argraw(tf, pos);
     let pg_begin = page_down(ptr);
     if ptr + sz >= pg_begin + PAGE_SIZE {
         panic!("out of bound!");
     }
-    let paddr = pgtable.paddr_of(pg_begin).unwrap();
+    let paddr = match swap::pin(pgtable, pg_begin) {
+        Some(paddr) => paddr,
+        // not mapped, or killed as there's no memory to bring it back
+        None => exit(-1)
+    };
     unsafe { (paddr as *const u8).add(ptr - pg_begin) }
 }
 
 /// Get the `pos`th argument as a pointer from syscall, return kernel-space mutable pointer (involve security issues!)
-pub fn arg_ptr_mut(pgtable: &page::Table, tf: &TrapFrame, pos: usize, sz: usize) -> *mut u8 {
+pub fn arg_ptr_mut(pgtable: &PageTable, tf: &TrapFrame, pos: usize, sz: usize) -> *mut u8 {
     arg_ptr(pgtable, tf, pos, sz) as *mut u8
 }
 
 
-/// Get file corresponding to a file descriptor
-pub fn arg_fd(p: &Process, pos: usize) -> &Arc<File> {
+/// Get file corresponding to a file descriptor, `None` if it's not open
+pub fn arg_fd(p: &Process, pos: usize) -> Option<Arc<File>> {
     let fd = argraw(&p.trapframe, pos);
-    match &p.files[fd] {
-        Some(x) => return x,
-        None => panic!("invalid file handler {}", fd)
-    }
+    p.files.lock().get(fd).cloned()
 }
 
 /// fork syscall entry
@@ -91,6 +112,10 @@ fn sys_exec() -> i32 {
     let path;
     {
         let p = my_proc();
+        // other threads would lose their address space
+        if Arc::strong_count(&p.pgtable) > 1 {
+            return -1;
+        }
         let sz = arg_uint(&p.trapframe, 1);
         let ptr = arg_ptr(&p.pgtable, &p.trapframe, 0, sz);
         path = unsafe {
@@ -104,8 +129,7 @@ fn sys_exec() -> i32 {
         info!("running tests before init...");
         crate::test::run_tests();
     }
-    exec(path);
-    0
+    exec(path)
 }
 
 /// exit syscall entry
@@ -118,6 +142,128 @@ fn sys_exit() -> i32 {
     exit(code);
 }
 
+/// sbrk syscall entry
+fn sys_sbrk() -> i32 {
+    let increment;
+    {
+        let p = my_proc();
+        increment = argraw(&p.trapframe, 0) as isize;
+    }
+    sbrk(increment)
+}
+
+/// sched_setaffinity syscall entry
+fn sys_sched_setaffinity() -> i32 {
+    let (pid, mask);
+    {
+        let p = my_proc();
+        pid = arg_int(&p.trapframe, 0);
+        mask = argraw(&p.trapframe, 1);
+    }
+    set_affinity(pid, mask)
+}
+
+/// sched_getaffinity syscall entry
+fn sys_sched_getaffinity() -> i32 {
+    let pid;
+    {
+        let p = my_proc();
+        pid = arg_int(&p.trapframe, 0);
+    }
+    get_affinity(pid)
+}
+
+/// clone syscall entry
+fn sys_clone() -> i32 {
+    let (entry, arg0, arg1);
+    {
+        let p = my_proc();
+        entry = argraw(&p.trapframe, 0);
+        arg0 = argraw(&p.trapframe, 1);
+        arg1 = argraw(&p.trapframe, 2);
+    }
+    clone(entry, arg0, arg1)
+}
+
+/// join syscall entry
+fn sys_join() -> i32 {
+    let (tid, code_ptr);
+    {
+        let p = my_proc();
+        tid = arg_int(&p.trapframe, 0);
+        code_ptr = arg_ptr_mut(&p.pgtable, &p.trapframe, 1, core::mem::size_of::<i32>()) as *mut i32;
+    }
+    match join(tid) {
+        Some(code) => {
+            unsafe { code_ptr.write_unaligned(code); }
+            0
+        }
+        None => -1
+    }
+}
+
+/// futex syscall entry
+fn sys_futex() -> i32 {
+    let (addr, op, val);
+    {
+        let p = my_proc();
+        addr = argraw(&p.trapframe, 0);
+        op = arg_int(&p.trapframe, 1);
+        val = argraw(&p.trapframe, 2) as u32;
+    }
+    futex(addr, op, val)
+}
+
+/// kill syscall entry. `pid` > 0 is a process, `pid` < 0 is process group
+/// `-pid`, and `pid` = 0 is process group of caller.
+fn sys_kill() -> i32 {
+    let (pid, sig, me);
+    {
+        let p = my_proc();
+        pid = arg_int(&p.trapframe, 0);
+        sig = arg_uint(&p.trapframe, 1);
+        me = p.pid;
+    }
+    let sent = if pid > 0 {
+        signal::kill(pid, sig)
+    } else if pid < 0 {
+        signal::kill_group(-pid, sig)
+    } else {
+        signal::kill_group(signal::pgid(me), sig)
+    };
+    if sent { 0 } else { -1 }
+}
+
+/// setpgid syscall entry. `pid` = 0 is caller, and `pgid` = 0 is `pid`.
+fn sys_setpgid() -> i32 {
+    let (mut pid, mut pgid);
+    {
+        let p = my_proc();
+        pid = arg_int(&p.trapframe, 0);
+        pgid = arg_int(&p.trapframe, 1);
+        if pid == 0 {
+            pid = p.pid;
+        }
+    }
+    if pgid == 0 {
+        pgid = pid;
+    }
+    if signal::set_pgid(pid, pgid) { 0 } else { -1 }
+}
+
+/// getpgid syscall entry. `pid` = 0 is caller.
+fn sys_getpgid() -> i32 {
+    let pid;
+    {
+        let p = my_proc();
+        pid = match arg_int(&p.trapframe, 0) {
+            0 => p.pid,
+            pid => pid
+        };
+    }
+    if signal::exists(pid) { signal::pgid(pid) } else { -1 }
+}
+
 /// Process all syscall
 pub fn syscall() -> i32 {
     let syscall_id;
@@ -135,6 +281,31 @@ pub fn syscall() -> i32 {
         SYS_DUP => sys_dup(),
         SYS_OPEN => sys_open(),
         SYS_CLOSE => sys_close(),
+        SYS_UNLINK => sys_unlink(),
+        SYS_SCHED_SETAFFINITY => sys_sched_setaffinity(),
+        SYS_SCHED_GETAFFINITY => sys_sched_getaffinity(),
+        SYS_CLONE => sys_clone(),
+        SYS_JOIN => sys_join(),
+        SYS_FUTEX => sys_futex(),
+        SYS_DUP2 => sys_dup2(),
+        SYS_DUP3 => sys_dup3(),
+        SYS_FCNTL => sys_fcntl(),
+        SYS_SBRK => sys_sbrk(),
+        SYS_KILL => sys_kill(),
+        SYS_IOCTL => sys_ioctl(),
+        SYS_SETPGID => sys_setpgid(),
+        SYS_GETPGID => sys_getpgid(),
+        SYS_SOCKET => sys_socket(),
+        SYS_BIND => sys_bind(),
+        SYS_LISTEN => sys_listen(),
+        SYS_ACCEPT => sys_accept(),
+        SYS_CONNECT => sys_connect(),
+        SYS_SENDTO => sys_sendto(),
+        SYS_RECVFROM => sys_recvfrom(),
+        SYS_SENDMSG => sys_sendmsg(),
+        SYS_RECVMSG => sys_recvmsg(),
+        SYS_GETRLIMIT => sys_getrlimit(),
+        SYS_SETRLIMIT => sys_setrlimit(),
         _ => unreachable!()
     }
 }
@@ -6,12 +6,19 @@
 //! File-related syscalls
 
 use crate::process::my_proc;
-use crate::syscall::{arg_int, arg_uint, arg_ptr, arg_fd, arg_ptr_mut};
-use crate::file::{File, Console, FsFile};
+use crate::syscall::{argraw, arg_int, arg_uint, arg_ptr, arg_fd, arg_ptr_mut, ENXIO};
+use crate::file::{File, Console, MemInfo, FsFile, FD_CLOEXEC, O_CLOEXEC};
+use crate::file::fsfile::{self, Inode};
+use crate::file::socket::unix;
 use alloc::sync::Arc;
 use crate::spinlock::Mutex;
 use crate::symbols::PAGE_SIZE;
 use crate::virtio::BSIZE;
+use crate::tty::ioctl_size;
+use crate::tty::pty::{PtyMaster, PtySlave};
+
+/// Directory of pseudo-terminal slaves
+const PTS: &str = "/dev/pts/";
 
 /// write syscall
 pub fn sys_write() -> i32 {
@@ -22,10 +29,14 @@ pub fn sys_write() -> i32 {
     }
     let content = arg_ptr(&p.pgtable, &p.trapframe, 1, sz);
     let u8_slice = unsafe { core::slice::from_raw_parts(content, sz) };
-    let file = arg_fd(&p, 0);
-    match (*file).as_ref() {
+    let file = match arg_fd(&p, 0) {
+        Some(file) => file,
+        None => return -1
+    };
+    match file.as_ref() {
         File::Device(dev) => dev.write(u8_slice),
         File::FsFile(file) => file.write(u8_slice),
+        File::Socket(socket) => socket.write(u8_slice),
         _ => { unimplemented!(); }
     }
 }
@@ -39,61 +50,220 @@ pub fn sys_read() -> i32 {
     }
     let content = arg_ptr_mut(&p.pgtable, &p.trapframe, 1, sz);
     let u8_slice = unsafe { core::slice::from_raw_parts_mut(content, sz) };
-    let file = arg_fd(&p, 0);
-    match (*file).as_ref() {
+    let file = match arg_fd(&p, 0) {
+        Some(file) => file,
+        None => return -1
+    };
+    match file.as_ref() {
         File::Device(dev) => dev.read(u8_slice),
         File::FsFile(file) => file.read(u8_slice),
+        File::Socket(socket) => socket.read(u8_slice),
         _ => { unimplemented!(); }
     }
 }
 
-/// find a available file descriptor from files array in process
-fn next_available_fd<T>(files: &[Option<T>]) -> Option<usize> {
-    for i in 0..files.len() {
-        match files[i] {
-            None => { return Some(i); }
-            _ => { continue; }
-        }
-    }
-    return None;
-}
-
-/// open syscall, currently only support `/console` file.
+/// open syscall, supporting `/console`, `/proc/meminfo`, pseudo-terminals
+/// `/dev/ptmx` and `/dev/pts/N`, and files on disk.
+///
+/// `O_CLOEXEC` in `mode` sets `FD_CLOEXEC` on the new descriptor. Socket
+/// nodes can't be opened, which returns `ENXIO`.
 pub fn sys_open() -> i32 {
     let p = my_proc();
     let sz = arg_uint(&p.trapframe, 1);
     let mode = arg_uint(&p.trapframe, 2);
     let content = arg_ptr(&p.pgtable, &p.trapframe, 0, sz);
     let path = core::str::from_utf8(unsafe { core::slice::from_raw_parts(content, sz) }).unwrap();
-    let fd = match next_available_fd(&p.files) {
-        Some(fd) => fd,
-        None => { return -1; }
-    };
-    if path == "/console" {
-        p.files[fd] = Some(Arc::new(File::Device(box Console {})));
+    let file = if path == "/console" {
+        Arc::new(File::Device(box Console {}))
+    } else if path == "/proc/meminfo" {
+        Arc::new(File::Device(box MemInfo::new()))
+    } else if path == "/dev/ptmx" {
+        match PtyMaster::open() {
+            Some(master) => Arc::new(File::Device(box master)),
+            None => return -1
+        }
+    } else if path.starts_with(PTS) {
+        match path[PTS.len()..].parse().ok().and_then(PtySlave::open) {
+            Some(slave) => Arc::new(File::Device(box slave)),
+            None => return -1
+        }
+    } else if fsfile::lookup(path) == Some(Inode::Socket) {
+        return ENXIO;
     } else {
-        p.files[fd] = Some(Arc::new(File::FsFile(FsFile::open(path, mode))));
+        Arc::new(File::FsFile(FsFile::open(path, mode & !O_CLOEXEC)))
+    };
+    match p.files.lock().alloc(file, mode & O_CLOEXEC != 0) {
+        Some(fd) => fd as i32,
+        None => -1
+    }
+}
+
+/// unlink syscall. Only socket nodes can be removed.
+pub fn sys_unlink() -> i32 {
+    let p = my_proc();
+    let sz = arg_uint(&p.trapframe, 1);
+    let content = arg_ptr(&p.pgtable, &p.trapframe, 0, sz);
+    match core::str::from_utf8(unsafe { core::slice::from_raw_parts(content, sz) }) {
+        Ok(path) => unix::unlink(path),
+        Err(_) => -1
     }
-    return fd as i32;
 }
 
 /// close syscall
 pub fn sys_close() -> i32 {
     let p = my_proc();
-    let fd = arg_int(&p.trapframe, 0) as usize;
-    p.files[fd] = None;
-    0
+    let fd = arg_int(&p.trapframe, 0);
+    if fd < 0 {
+        return -1;
+    }
+    match p.files.lock().close(fd as usize) {
+        Some(_) => 0,
+        None => -1
+    }
 }
 
 /// dup syscall
 pub fn sys_dup() -> i32 {
     let p = my_proc();
-    let old_fd = arg_uint(&p.trapframe, 0);
-    let fd = match next_available_fd(&p.files) {
-        Some(fd) => fd,
-        None => { return -1; }
+    let file = match arg_fd(&p, 0) {
+        Some(file) => file,
+        None => return -1
     };
-    p.files[fd] = Some(p.files[old_fd].as_ref().unwrap().clone());
-    use crate::info;
-    fd as i32
+    match p.files.lock().alloc(file, false) {
+        Some(fd) => fd as i32,
+        None => -1
+    }
+}
+
+/// Duplicate `old_fd` to `new_fd`, closing `new_fd` first if it's open
+fn dup_to(old_fd: i32, new_fd: i32, cloexec: bool) -> i32 {
+    let p = my_proc();
+    if old_fd < 0 || new_fd < 0 {
+        return -1;
+    }
+    let mut files = p.files.lock();
+    let file = match files.get(old_fd as usize) {
+        Some(file) => file.clone(),
+        None => return -1
+    };
+    match files.install(new_fd as usize, file, cloexec) {
+        Some(()) => new_fd,
+        None => -1
+    }
+}
+
+/// dup2 syscall
+pub fn sys_dup2() -> i32 {
+    let p = my_proc();
+    let old_fd = arg_int(&p.trapframe, 0);
+    let new_fd = arg_int(&p.trapframe, 1);
+    if old_fd == new_fd {
+        // `FD_CLOEXEC` of `old_fd` is kept
+        return match p.files.lock().get(old_fd as usize) {
+            Some(_) => new_fd,
+            None => -1
+        };
+    }
+    dup_to(old_fd, new_fd, false)
+}
+
+/// dup3 syscall, `flags` may only contain `O_CLOEXEC`
+pub fn sys_dup3() -> i32 {
+    let p = my_proc();
+    let old_fd = arg_int(&p.trapframe, 0);
+    let new_fd = arg_int(&p.trapframe, 1);
+    let flags = argraw(&p.trapframe, 2);
+    if old_fd == new_fd || flags & !O_CLOEXEC != 0 {
+        return -1;
+    }
+    dup_to(old_fd, new_fd, flags & O_CLOEXEC != 0)
+}
+
+/// Duplicate to lowest free descriptor not less than `arg`
+pub const F_DUPFD: i32 = 0;
+/// Get descriptor flags
+pub const F_GETFD: i32 = 1;
+/// Set descriptor flags
+pub const F_SETFD: i32 = 2;
+/// Same as `F_DUPFD`, and set `FD_CLOEXEC` on new descriptor
+pub const F_DUPFD_CLOEXEC: i32 = 1030;
+
+/// Resource of `getrlimit` and `setrlimit`, number of file descriptors
+pub const RLIMIT_NOFILE: i32 = 7;
+
+/// getrlimit syscall. Returns limit of `resource`, of which only
+/// `RLIMIT_NOFILE` is supported.
+pub fn sys_getrlimit() -> i32 {
+    let p = my_proc();
+    match arg_int(&p.trapframe, 0) {
+        RLIMIT_NOFILE => p.files.lock().limit() as i32,
+        _ => -1
+    }
+}
+
+/// setrlimit syscall. Sets limit of `resource` to `limit`. Descriptors
+/// can't be raised above `NOFILE`, nor lowered below an open descriptor.
+pub fn sys_setrlimit() -> i32 {
+    let p = my_proc();
+    let limit = argraw(&p.trapframe, 1);
+    match arg_int(&p.trapframe, 0) {
+        RLIMIT_NOFILE => match p.files.lock().set_limit(limit) {
+            Some(()) => 0,
+            None => -1
+        },
+        _ => -1
+    }
+}
+
+/// fcntl syscall
+pub fn sys_fcntl() -> i32 {
+    let p = my_proc();
+    let fd = arg_int(&p.trapframe, 0);
+    let cmd = arg_int(&p.trapframe, 1);
+    let arg = argraw(&p.trapframe, 2);
+    if fd < 0 {
+        return -1;
+    }
+    let fd = fd as usize;
+    let mut files = p.files.lock();
+    let file = match files.get(fd) {
+        Some(file) => file.clone(),
+        None => return -1
+    };
+    match cmd {
+        F_DUPFD | F_DUPFD_CLOEXEC => {
+            match files.alloc_from(arg, file, cmd == F_DUPFD_CLOEXEC) {
+                Some(fd) => fd as i32,
+                None => -1
+            }
+        }
+        F_GETFD => {
+            if files.cloexec(fd) == Some(true) { FD_CLOEXEC } else { 0 }
+        }
+        F_SETFD => {
+            files.set_cloexec(fd, arg as i32 & FD_CLOEXEC != 0);
+            0
+        }
+        _ => -1
+    }
+}
+
+/// ioctl syscall, only terminal commands on console and pseudo-terminals
+/// are supported
+pub fn sys_ioctl() -> i32 {
+    let p = my_proc();
+    let cmd = arg_uint(&p.trapframe, 1) as u32;
+    let size = match ioctl_size(cmd) {
+        Some(size) => size,
+        None => return -1
+    };
+    let arg = arg_ptr_mut(&p.pgtable, &p.trapframe, 2, size);
+    let file = match arg_fd(&p, 0) {
+        Some(file) => file,
+        None => return -1
+    };
+    match file.as_ref() {
+        File::Device(dev) => dev.ioctl(cmd, arg),
+        _ => -1
+    }
 }
@@ -43,3 +43,47 @@ pub const SYS_SBRK : i64 = 18;
 pub const SYS_SLEEP : i64 = 19;
 /// `20`: uptime
 pub const SYS_UPTIME : i64 = 20;
+/// `21`: sched_setaffinity
+pub const SYS_SCHED_SETAFFINITY : i64 = 21;
+/// `22`: sched_getaffinity
+pub const SYS_SCHED_GETAFFINITY : i64 = 22;
+/// `23`: clone
+pub const SYS_CLONE : i64 = 23;
+/// `24`: join
+pub const SYS_JOIN : i64 = 24;
+/// `25`: futex
+pub const SYS_FUTEX : i64 = 25;
+/// `26`: dup2
+pub const SYS_DUP2 : i64 = 26;
+/// `27`: dup3
+pub const SYS_DUP3 : i64 = 27;
+/// `28`: fcntl
+pub const SYS_FCNTL : i64 = 28;
+/// `29`: ioctl
+pub const SYS_IOCTL : i64 = 29;
+/// `30`: setpgid
+pub const SYS_SETPGID : i64 = 30;
+/// `31`: getpgid
+pub const SYS_GETPGID : i64 = 31;
+/// `32`: socket
+pub const SYS_SOCKET : i64 = 32;
+/// `33`: bind
+pub const SYS_BIND : i64 = 33;
+/// `34`: listen
+pub const SYS_LISTEN : i64 = 34;
+/// `35`: accept
+pub const SYS_ACCEPT : i64 = 35;
+/// `36`: connect
+pub const SYS_CONNECT : i64 = 36;
+/// `37`: sendto
+pub const SYS_SENDTO : i64 = 37;
+/// `38`: recvfrom
+pub const SYS_RECVFROM : i64 = 38;
+/// `39`: sendmsg
+pub const SYS_SENDMSG : i64 = 39;
+/// `40`: recvmsg
+pub const SYS_RECVMSG : i64 = 40;
+/// `41`: getrlimit
+pub const SYS_GETRLIMIT : i64 = 41;
+/// `42`: setrlimit
+pub const SYS_SETRLIMIT : i64 = 42;
@@ -0,0 +1,242 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Socket syscalls
+//!
+//! Addresses are passed as pointers to `SockAddrIn` or `SockAddrUn`, each
+//! followed by length of buffer, and a null pointer means there's no
+//! address. Files are passed as arrays of file descriptors with length.
+
+use crate::process::{my_proc, Process};
+use crate::syscall::{argraw, arg_int, arg_uint, arg_ptr, arg_ptr_mut, arg_fd};
+use crate::file::File;
+use crate::file::socket::{Socket, SockAddr, SockAddrIn, SockAddrUn, SOMAXCONN, AF_INET, AF_UNIX};
+use crate::file::socket::unix::MAX_FDS;
+use crate::virtio::BSIZE;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+/// Get socket corresponding to a file descriptor, `None` if it's not an
+/// open socket
+fn arg_socket(p: &Process, pos: usize) -> Option<Arc<File>> {
+    arg_fd(p, pos).filter(|file| match file.as_ref() {
+        File::Socket(_) => true,
+        _ => false
+    })
+}
+
+/// Get the `pos`th argument as length of data, clamped to `BSIZE`, so that
+/// a syscall on more returns a short count
+fn arg_len(p: &Process, pos: usize) -> usize {
+    core::cmp::min(arg_uint(&p.trapframe, pos), BSIZE)
+}
+
+/// Get the `pos`th argument as socket address with length of the next one,
+/// `None` if it's null, or too short for its family
+fn arg_addr(p: &Process, pos: usize) -> Option<SockAddr> {
+    if argraw(&p.trapframe, pos) == 0 {
+        return None;
+    }
+    let len = arg_uint(&p.trapframe, pos + 1);
+    if len < size_of::<u16>() {
+        return None;
+    }
+    let ptr = arg_ptr(&p.pgtable, &p.trapframe, pos, len);
+    let family = unsafe { (ptr as *const u16).read_unaligned() } as i32;
+    match family {
+        AF_INET if len >= size_of::<SockAddrIn>() => Some(SockAddr::In(unsafe { (ptr as *const SockAddrIn).read_unaligned() })),
+        AF_UNIX if len >= size_of::<SockAddrUn>() => Some(SockAddr::Un(unsafe { (ptr as *const SockAddrUn).read_unaligned() })),
+        _ => None
+    }
+}
+
+/// Write `addr` to address pointed by the `pos`th argument if it isn't
+/// null, truncated to length of the next one
+fn put_addr(p: &Process, pos: usize, addr: SockAddr) {
+    if argraw(&p.trapframe, pos) == 0 {
+        return;
+    }
+    let bytes = addr.as_bytes();
+    let len = core::cmp::min(arg_uint(&p.trapframe, pos + 1), bytes.len());
+    let ptr = arg_ptr_mut(&p.pgtable, &p.trapframe, pos, len);
+    unsafe { core::slice::from_raw_parts_mut(ptr, len) }.copy_from_slice(&bytes[..len]);
+}
+
+/// Get `pos`th argument as array of file descriptors with length of the next
+/// one, `None` if any of them isn't open
+fn arg_files(p: &Process, pos: usize) -> Option<Vec<Arc<File>>> {
+    let n = arg_uint(&p.trapframe, pos + 1);
+    if n == 0 {
+        return Some(Vec::new());
+    }
+    if n > MAX_FDS {
+        return None;
+    }
+    let ptr = arg_ptr(&p.pgtable, &p.trapframe, pos, n * size_of::<i32>()) as *const i32;
+    let fds = unsafe { core::slice::from_raw_parts(ptr, n) };
+    let files = p.files.lock();
+    fds.iter().map(|&fd| files.get(fd as usize).cloned()).collect()
+}
+
+/// Install `files` and store their descriptors in array pointed by `pos`th
+/// argument with length of the next one. Slots left are filled with -1,
+/// and files not fitting are closed.
+fn put_files(p: &Process, pos: usize, files: Vec<Arc<File>>) {
+    let n = arg_uint(&p.trapframe, pos + 1);
+    if n == 0 {
+        return;
+    }
+    let ptr = arg_ptr_mut(&p.pgtable, &p.trapframe, pos, n * size_of::<i32>()) as *mut i32;
+    let fds = unsafe { core::slice::from_raw_parts_mut(ptr, n) };
+    let mut table = p.files.lock();
+    let mut files = files.into_iter();
+    for fd in fds.iter_mut() {
+        *fd = files.next().and_then(|file| table.alloc(file, false)).map_or(-1, |fd| fd as i32);
+    }
+}
+
+fn as_socket(file: &File) -> &Socket {
+    match file {
+        File::Socket(socket) => socket,
+        _ => unreachable!()
+    }
+}
+
+/// socket syscall, only `AF_INET` and `AF_UNIX` domain with `SOCK_STREAM`
+/// and `SOCK_DGRAM` is supported, and protocol is ignored
+pub fn sys_socket() -> i32 {
+    let p = my_proc();
+    let domain = arg_int(&p.trapframe, 0);
+    let ty = arg_int(&p.trapframe, 1);
+    let socket = match Socket::new(domain, ty) {
+        Some(socket) => socket,
+        None => return -1
+    };
+    match p.files.lock().alloc(Arc::new(File::Socket(socket)), false) {
+        Some(fd) => fd as i32,
+        None => -1
+    }
+}
+
+/// bind syscall
+pub fn sys_bind() -> i32 {
+    let p = my_proc();
+    let (file, addr) = match (arg_socket(&p, 0), arg_addr(&p, 1)) {
+        (Some(file), Some(addr)) => (file, addr),
+        _ => return -1
+    };
+    as_socket(&file).bind(&addr)
+}
+
+/// listen syscall, `backlog` <= 0 means `SOMAXCONN`
+pub fn sys_listen() -> i32 {
+    let p = my_proc();
+    let backlog = match arg_int(&p.trapframe, 1) {
+        backlog if backlog <= 0 => SOMAXCONN,
+        backlog => core::cmp::min(backlog as usize, SOMAXCONN)
+    };
+    match arg_socket(&p, 0) {
+        Some(file) => as_socket(&file).listen(backlog),
+        None => -1
+    }
+}
+
+/// accept syscall, storing address of peer if pointer isn't null
+pub fn sys_accept() -> i32 {
+    let p = my_proc();
+    let file = match arg_socket(&p, 0) {
+        Some(file) => file,
+        None => return -1
+    };
+    let (socket, addr) = match as_socket(&file).accept() {
+        Ok(accepted) => accepted,
+        Err(err) => return err
+    };
+    put_addr(&p, 1, addr);
+    match p.files.lock().alloc(Arc::new(File::Socket(socket)), false) {
+        Some(fd) => fd as i32,
+        None => -1
+    }
+}
+
+/// connect syscall
+pub fn sys_connect() -> i32 {
+    let p = my_proc();
+    let (file, addr) = match (arg_socket(&p, 0), arg_addr(&p, 1)) {
+        (Some(file), Some(addr)) => (file, addr),
+        _ => return -1
+    };
+    as_socket(&file).connect(&addr)
+}
+
+/// sendto syscall, sending to peer if address is null
+pub fn sys_sendto() -> i32 {
+    let p = my_proc();
+    let sz = arg_len(&p, 2);
+    let content = arg_ptr(&p.pgtable, &p.trapframe, 1, sz);
+    let u8_slice = unsafe { core::slice::from_raw_parts(content, sz) };
+    let addr = match (argraw(&p.trapframe, 3), arg_addr(&p, 3)) {
+        (0, _) => None,
+        (_, Some(addr)) => Some(addr),
+        (_, None) => return -1
+    };
+    match arg_socket(&p, 0) {
+        Some(file) => as_socket(&file).send_msg(u8_slice, Vec::new(), addr.as_ref()),
+        None => -1
+    }
+}
+
+/// recvfrom syscall, storing address of sender if pointer isn't null.
+/// Files passed are closed.
+pub fn sys_recvfrom() -> i32 {
+    let p = my_proc();
+    let sz = arg_len(&p, 2);
+    let content = arg_ptr_mut(&p.pgtable, &p.trapframe, 1, sz);
+    let u8_slice = unsafe { core::slice::from_raw_parts_mut(content, sz) };
+    let file = match arg_socket(&p, 0) {
+        Some(file) => file,
+        None => return -1
+    };
+    match as_socket(&file).recv_msg(u8_slice) {
+        Ok((n, _, addr)) => {
+            put_addr(&p, 3, addr);
+            n as i32
+        }
+        Err(err) => err
+    }
+}
+
+/// sendmsg syscall, sending data and files of descriptors to peer
+pub fn sys_sendmsg() -> i32 {
+    let p = my_proc();
+    let sz = arg_len(&p, 2);
+    let content = arg_ptr(&p.pgtable, &p.trapframe, 1, sz);
+    let u8_slice = unsafe { core::slice::from_raw_parts(content, sz) };
+    let (file, files) = match (arg_socket(&p, 0), arg_files(&p, 3)) {
+        (Some(file), Some(files)) => (file, files),
+        _ => return -1
+    };
+    as_socket(&file).send_msg(u8_slice, files, None)
+}
+
+/// recvmsg syscall, installing files passed as new descriptors
+pub fn sys_recvmsg() -> i32 {
+    let p = my_proc();
+    let sz = arg_len(&p, 2);
+    let content = arg_ptr_mut(&p.pgtable, &p.trapframe, 1, sz);
+    let u8_slice = unsafe { core::slice::from_raw_parts_mut(content, sz) };
+    let file = match arg_socket(&p, 0) {
+        Some(file) => file,
+        None => return -1
+    };
+    match as_socket(&file).recv_msg(u8_slice) {
+        Ok((n, files, _)) => {
+            put_files(&p, 3, files);
+            n as i32
+        }
+        Err(err) => err
+    }
+}
@@ -14,7 +14,34 @@ type TestSuite = fn() -> &'static [(&'static str, fn())];
 pub fn run_tests() {
     let suites = [
         ("virtio", crate::virtio::tests::tests as TestSuite),
-        ("fsfile", crate::file::fsfile::tests::tests as TestSuite)];
+        ("virtio queue", crate::virtio::queue::tests::tests as TestSuite),
+        ("net", crate::net::tests::tests as TestSuite),
+        ("arp", crate::net::arp::tests::tests as TestSuite),
+        ("ipv4", crate::net::ipv4::tests::tests as TestSuite),
+        ("icmp", crate::net::icmp::tests::tests as TestSuite),
+        ("udp", crate::net::udp::tests::tests as TestSuite),
+        ("tcp", crate::net::tcp::tests::tests as TestSuite),
+        ("loopback", crate::net::loopback::tests::tests as TestSuite),
+        ("unix socket", crate::file::socket::unix::tests::tests as TestSuite),
+        ("tty", crate::tty::tests::tests as TestSuite),
+        ("pty", crate::tty::pty::tests::tests as TestSuite),
+        ("fdt", crate::fdt::tests::tests as TestSuite),
+        ("executor", crate::executor::tests::tests as TestSuite),
+        ("buddy", crate::mem::buddy::tests::tests as TestSuite),
+        ("slab", crate::mem::slab::tests::tests as TestSuite),
+        ("memstat", crate::mem::stat::tests::tests as TestSuite),
+        ("page", crate::page::tests::tests as TestSuite),
+        ("asid", crate::mem::asid::tests::tests as TestSuite),
+        ("swap", crate::mem::swap::tests::tests as TestSuite),
+        ("oom", crate::mem::oom::tests::tests as TestSuite),
+        ("tlb", crate::mem::tlb::tests::tests as TestSuite),
+        ("schedule", crate::process::schedule::tests::tests as TestSuite),
+        ("process", crate::process::process::tests::tests as TestSuite),
+        ("futex", crate::process::futex::tests::tests as TestSuite),
+        ("kstack", crate::process::kstack::tests::tests as TestSuite),
+        ("signal", crate::process::signal::tests::tests as TestSuite),
+        ("fsfile", crate::file::fsfile::tests::tests as TestSuite),
+        ("fdtable", crate::file::fdtable::tests::tests as TestSuite)];
     for (name, suite) in &suites {
         let tests = suite();
         info!("  {}", name);
@@ -5,7 +5,7 @@
 
 //! Machine mode and supervisor mode traps
 
-use crate::{println, print, info, panic};
+use crate::{println, print, info, warn, panic};
 use crate::process::{TrapFrame, self, Process, CPU, my_proc, my_cpu, yield_cpu};
 use crate::arch;
 use crate::symbols::*;
@@ -16,8 +16,18 @@ use crate::process::Register::a0;
 use crate::arch::{hart_id, sp};
 use crate::jump::*;
 use crate::intr::devintr;
+use crate::mem::swap;
 use crate::intr::Intr::Timer;
 
+/// Called by `kernelvec` on boot stack of current hart when kernel stack
+/// of a process overflows into its guard pages
+#[no_mangle]
+extern "C" fn kstack_overflow(sp: usize) -> ! {
+    use riscv::register;
+    panic!("kernel stack overflow CPU#{}, pid {} -> epc 0x{:08x}, sp 0x{:08x}",
+           arch::hart_id(), process::kstack_owner(sp).unwrap(), register::sepc::read(), sp);
+}
+
 /// Process interrupt from supervisor mode
 #[no_mangle]
 extern "C" fn kerneltrap() {
@@ -74,6 +84,12 @@ extern "C" fn kerneltrap() {
                 panic!("E-call from Machine mode! CPU#{} -> 0x{:08x}\n", hart, epc);
             }
             // Page faults
+            12 | 13 | 15 if process::is_kstack_guard(tval) => {
+                panic!(
+                    "kernel stack overflow CPU#{}, pid {} -> 0x{:08x}: 0x{:08x}",
+                    hart, process::kstack_owner(tval).unwrap(), epc, tval
+                );
+            }
             12 => {
                 // Instruction page fault
                 panic!(
@@ -123,6 +139,7 @@ pub extern "C" fn usertrap() -> ! {
     if sstatus::read().spp() != sstatus::SPP::User {
         panic!("not from user mode");
     }
+    crate::mem::tlb::leave_user();
     unsafe {
         stvec::write(kernelvec as usize, stvec::TrapMode::Direct);
     }
@@ -134,11 +151,41 @@ pub extern "C" fn usertrap() -> ! {
     if scause == 8 {
         p.trapframe.epc += 4;
         arch::intr_on();
+        swap::balance();
+        if p.pgtable.lock().killed {
+            process::exit(-1);
+        }
         p.trapframe.regs[a0 as usize] = syscall::syscall() as usize;
+        swap::unpin(p);
     } else {
         intr = devintr();
         match intr {
-            None => panic!("unexpected scause {:x}", scause),
+            None => {
+                let stval = stval::read();
+                let page_fault = scause == 12 || scause == 13 || scause == 15;
+                if page_fault && process::is_user_stack_guard(stval) {
+                    warn!("user stack overflow CPU#{}, pid {} -> 0x{:08x}: 0x{:08x}",
+                          arch::hart_id(), p.pid, p.trapframe.epc, stval);
+                    process::exit(-1);
+                }
+                if !page_fault {
+                    panic!("unexpected scause {:x}", scause)
+                }
+                // bring page back from swap, which sleeps on disk
+                arch::intr_on();
+                swap::balance();
+                let pgtable = p.pgtable.clone();
+                let resolved = swap::swap_in(&pgtable, stval);
+                let killed = pgtable.lock().killed;
+                drop(pgtable);
+                if !resolved {
+                    if !killed {
+                        warn!("segmentation fault CPU#{}, pid {} -> 0x{:08x}: 0x{:08x}",
+                              arch::hart_id(), p.pid, p.trapframe.epc, stval);
+                    }
+                    process::exit(-1);
+                }
+            }
             _ => ()
         }
     }
@@ -147,6 +194,12 @@ pub extern "C" fn usertrap() -> ! {
         yield_cpu();
     }
 
+    // killed by OOM killer
+    if p.pgtable.lock().killed {
+        process::exit(-1);
+    }
+    process::signal::handle(p);
+
     usertrapret();
 }
 
@@ -166,7 +219,7 @@ fn trampoline_userret(tf: usize, satp_val: usize) -> ! {
 /// should be wrapped in brackets so that all objects are
 /// dropped before jumping to trampoline.
 pub fn usertrapret() -> ! {
-    let satp_val: usize;
+    let (satp_val, trapframe): (usize, usize);
     {
         use riscv::register::*;
         arch::intr_off();
@@ -199,13 +252,15 @@ pub fn usertrapret() -> ! {
         sepc::write(p.trapframe.epc);
 
         // tell trampoline.S the user page table to switch to.
-        let root_ppn = &mut *p.pgtable as *mut page::Table as usize;
-        satp_val = crate::arch::build_satp(8, 0, root_ppn);
+        satp_val = p.pgtable.lock().satp();
+        // flush translations other harts asked for while we were away
+        crate::mem::tlb::enter_user();
+        trapframe = TRAPFRAME(p.pid as usize);
     }
     // jump to trampoline.S at the top of memory, which
     // switches to the user page table, restores user registers,
     // and switches to user mode with sret.
-    trampoline_userret(TRAPFRAME_START, satp_val)
+    trampoline_userret(trapframe, satp_val)
 }
 
 /// Initialize supervisor-mode trap
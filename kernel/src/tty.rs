@@ -0,0 +1,511 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Terminal line discipline
+//!
+//! `Tty` turns characters received from a terminal into input for readers,
+//! according to a subset of POSIX termios settings. In canonical mode,
+//! input is edited line by line:
+//!
+//! * `VERASE`, backspace or delete: erase last character
+//! * `VKILL` (`^U`): erase current line
+//! * `VEOF` (`^D`): end of file, which makes `read` return what's typed so
+//!   far, or 0 at the beginning of a line
+//!
+//! In raw mode, characters are available as soon as they arrive, and `read`
+//! waits according to `VMIN` and `VTIME`. With `ISIG`, `VINTR` (`^C`),
+//! `VQUIT` (`^\`) and `VSUSP` (`^Z`) send signals to foreground process
+//! group instead.
+//!
+//! `tty_input`, `tty_read` and `tty_ioctl` drive a `Tty` behind a lock, for
+//! console and pseudo-terminals. Readers waiting with `VTIME` are woken up
+//! on timer `tick`, so that they can check whether the timer runs out.
+
+pub mod pty;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::process::signal::{self, SIGINT, SIGQUIT, SIGTSTP};
+use crate::process::{my_proc, sleep, wakeup};
+use crate::spinlock::Mutex;
+use crate::syscall::EINTR;
+use crate::arch;
+
+/// Size of input ring buffer
+pub const INPUT_BUF: usize = 128;
+
+/// Number of control characters
+pub const NCCS: usize = 16;
+
+/// Indices of control characters, same as Linux
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const VSUSP: usize = 10;
+
+/// Input flag: translate carriage return to newline
+pub const ICRNL: u32 = 0o400;
+
+/// Local flag: generate signals on `VINTR`, `VQUIT` and `VSUSP`
+pub const ISIG: u32 = 0o1;
+/// Local flag: canonical mode
+pub const ICANON: u32 = 0o2;
+/// Local flag: echo input
+pub const ECHO: u32 = 0o10;
+
+/// `ioctl` commands, same as Linux
+pub const TCGETS: u32 = 0x5401;
+pub const TCSETS: u32 = 0x5402;
+pub const TIOCGPGRP: u32 = 0x540f;
+pub const TIOCSPGRP: u32 = 0x5410;
+/// Get index of pseudo-terminal, only on master side
+pub const TIOCGPTN: u32 = 0x80045430;
+
+pub const BACKSPACE: u8 = 8;
+const DELETE: u8 = 127;
+
+/// Control-x
+pub const fn ctrl(x: u8) -> u8 {
+    x - b'@'
+}
+
+/// Terminal settings, exchanged with user space by `TCGETS` and `TCSETS`
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Termios {
+    pub iflag: u32,
+    pub lflag: u32,
+    /// Control characters, 0 if disabled
+    pub cc: [u8; NCCS],
+}
+
+impl Termios {
+    /// Canonical mode with echo and signals
+    pub const fn new() -> Self {
+        Self {
+            iflag: ICRNL,
+            lflag: ISIG | ICANON | ECHO,
+            cc: [
+                ctrl(b'C'), ctrl(b'\\'), DELETE, ctrl(b'U'),
+                ctrl(b'D'), 0, 1, 0,
+                0, 0, ctrl(b'Z'), 0,
+                0, 0, 0, 0,
+            ],
+        }
+    }
+
+    fn has(&self, lflag: u32) -> bool {
+        self.lflag & lflag != 0
+    }
+
+    /// Whether `c` is control character `idx`
+    fn is(&self, c: u8, idx: usize) -> bool {
+        self.cc[idx] != 0 && self.cc[idx] == c
+    }
+}
+
+/// `ioctl` argument size of terminal command `cmd`, `None` if unknown
+pub fn ioctl_size(cmd: u32) -> Option<usize> {
+    match cmd {
+        TCGETS | TCSETS => Some(core::mem::size_of::<Termios>()),
+        TIOCGPGRP | TIOCSPGRP | TIOCGPTN => Some(core::mem::size_of::<i32>()),
+        _ => None
+    }
+}
+
+/// Result of a character received
+#[derive(PartialEq, Debug)]
+pub enum Received {
+    Nothing,
+    /// Input is ready, readers should be woken up
+    Ready,
+    /// Signal should be sent to foreground process group
+    Signal(usize),
+}
+
+/// Line discipline of a terminal
+///
+/// Indices of input ring buffer wrap around at `INPUT_BUF`. `buf[r..w]` is
+/// input ready to be read, and `buf[w..e]` is the line being edited.
+pub struct Tty {
+    buf: [u8; INPUT_BUF],
+    /// Read index
+    r: usize,
+    /// Write index
+    w: usize,
+    /// Edit index
+    e: usize,
+    termios: Termios,
+    /// Foreground process group
+    pub fg: i32,
+    /// Whether terminal is gone, after which `read` doesn't wait
+    hung_up: bool,
+}
+
+impl Tty {
+    pub const fn new() -> Self {
+        Self { buf: [0; INPUT_BUF], r: 0, w: 0, e: 0, termios: Termios::new(), fg: 0, hung_up: false }
+    }
+
+    pub fn termios(&self) -> Termios {
+        self.termios
+    }
+
+    /// Change settings. Line being edited becomes ready when leaving
+    /// canonical mode.
+    pub fn set_termios(&mut self, termios: Termios) {
+        self.termios = termios;
+        if !termios.has(ICANON) {
+            self.w = self.e;
+        }
+    }
+
+    /// Erase last character of line being edited. Returns false if line is empty.
+    fn erase(&mut self) -> bool {
+        if self.e != self.w {
+            self.e -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Handle a character received from terminal, calling `echo` with
+    /// characters to be echoed
+    pub fn input(&mut self, c: u8, echo: &mut dyn FnMut(u8)) -> Received {
+        let t = self.termios;
+        let c = if t.iflag & ICRNL != 0 && c == b'\r' { b'\n' } else { c };
+        if t.has(ISIG) {
+            let sig = if t.is(c, VINTR) {
+                Some(SIGINT)
+            } else if t.is(c, VQUIT) {
+                Some(SIGQUIT)
+            } else if t.is(c, VSUSP) {
+                Some(SIGTSTP)
+            } else {
+                None
+            };
+            if let Some(sig) = sig {
+                if t.has(ECHO) {
+                    echo(b'^');
+                    echo(c + b'@');
+                    echo(b'\n');
+                }
+                // discard input
+                self.w = self.r;
+                self.e = self.r;
+                return Received::Signal(sig);
+            }
+        }
+        if t.has(ICANON) {
+            if t.is(c, VKILL) {
+                while self.erase() {
+                    if t.has(ECHO) {
+                        echo(BACKSPACE);
+                    }
+                }
+                return Received::Nothing;
+            }
+            if t.is(c, VERASE) || c == BACKSPACE || c == DELETE {
+                if self.erase() && t.has(ECHO) {
+                    echo(BACKSPACE);
+                }
+                return Received::Nothing;
+            }
+        }
+        if self.full() {
+            // buffer full, drop character
+            return Received::Nothing;
+        }
+        self.buf[self.e % INPUT_BUF] = c;
+        self.e += 1;
+        let eof = t.has(ICANON) && t.is(c, VEOF);
+        if t.has(ECHO) && !eof {
+            echo(c);
+        }
+        if !t.has(ICANON) || c == b'\n' || eof || self.e == self.r + INPUT_BUF {
+            self.w = self.e;
+            Received::Ready
+        } else {
+            Received::Nothing
+        }
+    }
+
+    /// Number of characters ready to be read
+    pub fn available(&self) -> usize {
+        self.w - self.r
+    }
+
+    /// Make `read` return 0 after input ready is read
+    pub fn hang_up(&mut self) {
+        self.hung_up = true;
+    }
+
+    /// Whether input buffer is full, and characters received are dropped
+    pub fn full(&self) -> bool {
+        self.e - self.r >= INPUT_BUF
+    }
+
+    /// Whether a `read` of `len` bytes, waiting since `waited` tenths of a
+    /// second, should return now
+    pub fn can_read(&self, len: usize, waited: usize) -> bool {
+        let t = self.termios;
+        if self.hung_up {
+            return true;
+        }
+        if t.has(ICANON) {
+            return self.available() > 0;
+        }
+        let (min, time) = (t.cc[VMIN] as usize, t.cc[VTIME] as usize);
+        if self.available() >= core::cmp::min(min, len) && (min > 0 || time == 0) {
+            return true;
+        }
+        // timer runs out, return what's read
+        time > 0 && waited >= time && (min == 0 || self.available() > 0)
+    }
+
+    /// Whether `read` should be woken up by timer
+    pub fn timed(&self) -> bool {
+        !self.termios.has(ICANON) && self.termios.cc[VTIME] != 0
+    }
+
+    /// Read input ready into `dst`. In canonical mode, it stops after a
+    /// newline or at `VEOF`. Returns number of characters read.
+    pub fn read(&mut self, dst: &mut [u8]) -> usize {
+        let t = self.termios;
+        let mut n = 0;
+        while n < dst.len() && self.available() > 0 {
+            let c = self.buf[self.r % INPUT_BUF];
+            self.r += 1;
+            if t.has(ICANON) && t.is(c, VEOF) {
+                if n > 0 {
+                    // keep it for next read, so that it returns 0
+                    self.r -= 1;
+                }
+                break;
+            }
+            dst[n] = c;
+            n += 1;
+            if t.has(ICANON) && c == b'\n' {
+                break;
+            }
+        }
+        n
+    }
+
+    /// Handle terminal `ioctl` except for foreground process group, which
+    /// is done by caller. `arg` is a kernel pointer to argument of size
+    /// `ioctl_size(cmd)`.
+    pub fn ioctl(&mut self, cmd: u32, arg: *mut u8) -> i32 {
+        match cmd {
+            TCGETS => unsafe { (arg as *mut Termios).write_unaligned(self.termios) },
+            TCSETS => self.set_termios(unsafe { (arg as *const Termios).read_unaligned() }),
+            TIOCGPGRP => unsafe { (arg as *mut i32).write_unaligned(self.fg) },
+            TIOCSPGRP => {
+                let pgid = unsafe { (arg as *const i32).read_unaligned() };
+                if pgid < 0 {
+                    return -1;
+                }
+                self.fg = pgid;
+            }
+            _ => return -1
+        }
+        0
+    }
+}
+
+/// Number of readers waiting with `VTIME`
+static TIMED_READERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Wake up readers of `tty`, including timed readers which sleep on
+/// `TIMED_READERS`
+fn wake_readers(tty: &Mutex<Tty>) {
+    wakeup(tty);
+    if TIMED_READERS.load(Ordering::Relaxed) != 0 {
+        wakeup(&TIMED_READERS);
+    }
+}
+
+/// Wake up readers waiting with `VTIME`. Called on timer interrupt.
+pub fn tick() {
+    if TIMED_READERS.load(Ordering::Relaxed) != 0 {
+        wakeup(&TIMED_READERS);
+    }
+}
+
+/// Handle a character received by `tty`, waking up readers, and sending
+/// signal to its foreground process group if any
+pub fn tty_input(tty: &Mutex<Tty>, c: u8, echo: &mut dyn FnMut(u8)) {
+    let (received, fg) = {
+        let mut t = tty.lock();
+        let received = t.input(c, echo);
+        if received == Received::Ready {
+            wake_readers(tty);
+        }
+        (received, t.fg)
+    };
+    if let Received::Signal(sig) = received {
+        signal::kill_group(fg, sig);
+    }
+}
+
+/// Read from `tty` into `dst`, sleeping until input is ready according to
+/// termios settings. Returns number of characters read, 0 at end of file,
+/// or `EINTR` if a signal is pending.
+///
+/// `VTIME` is counted from the beginning of `read` instead of between
+/// characters.
+pub fn tty_read(tty: &Mutex<Tty>, dst: &mut [u8]) -> i32 {
+    if dst.is_empty() {
+        return 0;
+    }
+    let pid = my_proc().pid;
+    let start = arch::time();
+    let mut t = tty.lock();
+    loop {
+        let waited = ((arch::time() - start).as_millis() / 100) as usize;
+        if t.can_read(dst.len(), waited) {
+            break;
+        }
+        if signal::interrupted(pid) {
+            return EINTR;
+        }
+        if t.timed() {
+            TIMED_READERS.fetch_add(1, Ordering::Relaxed);
+            t = sleep(&TIMED_READERS, t);
+            TIMED_READERS.fetch_sub(1, Ordering::Relaxed);
+        } else {
+            t = sleep(tty, t);
+        }
+    }
+    let n = t.read(dst);
+    // writers may wait for room
+    wakeup(tty);
+    n as i32
+}
+
+/// `ioctl` on `tty`, see `Tty::ioctl`
+pub fn tty_ioctl(tty: &Mutex<Tty>, cmd: u32, arg: *mut u8) -> i32 {
+    let mut t = tty.lock();
+    let result = t.ioctl(cmd, arg);
+    if cmd == TCSETS {
+        // readers may be satisfied with new settings
+        wake_readers(tty);
+    }
+    result
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("line editing", test_line_editing),
+            ("eof", test_eof),
+            ("full", test_full),
+            ("signal", test_signal),
+            ("raw", test_raw),
+        ]
+    }
+
+    fn type_in(tty: &mut Tty, s: &[u8]) -> Received {
+        let mut received = Received::Nothing;
+        for &c in s {
+            received = tty.input(c, &mut |_| {});
+        }
+        received
+    }
+
+    pub fn test_line_editing() {
+        let mut tty = Tty::new();
+        assert_eq!(type_in(&mut tty, b"lx\x08s"), Received::Nothing);
+        assert_eq!(tty.available(), 0);
+        assert_eq!(type_in(&mut tty, b"\r"), Received::Ready);
+        assert_eq!(type_in(&mut tty, b"junk\x15"), Received::Nothing);
+        assert_eq!(type_in(&mut tty, b"pwd\n"), Received::Ready);
+        let mut buf = [0; 16];
+        assert_eq!(tty.read(&mut buf), 3);
+        assert_eq!(&buf[..3], b"ls\n");
+        // erasing doesn't affect lines already done
+        assert_eq!(type_in(&mut tty, b"\x08\x15"), Received::Nothing);
+        assert_eq!(tty.read(&mut buf[..2]), 2);
+        assert_eq!(tty.read(&mut buf), 2);
+        assert_eq!(&buf[..2], b"d\n");
+        assert_eq!(tty.available(), 0);
+    }
+
+    pub fn test_eof() {
+        let mut tty = Tty::new();
+        assert_eq!(type_in(&mut tty, b"ab\x04"), Received::Ready);
+        let mut buf = [0; 16];
+        assert_eq!(tty.read(&mut buf), 2);
+        assert_eq!(tty.read(&mut buf), 0);
+        assert_eq!(tty.available(), 0);
+    }
+
+    pub fn test_full() {
+        let mut tty = Tty::new();
+        for _ in 0..INPUT_BUF - 1 {
+            assert_eq!(tty.input(b'x', &mut |_| {}), Received::Nothing);
+        }
+        // last slot completes the line
+        assert_eq!(tty.input(b'x', &mut |_| {}), Received::Ready);
+        assert_eq!(tty.input(b'y', &mut |_| {}), Received::Nothing);
+        let mut buf = [0; INPUT_BUF];
+        assert_eq!(tty.read(&mut buf), INPUT_BUF);
+    }
+
+    pub fn test_signal() {
+        let mut tty = Tty::new();
+        let mut echoed = [0; 4];
+        let mut n = 0;
+        type_in(&mut tty, b"sleep");
+        let received = tty.input(ctrl(b'C'), &mut |c| {
+            echoed[n] = c;
+            n += 1;
+        });
+        assert_eq!(received, Received::Signal(SIGINT));
+        assert_eq!(&echoed[..n], b"^C\n");
+        assert_eq!(type_in(&mut tty, b"\n"), Received::Ready);
+        assert_eq!(tty.available(), 1);
+        assert_eq!(type_in(&mut tty, &[ctrl(b'Z')]), Received::Signal(SIGTSTP));
+        // without ISIG, it's just a character
+        let mut termios = tty.termios();
+        termios.lflag &= !ISIG;
+        tty.set_termios(termios);
+        assert_eq!(type_in(&mut tty, &[ctrl(b'C'), b'\n']), Received::Ready);
+        assert_eq!(tty.available(), 2);
+    }
+
+    pub fn test_raw() {
+        let mut tty = Tty::new();
+        type_in(&mut tty, b"ab");
+        let mut termios = tty.termios();
+        termios.lflag &= !(ICANON | ECHO);
+        termios.cc[VMIN] = 3;
+        tty.set_termios(termios);
+        // partial line is kept
+        assert_eq!(tty.available(), 2);
+        assert!(!tty.can_read(16, 0));
+        assert!(tty.can_read(2, 0));
+        assert_eq!(type_in(&mut tty, b"\x7f"), Received::Ready);
+        assert!(tty.can_read(16, 0));
+        // polling
+        termios.cc[VMIN] = 0;
+        tty.set_termios(termios);
+        let mut buf = [0; 16];
+        assert_eq!(tty.read(&mut buf), 3);
+        assert_eq!(&buf[..3], b"ab\x7f");
+        assert!(tty.can_read(16, 0));
+        // timeout
+        termios.cc[VTIME] = 5;
+        tty.set_termios(termios);
+        assert!(!tty.can_read(16, 4));
+        assert!(tty.can_read(16, 5));
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Pseudo-terminals
+//!
+//! A pseudo-terminal is a pair of devices, master `/dev/ptmx` and slave
+//! `/dev/pts/N`. Slave behaves like console with a `Tty` of its own.
+//! What's written to master is received by slave `Tty`, and what's written
+//! to slave, including echo, is read from master.
+//!
+//! Opening `/dev/ptmx` allocates a free pair, whose index `N` is got by
+//! `TIOCGPTN`. When master is closed, slave is hung up: its foreground
+//! process group receives `SIGHUP`, reads return 0 and writes fail. After
+//! all slaves are closed, reads from master return 0 once output is drained.
+//! Pair is free again when both sides are closed.
+
+use crate::spinlock::Mutex;
+use crate::process::{my_proc, sleep, wakeup, signal};
+use crate::file::Device;
+use crate::syscall::EINTR;
+use super::{Tty, BACKSPACE, TIOCGPTN, tty_input, tty_read, tty_ioctl};
+
+/// Number of pseudo-terminals
+pub const NPTY: usize = 8;
+
+/// Size of output ring buffer
+const OUTPUT_BUF: usize = 512;
+
+/// Output from slave to master, and which sides are open
+struct Link {
+    buf: [u8; OUTPUT_BUF],
+    /// Read index
+    r: usize,
+    /// Write index
+    w: usize,
+    /// Whether master is open
+    master: bool,
+    /// Number of slaves open
+    slaves: usize,
+    /// Whether all slaves are closed after one is opened
+    slave_closed: bool,
+}
+
+impl Link {
+    const fn new() -> Self {
+        Self { buf: [0; OUTPUT_BUF], r: 0, w: 0, master: false, slaves: 0, slave_closed: false }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.r == self.w
+    }
+
+    fn is_full(&self) -> bool {
+        self.w - self.r == OUTPUT_BUF
+    }
+
+    /// Append `c` to output. Returns false if it's full.
+    fn push(&mut self, c: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.buf[self.w % OUTPUT_BUF] = c;
+        self.w += 1;
+        true
+    }
+
+    /// Take output into `dst`. Returns number of characters taken.
+    fn pop(&mut self, dst: &mut [u8]) -> usize {
+        let mut n = 0;
+        while n < dst.len() && !self.is_empty() {
+            dst[n] = self.buf[self.r % OUTPUT_BUF];
+            self.r += 1;
+            n += 1;
+        }
+        n
+    }
+}
+
+/// A pseudo-terminal pair
+///
+/// Locks are taken in order of `tty` and `link`. Master readers and slave
+/// writers sleep on `link`, and master writers on `tty`.
+struct Pty {
+    tty: Mutex<Tty>,
+    link: Mutex<Link>,
+}
+
+impl Pty {
+    const fn new() -> Self {
+        Self {
+            tty: Mutex::new(Tty::new(), "pty"),
+            link: Mutex::new(Link::new(), "pty link"),
+        }
+    }
+
+    /// Echo a character back to master. It's dropped if output is full.
+    fn echo(&self, c: u8) {
+        let mut link = self.link.lock();
+        if c == BACKSPACE {
+            link.push(BACKSPACE);
+            link.push(b' ');
+            link.push(BACKSPACE);
+        } else {
+            link.push(c);
+        }
+        wakeup(&self.link);
+    }
+}
+
+static PTYS: [Pty; NPTY] = [Pty::new(); NPTY];
+
+/// Master side of a pseudo-terminal
+pub struct PtyMaster {
+    n: usize,
+}
+
+impl PtyMaster {
+    /// Allocate a free pseudo-terminal. Returns `None` if there isn't any.
+    pub fn open() -> Option<Self> {
+        for (n, pty) in PTYS.iter().enumerate() {
+            let mut tty = pty.tty.lock();
+            let mut link = pty.link.lock();
+            if !link.master && link.slaves == 0 {
+                *tty = Tty::new();
+                *link = Link::new();
+                link.master = true;
+                return Some(Self { n });
+            }
+        }
+        None
+    }
+
+    /// Index of this pseudo-terminal
+    pub fn index(&self) -> usize {
+        self.n
+    }
+}
+
+impl Device for PtyMaster {
+    /// read output of slave, sleeping until there is some
+    fn read(&self, content: &mut [u8]) -> i32 {
+        if content.is_empty() {
+            return 0;
+        }
+        let pty = &PTYS[self.n];
+        let pid = my_proc().pid;
+        let mut link = pty.link.lock();
+        while link.is_empty() {
+            if link.slave_closed {
+                return 0;
+            }
+            if signal::interrupted(pid) {
+                return EINTR;
+            }
+            link = sleep(&pty.link, link);
+        }
+        let n = link.pop(content);
+        // slave writers may wait for room
+        wakeup(&pty.link);
+        n as i32
+    }
+
+    /// write input to slave, sleeping while its input buffer is full
+    fn write(&self, content: &[u8]) -> i32 {
+        let pty = &PTYS[self.n];
+        let pid = my_proc().pid;
+        for (i, &c) in content.iter().enumerate() {
+            let mut tty = pty.tty.lock();
+            while tty.full() && pty.link.lock().slaves != 0 {
+                if signal::interrupted(pid) {
+                    return if i > 0 { i as i32 } else { EINTR };
+                }
+                tty = sleep(&pty.tty, tty);
+            }
+            drop(tty);
+            tty_input(&pty.tty, c, &mut |c| pty.echo(c));
+        }
+        content.len() as i32
+    }
+
+    /// terminal settings of slave, and `TIOCGPTN`
+    fn ioctl(&self, cmd: u32, arg: *mut u8) -> i32 {
+        if cmd == TIOCGPTN {
+            unsafe { (arg as *mut i32).write_unaligned(self.n as i32); }
+            return 0;
+        }
+        tty_ioctl(&PTYS[self.n].tty, cmd, arg)
+    }
+}
+
+impl Drop for PtyMaster {
+    /// hang up slave
+    fn drop(&mut self) {
+        let pty = &PTYS[self.n];
+        let fg = {
+            let mut tty = pty.tty.lock();
+            let mut link = pty.link.lock();
+            link.master = false;
+            tty.hang_up();
+            if link.slaves != 0 { Some(tty.fg) } else { None }
+        };
+        wakeup(&pty.tty);
+        wakeup(&pty.link);
+        if let Some(fg) = fg {
+            signal::kill_group(fg, signal::SIGHUP);
+        }
+    }
+}
+
+/// Slave side of a pseudo-terminal
+pub struct PtySlave {
+    n: usize,
+}
+
+impl PtySlave {
+    /// Open slave of pseudo-terminal `n`. Returns `None` if its master
+    /// isn't open. First opener's process group becomes foreground.
+    pub fn open(n: usize) -> Option<Self> {
+        let pty = PTYS.get(n)?;
+        let mut tty = pty.tty.lock();
+        let mut link = pty.link.lock();
+        if !link.master {
+            return None;
+        }
+        if link.slaves == 0 && !link.slave_closed {
+            tty.fg = signal::pgid(my_proc().pid);
+        }
+        link.slaves += 1;
+        link.slave_closed = false;
+        Some(Self { n })
+    }
+}
+
+impl Device for PtySlave {
+    /// read input from master, see `tty_read`
+    fn read(&self, content: &mut [u8]) -> i32 {
+        tty_read(&PTYS[self.n].tty, content)
+    }
+
+    /// write output to master, sleeping while output buffer is full.
+    /// Fails if master is closed.
+    fn write(&self, content: &[u8]) -> i32 {
+        let pty = &PTYS[self.n];
+        let pid = my_proc().pid;
+        let mut link = pty.link.lock();
+        for (i, &c) in content.iter().enumerate() {
+            while link.master && link.is_full() {
+                if signal::interrupted(pid) {
+                    return if i > 0 { i as i32 } else { EINTR };
+                }
+                link = sleep(&pty.link, link);
+            }
+            if !link.master {
+                return -1;
+            }
+            link.push(c);
+            wakeup(&pty.link);
+        }
+        content.len() as i32
+    }
+
+    /// terminal settings, see `Tty::ioctl`
+    fn ioctl(&self, cmd: u32, arg: *mut u8) -> i32 {
+        tty_ioctl(&PTYS[self.n].tty, cmd, arg)
+    }
+}
+
+impl Drop for PtySlave {
+    fn drop(&mut self) {
+        let pty = &PTYS[self.n];
+        let mut link = pty.link.lock();
+        link.slaves -= 1;
+        if link.slaves == 0 {
+            link.slave_closed = true;
+            wakeup(&pty.link);
+        }
+    }
+}
+
+pub mod tests {
+    use super::*;
+    use crate::tty::TIOCSPGRP;
+    use crate::symbols::NMAXPROCS;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("link", test_link),
+            ("pair", test_pair),
+        ]
+    }
+
+    pub fn test_link() {
+        let mut link = Link::new();
+        assert!(link.is_empty());
+        for i in 0..OUTPUT_BUF {
+            assert!(link.push(i as u8));
+        }
+        assert!(!link.push(0));
+        let mut buf = [0; 4];
+        assert_eq!(link.pop(&mut buf), 4);
+        assert_eq!(buf, [0, 1, 2, 3]);
+        assert!(link.push(0));
+        let mut buf = [0; OUTPUT_BUF];
+        assert_eq!(link.pop(&mut buf), OUTPUT_BUF);
+        assert!(link.is_empty());
+    }
+
+    pub fn test_pair() {
+        let master = PtyMaster::open().unwrap();
+        let n = master.index();
+        let slave = PtySlave::open(n).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(master.write(b"ls\r"), 3);
+        assert_eq!(slave.read(&mut buf), 3);
+        assert_eq!(&buf[..3], b"ls\n");
+        // echo
+        assert_eq!(master.read(&mut buf), 3);
+        assert_eq!(&buf[..3], b"ls\n");
+        assert_eq!(slave.write(b"out"), 3);
+        assert_eq!(master.read(&mut buf), 3);
+        assert_eq!(&buf[..3], b"out");
+        // no one receives SIGHUP
+        let mut fg = NMAXPROCS as i32;
+        assert_eq!(master.ioctl(TIOCSPGRP, &mut fg as *mut i32 as *mut u8), 0);
+        drop(master);
+        // hung up
+        assert_eq!(slave.read(&mut buf), 0);
+        assert_eq!(slave.write(b"out"), -1);
+        assert!(PtySlave::open(n).is_none());
+        drop(slave);
+        let master = PtyMaster::open().unwrap();
+        assert_eq!(master.index(), n);
+    }
+}
@@ -9,21 +9,58 @@ use core::convert::TryInto;
 use core::fmt::Write;
 use core::fmt::Error;
 use crate::spinlock::Mutex;
-use crate::{println, print};
+use crate::console::consoleintr;
+use crate::executor::WaitQueue;
+use crate::platform::platform;
+use crate::process::{sleep, wakeup};
 
-/// UART base address on QEMU RISC-V
-pub const UART_BASE_ADDR: usize = 0x1000_0000;
+/// UART base address, from device tree
+#[allow(non_snake_case)]
+pub fn UART_BASE_ADDR() -> usize { platform().uart.base }
+
+/// Size of transmit ring buffer
+const TX_BUF: usize = 512;
 
 /// UART driver
+///
+/// Characters to be sent are put into a transmit ring buffer, which is
+/// drained into THR when THR is empty, and refilled from THR empty interrupt.
 pub struct Uart {
     /// UART MMIO base address
     base_address: usize,
+    tx_buf: [u8; TX_BUF],
+    /// Indices of transmit buffer, wrapping around at `TX_BUF`
+    tx_r: usize,
+    tx_w: usize,
 }
 
-impl Write for Uart {
+/// Writer for kernel prints through transmit buffer, which never sleeps so
+/// that it may be used in any context. If buffer is full, UART lock is
+/// released while waiting for characters to be sent.
+pub struct Printer;
+
+impl Write for Printer {
     fn write_str(&mut self, out: &str) -> Result<(), Error> {
+        let mut uart = UART().lock();
         for c in out.bytes() {
-            self.put(c);
+            while !uart.push(c) {
+                uart.start();
+                // let UART interrupt or other harts in
+                drop(uart);
+                uart = UART().lock();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writer putting characters synchronously, bypassing transmit buffer
+pub struct SyncWriter<'a>(pub &'a mut Uart);
+
+impl Write for SyncWriter<'_> {
+    fn write_str(&mut self, out: &str) -> Result<(), Error> {
+        for c in out.bytes() {
+            self.0.put(c);
         }
         Ok(())
     }
@@ -32,12 +69,19 @@ impl Write for Uart {
 impl Uart {
     pub const fn new(base_address: usize) -> Self {
         Uart {
-            base_address
+            base_address,
+            tx_buf: [0; TX_BUF],
+            tx_r: 0,
+            tx_w: 0,
         }
     }
 
-    /// Initialize UART driver
+    /// Initialize UART driver. Nothing is done if there's no UART,
+    /// where firmware console is used under SBI.
     pub fn init(&mut self) {
+        if self.base_address == 0 {
+            return;
+        }
         let ptr = self.base_address as *mut u8;
         unsafe {
             // First, set the word length, which
@@ -57,8 +101,9 @@ impl Uart {
             ptr.add(2).write_volatile(1 << 0);
 
             // Enable receiver buffer interrupts, which is at bit index
-            // 0 of the interrupt enable register (IER at offset 1).
-            ptr.add(1).write_volatile(1 << 0);
+            // 0 of the interrupt enable register (IER at offset 1), and
+            // transmitter holding register empty interrupts at bit index 1.
+            ptr.add(1).write_volatile((1 << 0) | (1 << 1));
 
             // If we cared about the divisor, the code below would set the divisor
             // from a global clock rate of 22.729 MHz (22,729,000 cycles per second)
@@ -101,8 +146,13 @@ impl Uart {
         }
     }
 
-    /// Put a character into UART
+    /// Put a character into UART synchronously, waiting for THR to be empty
     pub fn put(&mut self, c: u8) {
+        if self.base_address == 0 {
+            #[cfg(feature = "sbi")]
+            crate::sbi::console_putchar(c);
+            return;
+        }
         let ptr = self.base_address as *mut u8;
         loop {
             // Wait until previous data is flushed
@@ -116,8 +166,63 @@ impl Uart {
         }
     }
 
+    /// Whether THR is empty and ready for next character
+    fn tx_ready(&self) -> bool {
+        let ptr = self.base_address as *mut u8;
+        unsafe { ptr.add(5).read_volatile() & (1 << 5) != 0 }
+    }
+
+    /// Move characters from transmit buffer to THR while it's empty.
+    /// Returns true if any space is freed in buffer.
+    fn start(&mut self) -> bool {
+        let mut freed = false;
+        while self.tx_r != self.tx_w && self.tx_ready() {
+            let c = self.tx_buf[self.tx_r % TX_BUF];
+            self.tx_r += 1;
+            unsafe { (self.base_address as *mut u8).write_volatile(c); }
+            freed = true;
+        }
+        freed
+    }
+
+    /// Put `c` into transmit buffer. Returns false if buffer is full.
+    fn push(&mut self, c: u8) -> bool {
+        if self.base_address == 0 {
+            self.put(c);
+            return true;
+        }
+        if self.tx_w == self.tx_r + TX_BUF {
+            return false;
+        }
+        self.tx_buf[self.tx_w % TX_BUF] = c;
+        self.tx_w += 1;
+        self.start();
+        true
+    }
+
+    /// Put `c` into transmit buffer without waiting, dropping it if buffer
+    /// is full
+    pub fn put_nowait(&mut self, c: u8) {
+        self.push(c);
+    }
+
+    /// Send all characters in transmit buffer synchronously
+    pub fn flush(&mut self) {
+        while self.tx_r != self.tx_w {
+            let c = self.tx_buf[self.tx_r % TX_BUF];
+            self.tx_r += 1;
+            self.put(c);
+        }
+    }
+
     /// Get a character from UART
     pub fn get(&mut self) -> Option<u8> {
+        if self.base_address == 0 {
+            #[cfg(feature = "sbi")]
+            return crate::sbi::console_getchar();
+            #[cfg(not(feature = "sbi"))]
+            return None;
+        }
         let ptr = self.base_address as *mut u8;
         unsafe {
             if ptr.add(5).read_volatile() & 1 == 0 {
@@ -131,39 +236,54 @@ impl Uart {
     }
 }
 
+/// Kernel tasks waiting for UART interrupt
+pub static UART_WAIT: WaitQueue = WaitQueue::new("uart wait");
+
+/// Write `content` through transmit buffer, sleeping while it's full.
+/// Should be called in process context.
+pub fn uartwrite(content: &[u8]) {
+    let mut uart = UART().lock();
+    for &c in content {
+        while !uart.push(c) {
+            // woken up by `uartintr` when characters are sent
+            let channel = &uart.tx_r as *const usize;
+            uart = sleep(channel, uart);
+        }
+    }
+}
+
 /// Process UART interrupt. Should only be called when interrupt.
 pub fn uartintr() {
-    let mut uart = UART().lock();
-    if let Some(c) = uart.get() {
-        drop(uart);
+    loop {
+        let c = UART().lock().get();
         match c {
-            8 => {
-                // This is a backspace, so we
-                // essentially have to write a space and
-                // backup again:
-                print!("{} {}", 8 as char, 8 as char);
-            }
-            10 | 13 => {
-                // Newline or carriage-return
-                println!();
-            }
-            _ => {
-                print!("{}", c as char);
-                if c == 97 {
-                    crate::process::debug();
-                }
-            }
+            Some(c) => consoleintr(c),
+            None => break
+        }
+    }
+    let (freed, channel) = {
+        let mut uart = UART().lock();
+        if uart.base_address != 0 {
+            // reading IIR acknowledges THR empty interrupt
+            unsafe { (uart.base_address as *mut u8).add(2).read_volatile(); }
         }
+        (uart.start(), &uart.tx_r as *const usize)
+    };
+    if freed {
+        wakeup(channel);
     }
+    UART_WAIT.wake_all();
 }
 
-/// UART driver object
-static __UART: Mutex<Uart> = Mutex::new(Uart::new(UART_BASE_ADDR), "uart driver");
+/// UART driver object. Base address is set in `init`.
+static __UART: Mutex<Uart> = Mutex::new(Uart::new(0), "uart driver");
 
 /// Global function to get an instance of UART driver
 #[allow(non_snake_case)]
 pub fn UART() -> &'static Mutex<Uart> { &__UART }
 
 pub unsafe fn init() {
-    UART().get().init();
+    let uart = UART().get();
+    uart.base_address = UART_BASE_ADDR();
+    uart.init();
 }
\ No newline at end of file
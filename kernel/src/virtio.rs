@@ -3,169 +3,107 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-//! virt-io driver
+//! virt-io block driver
+//!
+//! All virtio-mmio slots are probed at boot, and every block device found
+//! is registered as disk `dev` in probe order, which is also recorded in
+//! `Buf::dev`. Disk 0 holds file system, and disk 1, if present, is used
+//! as swap. Capacity and whether a disk is read-only are read from its
+//! feature bits and configuration space. See `mmio` module for legacy and
+//! modern transports.
+
+pub mod queue;
+pub mod mmio;
+pub mod net;
 
 use crate::spinlock::{Mutex, MutexGuard};
-use crate::{panic, info};
+use crate::{panic, info, warn};
 use crate::symbols::{PAGE_SIZE, PAGE_ORDER};
 use crate::process::{wakeup, sleep};
 use alloc::boxed::Box;
-use crate::arch::__sync_synchronize;
 use crate::uart::UART;
 use core::sync::atomic::Ordering;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use alloc::vec::Vec;
+use crate::mem::stat::{self, Kind};
+use queue::{Queue, VRingDesc, VRING_DESC_F_NEXT, VRING_DESC_F_WRITE, QUEUE_SIZE, MAX_QUEUE_SIZE};
+pub use mmio::{Transport, VIRTIO_MMIO, VIRTIO_CONFIG_S, VIRTIO_F};
 
-/// VIRTIO base address on QEMU RISC-V
-pub const VIRTIO_MMIO_BASE: usize = 0x10001000;
+/// Maximum number of virtio block devices, one for each virtio-mmio slot
+pub const NDISK: usize = crate::platform::MAX_VIRTIO;
 
-/// VIRTIO MMIO address offset
-#[allow(non_camel_case_types)]
-pub enum VIRTIO_MMIO {
-    MAGIC_VALUE = 0x0,
-    VERSION = 0x4,
-    DEVICE_ID = 0x8,
-    VENDOR_ID = 0xc,
-    DEVICE_FEATURES = 0x10,
-    DRIVER_FEATURES = 0x20,
-    GUEST_PAGE_SIZE = 0x28,
-    QUEUE_SEL = 0x30,
-    QUEUE_NUM_MAX = 0x34,
-    QUEUE_NUM = 0x38,
-    QUEUE_ALIGN = 0x3c,
-    QUEUE_PFN = 0x40,
-    QUEUE_READY = 0x44,
-    QUEUE_NOTIFY = 0x50,
-    INTERRUPT_STATUS = 0x60,
-    INTERRUPT_ACK = 0x64,
-    STATUS = 0x70,
-}
-
-impl VIRTIO_MMIO {
-    /// Get address of MMIO from enum
-    pub const fn val(self) -> usize {
-        self as usize + VIRTIO_MMIO_BASE
-    }
-    /// Get pointer to MMIO register from enum
-    pub const fn ptr(self) -> *mut u32 {
-        self.val() as _
-    }
-}
+/// Disk holding file system
+pub const ROOTDEV: u32 = 0;
 
-#[allow(non_camel_case_types)]
-pub enum VIRTIO_CONFIG_S {
-    ACKNOWLDGE = 1,
-    DRIVER = 1 << 1,
-    DRIVER_OK = 1 << 2,
-    FEATURES_OK = 1 << 3,
-}
-
-impl VIRTIO_CONFIG_S {
-    pub const fn val(self) -> u32 { self as _ }
-}
+/// Device ID of block devices
+const VIRTIO_ID_BLOCK: u32 = 2;
 
+/// Block device feature bits
 #[allow(non_camel_case_types)]
 pub enum VIRTIO_FEATURE {
     BLK_F_RO = 5,
     BLK_F_SCSI = 7,
     BLK_F_CONFIG_WCE = 11,
     BLK_F_MQ = 12,
-    F_ANY_LAYOUT = 27,
-    RING_F_INDIRECT_DESC = 28,
-    RING_F_EVENT_IDX = 29,
 }
 
 impl VIRTIO_FEATURE {
-    pub fn bit(self) -> u32 {
-        (1 << self as usize) as u32
+    pub const fn bit(self) -> u64 {
+        1 << self as u64
     }
 }
 
-pub const DESC_NUM: usize = 8;
-
-#[repr(C)]
-pub struct VRingDesc {
-    pub addr: usize,
-    pub len: u32,
-    pub flags: u16,
-    pub next: u16,
-}
-
-impl VRingDesc {
-    pub const fn new() -> Self {
-        Self {
-            addr: 0,
-            len: 0,
-            flags: 0,
-            next: 0,
-        }
-    }
-}
-
-pub const VRING_DESC_F_NEXT: u16 = 1;
-pub const VRING_DESC_F_WRITE: u16 = 2;
-
-#[repr(C)]
-pub struct VRingUsedElem {
-    pub id: u32,
-    pub len: u32,
-}
-
-impl VRingUsedElem {
-    pub const fn new() -> Self {
-        Self {
-            id: 0,
-            len: 0,
-        }
-    }
-}
+/// Features accepted by block driver besides `VIRTIO_F_VERSION_1`. A
+/// read-only disk is accepted and never written to.
+const BLK_FEATURES: u64 = VIRTIO_FEATURE::BLK_F_RO.bit();
 
 pub const VIRTIO_BLK_T_IN: u32 = 0;
 pub const VIRTIO_BLK_T_OUT: u32 = 1;
 
-#[repr(C)]
-pub struct UsedArea {
-    pub flags: u16,
-    pub id: u16,
-    pub elems: [VRingUsedElem; DESC_NUM],
-}
-
-impl UsedArea {
-    pub const fn new() -> Self {
-        Self {
-            flags: 0,
-            id: 0,
-            elems: [VRingUsedElem::new(); DESC_NUM],
-        }
-    }
-}
-
 pub struct InflightOp {
     pub buf: Box<Buf>,
     pub status: u8,
+    /// request header, referred by the first descriptor
+    pub hdr: BlkOutHdr,
+    /// waker of async request
+    pub waker: Option<Waker>,
+    /// whether the future of this request was dropped, in which case the
+    /// interrupt handler frees it on completion
+    pub orphaned: bool,
 }
 
-/// Size of avail array
-const AVAIL_SZ: usize = (PAGE_SIZE - DESC_NUM * core::mem::size_of::<VRingDesc>()) / core::mem::size_of::<u16>();
-
-#[repr(C)]
-#[repr(align(4096))]
 pub struct VirtIOData {
-    /// VIRTIO MMIO descriptor register
-    pub desc: [VRingDesc; DESC_NUM],
-    /// VIRTIO MMIO descriptor avail register (padding to page size)
-    pub avail: [u16; AVAIL_SZ],
-    /// VIRTIO MMIO descriptor used register
-    pub used: [UsedArea; DESC_NUM],
-
-    /// is descriptor free
-    pub free: [bool; DESC_NUM],
-    /// used index of used array
-    pub used_idx: u16,
-    /// in-flight operations
-    pub info: [Option<InflightOp>; DESC_NUM],
+    /// request queue
+    pub queue: Queue,
+    /// in-flight operations, indexed by first descriptor
+    pub info: Vec<Option<InflightOp>>,
+    /// wakers of async requests waiting for free descriptors
+    pub free_wakers: Vec<Waker>,
+    /// index of this disk
+    pub index: usize,
+    /// virtio-mmio registers
+    pub transport: Transport,
+    /// interrupt number
+    pub irq: u32,
+    /// capacity in 512-byte sectors
+    pub capacity: usize,
+    /// whether device only accepts reads
+    pub readonly: bool,
 }
 
 pub struct VirtIO(Mutex<VirtIOData>);
 
+/// Error of a block request rejected before it is submitted
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BlockError {
+    /// write to a read-only disk
+    ReadOnly,
+    /// block beyond capacity of disk
+    OutOfRange,
+}
+
 /// VIRTIO buffer size
 pub const BSIZE: usize = 1024;
 
@@ -184,7 +122,8 @@ pub struct Buf {
 }
 
 impl Buf {
-    pub const fn new() -> Self {
+    pub fn new() -> Self {
+        stat::add(Kind::BufferCache, core::mem::size_of::<Buf>());
         Self {
             valid: false,
             disk: 0,
@@ -195,6 +134,12 @@ impl Buf {
     }
 }
 
+impl Drop for Buf {
+    fn drop(&mut self) {
+        stat::sub(Kind::BufferCache, core::mem::size_of::<Buf>());
+    }
+}
+
 #[repr(C)]
 pub struct BlkOutHdr {
     pub blk_type: u32,
@@ -203,257 +148,386 @@ pub struct BlkOutHdr {
 }
 
 impl VirtIOData {
-    /// Free one descriptor
-    fn free_desc(&mut self, i: usize) {
-        if i >= DESC_NUM {
-            panic!("invalid desc");
+    /// Free descriptor chain of a request, waking up requests waiting for
+    /// free descriptors
+    fn free_chain(&mut self, head: usize) {
+        self.queue.free_chain(head);
+        wakeup(&self.queue);
+        for waker in core::mem::replace(&mut self.free_wakers, Vec::new()) {
+            waker.wake();
         }
-        if self.free[i] {
-            panic!("already free");
-        }
-        self.desc[i].addr = 0;
-        self.free[i] = true;
-        wakeup(&self.free[0]);
     }
+}
 
-    /// Allocate one descriptor
-    fn alloc_desc(&mut self) -> Option<usize> {
-        for i in 0..DESC_NUM {
-            if self.free[i] {
-                self.free[i] = false;
-                return Some(i);
-            }
+impl VirtIO {
+    /// Initialize VIRTIO driver for disk `index` on `transport`, with a
+    /// queue of at most `queue_size` descriptors. Returns `None` if device
+    /// can't be driven.
+    ///
+    /// Should be called in booting hart.
+    pub fn init(index: usize, transport: Transport, irq: u32, queue_size: usize) -> Option<Self> {
+        let features = transport.begin_init(BLK_FEATURES)?;
+        let readonly = features & VIRTIO_FEATURE::BLK_F_RO.bit() != 0;
+
+        let max = transport.queue_max(0);
+        // queue size should be a power of 2
+        let mut num = core::cmp::min(core::cmp::min(max, queue_size), MAX_QUEUE_SIZE);
+        while num != 0 && !num.is_power_of_two() {
+            num &= num - 1;
+        }
+        // each request takes three descriptors
+        if num < 4 {
+            warn!("virtio disk {}: queue too short {} < 4", index, max);
+            return None;
         }
-        None
+        let queue = Queue::new(num);
+        transport.setup_queue(0, &queue);
+
+        transport.finish_init();
+
+        // capacity is a 64-bit field in device configuration
+        let capacity = transport.config_u64(0) as usize;
+
+        let mut info = Vec::with_capacity(num);
+        info.resize_with(num, || None);
+
+        Some(Self(Mutex::new(VirtIOData {
+            queue,
+            info,
+            free_wakers: Vec::new(),
+            index,
+            transport,
+            irq,
+            capacity,
+            readonly,
+        }, "vdisk")))
     }
 
-    /// Allocate three descriptors, return array of indices
-    fn alloc3_desc(&mut self) -> Option<[usize; 3]> {
-        let mut idx = [0; 3];
-        for i in 0..3 {
-            match self.alloc_desc() {
-                Some(x) => idx[i] = x,
-                None => {
-                    for j in 0..i {
-                        self.free_desc(idx[j]);
-                    }
-                    return None;
-                }
-            }
-        }
-        Some(idx)
+    /// Capacity of disk in blocks of `BSIZE`
+    pub fn capacity(&self) -> usize {
+        unsafe { self.0.get().capacity * 512 / BSIZE }
     }
 
-    /// Free descriptor chain
-    fn free_chain(&mut self, mut i: usize) {
-        loop {
-            self.free_desc(i);
-            if self.desc[i].flags & VRING_DESC_F_NEXT != 0 {
-                i = self.desc[i].next as usize;
-            } else {
-                break;
-            }
-        }
+    /// Whether disk only accepts reads
+    pub fn readonly(&self) -> bool {
+        unsafe { self.0.get().readonly }
     }
-}
 
-impl VirtIO {
-    pub const fn new() -> Self {
-        Self(
-            Mutex::new(VirtIOData {
-                desc: [VRingDesc::new(); DESC_NUM],
-                avail: [0; AVAIL_SZ],
-                used: [UsedArea::new(); DESC_NUM],
-                free: [false; DESC_NUM],
-                used_idx: 0,
-                info: [None; DESC_NUM],
-            }, "vdisk"))
-    }
-
-    /// Initialize VIRTIO driver.
-    ///
-    /// Should be called in booting hart.
-    pub unsafe fn init(&mut self) {
-        use VIRTIO_MMIO::*;
-        use VIRTIO_CONFIG_S::*;
-        use VIRTIO_FEATURE::*;
+    /// Number of descriptors in request queue
+    pub fn queue_size(&self) -> usize {
+        unsafe { self.0.get().queue.num() }
+    }
 
-        let mut vio = self.0.get();
+    /// Read-write operation
+    fn rw(&mut self, mut b: Box<Buf>, write: bool) -> Result<Box<Buf>, BlockError> {
+        let mut vio = self.0.lock();
+        vio.check(&b, write)?;
 
-        if MAGIC_VALUE.ptr().read_volatile() != 0x74726976 {
-            panic!("cannot find virtio disk: magic value");
-        }
-        if VERSION.ptr().read_volatile() != 1 {
-            panic!("cannot find virtio disk: version");
-        }
-        if DEVICE_ID.ptr().read_volatile() != 2 {
-            panic!("cannot find virtio disk: device id");
-        }
-        if VENDOR_ID.ptr().read_volatile() != 0x554d4551 {
-            panic!("cannot find virtio disk: vendor id");
-        }
+        let head = loop {
+            match vio.submit(b, write) {
+                Ok(head) => break head,
+                Err(buf) => b = buf
+            }
+            vio = sleep(&vio.queue as *const _, vio);
+        };
 
-        let mut status: u32 = 0;
-        status |= ACKNOWLDGE.val();
-        STATUS.ptr().write_volatile(status);
+        let buf_addr = &*vio.info[head].as_ref().unwrap().buf as *const _;
+        while vio.info[head].as_ref().unwrap().buf.disk == 1 {
+            vio = sleep(buf_addr, vio);
+        }
+        Ok(vio.finish(head))
+    }
 
-        status |= DRIVER.val();
-        STATUS.ptr().write_volatile(status);
+    /// Read block `blockno`
+    pub fn read(&mut self, blockno: u32) -> Result<Box<Buf>, BlockError> {
+        let mut buf = box Buf::new();
+        buf.blockno = blockno;
+        self.rw(buf, false)
+    }
 
-        let mut features: u32 = DEVICE_FEATURES.ptr().read_volatile();
-        features &= !BLK_F_RO.bit();
-        features &= !BLK_F_SCSI.bit();
-        features &= !BLK_F_CONFIG_WCE.bit();
-        features &= !BLK_F_MQ.bit();
-        features &= !F_ANY_LAYOUT.bit();
-        features &= !RING_F_EVENT_IDX.bit();
-        features &= !RING_F_INDIRECT_DESC.bit();
-        DEVICE_FEATURES.ptr().write_volatile(features);
+    /// Write buffer to disk
+    pub fn write(&mut self, buf: Box<Buf>) -> Result<(), BlockError> {
+        self.rw(buf, true).map(|_| ())
+    }
 
-        status |= FEATURES_OK.val();
-        STATUS.ptr().write_volatile(status);
+    /// Read block `blockno` asynchronously
+    pub fn read_async(&mut self, blockno: u32) -> BlockRequest {
+        let mut buf = box Buf::new();
+        buf.blockno = blockno;
+        BlockRequest::new(self.index(), buf, false)
+    }
 
-        status |= DRIVER_OK.val();
-        STATUS.ptr().write_volatile(status);
+    /// Write buffer to disk asynchronously. The future returns the buffer
+    /// after it is written.
+    pub fn write_async(&mut self, buf: Box<Buf>) -> BlockRequest {
+        BlockRequest::new(self.index(), buf, true)
+    }
 
-        GUEST_PAGE_SIZE.ptr().write_volatile(PAGE_SIZE as u32);
+    /// Index of this disk, which never changes after `init`
+    fn index(&self) -> usize {
+        unsafe { self.0.get().index }
+    }
+}
 
-        QUEUE_SEL.ptr().write_volatile(0);
-        let max = QUEUE_NUM_MAX.ptr().read_volatile();
-        if max == 0 {
-            panic!("virtio disk has no queue");
-        }
-        if max < DESC_NUM as u32 {
-            panic!("virtio disk max queue too short {} < {}", max, DESC_NUM);
+impl VirtIOData {
+    /// Check whether request on `b` can be submitted to this disk
+    fn check(&self, b: &Buf, write: bool) -> Result<(), BlockError> {
+        if write && self.readonly {
+            return Err(BlockError::ReadOnly);
         }
-        QUEUE_NUM.ptr().write_volatile(DESC_NUM as u32);
-
-        QUEUE_PFN.ptr().write_volatile(((vio as *mut _ as usize) >> PAGE_ORDER) as u32);
-
-        for i in 0..DESC_NUM {
-            vio.free[i] = true;
+        if (b.blockno as usize + 1) * (BSIZE / 512) > self.capacity {
+            return Err(BlockError::OutOfRange);
         }
+        Ok(())
     }
 
-    /// Read-write operation
-    fn rw(&mut self, mut b: Box<Buf>, write: bool) -> Box<Buf> {
-        use VIRTIO_MMIO::*;
+    /// Put a request checked by `check` into available ring and notify
+    /// device.
+    ///
+    /// Returns index of the first descriptor, or gives back the buffer if
+    /// there are not enough free descriptors.
+    fn submit(&mut self, mut b: Box<Buf>, write: bool) -> Result<usize, Box<Buf>> {
+        let mut idx = [0; 3];
+        if !self.queue.alloc_descs(&mut idx) {
+            return Err(b);
+        }
 
         let sector = b.blockno as usize * (BSIZE / 512);
 
-        let mut vio = self.0.lock();
-
-        let idx: [usize; 3] = loop {
-            if let Some(idx) = vio.alloc3_desc() {
-                break idx;
-            }
-            vio = sleep(&vio.free[0] as *const _, vio);
-        };
-
-        // info!("3 desc: {:?}", idx);
+        b.disk = 1;
+        b.dev = self.index as u32;
+        self.info[idx[0]] = Some(InflightOp {
+            buf: b,
+            status: 0,
+            hdr: BlkOutHdr {
+                reserved: 0,
+                sector,
+                blk_type: if write { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN },
+            },
+            waker: None,
+            orphaned: false,
+        });
 
-        let buf0 = BlkOutHdr {
-            reserved: 0,
-            sector,
-            blk_type: if write { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN },
+        let (hdr_addr, data_addr, status_addr) = {
+            let op = self.info[idx[0]].as_mut().unwrap();
+            (
+                &op.hdr as *const _ as usize,
+                op.buf.data.as_mut_ptr() as usize,
+                &op.status as *const _ as usize
+            )
         };
 
-
         // VIRTIO 5.2.6.4
         // MUST use a single 8-byte descriptor containing type, reserved and sector,
         // followed by descriptors for data, then finally a separate 1-byte descriptor for status.
 
-        {
-            let desc0 = &mut vio.desc[idx[0]];
-            desc0.addr = &buf0 as *const _ as usize;
-            desc0.len = core::mem::size_of::<BlkOutHdr>() as u32;
-            desc0.flags = VRING_DESC_F_NEXT;
-            desc0.next = idx[1] as u16;
-        }
+        self.queue.set_desc(idx[0], VRingDesc {
+            addr: hdr_addr,
+            len: core::mem::size_of::<BlkOutHdr>() as u32,
+            flags: VRING_DESC_F_NEXT,
+            next: idx[1] as u16,
+        });
 
-        {
-            let desc1 = &mut vio.desc[idx[1]];
-            desc1.addr = b.data.as_mut_ptr() as usize;
-            desc1.len = BSIZE as u32;
-            desc1.flags = if write { 0 } else { VRING_DESC_F_WRITE };
-            desc1.flags |= VRING_DESC_F_NEXT;
-            desc1.next = idx[2] as u16;
-        }
+        self.queue.set_desc(idx[1], VRingDesc {
+            addr: data_addr,
+            len: BSIZE as u32,
+            flags: if write { 0 } else { VRING_DESC_F_WRITE } | VRING_DESC_F_NEXT,
+            next: idx[2] as u16,
+        });
 
-        b.disk = 1;
-        vio.info[idx[0]] = Some(InflightOp {
-            buf: b,
-            status: 0,
+        self.queue.set_desc(idx[2], VRingDesc {
+            addr: status_addr,
+            len: 1,
+            flags: VRING_DESC_F_WRITE,
+            next: 0,
         });
 
-        {
-            let addr = &vio.info[idx[0]].as_ref().unwrap().status as *const _ as usize;
-            {
-                let desc2 = &mut vio.desc[idx[2]];
+        self.queue.push_avail(idx[0]);
 
-                desc2.addr = addr;
-                desc2.len = 1;
-                desc2.flags = VRING_DESC_F_WRITE;
-                desc2.next = 0;
-            }
+        self.transport.notify(0);
 
-            let idx_id = 2 + vio.avail[1] as usize % DESC_NUM;
-            vio.avail[idx_id] = idx[0] as u16;
+        Ok(idx[0])
+    }
 
-            __sync_synchronize();
+    /// Take the buffer of a completed request and free its descriptors
+    fn finish(&mut self, head: usize) -> Box<Buf> {
+        let result = core::mem::replace(&mut self.info[head], None);
+        self.free_chain(head);
+        result.unwrap().buf
+    }
+}
 
-            vio.avail[1] = vio.avail[1] + 1;
+/// Future of an async block request
+pub struct BlockRequest {
+    /// index of disk
+    disk: usize,
+    /// buffer before the request is submitted
+    buf: Option<Box<Buf>>,
+    write: bool,
+    /// first descriptor after the request is submitted
+    head: Option<usize>,
+}
 
-            unsafe { QUEUE_NOTIFY.ptr().write_volatile(0); }
-            // info!("{:x}", &vio.info[idx[0]].as_ref().unwrap().status as *const _ as usize);
-            let buf_addr = &*vio.info[idx[0]].as_ref().unwrap().buf as *const _;
-            // info!("sleep {:x} id={}", buf_addr as usize, vio.info[idx[0]].as_ref().unwrap().status);
-            while vio.info[idx[0]].as_ref().unwrap().buf.disk == 1 {
-                vio = sleep(buf_addr, vio);
-            }
+impl BlockRequest {
+    fn new(disk: usize, buf: Box<Buf>, write: bool) -> Self {
+        Self {
+            disk,
+            buf: Some(buf),
+            write,
+            head: None,
         }
-        let result = core::mem::replace(&mut vio.info[idx[0]], None);
-        unsafe { vio.info[idx[0]] = core::mem::zeroed(); }
-        vio.info[idx[0]] = None;
-        vio.free_chain(idx[0]);
-        result.unwrap().buf
     }
+}
 
-    /// Read from device and block number
-    pub fn read(&mut self, dev: u32, blockno: u32) -> Box<Buf> {
-        let mut buf = box Buf::new();
-        buf.dev = dev;
-        buf.blockno = blockno;
-        self.rw(buf, false)
+impl Future for BlockRequest {
+    type Output = Result<Box<Buf>, BlockError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut vio = DISK(this.disk).unwrap().0.lock();
+        match this.head {
+            None => {
+                let buf = this.buf.take().unwrap();
+                if let Err(err) = vio.check(&buf, this.write) {
+                    return Poll::Ready(Err(err));
+                }
+                match vio.submit(buf, this.write) {
+                    Ok(head) => {
+                        vio.info[head].as_mut().unwrap().waker = Some(cx.waker().clone());
+                        this.head = Some(head);
+                    }
+                    Err(buf) => {
+                        this.buf = Some(buf);
+                        vio.free_wakers.push(cx.waker().clone());
+                    }
+                }
+                Poll::Pending
+            }
+            Some(head) => {
+                let op = vio.info[head].as_mut().unwrap();
+                if op.buf.disk == 0 {
+                    this.head = None;
+                    Poll::Ready(Ok(vio.finish(head)))
+                } else {
+                    op.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
     }
+}
 
-    /// Write buffer to disk
-    pub fn write(&mut self, buf: Box<Buf>) {
-        self.rw(buf, true);
+impl Drop for BlockRequest {
+    /// Hand a submitted request over to the interrupt handler, or free it
+    /// now if it has already completed
+    fn drop(&mut self) {
+        if let Some(head) = self.head {
+            let mut vio = DISK(self.disk).unwrap().0.lock();
+            let op = vio.info[head].as_mut().unwrap();
+            if op.buf.disk == 0 {
+                vio.finish(head);
+            } else {
+                op.orphaned = true;
+                op.waker = None;
+            }
+        }
     }
 }
 
-/// VirtIO driver object
-static mut __VIRTIO: VirtIO = VirtIO::new();
+/// VirtIO driver objects, `None` for slots without a block device
+static mut __VIRTIO: [Option<VirtIO>; NDISK] = [None; NDISK];
 
-/// Global function to get an instance of VirtIO driver
+/// Number of disks found
+static mut NDISK_FOUND: usize = 0;
+
+/// Global function to get an instance of VirtIO driver of disk holding file system
 #[allow(non_snake_case)]
-pub fn VIRTIO() -> &'static mut VirtIO { unsafe { &mut __VIRTIO } }
+pub fn VIRTIO() -> &'static mut VirtIO { DISK(ROOTDEV as usize).unwrap() }
 
-pub unsafe fn init() {
-    VIRTIO().init();
+/// Get VirtIO driver of disk `index`, if it is present
+#[allow(non_snake_case)]
+pub fn DISK(index: usize) -> Option<&'static mut VirtIO> {
+    unsafe {
+        if index < NDISK_FOUND {
+            __VIRTIO[index].as_mut()
+        } else {
+            None
+        }
+    }
 }
 
+/// Number of disks found
+pub fn ndisk() -> usize {
+    unsafe { NDISK_FOUND }
+}
 
-/// VIRTIO interrupt
-pub fn virtiointr() {
-    use crate::info;
-    let mut disk = VIRTIO().0.lock();
-    while disk.used_idx as usize % DESC_NUM != disk.used[0].id as usize % DESC_NUM {
+/// Read block `blockno` of disk `dev`
+pub fn bread(dev: u32, blockno: u32) -> Result<Box<Buf>, BlockError> {
+    match DISK(dev as usize) {
+        Some(disk) => disk.read(blockno),
+        None => panic!("no disk {}", dev)
+    }
+}
 
-        let id = disk.used[0].elems[disk.used_idx as usize].id as usize;
+/// Write buffer to disk `buf.dev`
+pub fn bwrite(buf: Box<Buf>) -> Result<(), BlockError> {
+    match DISK(buf.dev as usize) {
+        Some(disk) => disk.write(buf),
+        None => panic!("no disk {}", buf.dev)
+    }
+}
 
-        if disk.info[id].is_none() {
+/// Probe all virtio-mmio slots and initialize block devices in order, and
+/// the first network device
+pub unsafe fn init() {
+    for slot in crate::platform::platform().virtio() {
+        if let Some(transport) = Transport::probe(slot.base, net::VIRTIO_ID_NET) {
+            if net::init(transport, slot.irq) {
+                info!("  network: MAC {}, {}", net::NET().unwrap().mac(),
+                      if transport.is_legacy() { "legacy" } else { "modern" });
+            }
+            continue;
+        }
+        let transport = match Transport::probe(slot.base, VIRTIO_ID_BLOCK) {
+            Some(transport) if NDISK_FOUND < NDISK => transport,
+            _ => continue
+        };
+        if let Some(disk) = VirtIO::init(NDISK_FOUND, transport, slot.irq, QUEUE_SIZE) {
+            info!("  disk {}: {} KiB{}, {} descriptors, {}", NDISK_FOUND,
+                  disk.capacity() * BSIZE / 1024,
+                  if disk.readonly() { ", read-only" } else { "" },
+                  disk.queue_size(),
+                  if transport.is_legacy() { "legacy" } else { "modern" });
+            __VIRTIO[NDISK_FOUND] = Some(disk);
+            NDISK_FOUND += 1;
+        }
+    }
+    if NDISK_FOUND == 0 {
+        panic!("cannot find virtio disk");
+    }
+}
+
+/// VIRTIO interrupt. Returns false if `irq` doesn't belong to any disk or
+/// network device.
+pub fn virtiointr(irq: u32) -> bool {
+    let virtio = match (0..NDISK).filter_map(DISK).find(|d| unsafe { d.0.get().irq } == irq) {
+        Some(virtio) => virtio,
+        None => {
+            return match net::NET() {
+                Some(net) if net.irq() == irq => {
+                    net.intr();
+                    true
+                }
+                _ => false
+            };
+        }
+    };
+    let mut disk = virtio.0.lock();
+    disk.transport.ack_interrupt();
+    while let Some(elem) = disk.queue.pop_used() {
+        let id = elem.id as usize;
+
+        if id >= disk.info.len() || disk.info[id].is_none() {
             panic!("invalid id");
         }
 
@@ -467,10 +541,17 @@ pub fn virtiointr() {
 
         info.buf.disk = 0;
 
-        wakeup(&*info.buf);
+        if info.orphaned {
+            disk.finish(id);
+            continue;
+        }
 
-        disk.used_idx = ((disk.used_idx + 1) as usize % DESC_NUM) as u16;
+        wakeup(&*info.buf);
+        if let Some(waker) = info.waker.take() {
+            waker.wake();
+        }
     }
+    true
 }
 
 pub mod tests {
@@ -478,17 +559,25 @@ pub mod tests {
 
     pub fn tests() -> &'static [(&'static str, fn())] {
         &[
-            ("memory layout", test_memory_layout),
-            ("read and write", test_rw)
+            ("devices", test_devices),
+            ("read and write", test_rw),
+            ("async read", test_async_read),
+            ("drop async request", test_drop_async),
+            ("rejected request", test_rejected),
         ]
     }
 
-    /// Test virtio memory layout
-    pub fn test_memory_layout() {
-        let virtio = VIRTIO().0.lock();
-        assert_eq!(&virtio.desc as *const _ as usize % PAGE_SIZE, 0);
-        assert_eq!(&virtio.used as *const _ as usize % PAGE_SIZE, 0);
-        assert_eq!(&virtio.used as *const _ as usize - &virtio.desc as *const _ as usize, PAGE_SIZE);
+    /// Test every disk found is registered with its own number
+    pub fn test_devices() {
+        assert!(ndisk() >= 1);
+        for dev in 0..ndisk() {
+            let disk = DISK(dev).unwrap();
+            assert_eq!(disk.index(), dev);
+            assert!(disk.capacity() > 0);
+            assert!(disk.queue_size().is_power_of_two());
+            assert_eq!(bread(dev as u32, 0).unwrap().dev, dev as u32);
+        }
+        assert!(DISK(ndisk()).is_none());
     }
 
     use crate::{print, println};
@@ -496,7 +585,7 @@ pub mod tests {
     /// Test read and write
     pub fn test_rw() {
         let virtio = VIRTIO();
-        let b = virtio.read(1, 0);
+        let b = virtio.read(0).unwrap();
         unsafe { println!("size: {}", core::ptr::read(b.data.as_ptr() as *const usize)); }
         unsafe { println!("offset: {}", core::ptr::read(b.data.as_ptr().add(8) as *const usize)); }
         for i in 16..b.data.len() {
@@ -508,4 +597,55 @@ pub mod tests {
         }
         println!();
     }
+
+    /// Test async read returns the same content as blocking read
+    pub fn test_async_read() {
+        use crate::executor::block_on;
+        let b = block_on(VIRTIO().read_async(0)).unwrap();
+        let expected = VIRTIO().read(0).unwrap();
+        assert_eq!(&b.data[..], &expected.data[..]);
+    }
+
+    /// Submits a request on first poll and drops it right after
+    struct SubmitAndDrop(Option<BlockRequest>);
+
+    impl Future for SubmitAndDrop {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            let mut req = self.0.take().unwrap();
+            assert!(Pin::new(&mut req).poll(cx).is_pending());
+            Poll::Ready(())
+        }
+    }
+
+    /// Test dropping submitted requests gives back their descriptors, so
+    /// that later requests won't wait forever
+    pub fn test_drop_async() {
+        use crate::executor::block_on;
+        for _ in 0..VIRTIO().queue_size() {
+            block_on(SubmitAndDrop(Some(VIRTIO().read_async(0))));
+        }
+        let b = block_on(VIRTIO().read_async(0)).unwrap();
+        assert_eq!(b.blockno, 0);
+    }
+
+    /// Test requests out of disk, or writing to read-only disk, fail
+    /// without taking descriptors
+    pub fn test_rejected() {
+        use crate::executor::block_on;
+        for dev in 0..ndisk() {
+            let disk = DISK(dev).unwrap();
+            let end = disk.capacity() as u32;
+            for _ in 0..disk.queue_size() + 1 {
+                assert_eq!(disk.read(end).err(), Some(BlockError::OutOfRange));
+                assert_eq!(block_on(disk.read_async(end)).err(), Some(BlockError::OutOfRange));
+            }
+            if disk.readonly() {
+                let b = disk.read(0).unwrap();
+                assert_eq!(disk.write(b), Err(BlockError::ReadOnly));
+            }
+            assert!(disk.read(end - 1).is_ok());
+        }
+    }
 }
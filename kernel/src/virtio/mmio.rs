@@ -0,0 +1,266 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! virtio-mmio transport
+//!
+//! Both legacy (version 1) and modern (version 2) devices are supported.
+//! QEMU exposes legacy devices by default, and modern ones with
+//! `-global virtio-mmio.force-legacy=false`. They differ in:
+//!
+//! * feature bits: modern devices have 64 of them selected by
+//!   `DEVICE_FEATURES_SEL`, and require `VIRTIO_F_VERSION_1`
+//! * queue setup: legacy devices take page number of a contiguous queue
+//!   with `QUEUE_PFN`, while modern ones take addresses of descriptor
+//!   table, available and used rings separately, and `QUEUE_READY`
+//! * `FEATURES_OK`, which modern devices may refuse
+
+use super::queue::Queue;
+use crate::symbols::PAGE_SIZE;
+
+/// VIRTIO MMIO address offset
+#[allow(non_camel_case_types)]
+pub enum VIRTIO_MMIO {
+    MAGIC_VALUE = 0x0,
+    VERSION = 0x4,
+    DEVICE_ID = 0x8,
+    VENDOR_ID = 0xc,
+    DEVICE_FEATURES = 0x10,
+    DEVICE_FEATURES_SEL = 0x14,
+    DRIVER_FEATURES = 0x20,
+    DRIVER_FEATURES_SEL = 0x24,
+    /// legacy only
+    GUEST_PAGE_SIZE = 0x28,
+    QUEUE_SEL = 0x30,
+    QUEUE_NUM_MAX = 0x34,
+    QUEUE_NUM = 0x38,
+    /// legacy only
+    QUEUE_ALIGN = 0x3c,
+    /// legacy only
+    QUEUE_PFN = 0x40,
+    /// modern only
+    QUEUE_READY = 0x44,
+    QUEUE_NOTIFY = 0x50,
+    INTERRUPT_STATUS = 0x60,
+    INTERRUPT_ACK = 0x64,
+    STATUS = 0x70,
+    /// modern only, address of descriptor table
+    QUEUE_DESC_LOW = 0x80,
+    QUEUE_DESC_HIGH = 0x84,
+    /// modern only, address of available ring
+    QUEUE_DRIVER_LOW = 0x90,
+    QUEUE_DRIVER_HIGH = 0x94,
+    /// modern only, address of used ring
+    QUEUE_DEVICE_LOW = 0xa0,
+    QUEUE_DEVICE_HIGH = 0xa4,
+    /// modern only, changed when configuration changes
+    CONFIG_GENERATION = 0xfc,
+    CONFIG = 0x100,
+}
+
+impl VIRTIO_MMIO {
+    /// Get address of MMIO register of device at `base`
+    pub fn val(self, base: usize) -> usize {
+        self as usize + base
+    }
+    /// Get pointer to MMIO register of device at `base`
+    pub fn ptr(self, base: usize) -> *mut u32 {
+        self.val(base) as _
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub enum VIRTIO_CONFIG_S {
+    ACKNOWLDGE = 1,
+    DRIVER = 1 << 1,
+    DRIVER_OK = 1 << 2,
+    FEATURES_OK = 1 << 3,
+    FAILED = 1 << 7,
+}
+
+impl VIRTIO_CONFIG_S {
+    pub const fn val(self) -> u32 { self as _ }
+}
+
+/// Device-independent feature bits
+#[allow(non_camel_case_types)]
+pub enum VIRTIO_F {
+    ANY_LAYOUT = 27,
+    RING_INDIRECT_DESC = 28,
+    RING_EVENT_IDX = 29,
+    VERSION_1 = 32,
+}
+
+impl VIRTIO_F {
+    pub const fn bit(self) -> u64 {
+        1 << self as u64
+    }
+}
+
+/// Magic value of virtio-mmio registers, "virt"
+const MAGIC: u32 = 0x74726976;
+
+/// A virtio-mmio device
+#[derive(Clone, Copy)]
+pub struct Transport {
+    /// base address of registers
+    pub base: usize,
+    /// 1 for legacy, 2 for modern
+    pub version: u32,
+}
+
+impl Transport {
+    /// Probe device of type `device_id` at `base`
+    pub unsafe fn probe(base: usize, device_id: u32) -> Option<Self> {
+        use VIRTIO_MMIO::*;
+
+        if MAGIC_VALUE.ptr(base).read_volatile() != MAGIC {
+            return None;
+        }
+        let version = VERSION.ptr(base).read_volatile();
+        if version != 1 && version != 2 {
+            return None;
+        }
+        if DEVICE_ID.ptr(base).read_volatile() != device_id {
+            return None;
+        }
+        Some(Self { base, version })
+    }
+
+    fn read(&self, reg: VIRTIO_MMIO) -> u32 {
+        unsafe { reg.ptr(self.base).read_volatile() }
+    }
+
+    fn write(&self, reg: VIRTIO_MMIO, val: u32) {
+        unsafe { reg.ptr(self.base).write_volatile(val) }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    fn add_status(&self, status: VIRTIO_CONFIG_S) {
+        self.write(VIRTIO_MMIO::STATUS, self.read(VIRTIO_MMIO::STATUS) | status.val());
+    }
+
+    /// Reset device, acknowledge it, and negotiate features. Driver accepts
+    /// features offered by device among `supported`, and `VIRTIO_F_VERSION_1`
+    /// on modern devices.
+    ///
+    /// Returns features negotiated, or `None` if device refuses them.
+    pub fn begin_init(&self, supported: u64) -> Option<u64> {
+        use VIRTIO_MMIO::*;
+        use VIRTIO_CONFIG_S::*;
+
+        self.write(STATUS, 0);
+        self.add_status(ACKNOWLDGE);
+        self.add_status(DRIVER);
+
+        let offered = if self.is_legacy() {
+            self.read(DEVICE_FEATURES) as u64
+        } else {
+            self.write(DEVICE_FEATURES_SEL, 0);
+            let low = self.read(DEVICE_FEATURES) as u64;
+            self.write(DEVICE_FEATURES_SEL, 1);
+            let high = self.read(DEVICE_FEATURES) as u64;
+            high << 32 | low
+        };
+        let features = if self.is_legacy() {
+            offered & supported & 0xffff_ffff
+        } else {
+            if offered & VIRTIO_F::VERSION_1.bit() == 0 {
+                self.add_status(FAILED);
+                return None;
+            }
+            offered & (supported | VIRTIO_F::VERSION_1.bit())
+        };
+
+        if self.is_legacy() {
+            self.write(DRIVER_FEATURES, features as u32);
+            self.write(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+        } else {
+            self.write(DRIVER_FEATURES_SEL, 0);
+            self.write(DRIVER_FEATURES, features as u32);
+            self.write(DRIVER_FEATURES_SEL, 1);
+            self.write(DRIVER_FEATURES, (features >> 32) as u32);
+        }
+
+        self.add_status(FEATURES_OK);
+        if !self.is_legacy() && self.read(STATUS) & FEATURES_OK.val() == 0 {
+            self.add_status(FAILED);
+            return None;
+        }
+        Some(features)
+    }
+
+    /// Maximum size of queue `sel`, 0 if it doesn't exist
+    pub fn queue_max(&self, sel: u32) -> usize {
+        self.write(VIRTIO_MMIO::QUEUE_SEL, sel);
+        self.read(VIRTIO_MMIO::QUEUE_NUM_MAX) as usize
+    }
+
+    /// Hand `queue` to device as queue `sel`
+    pub fn setup_queue(&self, sel: u32, queue: &Queue) {
+        use VIRTIO_MMIO::*;
+
+        self.write(QUEUE_SEL, sel);
+        self.write(QUEUE_NUM, queue.num() as u32);
+        if self.is_legacy() {
+            self.write(QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(QUEUE_PFN, queue.pfn());
+        } else {
+            let (desc, avail, used) = (queue.desc_addr(), queue.avail_addr(), queue.used_addr());
+            self.write(QUEUE_DESC_LOW, desc as u32);
+            self.write(QUEUE_DESC_HIGH, (desc >> 32) as u32);
+            self.write(QUEUE_DRIVER_LOW, avail as u32);
+            self.write(QUEUE_DRIVER_HIGH, (avail >> 32) as u32);
+            self.write(QUEUE_DEVICE_LOW, used as u32);
+            self.write(QUEUE_DEVICE_HIGH, (used >> 32) as u32);
+            self.write(QUEUE_READY, 1);
+        }
+    }
+
+    /// Tell device that driver is ready, after queues are set up
+    pub fn finish_init(&self) {
+        self.add_status(VIRTIO_CONFIG_S::DRIVER_OK);
+    }
+
+    /// Tell device there are new buffers in queue `sel`
+    pub fn notify(&self, sel: u32) {
+        self.write(VIRTIO_MMIO::QUEUE_NOTIFY, sel);
+    }
+
+    /// Acknowledge interrupt. Returns interrupt status.
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(VIRTIO_MMIO::INTERRUPT_STATUS);
+        self.write(VIRTIO_MMIO::INTERRUPT_ACK, status & 0x3);
+        status
+    }
+
+    /// Read byte at `offset` of device configuration
+    pub fn config_u8(&self, offset: usize) -> u8 {
+        unsafe { ((VIRTIO_MMIO::CONFIG.val(self.base) + offset) as *const u8).read_volatile() }
+    }
+
+    /// Read 32-bit field at `offset` of device configuration
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        unsafe { ((VIRTIO_MMIO::CONFIG.val(self.base) + offset) as *const u32).read_volatile() }
+    }
+
+    /// Read 64-bit field at `offset` of device configuration. On modern
+    /// devices, it's read again if configuration changes in the meantime.
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        if self.is_legacy() {
+            return (self.config_u32(offset + 4) as u64) << 32 | self.config_u32(offset) as u64;
+        }
+        loop {
+            let generation = self.read(VIRTIO_MMIO::CONFIG_GENERATION);
+            let low = self.config_u32(offset) as u64;
+            let high = self.config_u32(offset + 4) as u64;
+            if self.read(VIRTIO_MMIO::CONFIG_GENERATION) == generation {
+                return high << 32 | low;
+            }
+        }
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! virtio-net driver
+//!
+//! Queue 0 receives and queue 1 transmits. Each packet takes two
+//! descriptors, one for `virtio_net_hdr` and one for Ethernet frame, as
+//! legacy devices without `VIRTIO_F_ANY_LAYOUT` expect. Receive queue is
+//! kept full of buffers, and frames received are handed to `net` module
+//! after driver lock is released, as they may be answered right away.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use crate::spinlock::Mutex;
+use crate::net::MacAddr;
+use super::queue::{Queue, VRingDesc, VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
+use super::Transport;
+
+/// Device ID of network devices
+pub const VIRTIO_ID_NET: u32 = 1;
+
+/// Feature: device has MAC address in configuration
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+/// Largest Ethernet frame without frame check sequence
+pub const FRAME_SIZE: usize = 1514;
+
+/// Descriptors of each queue, two for a packet
+const NET_QUEUE_SIZE: usize = 32;
+
+/// Size of `virtio_net_hdr`. Legacy devices don't have `num_buffers`
+/// without `VIRTIO_NET_F_MRG_RXBUF`.
+const HDR_SIZE: usize = 12;
+const HDR_SIZE_LEGACY: usize = 10;
+
+/// MAC address used if device doesn't provide one, same as QEMU default
+const DEFAULT_MAC: MacAddr = MacAddr([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+
+const RX: u32 = 0;
+const TX: u32 = 1;
+
+/// Packet buffer
+#[repr(C)]
+struct NetBuf {
+    hdr: [u8; HDR_SIZE],
+    data: [u8; FRAME_SIZE],
+}
+
+impl NetBuf {
+    fn new() -> Box<Self> {
+        box Self { hdr: [0; HDR_SIZE], data: [0; FRAME_SIZE] }
+    }
+}
+
+pub struct NetData {
+    transport: Transport,
+    irq: u32,
+    /// size of `virtio_net_hdr`
+    hdr_len: usize,
+    rx: Queue,
+    /// buffers in receive queue, indexed by first descriptor
+    rx_bufs: Vec<Option<Box<NetBuf>>>,
+    tx: Queue,
+    /// buffers being transmitted, indexed by first descriptor
+    tx_bufs: Vec<Option<Box<NetBuf>>>,
+    mac: MacAddr,
+}
+
+pub struct VirtIONet(Mutex<NetData>);
+
+/// Size of a queue no more than `max`, a power of 2
+fn queue_size(max: usize) -> usize {
+    let mut num = core::cmp::min(max, NET_QUEUE_SIZE);
+    while num != 0 && !num.is_power_of_two() {
+        num &= num - 1;
+    }
+    num
+}
+
+impl NetData {
+    /// Put `buf` into receive queue. Returns false if queue is full.
+    fn post_rx(&mut self, buf: Box<NetBuf>) -> bool {
+        let mut idx = [0; 2];
+        if !self.rx.alloc_descs(&mut idx) {
+            return false;
+        }
+        self.rx.set_desc(idx[0], VRingDesc {
+            addr: buf.hdr.as_ptr() as usize,
+            len: self.hdr_len as u32,
+            flags: VRING_DESC_F_WRITE | VRING_DESC_F_NEXT,
+            next: idx[1] as u16,
+        });
+        self.rx.set_desc(idx[1], VRingDesc {
+            addr: buf.data.as_ptr() as usize,
+            len: FRAME_SIZE as u32,
+            flags: VRING_DESC_F_WRITE,
+            next: 0,
+        });
+        self.rx_bufs[idx[0]] = Some(buf);
+        self.rx.push_avail(idx[0]);
+        true
+    }
+
+    /// Free buffers transmitted
+    fn reap_tx(&mut self) {
+        while let Some(elem) = self.tx.pop_used() {
+            let head = elem.id as usize;
+            self.tx.free_chain(head);
+            self.tx_bufs[head] = None;
+        }
+    }
+}
+
+impl VirtIONet {
+    /// Initialize driver on `transport`. Returns `None` if device can't
+    /// be driven.
+    pub fn init(transport: Transport, irq: u32) -> Option<Self> {
+        let features = transport.begin_init(VIRTIO_NET_F_MAC)?;
+        let rx_num = queue_size(transport.queue_max(RX));
+        let tx_num = queue_size(transport.queue_max(TX));
+        if rx_num < 2 || tx_num < 2 {
+            return None;
+        }
+        let rx = Queue::new(rx_num);
+        let tx = Queue::new(tx_num);
+        transport.setup_queue(RX, &rx);
+        transport.setup_queue(TX, &tx);
+
+        let mac = if features & VIRTIO_NET_F_MAC != 0 {
+            let mut mac = [0; 6];
+            for i in 0..6 {
+                mac[i] = transport.config_u8(i);
+            }
+            MacAddr(mac)
+        } else {
+            DEFAULT_MAC
+        };
+
+        let mut data = NetData {
+            transport,
+            irq,
+            hdr_len: if transport.is_legacy() { HDR_SIZE_LEGACY } else { HDR_SIZE },
+            rx,
+            rx_bufs: (0..rx_num).map(|_| None).collect(),
+            tx,
+            tx_bufs: (0..tx_num).map(|_| None).collect(),
+            mac,
+        };
+        while data.post_rx(NetBuf::new()) {}
+
+        transport.finish_init();
+        transport.notify(RX);
+
+        Some(Self(Mutex::new(data, "virtio net")))
+    }
+
+    /// MAC address of device
+    pub fn mac(&self) -> MacAddr {
+        unsafe { self.0.get().mac }
+    }
+
+    /// Interrupt number of device
+    pub fn irq(&self) -> u32 {
+        unsafe { self.0.get().irq }
+    }
+
+    /// Transmit Ethernet `frame`. Returns false if it's dropped, as transmit
+    /// queue is full.
+    pub fn send(&self, frame: &[u8]) -> bool {
+        if frame.len() > FRAME_SIZE {
+            return false;
+        }
+        let mut buf = NetBuf::new();
+        buf.data[..frame.len()].copy_from_slice(frame);
+        let mut net = self.0.lock();
+        net.reap_tx();
+        let mut idx = [0; 2];
+        if !net.tx.alloc_descs(&mut idx) {
+            return false;
+        }
+        let hdr_len = net.hdr_len;
+        net.tx.set_desc(idx[0], VRingDesc {
+            addr: buf.hdr.as_ptr() as usize,
+            len: hdr_len as u32,
+            flags: VRING_DESC_F_NEXT,
+            next: idx[1] as u16,
+        });
+        net.tx.set_desc(idx[1], VRingDesc {
+            addr: buf.data.as_ptr() as usize,
+            len: frame.len() as u32,
+            flags: 0,
+            next: 0,
+        });
+        net.tx_bufs[idx[0]] = Some(buf);
+        net.tx.push_avail(idx[0]);
+        net.transport.notify(TX);
+        true
+    }
+
+    /// Handle interrupt, passing frames received to `net::receive`
+    pub fn intr(&self) {
+        let mut frames = Vec::new();
+        {
+            let mut net = self.0.lock();
+            net.transport.ack_interrupt();
+            net.reap_tx();
+            let mut received = false;
+            while let Some(elem) = net.rx.pop_used() {
+                let head = elem.id as usize;
+                net.rx.free_chain(head);
+                let buf = net.rx_bufs[head].take().expect("invalid id");
+                let len = (elem.len as usize).saturating_sub(net.hdr_len);
+                frames.push(buf.data[..core::cmp::min(len, FRAME_SIZE)].to_vec());
+                net.post_rx(buf);
+                received = true;
+            }
+            if received {
+                net.transport.notify(RX);
+            }
+        }
+        for frame in frames {
+            crate::net::receive(&frame);
+        }
+    }
+}
+
+/// Driver of first network device
+static mut __NET: Option<VirtIONet> = None;
+
+/// Get driver of network device, if it is present
+#[allow(non_snake_case)]
+pub fn NET() -> Option<&'static VirtIONet> {
+    unsafe { __NET.as_ref() }
+}
+
+/// Initialize network device on `transport` if there isn't one yet.
+/// Returns false if it's not used.
+///
+/// Should be called in booting hart.
+pub unsafe fn init(transport: Transport, irq: u32) -> bool {
+    if __NET.is_some() {
+        return false;
+    }
+    __NET = VirtIONet::init(transport, irq);
+    __NET.is_some()
+}
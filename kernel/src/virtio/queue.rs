@@ -0,0 +1,257 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Split virtqueue in legacy layout
+//!
+//! A queue of `num` descriptors lives in two pages: descriptor table
+//! followed by available ring in the first page, and used ring in the
+//! second one, as legacy devices expect used ring at next `QUEUE_ALIGN`
+//! boundary. Modern devices take addresses of the three parts separately,
+//! so the same layout works for both. Rings are accessed with volatile
+//! operations, as they are shared with device.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use crate::symbols::{PAGE_SIZE, PAGE_ORDER};
+use crate::arch::__sync_synchronize;
+use crate::mem::try_zeroed_box;
+
+/// Maximum number of descriptors, so that rings fit in two pages
+pub const MAX_QUEUE_SIZE: usize = 128;
+
+/// Default number of descriptors
+pub const QUEUE_SIZE: usize = 64;
+
+pub const VRING_DESC_F_NEXT: u16 = 1;
+pub const VRING_DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct VRingDesc {
+    pub addr: usize,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct VRingUsedElem {
+    pub id: u32,
+    pub len: u32,
+}
+
+/// Memory of rings
+#[repr(C)]
+#[repr(align(4096))]
+struct Rings([u8; 2 * PAGE_SIZE]);
+
+/// A virtqueue, and which of its descriptors are free
+pub struct Queue {
+    rings: Box<Rings>,
+    /// number of descriptors
+    num: usize,
+    /// is descriptor free
+    free: Vec<bool>,
+    /// next index of used ring to look at
+    used_idx: u16,
+}
+
+impl Queue {
+    /// Allocate a queue of `num` descriptors, which should be a power of 2
+    /// no more than `MAX_QUEUE_SIZE`
+    pub fn new(num: usize) -> Self {
+        if !num.is_power_of_two() || num > MAX_QUEUE_SIZE {
+            panic!("invalid virtqueue size {}", num);
+        }
+        Self {
+            rings: unsafe { try_zeroed_box() }.expect("virtqueue"),
+            num,
+            free: vec![true; num],
+            used_idx: 0,
+        }
+    }
+
+    /// Number of descriptors
+    pub fn num(&self) -> usize {
+        self.num
+    }
+
+    /// Page number of queue, written to `QUEUE_PFN`
+    pub fn pfn(&self) -> u32 {
+        (self.base() >> PAGE_ORDER) as u32
+    }
+
+    /// Address of descriptor table
+    pub fn desc_addr(&self) -> usize {
+        self.base()
+    }
+
+    /// Address of available ring
+    pub fn avail_addr(&self) -> usize {
+        self.avail_ptr() as usize
+    }
+
+    /// Address of used ring
+    pub fn used_addr(&self) -> usize {
+        self.used_ptr() as usize
+    }
+
+    fn base(&self) -> usize {
+        &*self.rings as *const _ as usize
+    }
+
+    fn desc_ptr(&self, i: usize) -> *mut VRingDesc {
+        (self.base() + i * core::mem::size_of::<VRingDesc>()) as *mut VRingDesc
+    }
+
+    /// Address of available ring, with `flags`, `idx` and `ring` of `u16`
+    fn avail_ptr(&self) -> *mut u16 {
+        (self.base() + self.num * core::mem::size_of::<VRingDesc>()) as *mut u16
+    }
+
+    /// Address of used ring, with `flags` and `idx` of `u16` followed by
+    /// `ring` of `VRingUsedElem`
+    fn used_ptr(&self) -> *mut u16 {
+        (self.base() + PAGE_SIZE) as *mut u16
+    }
+
+    /// Allocate one descriptor
+    pub fn alloc_desc(&mut self) -> Option<usize> {
+        let i = self.free.iter().position(|&free| free)?;
+        self.free[i] = false;
+        Some(i)
+    }
+
+    /// Allocate descriptors into `idx`. Returns false if there are not
+    /// enough, and none is allocated.
+    pub fn alloc_descs(&mut self, idx: &mut [usize]) -> bool {
+        for i in 0..idx.len() {
+            match self.alloc_desc() {
+                Some(x) => idx[i] = x,
+                None => {
+                    for j in 0..i {
+                        self.free_desc(idx[j]);
+                    }
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Free one descriptor
+    pub fn free_desc(&mut self, i: usize) {
+        if i >= self.num {
+            panic!("invalid desc");
+        }
+        if self.free[i] {
+            panic!("already free");
+        }
+        unsafe { self.desc_ptr(i).write_volatile(VRingDesc { addr: 0, len: 0, flags: 0, next: 0 }); }
+        self.free[i] = true;
+    }
+
+    /// Free descriptor chain starting at `head`
+    pub fn free_chain(&mut self, mut i: usize) {
+        loop {
+            let desc = unsafe { self.desc_ptr(i).read_volatile() };
+            self.free_desc(i);
+            if desc.flags & VRING_DESC_F_NEXT != 0 {
+                i = desc.next as usize;
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Fill in descriptor `i`
+    pub fn set_desc(&mut self, i: usize, desc: VRingDesc) {
+        unsafe { self.desc_ptr(i).write_volatile(desc); }
+    }
+
+    /// Make descriptor chain starting at `head` available to device. Device
+    /// should be notified afterwards.
+    pub fn push_avail(&mut self, head: usize) {
+        let avail = self.avail_ptr();
+        unsafe {
+            let idx = avail.add(1).read_volatile();
+            avail.add(2 + idx as usize % self.num).write_volatile(head as u16);
+            // device should see the ring entry before index
+            __sync_synchronize();
+            avail.add(1).write_volatile(idx.wrapping_add(1));
+        }
+    }
+
+    /// Take next element of used ring, if device has used one
+    pub fn pop_used(&mut self) -> Option<VRingUsedElem> {
+        let used = self.used_ptr();
+        unsafe {
+            if used.add(1).read_volatile() == self.used_idx {
+                return None;
+            }
+            __sync_synchronize();
+            let elems = used.add(2) as *const VRingUsedElem;
+            let elem = elems.add(self.used_idx as usize % self.num).read_volatile();
+            self.used_idx = self.used_idx.wrapping_add(1);
+            Some(elem)
+        }
+    }
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("memory layout", test_memory_layout),
+            ("alloc", test_alloc),
+            ("rings", test_rings),
+        ]
+    }
+
+    /// Test rings fit in their pages
+    pub fn test_memory_layout() {
+        let queue = Queue::new(MAX_QUEUE_SIZE);
+        assert_eq!(queue.base() % PAGE_SIZE, 0);
+        let avail_end = queue.avail_ptr() as usize + (3 + MAX_QUEUE_SIZE) * 2;
+        assert!(avail_end <= queue.base() + PAGE_SIZE);
+        let used_end = queue.used_ptr() as usize + 4 + MAX_QUEUE_SIZE * 8 + 2;
+        assert!(used_end <= queue.base() + 2 * PAGE_SIZE);
+    }
+
+    /// Test allocating and freeing descriptors
+    pub fn test_alloc() {
+        let mut queue = Queue::new(4);
+        let mut idx = [0; 3];
+        assert!(queue.alloc_descs(&mut idx));
+        assert!(!queue.alloc_descs(&mut idx));
+        assert_eq!(queue.alloc_desc(), Some(3));
+        queue.set_desc(idx[0], VRingDesc { addr: 0, len: 0, flags: VRING_DESC_F_NEXT, next: idx[1] as u16 });
+        queue.free_chain(idx[0]);
+        assert!(queue.alloc_descs(&mut idx[..2]));
+        assert_eq!(queue.alloc_desc(), None);
+    }
+
+    /// Test available and used rings wrap around
+    pub fn test_rings() {
+        let mut queue = Queue::new(4);
+        for head in 0..6 {
+            queue.push_avail(head % 4);
+        }
+        unsafe {
+            assert_eq!(queue.avail_ptr().add(1).read_volatile(), 6);
+            assert_eq!(queue.avail_ptr().add(2 + 1).read_volatile(), 1);
+        }
+        assert!(queue.pop_used().is_none());
+        unsafe {
+            let elems = queue.used_ptr().add(2) as *mut VRingUsedElem;
+            elems.add(0).write_volatile(VRingUsedElem { id: 2, len: 1 });
+            queue.used_ptr().add(1).write_volatile(1);
+        }
+        assert_eq!(queue.pop_used().map(|e| e.id), Some(2));
+        assert!(queue.pop_used().is_none());
+    }
+}
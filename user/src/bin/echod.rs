@@ -0,0 +1,46 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! TCP echo server on port 7, serving one connection at a time
+
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(global_asm)]
+#![feature(format_args_nl)]
+#![feature(const_generics)]
+
+use user::println;
+use user::syscall::{exit, socket, bind, listen, accept, read, write, close};
+use user::socket::{SockAddrIn, AF_INET, SOCK_STREAM, INADDR_ANY};
+
+const PORT: u16 = 7;
+
+#[no_mangle]
+pub unsafe extern "C" fn _start() -> ! {
+    let fd = socket(AF_INET, SOCK_STREAM, 0);
+    if fd < 0 || bind(fd, &SockAddrIn::new(INADDR_ANY, PORT)) < 0 || listen(fd, 4) < 0 {
+        println!("echod: failed to listen on port {}", PORT);
+        exit(-1);
+    }
+    println!("echod: listening on port {}", PORT);
+    let mut buf = [0; 512];
+    loop {
+        let mut peer = SockAddrIn::default();
+        let conn = accept(fd, Some(&mut peer));
+        if conn < 0 {
+            continue;
+        }
+        let a = peer.addr;
+        println!("echod: connection from {}.{}.{}.{}:{}", a[0], a[1], a[2], a[3], peer.port());
+        loop {
+            let n = read(conn, &mut buf);
+            if n <= 0 || write(conn, &buf[..n as usize]) < 0 {
+                break;
+            }
+        }
+        close(conn);
+    }
+}
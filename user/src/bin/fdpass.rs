@@ -0,0 +1,53 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Pass a file descriptor between processes over a Unix socket. Child sends
+//! its standard output to parent, which writes to it.
+
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(global_asm)]
+#![feature(format_args_nl)]
+#![feature(const_generics)]
+
+use user::println;
+use user::syscall::{exit, fork, close, write, unlink, socket, bind, listen, accept, connect, sendmsg, recvmsg};
+use user::socket::{SockAddrUn, AF_UNIX, SOCK_STREAM};
+use user::constant::STDOUT;
+
+const PATH: &str = "/tmp/fdpass";
+
+#[no_mangle]
+pub unsafe extern "C" fn _start() -> ! {
+    // socket node may be left by an earlier run
+    unlink(PATH);
+    let fd = socket(AF_UNIX, SOCK_STREAM, 0);
+    if fd < 0 || bind(fd, &SockAddrUn::new(PATH)) < 0 || listen(fd, 1) < 0 {
+        println!("fdpass: failed to listen on {}", PATH);
+        exit(-1);
+    }
+    if fork() == 0 {
+        close(fd);
+        let conn = socket(AF_UNIX, SOCK_STREAM, 0);
+        if connect(conn, &SockAddrUn::new(PATH)) < 0 || sendmsg(conn, b"stdout", &[STDOUT]) < 0 {
+            println!("fdpass: failed to send file descriptor");
+            exit(-1);
+        }
+        exit(0);
+    }
+    let conn = accept(fd, None);
+    let mut buf = [0; 16];
+    let mut fds = [-1; 1];
+    let n = recvmsg(conn, &mut buf, &mut fds);
+    if n <= 0 || fds[0] < 0 {
+        println!("fdpass: no file descriptor received");
+        exit(-1);
+    }
+    println!("fdpass: received {} as fd {}", core::str::from_utf8(&buf[..n as usize]).unwrap_or("?"), fds[0]);
+    write(fds[0], b"fdpass: written through file descriptor passed\n");
+    unlink(PATH);
+    exit(0);
+}
@@ -11,7 +11,7 @@
 #![feature(const_generics)]
 
 use user::println;
-use user::syscall::{fork, exec, open, dup};
+use user::syscall::{exit, fork, exec, open, dup};
 
 #[no_mangle]
 pub unsafe extern "C" fn _start() -> ! {
@@ -19,10 +19,19 @@ pub unsafe extern "C" fn _start() -> ! {
     dup(0);
     dup(0);
     println!("ready to fork!");
+    if fork() == 0 {
+        exec("/echod", &["echod"]);
+        exit(-1);
+    }
+    if fork() == 0 {
+        exec("/fdpass", &["fdpass"]);
+        exit(-1);
+    }
     let p = fork();
     if p == 0 {
         println!("calling test1...");
         exec("/test1", &["test1", "test2"]);
+        exit(-1);
     } else {
         loop {}
     }
@@ -8,3 +8,50 @@
 pub const STDIN: i32 = 0;
 pub const STDOUT: i32 = 1;
 pub const STDERR: i32 = 2;
+
+/// Sleep if futex word equals expected value
+pub const FUTEX_WAIT: i32 = 0;
+/// Wake up processes sleeping on futex word
+pub const FUTEX_WAKE: i32 = 1;
+
+/// `open` and `dup3` flag, close file descriptor on `exec`
+pub const O_CLOEXEC: i32 = 0x80000;
+
+/// Close file descriptor on `exec`
+pub const FD_CLOEXEC: i32 = 1;
+
+/// `fcntl` commands
+pub const F_DUPFD: i32 = 0;
+pub const F_GETFD: i32 = 1;
+pub const F_SETFD: i32 = 2;
+pub const F_DUPFD_CLOEXEC: i32 = 1030;
+
+/// `getrlimit` and `setrlimit` resource, number of file descriptors
+pub const RLIMIT_NOFILE: i32 = 7;
+
+/// Returned by syscalls when there's not enough memory
+pub const ENOMEM: i32 = -12;
+/// Returned by syscalls interrupted by a signal
+pub const EINTR: i32 = -4;
+/// Returned by writes on a Unix socket when peer is closed
+pub const EPIPE: i32 = -32;
+/// Returned by `bind`, `listen` and `connect` when address is in use
+pub const EADDRINUSE: i32 = -98;
+/// Returned by socket syscalls when connection is reset by peer
+pub const ECONNRESET: i32 = -104;
+/// Returned by socket syscalls when peer doesn't respond
+pub const ETIMEDOUT: i32 = -110;
+/// Returned by `connect` when peer refuses connection
+pub const ECONNREFUSED: i32 = -111;
+
+/// Signals, same as Linux
+pub const SIGHUP: i32 = 1;
+pub const SIGINT: i32 = 2;
+pub const SIGQUIT: i32 = 3;
+pub const SIGKILL: i32 = 9;
+pub const SIGTERM: i32 = 15;
+pub const SIGCHLD: i32 = 17;
+pub const SIGCONT: i32 = 18;
+pub const SIGSTOP: i32 = 19;
+pub const SIGTSTP: i32 = 20;
+pub const SIGWINCH: i32 = 28;
@@ -11,6 +11,10 @@
 pub mod print;
 pub mod syscall;
 pub mod constant;
+pub mod thread;
+pub mod sync;
+pub mod termios;
+pub mod socket;
 mod syscall_internal;
 
 use core::panic::PanicInfo;
@@ -0,0 +1,104 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Socket addresses and constants, used with `socket` and friends
+
+use core::mem::size_of;
+
+pub const AF_UNIX: i32 = 1;
+pub const AF_INET: i32 = 2;
+
+pub const SOCK_STREAM: i32 = 1;
+pub const SOCK_DGRAM: i32 = 2;
+
+/// Address of all interfaces, for `bind`
+pub const INADDR_ANY: [u8; 4] = [0; 4];
+/// Address of loopback interface
+pub const INADDR_LOOPBACK: [u8; 4] = [127, 0, 0, 1];
+
+/// Length of path in `SockAddrUn`
+pub const UNIX_PATH_MAX: usize = 108;
+
+/// Files passed by `sendmsg` at most
+pub const MAX_FDS: usize = 16;
+
+/// Socket address of a domain, passed to kernel with its size
+pub trait SockAddr {
+    /// Size of address in bytes
+    fn size(&self) -> usize;
+}
+
+/// IPv4 socket address
+#[repr(C)]
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct SockAddrIn {
+    pub family: u16,
+    /// port in network byte order
+    pub port: u16,
+    pub addr: [u8; 4],
+    pub zero: [u8; 8],
+}
+
+impl SockAddrIn {
+    /// Address of `port` on `addr`
+    ///
+    /// # Examples
+    /// ```
+    /// use user::socket::SockAddrIn;
+    /// let gateway = SockAddrIn::new([10, 0, 2, 2], 53);
+    /// ```
+    pub fn new(addr: [u8; 4], port: u16) -> Self {
+        Self { family: AF_INET as u16, port: port.to_be(), addr, zero: [0; 8] }
+    }
+
+    /// Port in host byte order
+    pub fn port(&self) -> u16 {
+        u16::from_be(self.port)
+    }
+}
+
+impl SockAddr for SockAddrIn {
+    fn size(&self) -> usize {
+        size_of::<Self>()
+    }
+}
+
+/// Unix domain socket address, naming a socket by path
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SockAddrUn {
+    pub family: u16,
+    /// path ending with NUL, unless it fills the array
+    pub path: [u8; UNIX_PATH_MAX],
+}
+
+impl SockAddrUn {
+    /// Address of `path`, which is truncated to `UNIX_PATH_MAX` bytes.
+    /// Empty path means an unnamed socket.
+    ///
+    /// # Examples
+    /// ```
+    /// use user::socket::SockAddrUn;
+    /// let addr = SockAddrUn::new("/tmp/server");
+    /// ```
+    pub fn new(path: &str) -> Self {
+        let mut addr = Self { family: AF_UNIX as u16, path: [0; UNIX_PATH_MAX] };
+        let n = core::cmp::min(path.len(), UNIX_PATH_MAX);
+        addr.path[..n].copy_from_slice(&path.as_bytes()[..n]);
+        addr
+    }
+
+    /// Path, empty for an unnamed socket
+    pub fn path(&self) -> &str {
+        let len = self.path.iter().position(|&c| c == 0).unwrap_or(UNIX_PATH_MAX);
+        core::str::from_utf8(&self.path[..len]).unwrap_or("")
+    }
+}
+
+impl SockAddr for SockAddrUn {
+    fn size(&self) -> usize {
+        size_of::<Self>()
+    }
+}
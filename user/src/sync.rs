@@ -0,0 +1,175 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Synchronization primitives for threads, built on futex
+//!
+//! ## Examples
+//!
+//! ```
+//! use user::sync::{Mutex, Condvar};
+//! static COUNTER: Mutex<usize> = Mutex::new(0);
+//! static CHANGED: Condvar = Condvar::new();
+//! *COUNTER.lock() += 1;
+//! CHANGED.notify_all();
+//! ```
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::syscall::futex;
+use crate::constant::{FUTEX_WAIT, FUTEX_WAKE};
+
+/// Mutex not locked
+const UNLOCKED: u32 = 0;
+/// Mutex locked, no thread waiting
+const LOCKED: u32 = 1;
+/// Mutex locked, and there may be threads waiting
+const CONTENDED: u32 = 2;
+
+/// A mutual exclusion lock which puts waiting threads into sleep
+pub struct Mutex<T> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Acquire the lock, sleeping until it's available
+    pub fn lock(&self) -> MutexGuard<T> {
+        if self.state.compare_and_swap(UNLOCKED, LOCKED, Ordering::Acquire) != UNLOCKED {
+            // mark as contended, so that unlocking thread will wake us up
+            while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+                futex(&self.state, FUTEX_WAIT, CONTENDED);
+            }
+        }
+        MutexGuard { mutex: self }
+    }
+
+    /// Acquire the lock if it's available
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if self.state.compare_and_swap(UNLOCKED, LOCKED, Ordering::Acquire) == UNLOCKED {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            futex(&self.state, FUTEX_WAKE, 1);
+        }
+    }
+}
+
+/// Lock guard of `Mutex`, which releases the lock on drop
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// A condition variable
+///
+/// `seq` is increased on every notification. Waiting threads sleep until
+/// it differs from what they saw before releasing the mutex.
+pub struct Condvar {
+    seq: AtomicU32,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self { seq: AtomicU32::new(0) }
+    }
+
+    /// Release `guard` and sleep until notified, then acquire the lock again.
+    ///
+    /// Spurious wakeups are possible, so callers should check their condition in a loop.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let seq = self.seq.load(Ordering::Relaxed);
+        let mutex = guard.mutex;
+        drop(guard);
+        futex(&self.seq, FUTEX_WAIT, seq);
+        mutex.lock()
+    }
+
+    /// Wake up one waiting thread
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        futex(&self.seq, FUTEX_WAKE, 1);
+    }
+
+    /// Wake up all waiting threads
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        futex(&self.seq, FUTEX_WAKE, u32::MAX);
+    }
+}
+
+/// `Once` not yet run
+const INCOMPLETE: u32 = 0;
+/// Some thread is running initialization
+const RUNNING: u32 = 1;
+/// Initialization done
+const COMPLETE: u32 = 2;
+
+/// Run an initialization exactly once across all threads
+pub struct Once {
+    state: AtomicU32,
+}
+
+impl Once {
+    pub const fn new() -> Self {
+        Self { state: AtomicU32::new(INCOMPLETE) }
+    }
+
+    /// Run `f` if no thread has run it. Other threads calling `call_once`
+    /// sleep until `f` returns.
+    pub fn call_once<F: FnOnce()>(&self, f: F) {
+        match self.state.compare_and_swap(INCOMPLETE, RUNNING, Ordering::Acquire) {
+            INCOMPLETE => {
+                f();
+                self.state.store(COMPLETE, Ordering::Release);
+                futex(&self.state, FUTEX_WAKE, u32::MAX);
+            }
+            _ => {
+                while self.state.load(Ordering::Acquire) == RUNNING {
+                    futex(&self.state, FUTEX_WAIT, RUNNING);
+                }
+            }
+        }
+    }
+
+    /// Whether initialization is done
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
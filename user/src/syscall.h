@@ -22,3 +22,25 @@
 #define SYS_sbrk 18
 #define SYS_sleep 19
 #define SYS_uptime 20
+#define SYS_sched_setaffinity 21
+#define SYS_sched_getaffinity 22
+#define SYS_clone 23
+#define SYS_join 24
+#define SYS_futex 25
+#define SYS_dup2 26
+#define SYS_dup3 27
+#define SYS_fcntl 28
+#define SYS_ioctl 29
+#define SYS_setpgid 30
+#define SYS_getpgid 31
+#define SYS_socket 32
+#define SYS_bind 33
+#define SYS_listen 34
+#define SYS_accept 35
+#define SYS_connect 36
+#define SYS_sendto 37
+#define SYS_recvfrom 38
+#define SYS_sendmsg 39
+#define SYS_recvmsg 40
+#define SYS_getrlimit 41
+#define SYS_setrlimit 42
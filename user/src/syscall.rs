@@ -14,7 +14,10 @@
 //! Usage of syscalls is listed in their corresponding sub-page.
 
 use crate::syscall_internal::*;
-use core::ptr::null;
+use core::ptr::{null, null_mut};
+use core::sync::atomic::AtomicU32;
+use crate::termios::{Termios, TCGETS, TCSETS, TIOCGPGRP, TIOCSPGRP, TIOCGPTN};
+use crate::socket::SockAddr;
 
 /// Exit current process with exit code `code`.
 /// 
@@ -53,14 +56,14 @@ pub const EXEC_MAX_ARGS: usize = 10;
 /// Replace current process image with the new one
 /// in the filesystem.
 ///
-/// This function will not return.
+/// This function only returns on error, for example `ENOMEM`.
 ///
 /// # Examples
 /// ```
 /// use user::syscall::exec;
 /// exec("/init", &[]);
 /// ```
-pub fn exec(path: &str, args: &[&str]) -> ! {
+pub fn exec(path: &str, args: &[&str]) -> i32 {
     let arg_cnt = args.len();
     let mut args_sz = [0; EXEC_MAX_ARGS];
     let mut args_ptr = [null(); EXEC_MAX_ARGS];
@@ -135,6 +138,17 @@ pub fn close(fd: i32) -> i32 {
     unsafe { __close(fd) }
 }
 
+/// Remove socket node at `path`. Files on disk can't be removed.
+///
+/// # Examples
+/// ```
+/// use user::syscall::unlink;
+/// unlink("/tmp/log");
+/// ```
+pub fn unlink(path: &str) -> i32 {
+    unsafe { __unlink(path.as_ptr(), path.len() as i32) }
+}
+
 /// Duplicate file descriptor `fd`.
 ///
 /// Returns new file descriptor.
@@ -152,3 +166,372 @@ pub fn dup(fd: i32) -> i32 {
 pub fn wait(pid: i32) -> i32 {
     unsafe { __wait(pid) }
 }
+
+/// Pin process `pid` to harts set in bitmask `mask`.
+///
+/// `pid` of 0 refers to the calling process. Harts which are not online
+/// are ignored. Returns 0 on success, and a negative value if no online
+/// hart is left in `mask`, or if there's no process `pid`. A process
+/// running on another hart moves when it's scheduled next time.
+///
+/// # Examples
+/// ```
+/// use user::syscall::sched_setaffinity;
+/// // only run on hart 1
+/// sched_setaffinity(0, 1 << 1);
+/// ```
+pub fn sched_setaffinity(pid: i32, mask: usize) -> i32 {
+    unsafe { __sched_setaffinity(pid, mask) }
+}
+
+/// Get bitmask of harts process `pid` may run on.
+///
+/// `pid` of 0 refers to the calling process. A negative return value means error.
+///
+/// # Examples
+/// ```
+/// use user::syscall::sched_getaffinity;
+/// let mask = sched_getaffinity(0);
+/// ```
+pub fn sched_getaffinity(pid: i32) -> i32 {
+    unsafe { __sched_getaffinity(pid) }
+}
+
+/// Create a thread sharing address space and files with the calling process.
+///
+/// The thread starts at `entry` with `arg0` and `arg1` as its first two
+/// arguments, on a newly allocated stack. Returns thread id, or a negative
+/// value on error. `entry` should never return, and should call `exit` instead.
+/// See `thread::spawn` for a safe wrapper.
+pub fn clone(entry: extern "C" fn(usize, usize) -> !, arg0: usize, arg1: usize) -> i32 {
+    unsafe { __clone(entry as usize, arg0, arg1) }
+}
+
+/// Wait for thread `tid` to exit, and return its exit code.
+///
+/// Returns `None` if `tid` is not a thread of the calling process.
+pub fn join(tid: i32) -> Option<i32> {
+    let mut code = 0;
+    if unsafe { __join(tid, &mut code) } < 0 {
+        None
+    } else {
+        Some(code)
+    }
+}
+
+/// Wait on or wake up processes waiting on futex word `word`.
+///
+/// With `FUTEX_WAIT`, sleep if `word` still equals `val`, and return a
+/// negative value immediately otherwise. With `FUTEX_WAKE`, wake up at most
+/// `val` waiters and return the number woken. See `sync` module for
+/// primitives built on it.
+///
+/// # Examples
+/// ```
+/// use core::sync::atomic::AtomicU32;
+/// use user::syscall::futex;
+/// use user::constant::FUTEX_WAKE;
+/// static WORD: AtomicU32 = AtomicU32::new(0);
+/// futex(&WORD, FUTEX_WAKE, 1);
+/// ```
+pub fn futex(word: &AtomicU32, op: i32, val: u32) -> i32 {
+    unsafe { __futex(word as *const AtomicU32 as *const u32, op, val) }
+}
+
+/// Duplicate file descriptor `old_fd` to `new_fd`, closing `new_fd` first if it's open.
+///
+/// `FD_CLOEXEC` is cleared on `new_fd`. Returns `new_fd`, or a negative value on error.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{open, dup2};
+/// use user::constant::STDOUT;
+/// let fd = open("/console", 0);
+/// dup2(fd, STDOUT);
+/// ```
+pub fn dup2(old_fd: i32, new_fd: i32) -> i32 {
+    unsafe { __dup2(old_fd, new_fd) }
+}
+
+/// Same as `dup2`, but `FD_CLOEXEC` is set on `new_fd` if `flags` contains `O_CLOEXEC`.
+///
+/// Fails if `old_fd` equals `new_fd`.
+pub fn dup3(old_fd: i32, new_fd: i32, flags: i32) -> i32 {
+    unsafe { __dup3(old_fd, new_fd, flags) }
+}
+
+/// Manipulate file descriptor `fd` with command `cmd`.
+///
+/// Supported commands are `F_DUPFD`, `F_DUPFD_CLOEXEC`, `F_GETFD` and `F_SETFD`.
+///
+/// # Examples
+/// ```
+/// use user::syscall::fcntl;
+/// use user::constant::{F_SETFD, FD_CLOEXEC};
+/// fcntl(3, F_SETFD, FD_CLOEXEC as usize);
+/// ```
+pub fn fcntl(fd: i32, cmd: i32, arg: usize) -> i32 {
+    unsafe { __fcntl(fd, cmd, arg) }
+}
+
+/// Get limit of `resource`. Only `RLIMIT_NOFILE` is supported.
+pub fn getrlimit(resource: i32) -> i32 {
+    unsafe { __getrlimit(resource) }
+}
+
+/// Set limit of `resource` to `limit`. File descriptors are limited to 256
+/// at most, and limit can't be lowered below an open descriptor.
+///
+/// # Examples
+/// ```
+/// use user::syscall::setrlimit;
+/// use user::constant::RLIMIT_NOFILE;
+/// setrlimit(RLIMIT_NOFILE, 16);
+/// ```
+pub fn setrlimit(resource: i32, limit: usize) -> i32 {
+    unsafe { __setrlimit(resource, limit) }
+}
+
+/// Grow user heap by `increment` bytes, or shrink it if `increment` is negative.
+///
+/// Returns old end of heap, `ENOMEM` if there's not enough memory, or another
+/// negative value if the new end is out of heap region.
+///
+/// # Examples
+/// ```
+/// use user::syscall::sbrk;
+/// let heap = sbrk(4096);
+/// ```
+pub fn sbrk(increment: isize) -> i32 {
+    unsafe { __sbrk(increment) }
+}
+
+/// Send signal `sig` to process `pid` if `pid` > 0, to process group `-pid`
+/// if `pid` < 0, or to process group of caller if `pid` = 0.
+///
+/// Returns 0, or a negative value if there's no such process or signal.
+///
+/// # Examples
+/// ```
+/// use user::syscall::kill;
+/// use user::constant::SIGTERM;
+/// kill(2, SIGTERM);
+/// ```
+pub fn kill(pid: i32, sig: i32) -> i32 {
+    unsafe { __kill(pid, sig) }
+}
+
+/// Move process `pid` (caller if 0) to process group `pgid` (`pid` if 0).
+pub fn setpgid(pid: i32, pgid: i32) -> i32 {
+    unsafe { __setpgid(pid, pgid) }
+}
+
+/// Get process group of process `pid`, or of caller if `pid` is 0.
+pub fn getpgid(pid: i32) -> i32 {
+    unsafe { __getpgid(pid) }
+}
+
+/// Device-specific control of file descriptor `fd`.
+///
+/// `arg` points to argument of command `cmd`. Only terminal commands in
+/// `termios` module are supported, on `/console`.
+pub fn ioctl(fd: i32, cmd: u32, arg: *mut u8) -> i32 {
+    unsafe { __ioctl(fd, cmd, arg) }
+}
+
+/// Get terminal settings of `fd`.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{tcgetattr, tcsetattr};
+/// use user::termios::{Termios, ICANON, ECHO};
+/// let mut termios = Termios::default();
+/// tcgetattr(0, &mut termios);
+/// termios.lflag &= !(ICANON | ECHO);
+/// tcsetattr(0, &termios);
+/// ```
+pub fn tcgetattr(fd: i32, termios: &mut Termios) -> i32 {
+    ioctl(fd, TCGETS, termios as *mut Termios as *mut u8)
+}
+
+/// Set terminal settings of `fd`, taking effect immediately.
+pub fn tcsetattr(fd: i32, termios: &Termios) -> i32 {
+    ioctl(fd, TCSETS, termios as *const Termios as *mut u8)
+}
+
+/// Get foreground process group of terminal `fd`.
+pub fn tcgetpgrp(fd: i32) -> i32 {
+    let mut pgid = 0;
+    match ioctl(fd, TIOCGPGRP, &mut pgid as *mut i32 as *mut u8) {
+        0 => pgid,
+        err => err
+    }
+}
+
+/// Set foreground process group of terminal `fd`, to which signals
+/// generated by `^C`, `^\` and `^Z` are sent.
+pub fn tcsetpgrp(fd: i32, pgid: i32) -> i32 {
+    let mut pgid = pgid;
+    ioctl(fd, TIOCSPGRP, &mut pgid as *mut i32 as *mut u8)
+}
+
+/// Open a pseudo-terminal pair, storing master in `fds[0]` and slave in
+/// `fds[1]`.
+///
+/// What's written to master is input of slave, and output of slave is
+/// read from master. Returns 0, or a negative value on error.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{openpty, write};
+/// let mut fds = [0; 2];
+/// openpty(&mut fds);
+/// write(fds[0], b"ls\n");
+/// ```
+pub fn openpty(fds: &mut [i32; 2]) -> i32 {
+    let master = open("/dev/ptmx", 0);
+    if master < 0 {
+        return master;
+    }
+    let mut n = 0;
+    if ioctl(master, TIOCGPTN, &mut n as *mut i32 as *mut u8) < 0 {
+        close(master);
+        return -1;
+    }
+    // "/dev/pts/" followed by decimal digits of `n`
+    let mut path = *b"/dev/pts/0000000000";
+    let mut len = 9;
+    let mut div = 1;
+    while n / div >= 10 {
+        div *= 10;
+    }
+    while div > 0 {
+        path[len] = b'0' + (n / div % 10) as u8;
+        len += 1;
+        div /= 10;
+    }
+    let slave = open(unsafe { core::str::from_utf8_unchecked(&path[..len]) }, 0);
+    if slave < 0 {
+        close(master);
+        return slave;
+    }
+    fds[0] = master;
+    fds[1] = slave;
+    0
+}
+
+/// Create a socket of `domain` and type `ty`. `protocol` is ignored.
+///
+/// `AF_INET` with `SOCK_STREAM` (TCP) and `SOCK_DGRAM` (UDP), and `AF_UNIX`
+/// with both types are supported. Returns file descriptor, on which `read`, `write` and `close`
+/// also work, or a negative value on error.
+///
+/// # Examples
+/// ```
+/// use user::syscall::socket;
+/// use user::socket::{AF_INET, SOCK_STREAM};
+/// let fd = socket(AF_INET, SOCK_STREAM, 0);
+/// ```
+pub fn socket(domain: i32, ty: i32, protocol: i32) -> i32 {
+    unsafe { __socket(domain, ty, protocol) }
+}
+
+/// Bind socket `fd` to local `addr`, which is `SockAddrIn` or `SockAddrUn`.
+///
+/// Returns `EADDRINUSE` if port or path is in use. Binding a path creates a
+/// socket node, which stays after socket is closed until it's removed with
+/// `unlink`.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{socket, bind};
+/// use user::socket::{SockAddrUn, AF_UNIX, SOCK_DGRAM};
+/// let fd = socket(AF_UNIX, SOCK_DGRAM, 0);
+/// bind(fd, &SockAddrUn::new("/tmp/log"));
+/// ```
+pub fn bind(fd: i32, addr: &dyn SockAddr) -> i32 {
+    unsafe { __bind(fd, addr as *const dyn SockAddr as *const u8, addr.size() as i32) }
+}
+
+/// Listen for connections on stream socket `fd`, at most `backlog` of which
+/// wait for `accept`. Unix sockets must be bound first.
+pub fn listen(fd: i32, backlog: i32) -> i32 {
+    unsafe { __listen(fd, backlog) }
+}
+
+/// Wait for a connection on listening socket `fd`.
+///
+/// Returns file descriptor of the connection, and stores address of peer in
+/// `addr` if it's given.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{socket, bind, listen, accept};
+/// use user::socket::{SockAddrIn, AF_INET, SOCK_STREAM, INADDR_ANY};
+/// let fd = socket(AF_INET, SOCK_STREAM, 0);
+/// bind(fd, &SockAddrIn::new(INADDR_ANY, 7));
+/// listen(fd, 4);
+/// let conn = accept(fd, None);
+/// ```
+pub fn accept(fd: i32, addr: Option<&mut dyn SockAddr>) -> i32 {
+    let len = addr.as_ref().map_or(0, |addr| addr.size() as i32);
+    unsafe { __accept(fd, addr.map_or(null_mut(), |addr| addr as *mut dyn SockAddr as *mut u8), len) }
+}
+
+/// Connect socket `fd` to `addr`.
+///
+/// TCP sockets wait until connection is established, and get
+/// `ECONNREFUSED` or `ETIMEDOUT` on failure. Unix stream sockets are
+/// connected without waiting for `accept`. Datagram sockets only remember
+/// `addr` as destination of `write`.
+pub fn connect(fd: i32, addr: &dyn SockAddr) -> i32 {
+    unsafe { __connect(fd, addr as *const dyn SockAddr as *const u8, addr.size() as i32) }
+}
+
+/// Send `content` on socket `fd` to `addr`, or to peer if it's `None`.
+///
+/// Returns number of bytes sent, or a negative value on error.
+pub fn sendto(fd: i32, content: &[u8], addr: Option<&dyn SockAddr>) -> i32 {
+    let len = addr.map_or(0, |addr| addr.size() as i32);
+    unsafe { __sendto(fd, content.as_ptr(), content.len() as i32, addr.map_or(null(), |addr| addr as *const dyn SockAddr as *const u8), len) }
+}
+
+/// Receive into `content` from socket `fd`, storing address of sender in
+/// `addr` if it's given.
+///
+/// Returns number of bytes received, 0 if peer closes TCP connection, or a
+/// negative value on error. Part of a datagram not fitting in `content` is
+/// discarded.
+pub fn recvfrom(fd: i32, content: &mut [u8], addr: Option<&mut dyn SockAddr>) -> i32 {
+    let len = addr.as_ref().map_or(0, |addr| addr.size() as i32);
+    unsafe { __recvfrom(fd, content.as_mut_ptr(), content.len() as i32, addr.map_or(null_mut(), |addr| addr as *mut dyn SockAddr as *mut u8), len) }
+}
+
+/// Send `content` on connected Unix socket `fd`, passing files of
+/// descriptors `fds` along, at most `MAX_FDS` of them.
+///
+/// Files stay open until they're received, even if `fds` are closed. On
+/// stream sockets, `content` can't be empty if `fds` isn't.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{socket, connect, open, sendmsg};
+/// use user::socket::{SockAddrUn, AF_UNIX, SOCK_STREAM};
+/// let fd = socket(AF_UNIX, SOCK_STREAM, 0);
+/// connect(fd, &SockAddrUn::new("/tmp/server"));
+/// let console = open("/console", 0);
+/// sendmsg(fd, b"console", &[console]);
+/// ```
+pub fn sendmsg(fd: i32, content: &[u8], fds: &[i32]) -> i32 {
+    unsafe { __sendmsg(fd, content.as_ptr(), content.len() as i32, fds.as_ptr(), fds.len() as i32) }
+}
+
+/// Receive into `content` from socket `fd`, storing new descriptors of
+/// files passed in `fds`.
+///
+/// Slots of `fds` left are set to -1, and files not fitting are closed.
+/// On stream sockets, files come with the first byte of the message they're
+/// sent with. Returns number of bytes received.
+pub fn recvmsg(fd: i32, content: &mut [u8], fds: &mut [i32]) -> i32 {
+    unsafe { __recvmsg(fd, content.as_mut_ptr(), content.len() as i32, fds.as_mut_ptr(), fds.len() as i32) }
+}
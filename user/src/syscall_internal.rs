@@ -17,9 +17,34 @@ extern "C" {
     pub fn __read(fd: i32, content: *mut u8, sz: i32) -> i32;
     pub fn __exit(code: i32) -> !;
     pub fn __fork() -> i32;
-    pub fn __exec(path: *const u8, path_sz: i32, arg_cnt: i32, args: *const *const u8, args_sz: *const i32) -> !;
+    pub fn __exec(path: *const u8, path_sz: i32, arg_cnt: i32, args: *const *const u8, args_sz: *const i32) -> i32;
     pub fn __open(path: *const u8, sz: i32, mode: i32) -> i32;
     pub fn __close(fd: i32) -> i32;
+    pub fn __unlink(path: *const u8, sz: i32) -> i32;
     pub fn __dup(fd: i32) -> i32;
     pub fn __wait(pid: i32) -> i32;
+    pub fn __sched_setaffinity(pid: i32, mask: usize) -> i32;
+    pub fn __sched_getaffinity(pid: i32) -> i32;
+    pub fn __clone(entry: usize, arg0: usize, arg1: usize) -> i32;
+    pub fn __join(tid: i32, code: *mut i32) -> i32;
+    pub fn __futex(addr: *const u32, op: i32, val: u32) -> i32;
+    pub fn __dup2(old_fd: i32, new_fd: i32) -> i32;
+    pub fn __dup3(old_fd: i32, new_fd: i32, flags: i32) -> i32;
+    pub fn __fcntl(fd: i32, cmd: i32, arg: usize) -> i32;
+    pub fn __sbrk(increment: isize) -> i32;
+    pub fn __kill(pid: i32, sig: i32) -> i32;
+    pub fn __ioctl(fd: i32, cmd: u32, arg: *mut u8) -> i32;
+    pub fn __setpgid(pid: i32, pgid: i32) -> i32;
+    pub fn __getpgid(pid: i32) -> i32;
+    pub fn __socket(domain: i32, ty: i32, protocol: i32) -> i32;
+    pub fn __bind(fd: i32, addr: *const u8, len: i32) -> i32;
+    pub fn __listen(fd: i32, backlog: i32) -> i32;
+    pub fn __accept(fd: i32, addr: *mut u8, len: i32) -> i32;
+    pub fn __connect(fd: i32, addr: *const u8, len: i32) -> i32;
+    pub fn __sendto(fd: i32, content: *const u8, sz: i32, addr: *const u8, len: i32) -> i32;
+    pub fn __recvfrom(fd: i32, content: *mut u8, sz: i32, addr: *mut u8, len: i32) -> i32;
+    pub fn __sendmsg(fd: i32, content: *const u8, sz: i32, fds: *const i32, nfds: i32) -> i32;
+    pub fn __recvmsg(fd: i32, content: *mut u8, sz: i32, fds: *mut i32, nfds: i32) -> i32;
+    pub fn __getrlimit(resource: i32) -> i32;
+    pub fn __setrlimit(resource: i32, limit: usize) -> i32;
 }
@@ -0,0 +1,50 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Terminal settings, used with `tcgetattr` and `tcsetattr`
+
+/// Number of control characters
+pub const NCCS: usize = 16;
+
+/// Indices of control characters
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const VSUSP: usize = 10;
+
+/// Input flag: translate carriage return to newline
+pub const ICRNL: u32 = 0o400;
+
+/// Local flag: generate signals on `VINTR`, `VQUIT` and `VSUSP`
+pub const ISIG: u32 = 0o1;
+/// Local flag: canonical mode, input is available line by line
+pub const ICANON: u32 = 0o2;
+/// Local flag: echo input
+pub const ECHO: u32 = 0o10;
+
+/// `ioctl` commands
+pub const TCGETS: u32 = 0x5401;
+pub const TCSETS: u32 = 0x5402;
+pub const TIOCGPGRP: u32 = 0x540f;
+pub const TIOCSPGRP: u32 = 0x5410;
+/// Get index `N` of pseudo-terminal `/dev/pts/N`, on master
+pub const TIOCGPTN: u32 = 0x80045430;
+
+/// Terminal settings
+///
+/// In raw mode (without `ICANON`), `read` returns when `cc[VMIN]`
+/// characters are available, or `cc[VTIME]` tenths of a second pass.
+#[repr(C)]
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Termios {
+    pub iflag: u32,
+    pub lflag: u32,
+    /// Control characters, 0 if disabled
+    pub cc: [u8; NCCS],
+}
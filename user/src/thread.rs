@@ -0,0 +1,59 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Threads sharing address space with the calling process
+//!
+//! ## Examples
+//!
+//! ```
+//! use user::thread;
+//! fn work(arg: usize) -> i32 {
+//!     arg as i32 * 2
+//! }
+//! let handle = thread::spawn(work, 21).unwrap();
+//! assert_eq!(handle.join(), Some(42));
+//! ```
+
+use crate::syscall;
+
+/// Handle of a spawned thread
+pub struct JoinHandle {
+    tid: i32,
+}
+
+impl JoinHandle {
+    /// Thread id of this thread
+    pub fn tid(&self) -> i32 {
+        self.tid
+    }
+
+    /// Wait for this thread to exit, and return its exit code
+    pub fn join(self) -> Option<i32> {
+        syscall::join(self.tid)
+    }
+}
+
+/// Entry of all threads, `f` is the function to run
+extern "C" fn thread_start(f: usize, arg: usize) -> ! {
+    let f: fn(usize) -> i32 = unsafe { core::mem::transmute(f) };
+    syscall::exit(f(arg))
+}
+
+/// Run `f(arg)` in a new thread. Exit code of the thread is return value of `f`.
+///
+/// Returns `None` if no more thread can be created.
+pub fn spawn(f: fn(usize) -> i32, arg: usize) -> Option<JoinHandle> {
+    let tid = syscall::clone(thread_start, f as usize, arg);
+    if tid < 0 {
+        None
+    } else {
+        Some(JoinHandle { tid })
+    }
+}
+
+/// Exit the calling thread with exit code `code`
+pub fn exit(code: i32) -> ! {
+    syscall::exit(code)
+}
@@ -0,0 +1,15 @@
+#!/usr/bin/env python3
+
+### Copyright (c) 2020 Alex Chi
+### 
+### This software is released under the MIT License.
+### https://opensource.org/licenses/MIT
+
+from symbols import params
+
+print("""// This file is automatically generated with `param.h.py`,
+// which contains constants shared by assembly and Rust.
+""")
+
+for (name, value) in params.items():
+    print(f"#define {name} {value}")
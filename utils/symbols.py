@@ -13,3 +13,10 @@ symbols = [
     "KERNEL_STACK_END",
     "TRAMPOLINE_TEXT_START"
 ]
+
+# Constants shared by assembly and Rust
+params = {
+    # Maximum number of harts. Harts with larger id are parked at boot, as
+    # kernel stack region in linker script only holds this many stacks.
+    "NCPUS": 8,
+}
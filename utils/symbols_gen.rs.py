@@ -23,10 +23,12 @@ print("""//! This module is automatically generated with `symbols_gen.rs.py`,
 #![allow(non_snake_case)]
 """)
 
-from symbols import symbols
+from symbols import symbols, params
 
 for symbol in symbols:
     # print("symbol! { __%s, %s, usize }" % (symbol, symbol))
     print("extern \"C\" { static __%s: usize; }" % symbol.lower())
     print("#[inline] pub fn %s() -> usize { unsafe { &__%s as *const _ as _ } }" % (symbol, symbol.lower()))
-    
\ No newline at end of file
+    
+for (name, value) in params.items():
+    print("pub const %s: usize = %s;" % (name, value))
@@ -19,5 +19,27 @@ syscalls = [
     "getpid",
     "sbrk",
     "sleep",
-    "uptime"
+    "uptime",
+    "sched_setaffinity",
+    "sched_getaffinity",
+    "clone",
+    "join",
+    "futex",
+    "dup2",
+    "dup3",
+    "fcntl",
+    "ioctl",
+    "setpgid",
+    "getpgid",
+    "socket",
+    "bind",
+    "listen",
+    "accept",
+    "connect",
+    "sendto",
+    "recvfrom",
+    "sendmsg",
+    "recvmsg",
+    "getrlimit",
+    "setrlimit"
 ]